@@ -4,6 +4,7 @@ use std::str::FromStr;
 use knuffel::errors::DecodeError;
 use miette::{miette, IntoDiagnostic as _};
 use smithay::backend::renderer::Color32F;
+use smithay::utils::{Logical, Point, Size};
 
 use crate::utils::{Flag, MergeWith};
 use crate::FloatOrInt;
@@ -218,6 +219,25 @@ impl CornerRadius {
         self
     }
 
+    /// Returns these corners with negative and non-finite radii replaced by zero.
+    ///
+    /// Animations can overshoot below zero, which the rounding shader can't handle.
+    pub fn sanitized(self) -> Self {
+        let sanitize = |radius: f32| {
+            if radius.is_finite() {
+                radius.max(0.)
+            } else {
+                0.
+            }
+        };
+        Self {
+            top_left: sanitize(self.top_left),
+            top_right: sanitize(self.top_right),
+            bottom_right: sanitize(self.bottom_right),
+            bottom_left: sanitize(self.bottom_left),
+        }
+    }
+
     pub fn scaled_by(self, scale: f32) -> Self {
         Self {
             top_left: self.top_left * scale,
@@ -226,6 +246,38 @@ impl CornerRadius {
             bottom_left: self.bottom_left * scale,
         }
     }
+
+    /// Returns whether `point` is inside a rectangle of `size` with these corners.
+    ///
+    /// This uses the same corner test as the rounding shader: a point within a corner's radius
+    /// along both axes is inside only if it's within the radius of the corner circle's center.
+    /// Points exactly on the circle, which the shader draws half-transparent, count as inside.
+    pub fn contains(self, point: Point<f64, Logical>, size: Size<f64, Logical>) -> bool {
+        let Point { x, y, .. } = point;
+        let Size { w, h, .. } = size;
+        if !(0. <= x && x < w && 0. <= y && y < h) {
+            return false;
+        }
+
+        let (radius, center) = if x < f64::from(self.top_left) && y < f64::from(self.top_left) {
+            let r = f64::from(self.top_left);
+            (r, (r, r))
+        } else if w - f64::from(self.top_right) < x && y < f64::from(self.top_right) {
+            let r = f64::from(self.top_right);
+            (r, (w - r, r))
+        } else if w - f64::from(self.bottom_right) < x && h - f64::from(self.bottom_right) < y {
+            let r = f64::from(self.bottom_right);
+            (r, (w - r, h - r))
+        } else if x < f64::from(self.bottom_left) && h - f64::from(self.bottom_left) < y {
+            let r = f64::from(self.bottom_left);
+            (r, (r, h - r))
+        } else {
+            return true;
+        };
+
+        let (dx, dy) = (x - center.0, y - center.1);
+        dx * dx + dy * dy <= radius * radius
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -1011,8 +1063,44 @@ pub struct Blur {
     pub off: bool,
     pub passes: u8,
     pub offset: f64,
+    /// Unit of `offset`.
+    pub offset_unit: BlurOffsetUnit,
+    /// Factor to multiply `offset` by for every pass after the first.
+    pub offset_growth: f64,
+    /// Filter for the final up pass.
+    pub upsample: BlurUpsample,
+    /// Weighting of the samples in the blur passes.
+    pub algorithm: BlurAlgorithm,
+    /// Color difference that the bilateral algorithm preserves edges above.
+    pub range_sigma: f64,
     pub noise: f64,
+    /// Blur radius in pixels above which the noise fades out, 0 to disable.
+    pub noise_falloff: f64,
     pub saturation: f64,
+    /// Saturation for all background effects, blurred or not, unless overridden by a rule.
+    pub default_saturation: Option<f64>,
+    /// Temporarily reduce passes when frames take longer than the refresh interval.
+    pub adaptive_passes: bool,
+    /// Limit on the total size of background effect textures, in MiB.
+    pub texture_budget_mib: Option<u32>,
+    /// How much of the previous frame's blur to mix into the current one, 0 to disable.
+    pub temporal_blend: f64,
+    /// Effect regions with more rects than this are drawn as their bounding box, 0 to disable.
+    pub max_region_rects: u32,
+    /// Limit on the blur radius as a fraction of the smaller side of the blurred area.
+    pub max_relative_radius: Option<f64>,
+    /// Snap non-xray effects to whole physical pixels to avoid shimmering as they move.
+    pub pixel_snap: bool,
+    /// Color to fill the off-screen part of non-xray effects with before blurring.
+    pub offscreen_color: Option<Color>,
+    /// Fraction of its size that a non-xray effect texture can shrink by and still be reused.
+    pub texture_shrink_threshold: f64,
+    /// Round non-xray effect texture sizes up to powers of two, so effects can share textures.
+    pub power_of_two_textures: bool,
+    /// Limit on the width and height of non-xray effect textures, 0 to disable.
+    pub max_texture_size: u32,
+    /// Turn off all background effects, overriding window rules and client requests.
+    pub reduce_effects: bool,
 }
 
 impl Default for Blur {
@@ -1021,12 +1109,59 @@ impl Default for Blur {
             off: false,
             passes: 3,
             offset: 3.,
+            offset_unit: BlurOffsetUnit::Physical,
+            offset_growth: 1.,
+            upsample: BlurUpsample::Bilinear,
+            algorithm: BlurAlgorithm::Kawase,
+            range_sigma: 0.2,
             noise: 0.02,
+            noise_falloff: 40.,
             saturation: 1.5,
+            default_saturation: None,
+            adaptive_passes: false,
+            texture_budget_mib: None,
+            temporal_blend: 0.,
+            max_region_rects: 64,
+            max_relative_radius: None,
+            pixel_snap: false,
+            offscreen_color: None,
+            texture_shrink_threshold: 0.,
+            power_of_two_textures: false,
+            max_texture_size: 2560,
+            reduce_effects: false,
         }
     }
 }
 
+/// Filter for the final up pass of the blur.
+#[derive(knuffel::DecodeScalar, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlurUpsample {
+    #[default]
+    Bilinear,
+    /// Smoother, but more expensive.
+    Bicubic,
+}
+
+/// Unit that the blur offset is in.
+#[derive(knuffel::DecodeScalar, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlurOffsetUnit {
+    /// Pixels of the blurred texture, so the blur spreads over fewer logical pixels on HiDPI
+    /// outputs.
+    #[default]
+    Physical,
+    /// Logical pixels, so the blur spreads over the same logical area on outputs of any scale.
+    Logical,
+}
+
+/// Weighting of the samples in the blur passes.
+#[derive(knuffel::DecodeScalar, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlurAlgorithm {
+    #[default]
+    Kawase,
+    /// Weights samples by their color similarity, so that high-contrast edges bleed less.
+    Bilateral,
+}
+
 #[derive(knuffel::Decode, Debug, Default, Clone, Copy, PartialEq)]
 pub struct BlurPart {
     #[knuffel(child)]
@@ -1038,9 +1173,45 @@ pub struct BlurPart {
     #[knuffel(child, unwrap(argument))]
     pub offset: Option<FloatOrInt<0, 100>>,
     #[knuffel(child, unwrap(argument))]
+    pub offset_unit: Option<BlurOffsetUnit>,
+    #[knuffel(child, unwrap(argument))]
+    pub offset_growth: Option<FloatOrInt<0, 10>>,
+    #[knuffel(child, unwrap(argument))]
+    pub upsample: Option<BlurUpsample>,
+    #[knuffel(child, unwrap(argument))]
+    pub algorithm: Option<BlurAlgorithm>,
+    #[knuffel(child, unwrap(argument))]
+    pub range_sigma: Option<FloatOrInt<0, 10>>,
+    #[knuffel(child, unwrap(argument))]
     pub noise: Option<FloatOrInt<0, 1000>>,
     #[knuffel(child, unwrap(argument))]
+    pub noise_falloff: Option<FloatOrInt<0, 10000>>,
+    #[knuffel(child, unwrap(argument))]
     pub saturation: Option<FloatOrInt<0, 1000>>,
+    #[knuffel(child, unwrap(argument))]
+    pub default_saturation: Option<FloatOrInt<0, 1000>>,
+    #[knuffel(child)]
+    pub adaptive_passes: Option<Flag>,
+    #[knuffel(child, unwrap(argument))]
+    pub texture_budget_mib: Option<u32>,
+    #[knuffel(child, unwrap(argument))]
+    pub temporal_blend: Option<FloatOrInt<0, 1>>,
+    #[knuffel(child, unwrap(argument))]
+    pub max_region_rects: Option<u32>,
+    #[knuffel(child, unwrap(argument))]
+    pub max_relative_radius: Option<FloatOrInt<0, 1>>,
+    #[knuffel(child)]
+    pub pixel_snap: Option<Flag>,
+    #[knuffel(child)]
+    pub offscreen_color: Option<Color>,
+    #[knuffel(child, unwrap(argument))]
+    pub texture_shrink_threshold: Option<FloatOrInt<0, 1>>,
+    #[knuffel(child)]
+    pub power_of_two_textures: Option<Flag>,
+    #[knuffel(child, unwrap(argument))]
+    pub max_texture_size: Option<u32>,
+    #[knuffel(child)]
+    pub reduce_effects: Option<Flag>,
 }
 
 impl MergeWith<BlurPart> for Blur {
@@ -1050,8 +1221,43 @@ impl MergeWith<BlurPart> for Blur {
             self.off = false;
         }
 
-        merge_clone!((self, part), passes);
-        merge!((self, part), offset, noise, saturation);
+        merge_clone!(
+            (self, part),
+            passes,
+            offset_unit,
+            upsample,
+            algorithm,
+            max_region_rects,
+            max_texture_size,
+        );
+        merge!(
+            (self, part),
+            offset,
+            offset_growth,
+            range_sigma,
+            noise,
+            noise_falloff,
+            saturation,
+            adaptive_passes,
+            temporal_blend,
+            pixel_snap,
+            texture_shrink_threshold,
+            power_of_two_textures,
+            reduce_effects,
+        );
+
+        if let Some(x) = part.default_saturation {
+            self.default_saturation = Some(x.0);
+        }
+        if let Some(x) = part.texture_budget_mib {
+            self.texture_budget_mib = Some(x);
+        }
+        if let Some(x) = part.max_relative_radius {
+            self.max_relative_radius = Some(x.0);
+        }
+        if let Some(x) = part.offscreen_color {
+            self.offscreen_color = Some(x);
+        }
     }
 }
 
@@ -1065,6 +1271,26 @@ pub struct BackgroundEffectRule {
     pub noise: Option<FloatOrInt<0, 1000>>,
     #[knuffel(child, unwrap(argument))]
     pub saturation: Option<FloatOrInt<0, 1000>>,
+    #[knuffel(child, unwrap(argument))]
+    pub opacity: Option<FloatOrInt<0, 1>>,
+    #[knuffel(child)]
+    pub tint: Option<Color>,
+    #[knuffel(child, unwrap(argument))]
+    pub adaptive_tint: Option<bool>,
+    #[knuffel(child)]
+    pub geometry_corner_radius: Option<CornerRadius>,
+    #[knuffel(child, unwrap(argument))]
+    pub exclude_opaque_body: Option<bool>,
+    #[knuffel(child, unwrap(argument))]
+    pub blur_unfocused_only: Option<bool>,
+    #[knuffel(child, unwrap(argument))]
+    pub region_edge_falloff: Option<FloatOrInt<0, 1000>>,
+    #[knuffel(child, unwrap(argument))]
+    pub edge_ring_width: Option<FloatOrInt<0, 1000>>,
+    #[knuffel(child)]
+    pub edge_ring_color: Option<Color>,
+    #[knuffel(child, unwrap(argument))]
+    pub edge_ring_intensity: Option<FloatOrInt<0, 1>>,
 }
 
 /// Resolved background effect rule.
@@ -1072,7 +1298,7 @@ pub struct BackgroundEffectRule {
 pub struct BackgroundEffect {
     /// Whether to render with xray effect (see through).
     ///
-    /// - `None`: xray if any background effect is active
+    /// - `None`: xray if blur is active; noise and saturation alone use the non-xray path
     /// - `Some(false)`: no xray
     /// - `Some(true)`: xray even if no other background effect is active
     pub xray: Option<bool>,
@@ -1087,11 +1313,48 @@ pub struct BackgroundEffect {
 
     pub noise: Option<f64>,
     pub saturation: Option<f64>,
+
+    /// Opacity of the background effect itself, not affecting the window on top.
+    pub opacity: Option<f64>,
+    /// Color drawn over the background effect, below the window.
+    pub tint: Option<Color>,
+    /// Whether to adjust the tint opacity to the brightness of the background.
+    pub adaptive_tint: Option<bool>,
+
+    /// Corner radius to clip the background effect with, if different from the window's.
+    pub geometry_corner_radius: Option<CornerRadius>,
+
+    /// Whether to leave out the part of the effect covered by the opaque window body.
+    pub exclude_opaque_body: Option<bool>,
+
+    /// Whether to only blur while the window is unfocused.
+    pub blur_unfocused_only: Option<bool>,
+
+    /// Distance in logical pixels over which the effect fades out toward the edges of the
+    /// surface's effect region.
+    pub region_edge_falloff: Option<f64>,
+
+    /// Width in logical pixels of a soft highlight along the inside of the effect edge.
+    pub edge_ring_width: Option<f64>,
+    /// Color of the edge highlight.
+    pub edge_ring_color: Option<Color>,
+    /// Strength of the edge highlight, from 0 to 1.
+    pub edge_ring_intensity: Option<f64>,
 }
 
 impl MergeWith<BackgroundEffectRule> for BackgroundEffect {
     fn merge_with(&mut self, part: &BackgroundEffectRule) {
-        merge_clone_opt!((self, part), xray, blur);
+        merge_clone_opt!(
+            (self, part),
+            xray,
+            blur,
+            tint,
+            adaptive_tint,
+            geometry_corner_radius,
+            exclude_opaque_body,
+            blur_unfocused_only,
+            edge_ring_color,
+        );
 
         if let Some(x) = part.noise {
             self.noise = Some(x.0);
@@ -1100,6 +1363,22 @@ impl MergeWith<BackgroundEffectRule> for BackgroundEffect {
         if let Some(x) = part.saturation {
             self.saturation = Some(x.0);
         }
+
+        if let Some(x) = part.opacity {
+            self.opacity = Some(x.0);
+        }
+
+        if let Some(x) = part.region_edge_falloff {
+            self.region_edge_falloff = Some(x.0);
+        }
+
+        if let Some(x) = part.edge_ring_width {
+            self.edge_ring_width = Some(x.0);
+        }
+
+        if let Some(x) = part.edge_ring_intensity {
+            self.edge_ring_intensity = Some(x.0);
+        }
     }
 }
 
@@ -1110,6 +1389,40 @@ mod tests {
     use super::*;
     use crate::Config;
 
+    #[test]
+    fn corner_radius_contains() {
+        let radius = CornerRadius {
+            top_left: 10.,
+            top_right: 10.,
+            bottom_right: 0.,
+            bottom_left: 20.,
+        };
+        let size = Size::from((100., 50.));
+        let contains = |x, y| radius.contains(Point::from((x, y)), size);
+
+        // The very corners are cut off.
+        assert!(!contains(0., 0.));
+        assert!(!contains(99.5, 0.5));
+        assert!(!contains(1., 49.));
+        // But the square corner isn't.
+        assert!(contains(99.5, 49.5));
+
+        // Just inside and outside the top-left circle, along the diagonal.
+        let d = 10. - 10. / 2f64.sqrt();
+        assert!(contains(d + 0.1, d + 0.1));
+        assert!(!contains(d - 0.1, d - 0.1));
+
+        // The edges and the middle are inside.
+        assert!(contains(50., 0.));
+        assert!(contains(0., 25.));
+        assert!(contains(50., 25.));
+
+        // Outside of the rectangle.
+        assert!(!contains(-1., 25.));
+        assert!(!contains(100., 25.));
+        assert!(!contains(50., 50.));
+    }
+
     #[test]
     fn parse_gradient_interpolation() {
         assert_eq!(
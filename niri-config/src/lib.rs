@@ -534,6 +534,15 @@ impl Config {
     pub fn parse_mem(text: &str) -> Result<Self, ConfigIncludeError> {
         Self::parse(Path::new("config.kdl"), text).config
     }
+
+    /// Returns the blur config for an output, with its `blur {}` overrides applied.
+    pub fn blur_for_output(&self, name: &OutputName) -> Blur {
+        let mut blur = self.blur;
+        if let Some(part) = self.outputs.find(name).and_then(|o| o.blur.as_ref()) {
+            blur.merge_with(part);
+        }
+        blur
+    }
 }
 
 impl ConfigPath {
@@ -1222,6 +1231,7 @@ mod tests {
                         backdrop_color: None,
                         hot_corners: None,
                         layout: None,
+                        blur: None,
                     },
                     Output {
                         off: false,
@@ -1251,6 +1261,7 @@ mod tests {
                         backdrop_color: None,
                         hot_corners: None,
                         layout: None,
+                        blur: None,
                     },
                 ],
             ),
@@ -1640,8 +1651,26 @@ mod tests {
                 off: false,
                 passes: 3,
                 offset: 3.0,
+                offset_unit: Physical,
+                offset_growth: 1.0,
+                upsample: Bilinear,
+                algorithm: Kawase,
+                range_sigma: 0.2,
                 noise: 0.02,
+                noise_falloff: 40.0,
                 saturation: 1.5,
+                default_saturation: None,
+                adaptive_passes: false,
+                texture_budget_mib: None,
+                temporal_blend: 0.0,
+                max_region_rects: 64,
+                max_relative_radius: None,
+                pixel_snap: false,
+                offscreen_color: None,
+                texture_shrink_threshold: 0.0,
+                power_of_two_textures: false,
+                max_texture_size: 2560,
+                reduce_effects: false,
             },
             gestures: Gestures {
                 dnd_edge_view_scroll: DndEdgeViewScroll {
@@ -1689,6 +1718,7 @@ mod tests {
                         a: 0.3137255,
                     },
                 },
+                blur: None,
             },
             environment: Environment(
                 [
@@ -1877,6 +1907,16 @@ mod tests {
                         blur: None,
                         noise: None,
                         saturation: None,
+                        opacity: None,
+                        tint: None,
+                        adaptive_tint: None,
+                        geometry_corner_radius: None,
+                        exclude_opaque_body: None,
+                        blur_unfocused_only: None,
+                        region_edge_falloff: None,
+                        edge_ring_width: None,
+                        edge_ring_color: None,
+                        edge_ring_intensity: None,
                     },
                     popups: PopupsRule {
                         opacity: None,
@@ -1886,6 +1926,16 @@ mod tests {
                             blur: None,
                             noise: None,
                             saturation: None,
+                            opacity: None,
+                            tint: None,
+                            adaptive_tint: None,
+                            geometry_corner_radius: None,
+                            exclude_opaque_body: None,
+                            blur_unfocused_only: None,
+                            region_edge_falloff: None,
+                            edge_ring_width: None,
+                            edge_ring_color: None,
+                            edge_ring_intensity: None,
                         },
                     },
                 },
@@ -1928,6 +1978,16 @@ mod tests {
                         blur: None,
                         noise: None,
                         saturation: None,
+                        opacity: None,
+                        tint: None,
+                        adaptive_tint: None,
+                        geometry_corner_radius: None,
+                        exclude_opaque_body: None,
+                        blur_unfocused_only: None,
+                        region_edge_falloff: None,
+                        edge_ring_width: None,
+                        edge_ring_color: None,
+                        edge_ring_intensity: None,
                     },
                     popups: PopupsRule {
                         opacity: None,
@@ -1937,6 +1997,16 @@ mod tests {
                             blur: None,
                             noise: None,
                             saturation: None,
+                            opacity: None,
+                            tint: None,
+                            adaptive_tint: None,
+                            geometry_corner_radius: None,
+                            exclude_opaque_body: None,
+                            blur_unfocused_only: None,
+                            region_edge_falloff: None,
+                            edge_ring_width: None,
+                            edge_ring_color: None,
+                            edge_ring_intensity: None,
                         },
                     },
                 },
@@ -2262,6 +2332,7 @@ mod tests {
                 honor_xdg_activation_with_invalid_serial: false,
                 deactivate_unfocused_windows: false,
                 skip_cursor_only_updates_during_vrr: false,
+                skip_occluded_background_effects: false,
             },
             workspaces: [
                 Workspace {
@@ -2272,6 +2343,7 @@ mod tests {
                         "eDP-1",
                     ),
                     layout: None,
+                    disable_xray: false,
                 },
                 Workspace {
                     name: WorkspaceName(
@@ -2279,6 +2351,7 @@ mod tests {
                     ),
                     open_on_output: None,
                     layout: None,
+                    disable_xray: false,
                 },
                 Workspace {
                     name: WorkspaceName(
@@ -2286,6 +2359,7 @@ mod tests {
                     ),
                     open_on_output: None,
                     layout: None,
+                    disable_xray: false,
                 },
             ],
             recent_windows: RecentWindows {
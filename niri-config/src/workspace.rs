@@ -10,6 +10,8 @@ pub struct Workspace {
     pub open_on_output: Option<String>,
     #[knuffel(child)]
     pub layout: Option<WorkspaceLayoutPart>,
+    #[knuffel(child)]
+    pub disable_xray: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -119,6 +119,8 @@ pub enum Request {
     OverviewState,
     /// Request information about screencasts.
     Casts,
+    /// Request the background effect state of the focused window.
+    FocusedWindowBackgroundEffect,
 }
 
 /// Reply from niri to client.
@@ -165,6 +167,10 @@ pub enum Response {
     OverviewState(Overview),
     /// Information about screencasts.
     Casts(Vec<Cast>),
+    /// Background effect state of the focused window.
+    ///
+    /// `None` if there's no focused window, or if it has never rendered a background effect.
+    FocusedWindowBackgroundEffect(Option<BackgroundEffectState>),
 }
 
 /// Overview information.
@@ -175,6 +181,41 @@ pub struct Overview {
     pub is_open: bool,
 }
 
+/// Resolved state of a background effect.
+///
+/// Meant for inspecting and reporting effect issues; the set of fields may change between
+/// releases.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
+pub struct BackgroundEffectState {
+    /// Whether the background is blurred.
+    pub blur: bool,
+    /// Whether the effect renders through xray rather than capturing what's behind it.
+    pub xray: bool,
+    /// Whether the render target doesn't support framebuffer capture, forcing xray.
+    pub capture_unsupported: bool,
+    /// Number of blur passes.
+    pub blur_passes: u8,
+    /// Blur offset of every pass.
+    pub blur_offset: f64,
+    /// Strength of the noise drawn over the effect.
+    pub noise: f64,
+    /// Saturation of the background.
+    pub saturation: f64,
+    /// Premultiplied RGBA tint drawn over the effect.
+    pub tint: [f64; 4],
+    /// Whether the tint scales with the brightness of the blurred background.
+    pub adaptive_tint: bool,
+    /// Opacity of the effect, including the opacity of the window.
+    pub opacity: f64,
+    /// Whether the effect skips the opaque body of the window.
+    pub exclude_opaque_body: bool,
+    /// Corner radius of the effect clip: top left, top right, bottom right, bottom left.
+    pub corner_radius: [f64; 4],
+    /// Number of rectangles in the blur region set by the window, if any.
+    pub blur_region_rects: Option<u32>,
+}
+
 /// Color picked from the screen.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[cfg_attr(feature = "json-schema", derive(schemars::JsonSchema))]
@@ -807,6 +848,10 @@ pub enum Action {
     DebugToggleOpaqueRegions {},
     /// Toggle visualization of output damage.
     DebugToggleDamage {},
+    /// Log the background effect elements rendered in the next frame of every output.
+    DebugLogBackgroundEffects {},
+    /// Dump the blur regions of the focused window and their decomposition to a file.
+    DebugDumpEffectRegions {},
     /// Move the focused window between the floating and the tiling layout.
     ToggleWindowFloating {
         /// Id of the window to move.
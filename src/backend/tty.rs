@@ -1203,6 +1203,13 @@ impl Tty {
             }
 
             if was_last {
+                match self.gpu_manager.single_renderer(&render_node) {
+                    Ok(mut renderer) => shaders::destroy_all(renderer.as_gles_renderer()),
+                    Err(err) => {
+                        warn!("error creating renderer during device removal: {err}");
+                    }
+                }
+
                 self.gpu_manager.as_mut().remove_node(&render_node);
                 // Trigger re-enumeration in order to remove the device from gpu_manager.
                 let _ = self.gpu_manager.devices();
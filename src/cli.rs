@@ -109,6 +109,8 @@ pub enum Msg {
     OverviewState,
     /// List screencasts.
     Casts,
+    /// Print the background effect state of the focused window.
+    FocusedWindowBackgroundEffect,
 }
 
 #[derive(Clone, Debug, clap::ValueEnum)]
@@ -1,17 +1,44 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::VecDeque;
+use std::fmt::Write as _;
+use std::hash::{Hash as _, Hasher as _};
+use std::mem;
 use std::sync::{Arc, Mutex};
 
 use smithay::delegate_background_effect;
 use smithay::reexports::wayland_server::protocol::wl_surface::WlSurface;
-use smithay::utils::{Logical, Rectangle};
+use smithay::utils::{Logical, Point, Rectangle};
 use smithay::wayland::background_effect::{
     self, BackgroundEffectSurfaceCachedState, ExtBackgroundEffectHandler,
 };
 use smithay::wayland::compositor::{
-    add_post_commit_hook, with_states, RegionAttributes, SurfaceData,
+    add_post_commit_hook, get_parent, with_states, with_surface_tree_downward, RectangleKind,
+    RegionAttributes, SubsurfaceCachedState, SurfaceData, TraversalAction,
 };
 
 use crate::niri::State;
-use crate::utils::region::region_to_non_overlapping_rects;
+use crate::render_helpers::background_effect::damage_surface;
+use crate::utils::get_credentials_for_surface;
+use crate::utils::region::{
+    format_region_reproducer, is_large_region, rects_to_region, region_to_non_overlapping_rects,
+    LARGE_REGION_RECTS,
+};
+
+/// Protocol through which a surface declared its blur region.
+///
+/// Only ext-background-effect is supported for now, so this is mostly for debugging clients that
+/// unexpectedly end up without a blur region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlurRegionSource {
+    /// The surface doesn't declare a blur region.
+    #[default]
+    None,
+    /// The region comes from ext-background-effect.
+    Ext,
+}
+
+/// Processed blur region, `None` if the surface doesn't declare one.
+type CachedRects = Option<Arc<Vec<Rectangle<i32, Logical>>>>;
 
 /// Per-surface cache for processed blur region (non-overlapping rects).
 #[derive(Default)]
@@ -21,55 +48,299 @@ struct CachedBlurRegionUserData(Mutex<CachedBlurRegionInner>);
 struct CachedBlurRegionInner {
     /// Whether a region change is pending to be committed.
     pending_dirty: bool,
-    /// Whether the region must be recomputed.
-    dirty: bool,
     /// Whether the post-commit hook has been registered for this surface.
     hook_registered: bool,
+    /// Whether a warning about a large region was logged for this surface.
+    warned_large_region: bool,
+    /// Protocol that the cached blur region came from.
+    blur_source: BlurRegionSource,
     /// Cached non-overlapping rects in surface-local coordinates.
     ///
     /// `None` means there's no blur region.
-    rects: Option<Arc<Vec<Rectangle<i32, Logical>>>>,
+    rects: CachedRects,
+}
+
+impl CachedBlurRegionInner {
+    /// Marks a region change as pending until the next commit.
+    ///
+    /// Returns `true` if the post-commit hook needs to be registered.
+    fn mark_pending_dirty(&mut self) -> bool {
+        self.pending_dirty = true;
+
+        if self.hook_registered {
+            false
+        } else {
+            self.hook_registered = true;
+            true
+        }
+    }
+
+    /// Applies a pending region change on commit.
+    ///
+    /// Any number of changes within one commit collapse into a single recompute. Returns `true`
+    /// if there was a pending change, and so the region must be recomputed.
+    fn apply_commit(&mut self) -> bool {
+        mem::take(&mut self.pending_dirty)
+    }
+
+    /// Returns whether to warn about `region` being slow to process.
+    ///
+    /// This is only `true` once per surface, so that a client re-committing a large region doesn't
+    /// flood the log.
+    fn should_warn_large_region(&mut self, region: &RegionAttributes) -> bool {
+        if self.warned_large_region || !is_large_region(region) {
+            return false;
+        }
+
+        self.warned_large_region = true;
+        true
+    }
+
+    /// Recomputes the rects from the region declared through `source`, if any.
+    fn update_blur_rects(
+        &mut self,
+        declared: Option<(BlurRegionSource, &RegionAttributes)>,
+        cache: &mut SharedRegionCache,
+    ) {
+        self.blur_source = declared.map_or(BlurRegionSource::None, |(source, _)| source);
+        self.update_rects(declared.map(|(_, region)| region), cache);
+    }
+
+    /// Recomputes the rects from the current committed region.
+    fn update_rects(&mut self, region: Option<&RegionAttributes>, cache: &mut SharedRegionCache) {
+        self.rects = region.map(|region| cache.get_or_compute(region));
+    }
+}
+
+/// Cache for the blur region of a surface merged with those of its subsurfaces.
+///
+/// Stored on the root surface, and cleared by any commit in its surface tree. `None` if not
+/// computed since the last commit.
+#[derive(Default)]
+struct AggregatedBlurRegionUserData(Mutex<Option<CachedRects>>);
+
+/// Maximum number of processed regions kept in a [`SharedRegionCache`].
+const SHARED_REGION_CACHE_SIZE: usize = 32;
+
+/// Processed blur regions shared between all surfaces declaring the same region.
+///
+/// Surfaces of the same toolkit often declare identical regions, and clients like a restarting
+/// panel re-create their surface with the same region. This way they share a single set of rects
+/// rather than each recomputing and storing their own.
+///
+/// Bounded, least recently used first.
+#[derive(Default)]
+pub struct SharedRegionCache {
+    entries: VecDeque<SharedRegion>,
+}
+
+struct SharedRegion {
+    /// Hash of `region`, to skip comparing regions that can't match.
+    hash: u64,
+    /// Region that the rects were computed from.
+    region: Vec<(RectangleKind, Rectangle<i32, Logical>)>,
+    rects: Arc<Vec<Rectangle<i32, Logical>>>,
+}
+
+/// Returns the hash of the content of a region.
+fn region_hash(region: &RegionAttributes) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for (kind, r) in &region.rects {
+        matches!(kind, RectangleKind::Add).hash(&mut hasher);
+        (r.loc.x, r.loc.y, r.size.w, r.size.h).hash(&mut hasher);
+    }
+    hasher.finish()
 }
 
-/// Gets the cached blur region for a surface, lazily recomputing if dirty.
-pub fn get_cached_blur_region(states: &SurfaceData) -> Option<Arc<Vec<Rectangle<i32, Logical>>>> {
-    let cache = states
-        .data_map
-        .get_or_insert_threadsafe(CachedBlurRegionUserData::default);
-    let mut guard = cache.0.lock().unwrap();
+impl SharedRegionCache {
+    /// Returns the rects for `region`, reusing the cached ones if the same region was processed
+    /// before.
+    fn get_or_compute(&mut self, region: &RegionAttributes) -> Arc<Vec<Rectangle<i32, Logical>>> {
+        let hash = region_hash(region);
+
+        if let Some(idx) = self
+            .entries
+            .iter()
+            .position(|entry| entry.hash == hash && entry.region == region.rects)
+        {
+            // Move the entry to the back as the most recently used one.
+            let entry = self.entries.remove(idx).unwrap();
+            let rects = entry.rects.clone();
+            self.entries.push_back(entry);
+            return rects;
+        }
+
+        let mut rects = Vec::new();
+        region_to_non_overlapping_rects(region, &mut rects);
+        let rects = Arc::new(rects);
+
+        if self.entries.len() >= SHARED_REGION_CACHE_SIZE {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(SharedRegion {
+            hash,
+            region: region.rects.clone(),
+            rects: rects.clone(),
+        });
 
-    if guard.dirty {
-        guard.dirty = false;
-        recompute_blur_region(states, &mut guard);
+        rects
     }
+}
 
+/// Gets the cached blur region for a surface.
+///
+/// The region is recomputed when committed, so this is always up to date.
+pub fn get_cached_blur_region(states: &SurfaceData) -> CachedRects {
+    let cache = states.data_map.get::<CachedBlurRegionUserData>()?;
+    let guard = cache.0.lock().unwrap();
     guard.rects.clone()
 }
 
-fn recompute_blur_region(states: &SurfaceData, inner: &mut CachedBlurRegionInner) {
-    let cached = &states.cached_state;
+/// Gets the protocol that a surface's blur region came from.
+pub fn get_blur_region_source(states: &SurfaceData) -> BlurRegionSource {
+    let Some(cache) = states.data_map.get::<CachedBlurRegionUserData>() else {
+        return BlurRegionSource::None;
+    };
+    let guard = cache.0.lock().unwrap();
+    guard.blur_source
+}
+
+/// Gets the blur region of a surface merged with those of its subsurfaces.
+///
+/// Subsurface regions are translated by the subsurface offsets into the surface's coordinates.
+/// When only the surface itself declares a region, this is the same as
+/// [`get_cached_blur_region()`]. The result is cached until the next commit in the surface tree,
+/// see [`invalidate_aggregated_blur_region()`].
+pub fn get_aggregated_blur_region(surface: &WlSurface) -> CachedRects {
+    let aggregated = |states: &SurfaceData| {
+        states
+            .data_map
+            .get_or_insert_threadsafe(AggregatedBlurRegionUserData::default)
+            .0
+            .lock()
+            .unwrap()
+            .clone()
+    };
+    if let Some(rects) = with_states(surface, aggregated) {
+        return rects;
+    }
+
+    // Walks the subsurface tree, so it can't be inside with_states() for the same surface.
+    let mut parts = Vec::new();
+    with_surface_tree_downward(
+        surface,
+        Point::from((0, 0)),
+        |_, states, location| TraversalAction::DoChildren(subsurface_location(states, *location)),
+        |_, states, location| {
+            let location = subsurface_location(states, *location);
+            parts.push((location, get_cached_blur_region(states)));
+        },
+        |_, _, _| true,
+    );
+    let rects = aggregate_rects(parts);
+
+    with_states(surface, |states| {
+        let data = states
+            .data_map
+            .get_or_insert_threadsafe(AggregatedBlurRegionUserData::default);
+        *data.0.lock().unwrap() = Some(rects.clone());
+    });
+
+    rects
+}
 
-    let rects = if let Some(arc) = &mut inner.rects {
-        if Arc::strong_count(arc) > 1 {
-            debug!("cloning rects due to non-unique reference");
+/// Clears the cached aggregated blur region of a root surface.
+///
+/// Called on every commit in the surface tree, since a commit can change the region or the offset
+/// of any subsurface, or add and remove subsurfaces.
+pub fn invalidate_aggregated_blur_region(root: &WlSurface) {
+    with_states(root, |states| {
+        if let Some(data) = states.data_map.get::<AggregatedBlurRegionUserData>() {
+            *data.0.lock().unwrap() = None;
         }
-        arc
+    });
+}
+
+/// Dumps the committed blur regions of a surface and its subsurfaces with their cached rects.
+///
+/// The output can be pasted into the `region.rs` tests to reproduce a wrong decomposition.
+pub fn dump_blur_regions(surface: &WlSurface) -> String {
+    let mut s = String::new();
+    with_surface_tree_downward(
+        surface,
+        Point::from((0, 0)),
+        |_, states, location| TraversalAction::DoChildren(subsurface_location(states, *location)),
+        |surface, states, location| {
+            let cached = &states.cached_state;
+            if !cached.has::<BackgroundEffectSurfaceCachedState>() {
+                return;
+            }
+            let region = cached
+                .get::<BackgroundEffectSurfaceCachedState>()
+                .current()
+                .blur_region
+                .clone();
+            let Some(region) = region else {
+                return;
+            };
+
+            let location = subsurface_location(states, *location);
+            let rects = get_cached_blur_region(states).unwrap_or_default();
+            writeln!(s, "// {surface:?} at {}, {}", location.x, location.y).unwrap();
+            s.push_str(&format_region_reproducer(&region, &rects));
+        },
+        |_, _, _| true,
+    );
+    s
+}
+
+fn subsurface_location(states: &SurfaceData, parent: Point<i32, Logical>) -> Point<i32, Logical> {
+    if states.role == Some("subsurface") {
+        let mut guard = states.cached_state.get::<SubsurfaceCachedState>();
+        parent + guard.current().location
     } else {
-        inner.rects.insert(Arc::new(Vec::new()))
-    };
-    let rects = Arc::make_mut(rects);
+        parent
+    }
+}
 
-    if cached.has::<BackgroundEffectSurfaceCachedState>() {
-        let mut guard = cached.get::<BackgroundEffectSurfaceCachedState>();
-        if let Some(region) = &guard.current().blur_region {
-            region_to_non_overlapping_rects(region, rects);
-        } else {
-            inner.rects = None;
+/// Merges effect regions at the given offsets into non-overlapping rects.
+fn aggregate_rects(parts: Vec<(Point<i32, Logical>, CachedRects)>) -> CachedRects {
+    let parts: Vec<_> = parts
+        .into_iter()
+        .filter_map(|(loc, rects)| Some((loc, rects?)))
+        .collect();
+
+    match &parts[..] {
+        [] => None,
+        // Only the surface itself declares a region, which is already non-overlapping.
+        [(loc, rects)] if *loc == Point::from((0, 0)) => Some(rects.clone()),
+        _ => {
+            let translated: Vec<_> = parts
+                .iter()
+                .flat_map(|(loc, rects)| rects.iter().map(|r| Rectangle::new(r.loc + *loc, r.size)))
+                .collect();
+
+            let mut rects = Vec::new();
+            region_to_non_overlapping_rects(&rects_to_region(&translated), &mut rects);
+            Some(Arc::new(rects))
         }
-        return;
     }
+}
+
+fn recompute_blur_region(
+    states: &SurfaceData,
+    inner: &mut CachedBlurRegionInner,
+    cache: &mut SharedRegionCache,
+) {
+    let cached = &states.cached_state;
 
-    inner.rects = None;
+    if cached.has::<BackgroundEffectSurfaceCachedState>() {
+        let mut guard = cached.get::<BackgroundEffectSurfaceCachedState>();
+        let region = guard.current().blur_region.as_ref();
+        inner.update_blur_rects(region.map(|region| (BlurRegionSource::Ext, region)), cache);
+    } else {
+        inner.update_blur_rects(None, cache);
+    }
 }
 
 fn mark_blur_region_pending_dirty(wl_surface: &WlSurface) {
@@ -78,31 +349,59 @@ fn mark_blur_region_pending_dirty(wl_surface: &WlSurface) {
             .data_map
             .get_or_insert_threadsafe(CachedBlurRegionUserData::default);
         let mut guard = cache.0.lock().unwrap();
-        guard.pending_dirty = true;
-
-        if guard.hook_registered {
-            false
-        } else {
-            guard.hook_registered = true;
-            true
-        }
+        guard.mark_pending_dirty()
     });
 
     if register_hook {
-        add_post_commit_hook::<State, _>(wl_surface, |_state, _dh, surface| {
-            with_states(surface, |states| {
+        add_post_commit_hook::<State, _>(wl_surface, |state, _dh, surface| {
+            let mut large_region = None;
+            let changed = with_states(surface, |states| {
                 if let Some(cache) = states.data_map.get::<CachedBlurRegionUserData>() {
                     let mut guard = cache.0.lock().unwrap();
-                    if guard.pending_dirty {
-                        guard.pending_dirty = false;
-                        guard.dirty = true;
+                    if guard.apply_commit() {
+                        recompute_blur_region(
+                            states,
+                            &mut guard,
+                            &mut state.niri.blur_region_cache,
+                        );
 
-                        crate::render_helpers::background_effect::damage_surface(states);
+                        let cached = &states.cached_state;
+                        if cached.has::<BackgroundEffectSurfaceCachedState>() {
+                            let mut state = cached.get::<BackgroundEffectSurfaceCachedState>();
+                            if let Some(region) = &state.current().blur_region {
+                                if guard.should_warn_large_region(region) {
+                                    large_region = Some(region.rects.len());
+                                }
+                            }
+                        }
+
+                        damage_surface(states);
+                        return true;
                     }
                 } else {
                     error!("unexpected missing CachedBlurRegionUserData");
                 }
+                false
             });
+
+            if let Some(rects) = large_region {
+                let pid = get_credentials_for_surface(surface).map(|credentials| credentials.pid);
+                warn!(
+                    "surface {surface:?} of client with pid {pid:?} set a blur region of {rects} \
+                     rects, over {LARGE_REGION_RECTS}; processing it may be slow"
+                );
+            }
+
+            // The region of a subsurface is part of the effect of its root surface.
+            if changed {
+                let mut root = surface.clone();
+                while let Some(parent) = get_parent(&root) {
+                    root = parent;
+                }
+                if root != *surface {
+                    with_states(&root, damage_surface);
+                }
+            }
         });
     }
 }
@@ -121,3 +420,187 @@ impl ExtBackgroundEffectHandler for State {
     }
 }
 delegate_background_effect!(State);
+
+#[cfg(test)]
+mod tests {
+    use smithay::wayland::compositor::RectangleKind;
+
+    use super::*;
+
+    fn region(rects: &[(i32, i32, i32, i32)]) -> RegionAttributes {
+        RegionAttributes {
+            rects: rects
+                .iter()
+                .map(|(x, y, w, h)| {
+                    (
+                        RectangleKind::Add,
+                        Rectangle::new((*x, *y).into(), (*w, *h).into()),
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn multiple_changes_in_one_commit_recompute_once() {
+        let mut inner = CachedBlurRegionInner::default();
+
+        // Set, then unset within the same commit.
+        assert!(inner.mark_pending_dirty());
+        assert!(!inner.mark_pending_dirty());
+
+        // Only the first commit recomputes.
+        assert!(inner.apply_commit());
+        assert!(!inner.apply_commit());
+    }
+
+    #[test]
+    fn recompute_reads_final_state() {
+        let mut cache = SharedRegionCache::default();
+        let mut inner = CachedBlurRegionInner::default();
+
+        inner.mark_pending_dirty();
+        inner.mark_pending_dirty();
+        assert!(inner.apply_commit());
+        let region = region(&[(0, 0, 10, 10)]);
+        inner.update_rects(Some(&region), &mut cache);
+        assert_eq!(
+            inner.rects.as_deref().map(|r| &r[..]),
+            Some(&[Rectangle::new((0, 0).into(), (10, 10).into())][..])
+        );
+
+        // A later unset clears the region.
+        inner.mark_pending_dirty();
+        assert!(inner.apply_commit());
+        inner.update_rects(None, &mut cache);
+        assert!(inner.rects.is_none());
+    }
+
+    #[test]
+    fn large_region_warns_once() {
+        let mut inner = CachedBlurRegionInner::default();
+
+        // Normal regions never warn.
+        let small = region(&[(0, 0, 10, 10), (20, 0, 10, 10)]);
+        assert!(!inner.should_warn_large_region(&small));
+
+        let rects: Vec<_> = (0..=LARGE_REGION_RECTS as i32)
+            .map(|i| (i * 2, i * 2, 1, 1))
+            .collect();
+        let large = region(&rects);
+        assert!(inner.should_warn_large_region(&large));
+        // Not again for the same surface.
+        assert!(!inner.should_warn_large_region(&large));
+
+        // Another surface warns on its own.
+        let mut other = CachedBlurRegionInner::default();
+        assert!(other.should_warn_large_region(&large));
+    }
+
+    #[test]
+    fn blur_region_records_its_source() {
+        let mut cache = SharedRegionCache::default();
+        let mut inner = CachedBlurRegionInner::default();
+        assert_eq!(inner.blur_source, BlurRegionSource::None);
+
+        let region = region(&[(0, 0, 10, 10)]);
+        inner.update_blur_rects(Some((BlurRegionSource::Ext, &region)), &mut cache);
+        assert_eq!(inner.blur_source, BlurRegionSource::Ext);
+        assert!(inner.rects.is_some());
+
+        inner.update_blur_rects(None, &mut cache);
+        assert_eq!(inner.blur_source, BlurRegionSource::None);
+        assert!(inner.rects.is_none());
+    }
+
+    #[test]
+    fn identical_regions_share_rects() {
+        let mut cache = SharedRegionCache::default();
+        let rects = |inner: &CachedBlurRegionInner| inner.rects.clone().unwrap();
+        let l_shape = region(&[(0, 0, 101, 21), (0, 0, 21, 101)]);
+
+        let mut first = CachedBlurRegionInner::default();
+        first.update_rects(Some(&l_shape), &mut cache);
+
+        // Another surface declaring the same region gets the same rects.
+        let mut second = CachedBlurRegionInner::default();
+        second.update_rects(Some(&l_shape), &mut cache);
+        assert!(Arc::ptr_eq(&rects(&first), &rects(&second)));
+
+        // A different region is computed separately.
+        let mut third = CachedBlurRegionInner::default();
+        let bar = region(&[(0, 0, 101, 21)]);
+        third.update_rects(Some(&bar), &mut cache);
+        assert!(!Arc::ptr_eq(&rects(&first), &rects(&third)));
+        assert_eq!(rects(&third).len(), 1);
+    }
+
+    #[test]
+    fn shared_region_cache_is_bounded() {
+        let mut cache = SharedRegionCache::default();
+
+        let first = cache.get_or_compute(&region(&[(0, 0, 10, 10)]));
+        for i in 1..=SHARED_REGION_CACHE_SIZE as i32 {
+            cache.get_or_compute(&region(&[(i, 0, 10, 10)]));
+        }
+        assert_eq!(cache.entries.len(), SHARED_REGION_CACHE_SIZE);
+
+        // The least recently used entry was evicted.
+        let again = cache.get_or_compute(&region(&[(0, 0, 10, 10)]));
+        assert!(!Arc::ptr_eq(&first, &again));
+
+        // Looking up an entry makes it the most recently used one.
+        let second = cache.get_or_compute(&region(&[(2, 0, 10, 10)]));
+        cache.get_or_compute(&region(&[(100, 0, 10, 10)]));
+        assert!(Arc::ptr_eq(
+            &second,
+            &cache.get_or_compute(&region(&[(2, 0, 10, 10)]))
+        ));
+    }
+
+    #[test]
+    fn subsurface_region_is_aggregated_at_its_offset() {
+        let mut cache = SharedRegionCache::default();
+        let mut own = |rects: &[(i32, i32, i32, i32)]| {
+            let mut inner = CachedBlurRegionInner::default();
+            inner.update_rects(Some(&region(rects)), &mut cache);
+            inner.rects
+        };
+        let format = |rects: &CachedRects| {
+            rects
+                .as_ref()
+                .unwrap()
+                .iter()
+                .map(|r| (r.loc.x, r.loc.y, r.size.w, r.size.h))
+                .collect::<Vec<_>>()
+        };
+
+        let parent = own(&[(0, 0, 100, 20)]);
+        let subsurface = own(&[(0, 0, 50, 10)]);
+
+        // Without subsurface regions, the surface's own region is used as is.
+        let aggregated = aggregate_rects(vec![
+            (Point::from((0, 0)), parent.clone()),
+            (Point::from((10, 30)), None),
+        ]);
+        assert!(Arc::ptr_eq(
+            aggregated.as_ref().unwrap(),
+            parent.as_ref().unwrap()
+        ));
+
+        let aggregated = aggregate_rects(vec![
+            (Point::from((0, 0)), parent.clone()),
+            (Point::from((10, 30)), subsurface.clone()),
+        ]);
+        assert_eq!(format(&aggregated), [(0, 0, 100, 20), (10, 30, 50, 10)]);
+
+        // A subsurface region alone is still translated.
+        let aggregated = aggregate_rects(vec![
+            (Point::from((0, 0)), None),
+            (Point::from((10, 30)), subsurface),
+        ]);
+        assert_eq!(format(&aggregated), [(10, 30, 50, 10)]);
+
+        assert_eq!(aggregate_rects(vec![(Point::from((0, 0)), None)]), None);
+    }
+}
@@ -19,6 +19,7 @@ use smithay::wayland::shm::{ShmHandler, ShmState};
 use smithay::{delegate_compositor, delegate_shm};
 
 use super::xdg_shell::add_mapped_toplevel_pre_commit_hook;
+use crate::handlers::background_effect::invalidate_aggregated_blur_region;
 use crate::handlers::XDG_ACTIVATION_TOKEN_TIMEOUT;
 use crate::layout::{ActivateWindow, AddWindowTarget, LayoutElement as _};
 use crate::niri::{CastTarget, ClientState, LockState, State};
@@ -72,6 +73,8 @@ impl CompositorHandler for State {
             .root_surface
             .insert(surface.clone(), root_surface.clone());
 
+        invalidate_aggregated_blur_region(&root_surface);
+
         if is_sync_subsurface(surface) {
             return;
         }
@@ -489,6 +492,11 @@ impl CompositorHandler for State {
         // subsurface is destroyed; in the case of alacritty, this is the top CSD shadow. But, it
         // gets most of the job done.
         if let Some(root) = self.niri.root_surface.get(surface) {
+            // A destroyed subsurface drops out of the tree without a commit on the root.
+            if root != surface && root.is_alive() {
+                invalidate_aggregated_blur_region(root);
+            }
+
             if let Some((mapped, output)) = self.niri.layout.find_window_and_output(root) {
                 let window = mapped.window.clone();
                 let output = output.cloned();
@@ -1,3 +1,4 @@
+use niri_config::OutputName;
 use smithay::delegate_layer_shell;
 use smithay::desktop::{layer_map_for_output, LayerSurface, PopupKind, WindowSurfaceType};
 use smithay::reexports::wayland_server::protocol::wl_output::WlOutput;
@@ -134,6 +135,7 @@ impl State {
                     scale,
                     self.niri.clock.clone(),
                     &config,
+                    config.blur_for_output(output.user_data().get::<OutputName>().unwrap()),
                 );
 
                 let prev = self
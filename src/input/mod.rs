@@ -693,6 +693,12 @@ impl State {
             Action::DebugToggleDamage => {
                 self.niri.debug_toggle_damage();
             }
+            Action::DebugLogBackgroundEffects => {
+                self.niri.debug_log_background_effects();
+            }
+            Action::DebugDumpEffectRegions => {
+                self.niri.debug_dump_effect_regions();
+            }
             Action::Spawn(command) => {
                 let (token, _) = self.niri.activation_state.create_external_token(None);
                 spawn(command, Some(token.clone()));
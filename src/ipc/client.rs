@@ -7,8 +7,9 @@ use anyhow::{anyhow, bail, Context};
 use niri_config::OutputName;
 use niri_ipc::socket::Socket;
 use niri_ipc::{
-    Action, Cast, CastKind, CastTarget, Event, KeyboardLayouts, LogicalOutput, Mode, Output,
-    OutputConfigChanged, Overview, Request, Response, Transform, Window, WindowLayout,
+    Action, BackgroundEffectState, Cast, CastKind, CastTarget, Event, KeyboardLayouts,
+    LogicalOutput, Mode, Output, OutputConfigChanged, Overview, Request, Response, Transform,
+    Window, WindowLayout,
 };
 use serde_json::json;
 
@@ -49,6 +50,7 @@ pub fn handle_msg(mut msg: Msg, json: bool) -> anyhow::Result<()> {
         Msg::RequestError => Request::ReturnError,
         Msg::OverviewState => Request::OverviewState,
         Msg::Casts => Request::Casts,
+        Msg::FocusedWindowBackgroundEffect => Request::FocusedWindowBackgroundEffect,
     };
 
     let mut socket = Socket::connect().context("error connecting to the niri socket")?;
@@ -550,6 +552,25 @@ pub fn handle_msg(mut msg: Msg, json: bool) -> anyhow::Result<()> {
                 println!();
             }
         }
+        Msg::FocusedWindowBackgroundEffect => {
+            let Response::FocusedWindowBackgroundEffect(state) = response else {
+                bail!(
+                    "unexpected response: expected FocusedWindowBackgroundEffect, got {response:?}"
+                );
+            };
+
+            if json {
+                let state = serde_json::to_string(&state).context("error formatting response")?;
+                println!("{state}");
+                return Ok(());
+            }
+
+            if let Some(state) = state {
+                print_background_effect(&state);
+            } else {
+                println!("No focused window with a background effect.");
+            }
+        }
     }
 
     Ok(())
@@ -738,6 +759,50 @@ fn print_window(window: &Window) {
     );
 }
 
+fn print_background_effect(state: &BackgroundEffectState) {
+    let path = if state.capture_unsupported {
+        "xray (framebuffer capture unsupported)"
+    } else if state.xray {
+        "xray"
+    } else {
+        "framebuffer"
+    };
+    println!("Background effect: {path}");
+
+    if state.blur {
+        println!(
+            "  Blur: {} passes, offset {}",
+            state.blur_passes, state.blur_offset
+        );
+    } else {
+        println!("  Blur: off");
+    }
+
+    println!("  Noise: {}", state.noise);
+    println!("  Saturation: {}", state.saturation);
+
+    let [r, g, b, a] = state.tint;
+    let adaptive = if state.adaptive_tint {
+        " (adaptive)"
+    } else {
+        ""
+    };
+    println!("  Tint: [{r}, {g}, {b}, {a}]{adaptive}");
+
+    println!("  Opacity: {}", state.opacity);
+
+    let [tl, tr, br, bl] = state.corner_radius;
+    println!("  Corner radius: [{tl}, {tr}, {br}, {bl}]");
+
+    if state.exclude_opaque_body {
+        println!("  Excludes the opaque window body");
+    }
+
+    if let Some(rects) = state.blur_region_rects {
+        println!("  Blur region: {rects} rectangles");
+    }
+}
+
 fn print_cast(cast: &Cast) {
     let active = if cast.is_active { "" } else { " (inactive)" };
     println!("Cast stream ID {}:{active}", cast.stream_id);
@@ -34,6 +34,7 @@ use crate::backend::IpcOutputMap;
 use crate::input::pick_window_grab::PickWindowGrab;
 use crate::layout::workspace::WorkspaceId;
 use crate::niri::State;
+use crate::render_helpers::background_effect;
 use crate::utils::{version, with_toplevel_role};
 use crate::window::Mapped;
 
@@ -455,6 +456,19 @@ async fn process(ctx: &ClientCtx, request: Request) -> Reply {
             let casts = state.casts.casts.values().cloned().collect();
             Response::Casts(casts)
         }
+        Request::FocusedWindowBackgroundEffect => {
+            let (tx, rx) = async_channel::bounded(1);
+            ctx.event_loop.insert_idle(move |state| {
+                let effect = state.niri.layout.focus().and_then(|mapped| {
+                    background_effect::snapshot_for_surface(mapped.toplevel().wl_surface())
+                });
+                let _ = tx.send_blocking(effect);
+            });
+            let result = rx.recv().await;
+            let effect =
+                result.map_err(|_| String::from("error getting background effect state"))?;
+            Response::FocusedWindowBackgroundEffect(effect)
+        }
     };
 
     Ok(response)
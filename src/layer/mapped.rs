@@ -11,7 +11,7 @@ use super::ResolvedLayerRules;
 use crate::animation::Clock;
 use crate::layout::shadow::Shadow;
 use crate::niri_render_elements;
-use crate::render_helpers::background_effect::BackgroundEffectElement;
+use crate::render_helpers::background_effect::{BackgroundEffectElement, TileParams};
 use crate::render_helpers::renderer::NiriRenderer;
 use crate::render_helpers::shadow::ShadowRenderElement;
 use crate::render_helpers::solid_color::{SolidColorBuffer, SolidColorRenderElement};
@@ -73,6 +73,7 @@ impl MappedLayer {
         scale: f64,
         clock: Clock,
         config: &Config,
+        blur_config: niri_config::Blur,
     ) -> Self {
         let mut shadow_config = config.layout.shadow;
         // Shadows for layer surfaces need to be explicitly enabled.
@@ -88,7 +89,7 @@ impl MappedLayer {
             view_size,
             scale,
             shadow: Shadow::new(shadow_config),
-            blur_config: config.blur,
+            blur_config,
             clock,
         }
     }
@@ -99,8 +100,15 @@ impl MappedLayer {
         shadow_config.on = false;
         shadow_config.merge_with(&self.rules.shadow);
         self.shadow.update_config(shadow_config);
+    }
 
-        self.blur_config = config.blur;
+    pub fn update_blur_config(&mut self, blur_config: niri_config::Blur) -> bool {
+        if self.blur_config == blur_config {
+            return false;
+        }
+
+        self.blur_config = blur_config;
+        true
     }
 
     pub fn update_shaders(&mut self) {
@@ -237,22 +245,26 @@ impl MappedLayer {
         let surface_off = Point::new(0., 0.); // No geometry on layer surfaces.
         let surface_anim_scale = Scale::from(1.);
         let radius = self.rules.geometry_corner_radius.unwrap_or_default();
-        background_effect::render_for_tile(
-            ctx.as_gles(),
-            ns,
+        let tile = TileParams {
             geometry,
-            self.scale,
-            false,
-            surface,
+            visible: None,
+            scale: self.scale,
+            clip_to_geometry: false,
             surface_off,
             surface_anim_scale,
-            self.blur_config,
+            blur_config: self.blur_config,
             radius,
-            self.rules.background_effect,
+            effect: self.rules.background_effect,
             should_block_out,
+            alpha: 1.,
+            resizing: false,
+            focused: false,
             xray_pos,
-            &mut |elem| push(elem.into()),
-        );
+            velocity: Point::new(0., 0.),
+        };
+        background_effect::render_for_tile(ctx.as_gles(), ns, surface, tile, &mut |elem| {
+            push(elem.into())
+        });
     }
 
     pub fn render_popups<R: NiriRenderer>(
@@ -306,22 +318,26 @@ impl MappedLayer {
                 effect.xray = Some(false);
             }
             let xray_pos = xray_pos.offset(offset.to_f64());
-            background_effect::render_for_tile(
-                ctx.as_gles(),
-                ns,
+            let tile = TileParams {
                 geometry,
-                self.scale,
-                false,
-                surface,
+                visible: None,
+                scale: self.scale,
+                clip_to_geometry: false,
                 surface_off,
                 surface_anim_scale,
-                self.blur_config,
-                popup_rules.geometry_corner_radius.unwrap_or_default(),
+                blur_config: self.blur_config,
+                radius: popup_rules.geometry_corner_radius.unwrap_or_default(),
                 effect,
-                false,
+                should_block_out: false,
+                alpha: 1.,
+                resizing: false,
+                focused: false,
                 xray_pos,
-                &mut |elem| push(elem.into()),
-            );
+                velocity: Point::new(0., 0.),
+            };
+            background_effect::render_for_tile(ctx.as_gles(), ns, surface, tile, &mut |elem| {
+                push(elem.into())
+            });
         }
     }
 }
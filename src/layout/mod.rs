@@ -39,7 +39,8 @@ use std::time::Duration;
 use monitor::{InsertHint, InsertPosition, InsertWorkspace, MonitorAddWindowTarget};
 use niri_config::utils::MergeWith as _;
 use niri_config::{
-    Config, CornerRadius, LayoutPart, PresetSize, Workspace as WorkspaceConfig, WorkspaceReference,
+    BlurPart, Config, CornerRadius, LayoutPart, PresetSize, Workspace as WorkspaceConfig,
+    WorkspaceReference,
 };
 use niri_ipc::{ColumnDisplay, PositionChange, SizeChange, WindowLayout};
 use scrolling::{Column, ColumnWidth};
@@ -199,16 +200,22 @@ pub trait LayoutElement {
     }
 
     /// Renders the background effect behind the main surface of the element.
+    ///
+    /// `velocity` is the motion of the element in logical pixels per second, e.g. while it's
+    /// being dragged.
     #[allow(clippy::too_many_arguments)]
     fn render_background_effect(
         &self,
         _ctx: RenderCtx<GlesRenderer>,
         _geometry: Rectangle<f64, Logical>,
+        _visible: Option<Rectangle<f64, Logical>>,
         _scale: f64,
         _clip_to_geometry: bool,
         _surface_anim_scale: Scale<f64>,
         _radius: CornerRadius,
+        _alpha: f32,
         _xray_pos: XrayPos,
+        _velocity: Point<f64, Logical>,
         _push: &mut dyn FnMut(BackgroundEffectElement),
     ) {
     }
@@ -445,6 +452,17 @@ struct InteractiveMoveData<W: LayoutElement> {
     /// config overrides for the workspace where the move originated from. As soon as the window
     /// moves over some different workspace though, this override will reset.
     pub(self) workspace_config: Option<(WorkspaceId, niri_config::LayoutPart)>,
+    /// Velocity of the pointer dragging the window.
+    pub(self) velocity: MoveVelocity,
+}
+
+/// Tracks the velocity of an interactive move.
+#[derive(Debug)]
+struct MoveVelocity {
+    x: SwipeTracker,
+    y: SwipeTracker,
+    /// Time of the last pointer motion.
+    last_motion: Option<Duration>,
 }
 
 #[derive(Debug)]
@@ -607,6 +625,35 @@ impl<W: LayoutElement> InteractiveMoveData<W> {
     }
 }
 
+/// Time without pointer motion after which a dragged window is considered at rest.
+const MOVE_REST_TIMEOUT: Duration = Duration::from_millis(100);
+
+impl MoveVelocity {
+    fn new() -> Self {
+        Self {
+            x: SwipeTracker::new(),
+            y: SwipeTracker::new(),
+            last_motion: None,
+        }
+    }
+
+    fn push(&mut self, delta: Point<f64, Logical>, timestamp: Duration) {
+        self.x.push(delta.x, timestamp);
+        self.y.push(delta.y, timestamp);
+        self.last_motion = Some(timestamp);
+    }
+
+    /// Returns the velocity in logical pixels per second, zero once the pointer rests.
+    fn velocity(&self, now: Duration) -> Point<f64, Logical> {
+        match self.last_motion {
+            Some(last) if now.saturating_sub(last) < MOVE_REST_TIMEOUT => {
+                Point::new(self.x.velocity(), self.y.velocity())
+            }
+            _ => Point::new(0., 0.),
+        }
+    }
+}
+
 impl ActivateWindow {
     pub fn map_smart(self, f: impl FnOnce() -> bool) -> bool {
         match self {
@@ -667,6 +714,13 @@ impl Options {
         self
     }
 
+    fn with_merged_blur(mut self, part: Option<&BlurPart>) -> Self {
+        if let Some(part) = part {
+            self.blur.merge_with(part);
+        }
+        self
+    }
+
     fn adjusted_for_scale(mut self, scale: f64) -> Self {
         self.layout.gaps = round_logical_in_physical_max1(scale, self.layout.gaps);
         self
@@ -732,7 +786,12 @@ impl<W: LayoutElement> Layout<W> {
         }
     }
 
-    pub fn add_output(&mut self, output: Output, layout_config: Option<LayoutPart>) {
+    pub fn add_output(
+        &mut self,
+        output: Output,
+        layout_config: Option<LayoutPart>,
+        blur_config: Option<BlurPart>,
+    ) {
         self.monitor_set = match mem::take(&mut self.monitor_set) {
             MonitorSet::Normal {
                 mut monitors,
@@ -806,6 +865,7 @@ impl<W: LayoutElement> Layout<W> {
                     self.clock.clone(),
                     self.options.clone(),
                     layout_config,
+                    blur_config,
                 );
                 monitor.overview_open = self.overview_open;
                 monitor.set_overview_progress(self.overview_progress.as_ref());
@@ -827,6 +887,7 @@ impl<W: LayoutElement> Layout<W> {
                     self.clock.clone(),
                     self.options.clone(),
                     layout_config,
+                    blur_config,
                 );
                 monitor.overview_open = self.overview_open;
                 monitor.set_overview_progress(self.overview_progress.as_ref());
@@ -2738,6 +2799,11 @@ impl<W: LayoutElement> Layout<W> {
                     return true;
                 }
 
+                // Keep redrawing until the motion blur settles after the pointer stops.
+                if move_.tile.motion_velocity() != Point::new(0., 0.) {
+                    return true;
+                }
+
                 // Keep advancing animations if we might need to scroll the view.
                 if !move_.is_floating || self.overview_open {
                     return true;
@@ -2774,6 +2840,9 @@ impl<W: LayoutElement> Layout<W> {
         let zoom = self.overview_zoom();
         if let Some(InteractiveMoveState::Moving(move_)) = &mut self.interactive_move {
             if output.is_none_or(|output| move_.output == *output) {
+                let velocity = move_.velocity.velocity(self.clock.now_unadjusted());
+                move_.tile.set_motion_velocity(velocity);
+
                 let pos_within_output = move_.tile_render_location(zoom);
 
                 // We're not on any specific workspace so we can't compute a "workspace view" rect.
@@ -2949,7 +3018,12 @@ impl<W: LayoutElement> Layout<W> {
         // Update workspace-specific config for all named workspaces.
         for ws in self.workspaces_mut() {
             let Some(name) = ws.name() else { continue };
-            if let Some(config) = config.workspaces.iter().find(|w| &w.name.0 == name) {
+            let ws_config = config
+                .workspaces
+                .iter()
+                .find(|w| w.name.0.eq_ignore_ascii_case(name));
+            ws.set_xray_disabled(ws_config.is_some_and(|c| c.disable_xray));
+            if let Some(config) = ws_config {
                 ws.update_layout_config(config.layout.clone().map(|x| x.0));
             }
         }
@@ -4009,6 +4083,7 @@ impl<W: LayoutElement> Layout<W> {
                     pointer_ratio_within_window,
                     output_config,
                     workspace_config,
+                    velocity: MoveVelocity::new(),
                 };
 
                 if let Some((tile_pos, zoom)) = tile_pos {
@@ -4070,6 +4145,7 @@ impl<W: LayoutElement> Layout<W> {
                 }
 
                 move_.pointer_pos_within_output = pointer_pos_within_output;
+                move_.velocity.push(delta, self.clock.now_unadjusted());
 
                 self.interactive_move = Some(InteractiveMoveState::Moving(move_));
             }
@@ -3,7 +3,7 @@ use std::iter::zip;
 use std::rc::Rc;
 use std::time::Duration;
 
-use niri_config::{CornerRadius, LayoutPart};
+use niri_config::{BlurPart, CornerRadius, LayoutPart};
 use smithay::backend::renderer::element::utils::{
     CropRenderElement, Relocate, RelocateRenderElement, RescaleRenderElement,
 };
@@ -88,6 +88,8 @@ pub struct Monitor<W: LayoutElement> {
     pub(super) options: Rc<Options>,
     /// Layout config overrides for this monitor.
     layout_config: Option<niri_config::LayoutPart>,
+    /// Blur config overrides for this monitor.
+    blur_config: Option<BlurPart>,
 }
 
 #[derive(Debug)]
@@ -296,9 +298,13 @@ impl<W: LayoutElement> Monitor<W> {
         clock: Clock,
         base_options: Rc<Options>,
         layout_config: Option<LayoutPart>,
+        blur_config: Option<BlurPart>,
     ) -> Self {
-        let options =
-            Rc::new(Options::clone(&base_options).with_merged_layout(layout_config.as_ref()));
+        let options = Rc::new(
+            Options::clone(&base_options)
+                .with_merged_layout(layout_config.as_ref())
+                .with_merged_blur(blur_config.as_ref()),
+        );
 
         let scale = output.current_scale();
         let view_size = output_size(&output);
@@ -346,6 +352,7 @@ impl<W: LayoutElement> Monitor<W> {
             base_options,
             options,
             layout_config,
+            blur_config,
         }
     }
 
@@ -1184,8 +1191,11 @@ impl<W: LayoutElement> Monitor<W> {
     }
 
     pub fn update_config(&mut self, base_options: Rc<Options>) {
-        let options =
-            Rc::new(Options::clone(&base_options).with_merged_layout(self.layout_config.as_ref()));
+        let options = Rc::new(
+            Options::clone(&base_options)
+                .with_merged_layout(self.layout_config.as_ref())
+                .with_merged_blur(self.blur_config.as_ref()),
+        );
 
         if self.options.layout.empty_workspace_above_first
             != options.layout.empty_workspace_above_first
@@ -1221,6 +1231,17 @@ impl<W: LayoutElement> Monitor<W> {
         true
     }
 
+    pub fn update_blur_config(&mut self, blur_config: Option<BlurPart>) -> bool {
+        if self.blur_config == blur_config {
+            return false;
+        }
+
+        self.blur_config = blur_config;
+        self.update_config(self.base_options.clone());
+
+        true
+    }
+
     pub fn update_shaders(&mut self) {
         for ws in &mut self.workspaces {
             ws.update_shaders();
@@ -2058,8 +2079,9 @@ impl<W: LayoutElement> Monitor<W> {
     pub(super) fn verify_invariants(&self) {
         use approx::assert_abs_diff_eq;
 
-        let options =
-            Options::clone(&self.base_options).with_merged_layout(self.layout_config.as_ref());
+        let options = Options::clone(&self.base_options)
+            .with_merged_layout(self.layout_config.as_ref())
+            .with_merged_blur(self.blur_config.as_ref());
         assert_eq!(&*self.options, &options);
 
         assert!(
@@ -789,7 +789,7 @@ impl Op {
                     model: None,
                     serial: None,
                 });
-                layout.add_output(output.clone(), None);
+                layout.add_output(output.clone(), None, None);
             }
             Op::AddScaledOutput {
                 id,
@@ -826,7 +826,7 @@ impl Op {
                     model: None,
                     serial: None,
                 });
-                layout.add_output(output.clone(), layout_config.map(|x| *x));
+                layout.add_output(output.clone(), layout_config.map(|x| *x), None);
             }
             Op::RemoveOutput(id) => {
                 let name = format!("output{id}");
@@ -861,6 +861,7 @@ impl Op {
                     name: WorkspaceName(format!("ws{ws_name}")),
                     open_on_output: output_name.map(|name| format!("output{name}")),
                     layout: layout_config.map(|x| niri_config::WorkspaceLayoutPart(*x)),
+                    disable_xray: false,
                 });
             }
             Op::UnnameWorkspace { ws_name } => {
@@ -2437,6 +2438,44 @@ fn preset_height_change_removes_preset() {
     layout.verify_invariants();
 }
 
+#[test]
+fn workspace_xray_disabled_follows_config() {
+    let mut config = Config::default();
+    let mut layout = Layout::<TestWindow>::new(Clock::default(), &config);
+    Op::AddOutput(1).apply(&mut layout);
+
+    let ws_config = WorkspaceConfig {
+        name: WorkspaceName(String::from("ws")),
+        open_on_output: None,
+        layout: None,
+        disable_xray: true,
+    };
+    layout.ensure_named_workspace(&ws_config);
+    let xray_disabled = |layout: &Layout<TestWindow>, name| {
+        let (_, ws) = layout.find_workspace_by_name(name).unwrap();
+        ws.is_xray_disabled()
+    };
+    assert!(xray_disabled(&layout, "ws"));
+
+    // Names are matched case-insensitively on config reload.
+    config.workspaces = vec![WorkspaceConfig {
+        name: WorkspaceName(String::from("WS")),
+        disable_xray: false,
+        ..ws_config.clone()
+    }];
+    layout.update_config(&config);
+    assert!(!xray_disabled(&layout, "ws"));
+
+    config.workspaces[0].disable_xray = true;
+    layout.update_config(&config);
+    assert!(xray_disabled(&layout, "ws"));
+
+    // Removing the workspace from the config enables xray again.
+    config.workspaces.clear();
+    layout.update_config(&config);
+    assert!(!xray_disabled(&layout, "ws"));
+}
+
 #[test]
 fn set_window_height_recomputes_to_auto() {
     let ops = [
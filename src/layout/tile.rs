@@ -99,12 +99,21 @@ pub struct Tile<W: LayoutElement> {
     /// Offset during the initial interactive move rubberband.
     pub(super) interactive_move_offset: Point<f64, Logical>,
 
+    /// Velocity of the tile during an interactive move, for the motion blur of its effect.
+    motion_velocity: Point<f64, Logical>,
+
     /// Snapshot of the last render for use in the close animation.
     unmap_snapshot: Option<TileRenderSnapshot>,
 
     /// Extra damage for clipped surface corner radius changes.
     rounded_corner_damage: RoundedCornerDamage,
 
+    /// The visible part of the view, relative to the tile.
+    ///
+    /// Limits the background effect to what can be seen. Unknown until the first
+    /// update_render_elements().
+    view_rect: Option<Rectangle<f64, Logical>>,
+
     /// The view size for the tile's workspace.
     ///
     /// Used as the fullscreen target size.
@@ -206,8 +215,10 @@ impl<W: LayoutElement> Tile<W> {
             move_y_animation: None,
             alpha_animation: None,
             interactive_move_offset: Point::from((0., 0.)),
+            motion_velocity: Point::from((0., 0.)),
             unmap_snapshot: None,
             rounded_corner_damage: Default::default(),
+            view_rect: None,
             view_size,
             scale,
             clock,
@@ -537,6 +548,7 @@ impl<W: LayoutElement> Tile<W> {
         );
 
         self.fullscreen_backdrop.resize(animated_tile_size);
+        self.view_rect = Some(view_rect);
     }
 
     pub fn scale(&self) -> f64 {
@@ -558,6 +570,14 @@ impl<W: LayoutElement> Tile<W> {
         offset
     }
 
+    pub fn motion_velocity(&self) -> Point<f64, Logical> {
+        self.motion_velocity
+    }
+
+    pub fn set_motion_velocity(&mut self, velocity: Point<f64, Logical>) {
+        self.motion_velocity = velocity;
+    }
+
     pub fn start_open_animation(&mut self) {
         self.open_animation = Some(OpenAnimation::new(Animation::new(
             self.clock.clone(),
@@ -873,6 +893,22 @@ impl<W: LayoutElement> Tile<W> {
 
     fn is_in_input_region(&self, mut point: Point<f64, Logical>) -> bool {
         point -= self.window_loc().to_f64();
+
+        // When clipping to geometry, the rounded corners are cut out of the window, so clicks
+        // there shouldn't reach it either. This matches the clipping in render_inner().
+        let clip_to_geometry =
+            self.fullscreen_progress() < 1. && self.window.rules().clip_to_geometry == Some(true);
+        if clip_to_geometry {
+            let size = self.window_size();
+            let radius = self
+                .window
+                .geometry_corner_radius()
+                .scaled_by(1. - self.expanded_progress() as f32);
+            if Rectangle::from_size(size).contains(point) && !radius.contains(point, size) {
+                return false;
+            }
+        }
+
         self.window.is_in_input_region(point)
     }
 
@@ -1021,12 +1057,18 @@ impl<W: LayoutElement> Tile<W> {
         Point::from((0., y))
     }
 
+    /// Renders the tile contents.
+    ///
+    /// `effect_alpha` fades the background effect. It should match the opacity that the tile is
+    /// drawn with when that isn't already applied through an offscreen.
+    #[allow(clippy::too_many_arguments)]
     fn render_inner<R: NiriRenderer>(
         &self,
         mut ctx: RenderCtx<R>,
         location: Point<f64, Logical>,
         mut xray_pos: XrayPos,
         focus_ring: bool,
+        effect_alpha: f32,
         push: &mut dyn FnMut(TileRenderElement<R>),
     ) {
         let _span = tracy_client::span!("Tile::render_inner");
@@ -1045,6 +1087,11 @@ impl<W: LayoutElement> Tile<W> {
             alpha * (1. - p) + 1. * p
         };
 
+        // The background effect doesn't need to capture what's outside the view.
+        let visible = self
+            .view_rect
+            .map(|view_rect| Rectangle::new(location + view_rect.loc, view_rect.size));
+
         // This is here rather than in render_offset() because render_offset() is currently assumed
         // by the code to be temporary. So, for example, interactive move will try to "grab" the
         // tile at its current render offset and reset the render offset to zero by cancelling the
@@ -1305,11 +1352,14 @@ impl<W: LayoutElement> Tile<W> {
         self.window.render_background_effect(
             ctx.as_gles(),
             area,
+            visible,
             self.scale,
             clip_to_geometry,
             surface_anim_scale,
             radius,
+            effect_alpha,
             xray_pos,
+            self.motion_velocity,
             &mut |elem| push(elem.into()),
         );
     }
@@ -1342,6 +1392,7 @@ impl<W: LayoutElement> Tile<W> {
                 Point::new(0., 0.),
                 xray_pos,
                 focus_ring,
+                1.,
                 &mut |elem| elements.push(elem),
             );
             match open.render(
@@ -1369,6 +1420,7 @@ impl<W: LayoutElement> Tile<W> {
                 Point::new(0., 0.),
                 xray_pos,
                 focus_ring,
+                1.,
                 &mut |elem| elements.push(elem),
             );
             match alpha.offscreen.render(ctx.renderer, scale, &elements) {
@@ -1387,7 +1439,15 @@ impl<W: LayoutElement> Tile<W> {
         }
 
         if !pushed {
-            self.render_inner(ctx, location, xray_pos, focus_ring, &mut |elem| push(elem));
+            // Nothing faded the tile through an offscreen, so fade the background effect directly.
+            self.render_inner(
+                ctx,
+                location,
+                xray_pos,
+                focus_ring,
+                tile_alpha,
+                &mut |elem| push(elem),
+            );
         }
     }
 
@@ -109,6 +109,9 @@ pub struct Workspace<W: LayoutElement> {
     /// Layout config overrides for this workspace.
     layout_config: Option<niri_config::LayoutPart>,
 
+    /// Whether effects show the plain workspace background rather than xray on this workspace.
+    xray_disabled: bool,
+
     /// Unique ID of this workspace.
     id: WorkspaceId,
 }
@@ -223,6 +226,7 @@ impl<W: LayoutElement> Workspace<W> {
             .unwrap_or(OutputId::new(&output));
 
         let layout_config = config.as_mut().and_then(|c| c.layout.take().map(|x| x.0));
+        let xray_disabled = config.as_ref().is_some_and(|c| c.disable_xray);
 
         let scale = output.current_scale();
         let options = Rc::new(
@@ -270,6 +274,7 @@ impl<W: LayoutElement> Workspace<W> {
             options,
             name: config.map(|c| c.name.0),
             layout_config,
+            xray_disabled,
             id: WorkspaceId::next(),
         }
     }
@@ -287,6 +292,7 @@ impl<W: LayoutElement> Workspace<W> {
         );
 
         let layout_config = config.as_mut().and_then(|c| c.layout.take().map(|x| x.0));
+        let xray_disabled = config.as_ref().is_some_and(|c| c.disable_xray);
 
         let scale = smithay::output::Scale::Integer(1);
         let options = Rc::new(
@@ -334,6 +340,7 @@ impl<W: LayoutElement> Workspace<W> {
             options,
             name: config.map(|c| c.name.0),
             layout_config,
+            xray_disabled,
             id: WorkspaceId::next(),
         }
     }
@@ -352,6 +359,7 @@ impl<W: LayoutElement> Workspace<W> {
 
     pub fn unname(&mut self) {
         self.name = None;
+        self.xray_disabled = false;
     }
 
     pub fn has_windows_or_name(&self) -> bool {
@@ -425,6 +433,14 @@ impl<W: LayoutElement> Workspace<W> {
         self.options = options;
     }
 
+    pub fn is_xray_disabled(&self) -> bool {
+        self.xray_disabled
+    }
+
+    pub fn set_xray_disabled(&mut self, disabled: bool) {
+        self.xray_disabled = disabled;
+    }
+
     pub fn update_layout_config(&mut self, layout_config: Option<niri_config::LayoutPart>) {
         if self.layout_config == layout_config {
             return;
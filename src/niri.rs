@@ -8,7 +8,7 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use std::{env, mem, thread};
+use std::{env, fs, mem, thread};
 
 use _server_decoration::server::org_kde_kwin_server_decoration_manager::Mode as KdeDecorationsMode;
 use anyhow::{bail, ensure, Context};
@@ -127,6 +127,7 @@ use crate::dbus::gnome_shell_introspect::{self, IntrospectToNiri, NiriToIntrospe
 #[cfg(feature = "dbus")]
 use crate::dbus::gnome_shell_screenshot::{NiriToScreenshot, ScreenshotToNiri};
 use crate::frame_clock::FrameClock;
+use crate::handlers::background_effect::{dump_blur_regions, SharedRegionCache};
 use crate::handlers::{configure_lock_surface, XDG_ACTIVATION_TOKEN_TIMEOUT};
 use crate::input::pick_color_grab::PickColorGrab;
 use crate::input::scroll_swipe_gesture::ScrollSwipeGesture;
@@ -151,14 +152,15 @@ use crate::protocols::mutter_x11_interop::MutterX11InteropManagerState;
 use crate::protocols::output_management::OutputManagementManagerState;
 use crate::protocols::screencopy::{Screencopy, ScreencopyBuffer, ScreencopyManagerState};
 use crate::protocols::virtual_pointer::VirtualPointerManagerState;
-use crate::render_helpers::blur::BlurOptions;
-use crate::render_helpers::debug::push_opaque_regions;
+use crate::render_helpers::blur::{AdaptiveBlurPasses, BlurContext, BlurOptions, BlurOverride};
+use crate::render_helpers::debug::{push_opaque_regions, EffectCollector};
+use crate::render_helpers::framebuffer_effect::{CaptureOrder, OcclusionTracker};
 use crate::render_helpers::primary_gpu_texture::PrimaryGpuTextureRenderElement;
 use crate::render_helpers::renderer::NiriRenderer;
 use crate::render_helpers::solid_color::{SolidColorBuffer, SolidColorRenderElement};
 use crate::render_helpers::surface::push_elements_from_surface_tree;
 use crate::render_helpers::texture::TextureBuffer;
-use crate::render_helpers::xray::{Xray, XrayPos};
+use crate::render_helpers::xray::{Xray, XrayPos, XrayWorkspace};
 use crate::render_helpers::{
     encompassing_geo, render_to_dmabuf, render_to_encompassing_texture, render_to_shm,
     render_to_texture, render_to_vec, shaders, RenderCtx, RenderTarget,
@@ -283,6 +285,8 @@ pub struct Niri {
     pub output_management_state: OutputManagementManagerState,
     pub viewporter_state: ViewporterState,
     pub background_effect_state: BackgroundEffectState,
+    /// Processed background effect regions, shared between surfaces declaring the same region.
+    pub blur_region_cache: SharedRegionCache,
     pub xdg_foreign_state: XdgForeignState,
     pub shm_state: ShmState,
     pub output_manager_state: OutputManagerState,
@@ -478,12 +482,16 @@ pub struct OutputState {
     /// tracking issues and make screenshots easier.
     pub backdrop_buffer: SolidColorBuffer,
     pub xray: Xray,
+    /// Blur pass reduction for the xray buffers when the blur `adaptive-passes` flag is set.
+    pub adaptive_blur_passes: AdaptiveBlurPasses,
     pub lock_render_state: LockRenderState,
     pub lock_surface: Option<LockSurface>,
     pub lock_color_buffer: SolidColorBuffer,
     screen_transition: Option<ScreenTransition>,
     /// Damage tracker used for the debug damage visualization.
     pub debug_damage_tracker: OutputDamageTracker,
+    /// Whether to log the background effect elements of the next rendered frame.
+    pub debug_log_background_effects: Cell<bool>,
 }
 
 #[derive(Debug, Default)]
@@ -1596,6 +1604,10 @@ impl State {
         if config.layout.background_color != old_config.layout.background_color {
             output_config_changed = true;
         }
+        // Outputs can override parts of the blur config.
+        if config.blur != old_config.blur {
+            output_config_changed = true;
+        }
 
         if config.recent_windows != old_config.recent_windows {
             recent_windows_changed = true;
@@ -1787,8 +1799,21 @@ impl State {
                     // Also redraw these; if anything, the background color could've changed.
                     recolored_outputs.push(output.clone());
                 }
+                if mon.update_blur_config(config.and_then(|c| c.blur)) {
+                    recolored_outputs.push(output.clone());
+                }
                 break;
             }
+
+            let blur_config = full_config.blur_for_output(name);
+            let layer_map = layer_map_for_output(output);
+            for surface in layer_map.layers() {
+                if let Some(mapped) = self.niri.mapped_layer_surfaces.get_mut(surface) {
+                    if mapped.update_blur_config(blur_config) {
+                        recolored_outputs.push(output.clone());
+                    }
+                }
+            }
         }
 
         for output in resized_outputs {
@@ -2527,6 +2552,7 @@ impl Niri {
             screencopy_state,
             viewporter_state,
             background_effect_state,
+            blur_region_cache: SharedRegionCache::default(),
             xdg_foreign_state,
             text_input_state,
             input_method_state,
@@ -2839,6 +2865,7 @@ impl Niri {
                 layout.background_color = c.and_then(|c| c.background_color);
             }
         }
+        let blur_config = c.and_then(|c| c.blur);
         drop(config);
 
         // Set scale and transform before adding to the layout since that will read the output size.
@@ -2849,7 +2876,8 @@ impl Niri {
             None,
         );
 
-        self.layout.add_output(output.clone(), layout_config);
+        self.layout
+            .add_output(output.clone(), layout_config, blur_config);
 
         let lock_render_state = if self.is_locked() {
             // We haven't rendered anything yet so it's as good as locked.
@@ -2870,11 +2898,13 @@ impl Niri {
             frame_callback_sequence: 0,
             backdrop_buffer: SolidColorBuffer::new(size, backdrop_color),
             xray: Xray::new(),
+            adaptive_blur_passes: AdaptiveBlurPasses::default(),
             lock_render_state,
             lock_surface: None,
             lock_color_buffer: SolidColorBuffer::new(size, CLEAR_COLOR_LOCKED),
             screen_transition: None,
             debug_damage_tracker: OutputDamageTracker::from_output(&output),
+            debug_log_background_effects: Cell::new(false),
         };
         let rv = self.output_state.insert(output.clone(), state);
         assert!(rv.is_none(), "output was already tracked");
@@ -4107,19 +4137,42 @@ impl Niri {
                 state.xray.workspaces.clear();
                 let mon = self.layout.monitor_for_output(out).unwrap();
                 for (ws, geo) in mon.workspaces_with_render_geo() {
-                    let bg_color = ws.render_background().color();
-                    state.xray.workspaces.push((geo, bg_color));
+                    state.xray.workspaces.push(XrayWorkspace {
+                        geo,
+                        bg_color: ws.render_background().color(),
+                        xray_disabled: ws.is_xray_disabled(),
+                    });
                 }
                 state.xray.backdrop_color = state.backdrop_buffer.color();
-                let blur_options = BlurOptions::from(self.config.borrow().blur);
+                let name = out.user_data().get::<OutputName>().unwrap();
+                let context = if self.layout.is_overview_open() {
+                    BlurContext::Overview
+                } else {
+                    BlurContext::Window
+                };
+                let blur_config = {
+                    let config = self.config.borrow();
+                    context.resolve_config(config.blur_for_output(name), &config.overview)
+                };
+                state.xray.blur_config = (context == BlurContext::Overview).then_some(blur_config);
+                let runtime = BlurOverride {
+                    passes: blur_config
+                        .adaptive_passes
+                        .then(|| state.adaptive_blur_passes.passes(blur_config.passes)),
+                    ..Default::default()
+                };
+                let blur_options =
+                    BlurOptions::resolve(&blur_config, BlurOverride::default(), runtime)
+                        .scaled(scale.x);
+                // The xray buffers are rendered upright, in logical orientation.
                 for buf in &state.xray.background {
                     let mut buffer = buf.borrow_mut();
-                    buffer.update_size(size, scale);
+                    buffer.update_size(size, scale, Transform::Normal);
                     buffer.update_blur_options(blur_options);
                 }
                 for buf in &state.xray.backdrop {
                     let mut buffer = buf.borrow_mut();
-                    buffer.update_size(size, scale);
+                    buffer.update_size(size, scale, Transform::Normal);
                     buffer.update_blur_options(blur_options);
                 }
 
@@ -4184,7 +4237,46 @@ impl Niri {
         let state = self.output_state.get(output).unwrap();
         ctx.xray = Some(&state.xray);
 
-        self.render_inner(ctx, output, include_pointer, push);
+        let collector = (ctx.target == RenderTarget::Output
+            && state.debug_log_background_effects.take())
+        .then(EffectCollector::install);
+
+        // Framebuffer effects must not capture the pointer, which is why it's pushed first.
+        let mut capture_order = CaptureOrder::default();
+        let push = &mut |elem: OutputRenderElements<R>| {
+            let is_pointer = matches!(elem, OutputRenderElements::Pointer(_));
+            capture_order.add(&elem, is_pointer);
+            push(elem);
+        };
+
+        if self.config.borrow().debug.skip_occluded_background_effects {
+            let output_scale = Scale::from(output.current_scale().fractional_scale());
+            let mut occlusion = OcclusionTracker::default();
+            self.render_inner(ctx, output, include_pointer, &mut |elem| {
+                occlusion.add(&elem, output_scale);
+                push(elem);
+            });
+            occlusion.apply();
+        } else {
+            self.render_inner(ctx, output, include_pointer, push);
+            OcclusionTracker::clear();
+        }
+
+        if capture_order.captures_overlay() {
+            warn!("the pointer is drawn below a background effect and will be blurred into it");
+        }
+
+        if let Some(collector) = collector {
+            let descriptors = collector.take();
+            info!(
+                "rendered {} background effect elements on {}",
+                descriptors.len(),
+                output.name()
+            );
+            for descriptor in descriptors {
+                info!("{descriptor:?}");
+            }
+        }
 
         self.clear_xray_elements(output);
     }
@@ -4208,7 +4300,9 @@ impl Niri {
             push
         };
 
-        // The pointer goes on the top.
+        // The pointer goes on the top. This way, it's drawn after all framebuffer effects have
+        // captured what's below them, and doesn't get blurred into their backdrop. On the TTY, the
+        // pointer may also go on a cursor plane, which is never captured.
         if include_pointer && self.pointer_visibility.is_visible() {
             self.render_pointer(ctx.renderer, output, &mut |elem| push(elem.into()));
         }
@@ -4617,7 +4711,24 @@ impl Niri {
             }
 
             // Render.
+            let start = Instant::now();
             res = backend.render(self, output, target_presentation_time);
+            let frame_time = start.elapsed();
+
+            let name = output.user_data().get::<OutputName>().unwrap();
+            let blur_config = self.config.borrow().blur_for_output(name);
+            let state = self.output_state.get_mut(output).unwrap();
+            if res != RenderResult::Skipped && blur_config.adaptive_passes && !blur_config.off {
+                if let Some(budget) = state.frame_clock.refresh_interval() {
+                    let adaptive = &mut state.adaptive_blur_passes;
+                    if adaptive.record(frame_time, budget, blur_config.passes) {
+                        debug!(
+                            "adaptive blur: using {} passes after a {frame_time:?} frame",
+                            adaptive.passes(blur_config.passes)
+                        );
+                    }
+                }
+            }
         }
 
         let is_locked = self.is_locked();
@@ -5454,6 +5565,27 @@ impl Niri {
         self.queue_redraw_all();
     }
 
+    pub fn debug_log_background_effects(&mut self) {
+        for state in self.output_state.values() {
+            state.debug_log_background_effects.set(true);
+        }
+
+        self.queue_redraw_all();
+    }
+
+    pub fn debug_dump_effect_regions(&mut self) {
+        let Some(mapped) = self.layout.focus() else {
+            return;
+        };
+
+        let dump = dump_blur_regions(mapped.toplevel().wl_surface());
+        let path = env::temp_dir().join("niri-effect-regions.txt");
+        match fs::write(&path, dump) {
+            Ok(()) => info!("dumped effect regions of the focused window to {path:?}"),
+            Err(err) => warn!("error writing effect regions to {path:?}: {err:?}"),
+        }
+    }
+
     pub fn capture_screenshots<'a>(
         &'a self,
         renderer: &'a mut GlesRenderer,
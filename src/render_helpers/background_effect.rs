@@ -1,31 +1,49 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash as _, Hasher as _};
 use std::sync::{Arc, Mutex};
 
-use niri_config::CornerRadius;
-use smithay::backend::renderer::gles::GlesRenderer;
-use smithay::utils::{Logical, Point, Rectangle, Scale};
+use niri_config::{Color, CornerRadius};
+use smithay::backend::renderer::element::Id;
+use smithay::backend::renderer::gles::{
+    ffi, GlesError, GlesFrame, GlesRenderer, GlesTexture, Uniform, UniformValue,
+};
+use smithay::backend::renderer::Color32F;
+use smithay::utils::{Logical, Point, Rectangle, Scale, Size};
 use smithay::wayland::compositor::{with_states, SurfaceData};
 use wayland_server::protocol::wl_surface::WlSurface;
 
-use crate::handlers::background_effect::get_cached_blur_region;
+use crate::handlers::background_effect::get_aggregated_blur_region;
 use crate::niri_render_elements;
-use crate::render_helpers::blur::BlurOptions;
+use crate::render_helpers::blur::{BlurDirection, BlurOptions, BlurOverride};
 use crate::render_helpers::damage::ExtraDamage;
+use crate::render_helpers::debug::{record_effect, EffectDescriptor, EffectKind};
 use crate::render_helpers::framebuffer_effect::{FramebufferEffect, FramebufferEffectElement};
 use crate::render_helpers::xray::{XrayElement, XrayPos};
 use crate::render_helpers::RenderCtx;
 use crate::utils::region::TransformedRegion;
 use crate::utils::surface_geo;
 
+/// Background effect of a window or a layer surface.
+///
+/// Effect elements are always pushed after (so, below) all elements of the owning surface,
+/// including its border, focus ring and shadow. The surface therefore always composites on top of
+/// the effect, and the effect's opacity and tint apply only to the effect itself.
 #[derive(Debug)]
 pub struct BackgroundEffect {
     nonxray: FramebufferEffect,
     /// Damage when options change.
     damage: ExtraDamage,
-    /// Corner radius for clipping.
+    /// Corner radius for clipping the effect.
     ///
     /// Stored here in addition to `RenderParams` to damage when it changes.
     // FIXME: would be good to remove this duplication of radius.
     corner_radius: CornerRadius,
+    /// Corner radius of the window contents, used to find its opaque body.
+    body_corner_radius: CornerRadius,
+    /// Opacity of the owning surface during the last render, to damage when it changes.
+    alpha: f32,
+    /// Offset of the noise pattern, see [`Postprocess::noise_seed`].
+    noise_seed: f32,
     blur_config: niri_config::Blur,
     options: Options,
 }
@@ -33,18 +51,317 @@ pub struct BackgroundEffect {
 #[derive(Debug, Clone, Copy, PartialEq, Default)]
 pub struct Options {
     pub blur: bool,
-    pub xray: bool,
+    pub strategy: EffectStrategy,
     pub noise: Option<f64>,
     pub saturation: Option<f64>,
+    pub opacity: Option<f64>,
+    pub tint: Option<Color>,
+    pub adaptive_tint: bool,
+    pub exclude_opaque_body: bool,
+    pub region_edge_falloff: Option<f64>,
+    pub edge_ring: Option<EdgeRing>,
+}
+
+/// Color adjustments applied after sampling the background.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Postprocess {
+    pub noise: f32,
+    /// Offset of the noise pattern in logical pixels.
+    ///
+    /// Every effect gets its own, so that the noise of overlapping effects doesn't line up into a
+    /// visible pattern.
+    pub noise_seed: f32,
+    pub saturation: f32,
+    /// Premultiplied color drawn over the effect.
+    pub tint: Color32F,
+    /// Whether to scale the tint by the average brightness of the blurred background.
+    pub adaptive_tint: bool,
+    /// Opacity of the whole effect, including the tint.
+    pub alpha: f32,
+    /// Highlight along the inside of the clip edge.
+    pub edge_ring: Option<EdgeRing>,
+}
+
+impl Postprocess {
+    /// Whether the postprocessing leaves the sampled background unchanged.
+    pub fn is_neutral(&self) -> bool {
+        self.noise == 0.
+            && self.saturation == 1.
+            && self.tint.a() == 0.
+            && !self.adaptive_tint
+            && self.edge_ring.is_none()
+    }
+
+    /// Whether the postprocessing makes the effect fully opaque whatever the background.
+    ///
+    /// The captured background can itself be transparent, so only an opaque tint guarantees this.
+    /// The adaptive tint can make the tint weaker, so it rules this out.
+    pub fn is_opaque(&self) -> bool {
+        self.alpha >= 1. && self.tint.a() >= 1. && !self.adaptive_tint
+    }
+
+    /// Scales the postprocessing for a shader whose geometry is scaled by `zoom`.
+    pub fn upscale(self, zoom: f64) -> Self {
+        Self {
+            edge_ring: self.edge_ring.map(|ring| ring.upscale(zoom)),
+            ..self
+        }
+    }
+
+    /// Returns the active stages of the postprocess shader in the order they're applied.
+    ///
+    /// This is for debugging color issues and mirrors `postprocess.frag` and
+    /// `clipped_surface.frag`. `background` is whether a background color is mixed in behind the
+    /// texture, and `clip` is whether the result is clipped and rounded.
+    pub fn pipeline_order(&self, background: bool, clip: bool) -> String {
+        let tint = if self.adaptive_tint {
+            "adaptive-tint"
+        } else {
+            "tint"
+        };
+
+        let stages = [
+            (self.saturation != 1., "saturation"),
+            (background, "background"),
+            (self.tint.a() != 0., tint),
+            (self.noise > 0., "noise"),
+            (clip && self.edge_ring.is_some(), "edge-ring"),
+            (clip, "corner-clip"),
+            (self.alpha != 1., "opacity"),
+        ];
+
+        let active: Vec<_> = stages
+            .into_iter()
+            .filter_map(|(active, name)| active.then_some(name))
+            .collect();
+        if active.is_empty() {
+            return String::from("none");
+        }
+        active.join(" → ")
+    }
+
+    /// Returns the blur pyramid level to read the average color from for the adaptive tint.
+    ///
+    /// `levels` go from large to small. The smallest one is the most blurred, so the shader can
+    /// average a coarse grid of samples over it without reading anything back from the GPU.
+    /// Returns `None` if the adaptive tint is off or if there's no blurred level.
+    pub fn average_level<'a, T>(&self, levels: &'a [T]) -> Option<&'a T> {
+        if !self.adaptive_tint {
+            return None;
+        }
+
+        match levels {
+            [_output, .., smallest] => Some(smallest),
+            _ => None,
+        }
+    }
+
+    /// Returns the adaptive tint uniforms of the postprocess shader.
+    ///
+    /// `average_bound` is whether an average texture was bound with [`bind_average_texture()`].
+    pub fn adaptive_tint_uniforms(average_bound: bool) -> [Uniform<'static>; 2] {
+        [
+            Uniform::new("adaptive_tint", if average_bound { 1f32 } else { 0. }),
+            Uniform::new(
+                "niri_average",
+                UniformValue::_1i(AVERAGE_TEXTURE_UNIT as i32),
+            ),
+        ]
+    }
+}
+
+/// Texture unit that the postprocess shader samples the average color from.
+const AVERAGE_TEXTURE_UNIT: u32 = 1;
+
+/// Binds `texture` for the postprocess shader to read the average color from.
+///
+/// Pass `None` to unbind it after drawing.
+pub fn bind_average_texture(
+    frame: &mut GlesFrame,
+    texture: Option<&GlesTexture>,
+) -> Result<(), GlesError> {
+    let tex_id = texture.map_or(0, |texture| texture.tex_id());
+    frame.with_context(|gl| unsafe {
+        gl.ActiveTexture(ffi::TEXTURE0 + AVERAGE_TEXTURE_UNIT);
+        gl.BindTexture(ffi::TEXTURE_2D, tex_id);
+        gl.ActiveTexture(ffi::TEXTURE0);
+    })
+}
+
+/// Returns the `alpha_dither` uniform for a target with `bits` per color channel.
+///
+/// Dithering breaks up banding in the anti-aliased corner alpha on 8-bit targets. Deeper and
+/// floating point targets have enough precision, so they get none.
+pub fn alpha_dither(bits: i32) -> f32 {
+    if (1..=8).contains(&bits) {
+        1. / ((1 << bits) - 1) as f32
+    } else {
+        0.
+    }
+}
+
+/// Returns the `alpha_dither` uniform for the framebuffer that `frame` draws into.
+pub fn frame_alpha_dither(frame: &mut GlesFrame) -> Result<f32, GlesError> {
+    let bits = frame.with_context(|gl| unsafe {
+        let mut bits = 0;
+        gl.GetIntegerv(ffi::RED_BITS, &mut bits);
+        bits
+    })?;
+    Ok(alpha_dither(bits))
+}
+
+/// How to render the background effect.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EffectStrategy {
+    /// Pick xray or framebuffer depending on the active effects.
+    ///
+    /// The choice is made as follows:
+    ///
+    /// | blur | noise / saturation | xray  |
+    /// |------|--------------------|-------|
+    /// | on   | any                | true  |
+    /// | off  | set                | false |
+    /// | off  | unset              | false |
+    ///
+    /// Blurred effects default to xray since it's much cheaper: the backdrop is blurred once and
+    /// shared. Noise and saturation on their own default to the non-xray path, since they're
+    /// meant to apply to the actual contents behind the surface rather than the static backdrop.
+    #[default]
+    Auto,
+    /// Always sample the xray backdrop, even if no other effect is active.
+    ForceXray,
+    /// Always sample the actual contents behind the surface.
+    ForceFramebuffer,
+}
+
+impl EffectStrategy {
+    /// Converts from the `xray` background effect rule.
+    pub fn from_xray_rule(xray: Option<bool>) -> Self {
+        match xray {
+            None => Self::Auto,
+            Some(true) => Self::ForceXray,
+            Some(false) => Self::ForceFramebuffer,
+        }
+    }
+
+    /// Whether to use xray for an effect with the given blur state.
+    pub fn use_xray(self, blur: bool) -> bool {
+        match self {
+            Self::Auto => blur,
+            Self::ForceXray => true,
+            Self::ForceFramebuffer => false,
+        }
+    }
 }
 
 impl Options {
+    /// Resolves the effect options from the rule and the surface state.
+    fn resolve(
+        effect: niri_config::BackgroundEffect,
+        has_blur_region: bool,
+        focused: bool,
+    ) -> Self {
+        // If the surface explicitly requests a blur region, default blur to true.
+        let blur = if has_blur_region {
+            effect.blur != Some(false)
+        } else {
+            effect.blur == Some(true)
+        };
+        // Focused surfaces show a crisp backdrop under the unfocused-only policy.
+        let blur = blur && !(focused && effect.blur_unfocused_only == Some(true));
+
+        Self {
+            blur,
+            strategy: EffectStrategy::from_xray_rule(effect.xray),
+            noise: clamp_finite(effect.noise, 0., 1.),
+            saturation: clamp_finite(effect.saturation, 0., MAX_SATURATION),
+            opacity: clamp_finite(effect.opacity, 0., 1.),
+            tint: effect.tint,
+            adaptive_tint: effect.adaptive_tint == Some(true),
+            exclude_opaque_body: effect.exclude_opaque_body == Some(true),
+            region_edge_falloff: effect.region_edge_falloff,
+            edge_ring: EdgeRing::new(
+                effect.edge_ring_width,
+                effect.edge_ring_color,
+                effect.edge_ring_intensity,
+            ),
+        }
+    }
+
+    /// Computes the postprocessing for the effect with the given blur state.
+    ///
+    /// `blur_radius` is the effective radius of the blur in pixels, or `None` if the effect isn't
+    /// blurred.
+    fn postprocess(
+        &self,
+        blur_config: &niri_config::Blur,
+        blur_radius: Option<f64>,
+    ) -> Postprocess {
+        let blur = blur_radius.is_some();
+
+        // Use noise/saturation from options, falling back to blur defaults if blurred, and to no
+        // effect if not blurred.
+        let noise = blur_radius.map_or(0., |radius| {
+            (blur_config.noise * noise_falloff(blur_config.noise_falloff, radius)).clamp(0., 1.)
+        });
+        let noise = self.noise.unwrap_or(noise) as f32;
+
+        // Saturation precedence: rule > global default > blur saturation if blurred > 1.
+        let saturation = if blur { blur_config.saturation } else { 1. };
+        let saturation = blur_config.default_saturation.unwrap_or(saturation);
+        let saturation = self.saturation.unwrap_or(saturation.min(MAX_SATURATION)) as f32;
+
+        Postprocess {
+            noise,
+            noise_seed: 0.,
+            saturation,
+            tint: self.tint.map_or(Color32F::TRANSPARENT, Color32F::from),
+            // The average brightness comes from the blur pyramid.
+            adaptive_tint: blur && self.adaptive_tint,
+            alpha: self.opacity.unwrap_or(1.) as f32,
+            edge_ring: self.edge_ring,
+        }
+    }
+
+    fn xray(&self) -> bool {
+        self.strategy.use_xray(self.blur)
+    }
+
     fn is_visible(&self) -> bool {
-        self.xray
+        self.strategy == EffectStrategy::ForceXray
             || self.blur
             || self.noise.is_some_and(|x| x > 0.)
             || self.saturation.is_some_and(|x| x != 1.)
+            || self.tint.is_some_and(|x| x.a > 0.)
+    }
+}
+
+/// Upper bound for saturation, past which the colors are blown out anyway.
+const MAX_SATURATION: f64 = 10.;
+
+/// Clamps a value coming from a client or a rule, dropping it if it's not finite.
+fn clamp_finite(value: Option<f64>, min: f64, max: f64) -> Option<f64> {
+    value.filter(|x| x.is_finite()).map(|x| x.clamp(min, max))
+}
+
+/// Derives a noise pattern offset from an element id.
+fn noise_seed(id: &Id) -> f32 {
+    let mut hasher = DefaultHasher::new();
+    id.hash(&mut hasher);
+    // Up to 1024 pixels in 1/16384 steps, small enough for precise noise coordinates in f32.
+    (hasher.finish() >> 40) as f32 / 16384.
+}
+
+/// Returns the factor to scale the blur noise by for the blur radius.
+///
+/// Past the falloff radius the content is smooth enough for the noise to dominate, so it fades out
+/// in proportion to the radius.
+fn noise_falloff(falloff: f64, radius: f64) -> f64 {
+    if falloff.is_nan() || falloff <= 0. || radius <= falloff {
+        return 1.;
     }
+
+    falloff / radius
 }
 
 /// Render-time parameters.
@@ -56,10 +373,125 @@ pub struct RenderParams {
     ///
     /// `subregion.iter()` should return `geometry`-relative rectangles.
     pub subregion: Option<TransformedRegion>,
+    /// Visible part of the effect in the same coordinate space as `geometry`.
+    ///
+    /// Parts outside of it, e.g. scrolled out of view or covered by something opaque, are neither
+    /// captured nor drawn. This is on top of the clamping to the output.
+    pub visible: Option<Rectangle<f64, Logical>>,
     /// Geometry and radius for clipping in the same coordinate space as `geometry`.
     pub clip: Option<(Rectangle<f64, Logical>, CornerRadius)>,
     /// Scale to use for rounding to physical pixels.
     pub scale: f64,
+    /// Opacity of the owning surface, multiplied into the effect opacity.
+    ///
+    /// Lets the effect fade in and out together with its surface.
+    pub alpha: f32,
+    /// Fade-out toward the edges of the subregion, set in [`BackgroundEffect::render()`].
+    pub edge_falloff: Option<EdgeFalloff>,
+    /// Velocity of the owning surface in logical pixels per second.
+    ///
+    /// A moving surface gets a blur stretched along the motion. Only the framebuffer path can do
+    /// this since xray shares one blurred backdrop between all surfaces.
+    pub velocity: Point<f64, Logical>,
+}
+
+/// Soft highlight along the inside of the effect's rounded clip edge.
+///
+/// Unlike a border, it's drawn over the effect and fades out toward the inside, like light
+/// catching on the edge of frosted glass.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EdgeRing {
+    /// Premultiplied color at the very edge, with the intensity applied.
+    pub color: Color32F,
+    /// Width in logical pixels over which the highlight fades out.
+    pub width: f64,
+}
+
+impl EdgeRing {
+    /// Resolves the ring from the rule options, returning `None` when it's off.
+    fn new(width: Option<f64>, color: Option<Color>, intensity: Option<f64>) -> Option<Self> {
+        let width = clamp_finite(width, 0., 1000.).filter(|width| *width > 0.)?;
+        let color = color.unwrap_or(Color::new_unpremul(1., 1., 1., 1.));
+        let intensity = clamp_finite(intensity, 0., 1.).unwrap_or(0.5);
+
+        Some(Self {
+            color: Color32F::from(color) * intensity as f32,
+            width,
+        })
+    }
+
+    /// Scales the ring for a shader whose geometry is scaled by `zoom`.
+    pub fn upscale(self, zoom: f64) -> Self {
+        Self {
+            width: self.width * zoom,
+            ..self
+        }
+    }
+
+    /// Returns the uniforms for the postprocess shader, with the ring off for `None`.
+    pub fn uniforms(ring: Option<Self>) -> [Uniform<'static>; 2] {
+        let (color, width) = ring.map_or(([0.; 4], 0.), |ring| {
+            (ring.color.components(), ring.width as f32)
+        });
+
+        [
+            Uniform::new("edge_ring_color", color),
+            Uniform::new("edge_ring_width", width),
+        ]
+    }
+}
+
+/// Fade-out of the effect toward the edges of its region.
+///
+/// The effect fades toward the bounding box of the region rather than toward every rect, so that
+/// it doesn't fade along the seams between adjacent rects.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EdgeFalloff {
+    /// Bounds of the region relative to the clip geometry.
+    pub bounds: Rectangle<f64, Logical>,
+    /// Distance from the edge over which the effect fades out.
+    pub distance: f64,
+}
+
+impl EdgeFalloff {
+    /// Computes the falloff for `region` clipped to `clip_geo`, in the same coordinate space.
+    fn new(
+        region: &TransformedRegion,
+        clip_geo: Rectangle<f64, Logical>,
+        distance: f64,
+    ) -> Option<Self> {
+        if distance <= 0. {
+            return None;
+        }
+
+        let bbox = region.bbox()?;
+        Some(Self {
+            bounds: Rectangle::new(bbox.loc - clip_geo.loc, bbox.size),
+            distance,
+        })
+    }
+
+    /// Scales the falloff for a shader whose geometry is scaled by `zoom`.
+    pub fn upscale(self, zoom: f64) -> Self {
+        Self {
+            bounds: self.bounds.upscale(zoom),
+            distance: self.distance * zoom,
+        }
+    }
+
+    /// Returns the uniforms for the postprocess shader, with the falloff off for `None`.
+    pub fn uniforms(falloff: Option<Self>) -> [Uniform<'static>; 2] {
+        let (bounds, distance) = falloff.map_or(([0.; 4], 0.), |falloff| {
+            let Rectangle { loc, size } = falloff.bounds;
+            let bounds = [loc.x, loc.y, size.w, size.h].map(|x| x as f32);
+            (bounds, falloff.distance as f32)
+        });
+
+        [
+            Uniform::new("region_bounds", bounds),
+            Uniform::new("region_falloff", distance),
+        ]
+    }
 }
 
 impl RenderParams {
@@ -83,10 +515,16 @@ niri_render_elements! {
 
 impl BackgroundEffect {
     pub fn new() -> Self {
+        let nonxray = FramebufferEffect::new();
+        let noise_seed = noise_seed(nonxray.id());
+
         Self {
-            nonxray: FramebufferEffect::new(),
+            nonxray,
             damage: ExtraDamage::new(),
             corner_radius: CornerRadius::default(),
+            body_corner_radius: CornerRadius::default(),
+            alpha: 1.,
+            noise_seed,
             blur_config: niri_config::Blur::default(),
             options: Options::default(),
         }
@@ -106,6 +544,14 @@ impl BackgroundEffect {
         self.blur_config = config;
         self.damage.damage_all();
         self.nonxray.damage();
+        self.nonxray.set_pixel_snap(config.pixel_snap);
+        self.nonxray
+            .set_offscreen_color(config.offscreen_color.map(Color32F::from));
+        self.nonxray
+            .set_shrink_threshold(config.texture_shrink_threshold);
+        self.nonxray
+            .set_power_of_two_sizes(config.power_of_two_textures);
+        self.nonxray.set_max_texture_size(config.max_texture_size);
     }
 
     pub fn update_render_elements(
@@ -113,39 +559,121 @@ impl BackgroundEffect {
         corner_radius: CornerRadius,
         effect: niri_config::BackgroundEffect,
         has_blur_region: bool,
+        focused: bool,
     ) {
-        // If the surface explicitly requests a blur region, default blur to true.
-        let blur = if has_blur_region {
-            effect.blur != Some(false)
-        } else {
-            effect.blur == Some(true)
-        };
+        let options = Options::resolve(effect, has_blur_region, focused);
 
-        let mut options = Options {
-            blur,
-            xray: effect.xray == Some(true),
-            noise: effect.noise,
-            saturation: effect.saturation,
-        };
+        // The effect can be clipped with different corners from the window contents, e.g. to
+        // make a subtle halo around the window.
+        let body_corner_radius = corner_radius;
+        let corner_radius = effect.geometry_corner_radius.unwrap_or(corner_radius);
 
-        // If we have some background effect but xray wasn't explicitly set, default it to true
-        // since it's cheaper.
-        if options.is_visible() && effect.xray.is_none() {
-            options.xray = true;
+        if self.options == options
+            && self.corner_radius == corner_radius
+            && self.body_corner_radius == body_corner_radius
+        {
+            return;
         }
 
-        if self.options == options && self.corner_radius == corner_radius {
-            return;
+        // Switching between xray and framebuffer changes the effect entirely. Don't let the
+        // framebuffer path show a capture left over from before it was last switched away from.
+        if self.options.xray() != options.xray() {
+            self.nonxray.reset();
+        } else {
+            self.nonxray.damage();
         }
 
         self.options = options;
         self.corner_radius = corner_radius;
+        self.body_corner_radius = body_corner_radius;
         self.damage.damage_all();
-        self.nonxray.damage();
     }
 
     pub fn is_visible(&self) -> bool {
-        self.options.is_visible()
+        // Reduced effects override everything that could turn the effect on.
+        !self.blur_config.reduce_effects && self.options.is_visible()
+    }
+
+    /// Updates the opacity of the owning surface.
+    ///
+    /// The effect is damaged on every change, so that it keeps up with a fade animation.
+    pub fn update_alpha(&mut self, alpha: f32) {
+        if self.alpha == alpha {
+            return;
+        }
+
+        self.alpha = alpha;
+        self.damage.damage_all();
+        self.nonxray.damage();
+    }
+
+    /// Updates whether the owning surface is being interactively resized.
+    pub fn update_resizing(&mut self, resizing: bool) {
+        self.nonxray.set_resizing(resizing);
+    }
+
+    /// Returns the resolved state of the effect for inspection over IPC.
+    pub fn snapshot(&self, blur_region_rects: Option<usize>) -> niri_ipc::BackgroundEffectState {
+        let blur = self.options.blur && !self.blur_config.off;
+        let blur_options = BlurOptions::resolve(
+            &self.blur_config,
+            BlurOverride::default(),
+            BlurOverride::default(),
+        );
+        let postprocess = self.postprocess(&self.blur_config, blur, self.alpha);
+        let corner_radius: [f32; 4] = self.corner_radius.into();
+
+        niri_ipc::BackgroundEffectState {
+            blur,
+            xray: self.uses_xray(),
+            capture_unsupported: self.nonxray.is_capture_unsupported(),
+            blur_passes: blur_options.passes,
+            blur_offset: blur_options.offset,
+            noise: f64::from(postprocess.noise),
+            saturation: f64::from(postprocess.saturation),
+            tint: postprocess.tint.components().map(f64::from),
+            adaptive_tint: postprocess.adaptive_tint,
+            opacity: f64::from(postprocess.alpha),
+            exclude_opaque_body: self.options.exclude_opaque_body,
+            corner_radius: corner_radius.map(f64::from),
+            blur_region_rects: blur_region_rects.map(|n| u32::try_from(n).unwrap_or(u32::MAX)),
+        }
+    }
+
+    /// Whether to render with the xray path.
+    ///
+    /// Falls back to xray when the framebuffer path can't capture on this render target.
+    fn uses_xray(&self) -> bool {
+        self.options.xray() || self.nonxray.is_capture_unsupported()
+    }
+
+    /// Whether the effect resolves to no visible change.
+    ///
+    /// Unlike [`Self::is_visible()`], this looks at the resolved values rather than at which
+    /// options are set. A framebuffer effect with an identity blur and neutral postprocessing
+    /// draws the captured background back unchanged, and a transparent effect draws nothing.
+    fn is_noop(&self, blur_options: Option<&BlurOptions>, postprocess: &Postprocess) -> bool {
+        if postprocess.alpha <= 0. {
+            return true;
+        }
+
+        // Xray replaces the background with the backdrop, which is a visible change by itself.
+        !self.uses_xray()
+            && blur_options.is_none_or(BlurOptions::is_passthrough)
+            && postprocess.is_neutral()
+    }
+
+    /// Computes the postprocessing, fading the effect together with its surface.
+    fn postprocess(
+        &self,
+        blur_config: &niri_config::Blur,
+        blur_radius: Option<f64>,
+        surface_alpha: f32,
+    ) -> Postprocess {
+        let mut postprocess = self.options.postprocess(blur_config, blur_radius);
+        postprocess.alpha *= surface_alpha;
+        postprocess.noise_seed = self.noise_seed;
+        postprocess
     }
 
     pub fn render(
@@ -160,6 +688,41 @@ impl BackgroundEffect {
             return;
         }
 
+        // The clip, if any, is the window geometry.
+        let clip_geo = params.clip.map_or(params.geometry, |(geo, _)| geo);
+
+        // Compute the falloff before the subregion gets cut up below.
+        let falloff = self.options.region_edge_falloff;
+        params.edge_falloff = Option::zip(params.subregion.as_ref(), falloff)
+            .and_then(|(region, distance)| EdgeFalloff::new(region, clip_geo, distance));
+
+        if self.options.exclude_opaque_body {
+            let body = opaque_body(clip_geo, self.body_corner_radius);
+            let subregion = exclude_from_subregion(params.subregion.take(), params.geometry, &body);
+            if subregion.rects.is_empty() {
+                return;
+            }
+            params.subregion = Some(subregion);
+        }
+
+        if let Some(visible) = params.visible {
+            let Some(visible) = visible.intersection(params.geometry) else {
+                return;
+            };
+
+            // The framebuffer path clamps its capture to the visible region. Xray draws from
+            // the shared backdrop, so limit the drawn area through the subregion.
+            if self.uses_xray() && visible != params.geometry {
+                let hidden = outside(params.geometry, visible);
+                let subregion =
+                    exclude_from_subregion(params.subregion.take(), params.geometry, &hidden);
+                if subregion.rects.is_empty() {
+                    return;
+                }
+                params.subregion = Some(subregion);
+            }
+        }
+
         if let Some(clip) = &mut params.clip {
             clip.1 = self.corner_radius;
         }
@@ -167,44 +730,119 @@ impl BackgroundEffect {
 
         let damage = self.damage.render(params.geometry);
 
-        // Use noise/saturation from options, falling back to blur defaults if blurred, and
-        // to no effect if not blurred.
         let blur = self.options.blur && !self.blur_config.off;
-        let blur_options = blur.then_some(BlurOptions::from(self.blur_config));
-        let noise = if blur { self.blur_config.noise } else { 0. };
-        let noise = self.options.noise.unwrap_or(noise) as f32;
-        let saturation = if blur {
-            self.blur_config.saturation
-        } else {
-            1.
-        };
-        let saturation = self.options.saturation.unwrap_or(saturation) as f32;
+        let blur_options = blur.then(|| BlurOptions {
+            direction: BlurDirection::from_velocity(params.velocity),
+            ..BlurOptions::resolve(
+                &self.blur_config,
+                BlurOverride::default(),
+                BlurOverride::default(),
+            )
+            .scaled(params.scale)
+        });
+        // The radius only depends on the size once the smallest pyramid levels shrink to a few
+        // pixels, so the effect's own size also works for the shared xray blur.
+        let blur_radius = blur_options.map(|options| {
+            let size = params.geometry.size.to_physical_precise_ceil(params.scale);
+            f64::from(options.effective_radius_px(Size::new(size.w, size.h)))
+        });
+        // Xray samples the shared buffers, which can have their own blur config, e.g. in the
+        // overview.
+        let xray_blur_config = ctx
+            .xray
+            .filter(|_| self.uses_xray())
+            .and_then(|xray| xray.blur_config);
+        let blur_config = xray_blur_config.as_ref().unwrap_or(&self.blur_config);
+        let postprocess = self.postprocess(blur_config, blur_radius, params.alpha);
+        if self.is_noop(blur_options.as_ref(), &postprocess) {
+            return;
+        }
 
-        if self.options.xray {
+        if self.uses_xray() {
             let Some(xray) = ctx.xray else {
                 return;
             };
 
+            // This is pushed every frame on purpose. ExtraDamage only produces damage when its
+            // commit changes, so an unchanged effect adds no damage. Omitting the element on idle
+            // frames would instead damage its area as a removed element, and damage trackers
+            // that skip some frames (e.g. screencasts) could miss the commit change entirely.
             push(damage.into());
-            xray.render(
-                ctx,
-                params,
-                xray_pos,
-                blur,
-                noise,
-                saturation,
-                &mut |elem| push(elem.into()),
-            );
+            xray.render(ctx, params, xray_pos, blur, postprocess, &mut |elem| {
+                push(elem.into())
+            });
         } else {
             // Render non-xray effect.
-            let elem = self
-                .nonxray
-                .render(ns, params, blur_options, noise, saturation);
+            record_effect(|| EffectDescriptor {
+                kind: EffectKind::Framebuffer,
+                geometry: params.geometry,
+                blur,
+                subregion_rects: params.subregion.as_ref().map(|region| region.rects.len()),
+            });
+            let elem = self.nonxray.render(ns, params, blur_options, postprocess);
             push(elem.into());
         }
     }
 }
 
+/// Returns the fully opaque part of a rounded rectangle.
+///
+/// The rounded corners are left out, so that the window's anti-aliased corners still blend over
+/// the effect.
+fn opaque_body(geo: Rectangle<f64, Logical>, radius: CornerRadius) -> [Rectangle<f64, Logical>; 2] {
+    let r = radius
+        .top_left
+        .max(radius.top_right)
+        .max(radius.bottom_right)
+        .max(radius.bottom_left);
+    let r = f64::from(r).min(geo.size.w / 2.).min(geo.size.h / 2.);
+
+    let mut horizontal = geo;
+    horizontal.loc.y += r;
+    horizontal.size.h -= r * 2.;
+
+    let mut vertical = geo;
+    vertical.loc.x += r;
+    vertical.size.w -= r * 2.;
+
+    [horizontal, vertical]
+}
+
+/// Returns the parts of `geometry` outside of `inner`, which must be within `geometry`.
+fn outside(
+    geometry: Rectangle<f64, Logical>,
+    inner: Rectangle<f64, Logical>,
+) -> [Rectangle<f64, Logical>; 4] {
+    let a = geometry.loc;
+    let b = geometry.loc + geometry.size.to_point();
+    let ia = inner.loc;
+    let ib = inner.loc + inner.size.to_point();
+
+    [
+        Rectangle::from_extremities(a, Point::new(b.x, ia.y)),
+        Rectangle::from_extremities(Point::new(a.x, ib.y), b),
+        Rectangle::from_extremities(Point::new(a.x, ia.y), Point::new(ia.x, ib.y)),
+        Rectangle::from_extremities(Point::new(ib.x, ia.y), Point::new(b.x, ib.y)),
+    ]
+}
+
+/// Subtracts `exclude` from the subregion, or from the whole `geometry` if there's no subregion.
+fn exclude_from_subregion(
+    subregion: Option<TransformedRegion>,
+    geometry: Rectangle<f64, Logical>,
+    exclude: &[Rectangle<f64, Logical>],
+) -> TransformedRegion {
+    let subregion = subregion.unwrap_or_else(|| {
+        TransformedRegion::new(
+            Arc::new(vec![Rectangle::from_size(geometry.size.to_i32_ceil())]),
+            Scale::from(1.),
+            geometry.loc,
+        )
+    });
+    subregion.subtract(exclude)
+}
+
+#[allow(clippy::too_many_arguments)]
 fn render_params_for_tile(
     geometry: Rectangle<f64, Logical>,
     scale: f64,
@@ -213,6 +851,8 @@ fn render_params_for_tile(
     blur_region: Option<Arc<Vec<Rectangle<i32, Logical>>>>,
     surface_geo: Rectangle<f64, Logical>,
     surface_anim_scale: Scale<f64>,
+    alpha: f32,
+    max_region_rects: u32,
 ) -> Option<RenderParams> {
     // Effects not requested by the surface itself are drawn to match the geometry.
     let mut clip = true;
@@ -236,16 +876,19 @@ fn render_params_for_tile(
                 let mut surface_geo = surface_geo.upscale(surface_anim_scale);
                 surface_geo.loc += geometry.loc;
 
-                subregion = Some(TransformedRegion {
-                    rects,
-                    scale: surface_anim_scale,
-                    offset: surface_geo.loc,
-                });
+                let region = TransformedRegion::new(rects, surface_anim_scale, surface_geo.loc);
 
-                surface_geo = surface_geo
-                    .to_physical_precise_round(scale)
-                    .to_logical(scale);
-                effect_geometry = surface_geo;
+                // Limit the effect to the extent of the region rather than the whole surface. The
+                // surface may include client-side shadows, which the region usually excludes.
+                let extent = region
+                    .bbox()
+                    .and_then(|bbox| bbox.intersection(surface_geo))?;
+                // Clip the rects right away, so that the math downstream doesn't have to deal with
+                // huge out-of-bounds values.
+                let region = region.clipped(extent);
+                subregion = Some(region.simplified(max_region_rects as usize));
+
+                effect_geometry = extent.to_physical_precise_round(scale).to_logical(scale);
             }
         }
     }
@@ -256,8 +899,12 @@ fn render_params_for_tile(
     Some(RenderParams {
         geometry: effect_geometry,
         subregion,
+        visible: None,
         clip,
         scale,
+        alpha,
+        edge_falloff: None,
+        velocity: Point::new(0., 0.),
     })
 }
 
@@ -278,55 +925,994 @@ pub fn damage_surface(states: &SurfaceData) {
     }
 }
 
-// Silence, Clippy
-// A Smithay user is talking
-#[allow(clippy::too_many_arguments)]
+/// Returns the state of the surface's background effect, if it has ever rendered one.
+pub fn snapshot_for_surface(surface: &WlSurface) -> Option<niri_ipc::BackgroundEffectState> {
+    // Walks the subsurface tree, so it can't be inside with_states() for the same surface.
+    let blur_region = get_aggregated_blur_region(surface);
+
+    with_states(surface, |states| {
+        let effect = states.data_map.get::<SurfaceBackgroundEffect>()?;
+        let effect = effect.0.lock().unwrap();
+        Some(effect.snapshot(blur_region.map(|r| r.len())))
+    })
+}
+
+/// Parameters for [`render_for_tile()`].
+#[derive(Debug)]
+pub struct TileParams {
+    /// Geometry of the tile, or of the surface for layer surfaces and pop-ups.
+    pub geometry: Rectangle<f64, Logical>,
+    /// Visible part of the tile, see [`RenderParams::visible`].
+    pub visible: Option<Rectangle<f64, Logical>>,
+    /// Scale to use for rounding to physical pixels.
+    pub scale: f64,
+    /// Whether the tile clips its contents to `geometry`.
+    pub clip_to_geometry: bool,
+    /// Location of the surface relative to `geometry`.
+    pub surface_off: Point<f64, Logical>,
+    /// Scale of the surface from the resize animation.
+    pub surface_anim_scale: Scale<f64>,
+    pub blur_config: niri_config::Blur,
+    pub radius: CornerRadius,
+    pub effect: niri_config::BackgroundEffect,
+    pub should_block_out: bool,
+    /// Opacity of the surface.
+    pub alpha: f32,
+    /// Whether the surface is being interactively resized.
+    pub resizing: bool,
+    pub focused: bool,
+    pub xray_pos: XrayPos,
+    /// Velocity of the surface, see [`RenderParams::velocity`].
+    pub velocity: Point<f64, Logical>,
+}
+
 pub fn render_for_tile(
     ctx: RenderCtx<GlesRenderer>,
     ns: Option<usize>,
-    geometry: Rectangle<f64, Logical>,
-    scale: f64,
-    clip_to_geometry: bool,
     surface: &WlSurface,
-    surface_off: Point<f64, Logical>,
-    surface_anim_scale: Scale<f64>,
-    blur_config: niri_config::Blur,
-    radius: CornerRadius,
-    effect: niri_config::BackgroundEffect,
-    should_block_out: bool,
-    xray_pos: XrayPos,
+    tile: TileParams,
     push: &mut dyn FnMut(BackgroundEffectElement),
 ) {
+    // Walks the subsurface tree, so it can't be inside with_states() for the same surface.
+    let blur_region = get_aggregated_blur_region(surface);
+
     with_states(surface, |states| {
         let background_effect = SurfaceBackgroundEffect::get(states);
         let mut background_effect = background_effect.0.lock().unwrap();
 
-        let blur_region = get_cached_blur_region(states);
         let has_blur_region = blur_region.as_ref().is_some_and(|r| !r.is_empty());
 
-        background_effect.update_config(blur_config);
-        background_effect.update_render_elements(radius, effect, has_blur_region);
+        background_effect.update_config(tile.blur_config);
+        background_effect.update_render_elements(
+            tile.radius,
+            tile.effect,
+            has_blur_region,
+            tile.focused,
+        );
+        background_effect.update_alpha(tile.alpha);
+        background_effect.update_resizing(tile.resizing);
 
         if !background_effect.is_visible() {
             return;
         }
 
         let mut surface_geo = surface_geo(states).unwrap_or_default().to_f64();
-        surface_geo.loc += surface_off;
+        surface_geo.loc += tile.surface_off;
 
-        let Some(params) = render_params_for_tile(
-            geometry,
-            scale,
-            clip_to_geometry,
-            should_block_out,
+        let Some(mut params) = render_params_for_tile(
+            tile.geometry,
+            tile.scale,
+            tile.clip_to_geometry,
+            tile.should_block_out,
             blur_region,
             surface_geo,
-            surface_anim_scale,
+            tile.surface_anim_scale,
+            tile.alpha,
+            tile.blur_config.max_region_rects,
         ) else {
             return;
         };
+        params.visible = tile.visible;
+        params.velocity = tile.velocity;
 
-        let xray_pos = xray_pos.offset(params.geometry.loc - geometry.loc);
+        let xray_pos = tile
+            .xray_pos
+            .offset(params.geometry.loc - tile.geometry.loc);
         background_effect.render(ctx, ns, params, xray_pos, push);
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use smithay::backend::renderer::damage::OutputDamageTracker;
+    use smithay::backend::renderer::element::Element as _;
+    use smithay::utils::{Size, Transform};
+
+    use super::*;
+    use crate::render_helpers::blur::BlurContext;
+
+    /// Radius of a blur light enough to keep the full noise.
+    const SMALL_BLUR: Option<f64> = Some(1.);
+
+    #[test]
+    fn pipeline_order_lists_active_stages() {
+        let neutral = Postprocess {
+            noise: 0.,
+            noise_seed: 0.,
+            saturation: 1.,
+            tint: Color32F::TRANSPARENT,
+            adaptive_tint: false,
+            alpha: 1.,
+            edge_ring: None,
+        };
+        assert_eq!(neutral.pipeline_order(false, false), "none");
+        assert_eq!(neutral.pipeline_order(false, true), "corner-clip");
+
+        let postprocess = Postprocess {
+            noise: 0.02,
+            saturation: 1.5,
+            tint: Color32F::new(0., 0., 0., 0.2),
+            ..neutral
+        };
+        assert_eq!(
+            postprocess.pipeline_order(false, true),
+            "saturation → tint → noise → corner-clip"
+        );
+
+        let postprocess = Postprocess {
+            adaptive_tint: true,
+            alpha: 0.5,
+            ..postprocess
+        };
+        assert_eq!(
+            postprocess.pipeline_order(true, true),
+            "saturation → background → adaptive-tint → noise → corner-clip → opacity"
+        );
+    }
+
+    #[test]
+    fn snapshot_serializes_resolved_state() {
+        let mut background_effect = BackgroundEffect::new();
+        background_effect.update_config(niri_config::Blur {
+            passes: 3,
+            offset: 2.5,
+            ..Default::default()
+        });
+        background_effect.update_render_elements(
+            CornerRadius::from(8.),
+            niri_config::BackgroundEffect {
+                blur: Some(true),
+                xray: Some(false),
+                opacity: Some(0.5),
+                ..Default::default()
+            },
+            true,
+            false,
+        );
+
+        let snapshot = background_effect.snapshot(Some(2));
+        let value = serde_json::to_value(&snapshot).unwrap();
+        assert_eq!(value["blur"], true);
+        assert_eq!(value["xray"], false);
+        assert_eq!(value["capture_unsupported"], false);
+        assert_eq!(value["blur_passes"], 3);
+        assert_eq!(value["blur_offset"], 2.5);
+        assert_eq!(value["opacity"], 0.5);
+        assert_eq!(value["corner_radius"], serde_json::json!([8., 8., 8., 8.]));
+        assert_eq!(value["blur_region_rects"], 2);
+    }
+
+    #[test]
+    fn blur_defaults_to_xray() {
+        let effect = niri_config::BackgroundEffect {
+            blur: Some(true),
+            ..Default::default()
+        };
+        let options = Options::resolve(effect, false, false);
+        assert!(options.blur);
+        assert!(options.xray());
+
+        // Blur requested through a blur region.
+        let options = Options::resolve(niri_config::BackgroundEffect::default(), true, false);
+        assert!(options.blur);
+        assert!(options.xray());
+    }
+
+    #[test]
+    fn noise_only_does_not_default_to_xray() {
+        let effect = niri_config::BackgroundEffect {
+            blur: Some(false),
+            noise: Some(0.1),
+            ..Default::default()
+        };
+        let options = Options::resolve(effect, true, false);
+        assert!(!options.blur);
+        assert!(!options.xray());
+        assert!(options.is_visible());
+
+        let effect = niri_config::BackgroundEffect {
+            saturation: Some(2.),
+            ..Default::default()
+        };
+        let options = Options::resolve(effect, false, false);
+        assert!(!options.xray());
+        assert!(options.is_visible());
+    }
+
+    #[test]
+    fn explicit_xray_is_respected() {
+        let effect = niri_config::BackgroundEffect {
+            xray: Some(false),
+            blur: Some(true),
+            ..Default::default()
+        };
+        assert!(!Options::resolve(effect, false, false).xray());
+
+        let effect = niri_config::BackgroundEffect {
+            xray: Some(true),
+            ..Default::default()
+        };
+        let options = Options::resolve(effect, false, false);
+        assert!(options.xray());
+        assert!(options.is_visible());
+    }
+
+    #[test]
+    fn neutral_effect_is_noop() {
+        let mut background_effect = BackgroundEffect::new();
+        // Blur with a zero offset passes the background through as is.
+        background_effect.update_config(niri_config::Blur {
+            offset: 0.,
+            noise: 0.,
+            saturation: 1.,
+            ..Default::default()
+        });
+        let update = |background_effect: &mut BackgroundEffect, effect| {
+            background_effect.update_render_elements(CornerRadius::default(), effect, false, false);
+        };
+        let is_noop = |background_effect: &BackgroundEffect| {
+            let blur_options = BlurOptions::resolve(
+                &background_effect.blur_config,
+                BlurOverride::default(),
+                BlurOverride::default(),
+            );
+            let postprocess =
+                background_effect.postprocess(&background_effect.blur_config, SMALL_BLUR, 1.);
+            background_effect.is_noop(Some(&blur_options), &postprocess)
+        };
+
+        // Configured visible, but every resolved value is neutral.
+        let effect = niri_config::BackgroundEffect {
+            blur: Some(true),
+            xray: Some(false),
+            ..Default::default()
+        };
+        update(&mut background_effect, effect);
+        assert!(background_effect.is_visible());
+        assert!(is_noop(&background_effect));
+
+        // Any non-neutral value makes it do something.
+        update(
+            &mut background_effect,
+            niri_config::BackgroundEffect {
+                saturation: Some(1.5),
+                ..effect
+            },
+        );
+        assert!(!is_noop(&background_effect));
+
+        // Xray shows the backdrop, even without any blur.
+        update(
+            &mut background_effect,
+            niri_config::BackgroundEffect {
+                xray: Some(true),
+                ..effect
+            },
+        );
+        assert!(!is_noop(&background_effect));
+
+        // A fully transparent effect draws nothing.
+        update(
+            &mut background_effect,
+            niri_config::BackgroundEffect {
+                xray: Some(true),
+                opacity: Some(0.),
+                ..effect
+            },
+        );
+        assert!(is_noop(&background_effect));
+    }
+
+    #[test]
+    fn opacity_and_tint() {
+        let blur_config = niri_config::Blur::default();
+
+        let options = Options::default();
+        let postprocess = options.postprocess(&blur_config, SMALL_BLUR);
+        assert_eq!(postprocess.alpha, 1.);
+        assert_eq!(postprocess.tint, Color32F::TRANSPARENT);
+
+        let effect = niri_config::BackgroundEffect {
+            blur: Some(true),
+            opacity: Some(0.5),
+            tint: Some(Color::new_unpremul(1., 0.5, 0., 0.5)),
+            ..Default::default()
+        };
+        let options = Options::resolve(effect, false, false);
+        let postprocess = options.postprocess(&blur_config, SMALL_BLUR);
+        assert_eq!(postprocess.alpha, 0.5);
+        // The tint is premultiplied.
+        assert_eq!(postprocess.tint, Color32F::new(0.5, 0.25, 0., 0.5));
+        // Other postprocessing is unaffected.
+        assert_eq!(postprocess.saturation, blur_config.saturation as f32);
+
+        // Tint alone makes the effect visible.
+        let effect = niri_config::BackgroundEffect {
+            tint: Some(Color::new_unpremul(0., 0., 0., 0.2)),
+            ..Default::default()
+        };
+        assert!(Options::resolve(effect, false, false).is_visible());
+    }
+
+    #[test]
+    fn out_of_range_values_are_clamped() {
+        let blur_config = niri_config::Blur::default();
+        let effect = niri_config::BackgroundEffect {
+            blur: Some(true),
+            noise: Some(-5.),
+            saturation: Some(1e9),
+            opacity: Some(2.),
+            ..Default::default()
+        };
+        let postprocess =
+            Options::resolve(effect, false, false).postprocess(&blur_config, SMALL_BLUR);
+        assert_eq!(postprocess.noise, 0.);
+        assert_eq!(postprocess.saturation, MAX_SATURATION as f32);
+        assert_eq!(postprocess.alpha, 1.);
+
+        // Non-finite values fall back to the defaults.
+        let effect = niri_config::BackgroundEffect {
+            noise: Some(f64::NAN),
+            saturation: Some(f64::INFINITY),
+            ..effect
+        };
+        let postprocess =
+            Options::resolve(effect, false, false).postprocess(&blur_config, SMALL_BLUR);
+        assert_eq!(postprocess.noise, blur_config.noise as f32);
+        assert_eq!(postprocess.saturation, blur_config.saturation as f32);
+
+        // Misconfigured blur defaults are clamped too.
+        let blur_config = niri_config::Blur {
+            noise: 1000.,
+            saturation: 1000.,
+            ..blur_config
+        };
+        let postprocess = Options::default().postprocess(&blur_config, SMALL_BLUR);
+        assert_eq!(postprocess.noise, 1.);
+        assert_eq!(postprocess.saturation, MAX_SATURATION as f32);
+    }
+
+    #[test]
+    fn adaptive_tint_binds_smallest_level() {
+        let blur_config = niri_config::Blur::default();
+        let effect = niri_config::BackgroundEffect {
+            blur: Some(true),
+            tint: Some(Color::new_unpremul(0., 0., 0., 0.3)),
+            adaptive_tint: Some(true),
+            ..Default::default()
+        };
+        let options = Options::resolve(effect, false, false);
+        // Output texture followed by three pass levels.
+        let levels = [0, 1, 2, 3];
+
+        let postprocess = options.postprocess(&blur_config, SMALL_BLUR);
+        assert!(postprocess.adaptive_tint);
+        assert_eq!(postprocess.average_level(&levels), Some(&3));
+        // There's nothing to average without any passes.
+        assert_eq!(postprocess.average_level(&levels[..1]), None);
+
+        // Without blur, there's no pyramid to read from.
+        let postprocess = options.postprocess(&blur_config, None);
+        assert_eq!(postprocess.average_level(&levels), None);
+
+        // With the option off, nothing is bound.
+        let options = Options {
+            adaptive_tint: false,
+            ..options
+        };
+        let postprocess = options.postprocess(&blur_config, SMALL_BLUR);
+        assert_eq!(postprocess.average_level(&levels), None);
+    }
+
+    #[test]
+    fn noise_falls_off_with_heavy_blur() {
+        let options = Options::default();
+        let noise = |blur_config: &niri_config::Blur| {
+            let blur_options = BlurOptions::resolve(
+                blur_config,
+                BlurOverride::default(),
+                BlurOverride::default(),
+            );
+            let radius = blur_options.effective_radius_px(Size::new(1920, 1080));
+            options
+                .postprocess(blur_config, Some(f64::from(radius)))
+                .noise
+        };
+
+        // The default blur keeps the full noise.
+        let light = niri_config::Blur::default();
+        assert_eq!(noise(&light), light.noise as f32);
+
+        let heavy = niri_config::Blur {
+            passes: 5,
+            offset: 5.,
+            ..light
+        };
+        let heavy_noise = noise(&heavy);
+        assert!(0. < heavy_noise && heavy_noise < light.noise as f32 / 2.);
+
+        // Heavier blur has even less noise.
+        let heavier = niri_config::Blur {
+            offset: 10.,
+            ..heavy
+        };
+        assert!(noise(&heavier) < heavy_noise);
+
+        // A larger falloff radius keeps more noise.
+        let later = niri_config::Blur {
+            noise_falloff: 80.,
+            ..heavy
+        };
+        assert!(noise(&later) > heavy_noise);
+
+        // Zero disables the falloff.
+        let disabled = niri_config::Blur {
+            noise_falloff: 0.,
+            ..heavy
+        };
+        assert_eq!(noise(&disabled), heavy.noise as f32);
+
+        // Noise from a rule is used as is.
+        let with_rule = Options {
+            noise: Some(0.1),
+            ..Default::default()
+        };
+        assert_eq!(with_rule.postprocess(&heavy, Some(100.)).noise, 0.1);
+    }
+
+    #[test]
+    fn effects_get_different_noise_seeds() {
+        let a = BackgroundEffect::new();
+        let b = BackgroundEffect::new();
+        assert_ne!(
+            a.postprocess(&a.blur_config, SMALL_BLUR, 1.).noise_seed,
+            b.postprocess(&b.blur_config, SMALL_BLUR, 1.).noise_seed
+        );
+
+        // The seed stays the same from frame to frame, so the noise doesn't flicker.
+        assert_eq!(
+            a.postprocess(&a.blur_config, SMALL_BLUR, 1.).noise_seed,
+            a.postprocess(&a.blur_config, None, 0.5).noise_seed
+        );
+        assert!((0. ..1024.).contains(&a.noise_seed));
+    }
+
+    #[test]
+    fn overview_and_window_blur_configs_differ() {
+        let mut background_effect = BackgroundEffect::new();
+        background_effect.update_config(niri_config::Blur::default());
+        let overview = niri_config::Overview {
+            blur: Some(niri_config::BlurPart {
+                passes: Some(5),
+                offset: Some(niri_config::FloatOrInt(6.)),
+                noise: Some(niri_config::FloatOrInt(0.)),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let window = BlurContext::Window.resolve_config(background_effect.blur_config, &overview);
+        let in_overview =
+            BlurContext::Overview.resolve_config(background_effect.blur_config, &overview);
+        let resolve = |config: &niri_config::Blur| {
+            BlurOptions::resolve(config, BlurOverride::default(), BlurOverride::default())
+        };
+        assert_eq!(resolve(&window), resolve(&background_effect.blur_config));
+        assert_ne!(resolve(&window), resolve(&in_overview));
+        assert_eq!(resolve(&in_overview).passes, 5);
+        assert_eq!(resolve(&in_overview).offset, 6.);
+
+        // The effect postprocesses with whichever config its backdrop was blurred with.
+        let postprocess =
+            |config: &niri_config::Blur| background_effect.postprocess(config, SMALL_BLUR, 1.);
+        assert_eq!(postprocess(&window).noise, window.noise as f32);
+        assert_eq!(postprocess(&in_overview).noise, 0.);
+    }
+
+    #[test]
+    fn saturation_precedence() {
+        let mut blur_config = niri_config::Blur {
+            saturation: 1.5,
+            ..Default::default()
+        };
+        let options = Options::default();
+        let with_rule = Options {
+            saturation: Some(3.),
+            ..Default::default()
+        };
+
+        // Without a global default.
+        assert_eq!(
+            options.postprocess(&blur_config, SMALL_BLUR).saturation,
+            1.5
+        );
+        assert_eq!(options.postprocess(&blur_config, None).saturation, 1.);
+
+        // The global default applies with and without blur.
+        blur_config.default_saturation = Some(0.5);
+        assert_eq!(
+            options.postprocess(&blur_config, SMALL_BLUR).saturation,
+            0.5
+        );
+        assert_eq!(options.postprocess(&blur_config, None).saturation, 0.5);
+
+        // The rule overrides everything.
+        assert_eq!(
+            with_rule.postprocess(&blur_config, SMALL_BLUR).saturation,
+            3.
+        );
+        assert_eq!(with_rule.postprocess(&blur_config, None).saturation, 3.);
+    }
+
+    #[test]
+    fn effect_corner_radius() {
+        let content_radius = CornerRadius::from(8.);
+        let effect_radius = CornerRadius::from(12.);
+
+        let mut background_effect = BackgroundEffect::new();
+        let effect = niri_config::BackgroundEffect {
+            blur: Some(true),
+            ..Default::default()
+        };
+
+        // Defaults to the content radius.
+        background_effect.update_render_elements(content_radius, effect, false, false);
+        assert_eq!(background_effect.corner_radius, content_radius);
+
+        let effect = niri_config::BackgroundEffect {
+            geometry_corner_radius: Some(effect_radius),
+            ..effect
+        };
+        background_effect.update_render_elements(content_radius, effect, false, false);
+        assert_eq!(background_effect.corner_radius, effect_radius);
+    }
+
+    #[test]
+    fn path_switch_damages_and_resets() {
+        let radius = CornerRadius::from(8.);
+        // Blur follows the blur region, which in turn picks the xray path.
+        let effect = niri_config::BackgroundEffect {
+            noise: Some(0.1),
+            ..Default::default()
+        };
+
+        let mut background_effect = BackgroundEffect::new();
+        background_effect.update_render_elements(radius, effect, false, false);
+        assert!(!background_effect.options.xray());
+        let commit = background_effect.damage.current_commit();
+        let id = background_effect.nonxray.id().clone();
+
+        background_effect.update_render_elements(radius, effect, true, false);
+        assert!(background_effect.options.xray());
+        assert_ne!(background_effect.damage.current_commit(), commit);
+        // The framebuffer element starts over, without any stale capture.
+        assert_ne!(*background_effect.nonxray.id(), id);
+
+        let commit = background_effect.damage.current_commit();
+        let id = background_effect.nonxray.id().clone();
+        background_effect.update_render_elements(radius, effect, false, false);
+        assert!(!background_effect.options.xray());
+        assert_ne!(background_effect.damage.current_commit(), commit);
+        assert_ne!(*background_effect.nonxray.id(), id);
+
+        // Without a switch, the element keeps its cache.
+        let id = background_effect.nonxray.id().clone();
+        background_effect.update_render_elements(CornerRadius::from(4.), effect, false, false);
+        assert_eq!(*background_effect.nonxray.id(), id);
+    }
+
+    #[test]
+    fn region_edge_falloff_sets_uniforms() {
+        let falloff_uniforms = |falloff| {
+            EdgeFalloff::uniforms(falloff).map(|u| match u.value {
+                UniformValue::_1f(x) => vec![x],
+                UniformValue::_4f(a, b, c, d) => vec![a, b, c, d],
+                _ => unreachable!(),
+            })
+        };
+
+        // Without a configured distance, the shader falloff is off.
+        let options = Options::resolve(niri_config::BackgroundEffect::default(), true, false);
+        assert_eq!(options.region_edge_falloff, None);
+        assert_eq!(falloff_uniforms(None), [vec![0.; 4], vec![0.]]);
+
+        let effect = niri_config::BackgroundEffect {
+            region_edge_falloff: Some(16.),
+            ..Default::default()
+        };
+        let options = Options::resolve(effect, true, false);
+        assert_eq!(options.region_edge_falloff, Some(16.));
+
+        // A panel region inside a surface at (100, 50).
+        let region = TransformedRegion::new(
+            Arc::new(vec![Rectangle::new(Point::new(10, 0), Size::new(200, 40))]),
+            Scale::from(1.),
+            Point::new(100., 50.),
+        );
+        let clip_geo = Rectangle::new(Point::new(100., 50.), Size::new(220., 40.));
+        let falloff = EdgeFalloff::new(&region, clip_geo, 16.).unwrap();
+        assert_eq!(
+            falloff_uniforms(Some(falloff)),
+            [vec![10., 0., 200., 40.], vec![16.]]
+        );
+
+        // The xray backdrop shader works in zoomed coordinates.
+        assert_eq!(
+            falloff_uniforms(Some(falloff.upscale(0.5))),
+            [vec![5., 0., 100., 20.], vec![8.]]
+        );
+
+        assert_eq!(EdgeFalloff::new(&region, clip_geo, 0.), None);
+    }
+
+    #[test]
+    fn edge_ring_sets_uniforms() {
+        let ring_uniforms = |effect| {
+            let options = Options::resolve(effect, false, false);
+            let postprocess = options.postprocess(&niri_config::Blur::default(), true);
+            EdgeRing::uniforms(postprocess.edge_ring).map(|u| match u.value {
+                UniformValue::_1f(x) => vec![x],
+                UniformValue::_4f(a, b, c, d) => vec![a, b, c, d],
+                _ => unreachable!(),
+            })
+        };
+
+        // Off by default.
+        let off = [vec![0.; 4], vec![0.]];
+        assert_eq!(ring_uniforms(niri_config::BackgroundEffect::default()), off);
+
+        // The color is premultiplied, with the intensity applied.
+        let effect = niri_config::BackgroundEffect {
+            edge_ring_width: Some(2.),
+            edge_ring_color: Some(Color::new_unpremul(1., 0.5, 0., 0.5)),
+            edge_ring_intensity: Some(0.5),
+            ..Default::default()
+        };
+        assert_eq!(
+            ring_uniforms(effect),
+            [vec![0.25, 0.125, 0., 0.25], vec![2.]]
+        );
+
+        // A zero width is a no-op, whatever the other options.
+        let effect = niri_config::BackgroundEffect {
+            edge_ring_width: Some(0.),
+            ..effect
+        };
+        assert_eq!(ring_uniforms(effect), off);
+        let options = Options::resolve(effect, false, false);
+        assert!(options
+            .postprocess(&niri_config::Blur::default(), false)
+            .is_neutral());
+
+        // The xray backdrop shader works in zoomed coordinates.
+        let ring = EdgeRing::new(Some(2.), None, None).unwrap();
+        assert_eq!(ring.color, Color32F::new(0.5, 0.5, 0.5, 0.5));
+        assert_eq!(ring.upscale(0.5).width, 1.);
+    }
+
+    #[test]
+    fn reduce_effects_hides_every_effect() {
+        let mut background_effect = BackgroundEffect::new();
+        let reduced = niri_config::Blur {
+            reduce_effects: true,
+            ..Default::default()
+        };
+
+        for effect in [
+            niri_config::BackgroundEffect {
+                blur: Some(true),
+                ..Default::default()
+            },
+            niri_config::BackgroundEffect {
+                xray: Some(true),
+                ..Default::default()
+            },
+            niri_config::BackgroundEffect {
+                noise: Some(0.1),
+                saturation: Some(2.),
+                tint: Some(Color::new_unpremul(1., 0., 0., 0.5)),
+                ..Default::default()
+            },
+        ] {
+            background_effect.update_config(niri_config::Blur::default());
+            // A blur region requests blur too.
+            background_effect.update_render_elements(CornerRadius::default(), effect, true, false);
+            assert!(background_effect.is_visible());
+
+            // Toggling damages, and render() bails out before pushing any elements.
+            let commit = background_effect.damage.current_commit();
+            background_effect.update_config(reduced);
+            assert_ne!(background_effect.damage.current_commit(), commit);
+            assert!(!background_effect.is_visible());
+        }
+
+        let commit = background_effect.damage.current_commit();
+        background_effect.update_config(niri_config::Blur::default());
+        assert_ne!(background_effect.damage.current_commit(), commit);
+        assert!(background_effect.is_visible());
+    }
+
+    #[test]
+    fn unfocused_only_blur_follows_focus() {
+        let radius = CornerRadius::from(8.);
+        let effect = niri_config::BackgroundEffect {
+            blur: Some(true),
+            blur_unfocused_only: Some(true),
+            ..Default::default()
+        };
+
+        let mut background_effect = BackgroundEffect::new();
+        background_effect.update_render_elements(radius, effect, false, false);
+        assert!(background_effect.options.blur);
+
+        // Focusing turns the blur off and damages the effect.
+        let commit = background_effect.damage.current_commit();
+        background_effect.update_render_elements(radius, effect, false, true);
+        assert!(!background_effect.options.blur);
+        assert_ne!(background_effect.damage.current_commit(), commit);
+
+        // And unfocusing turns it back on.
+        let commit = background_effect.damage.current_commit();
+        background_effect.update_render_elements(radius, effect, false, false);
+        assert!(background_effect.options.blur);
+        assert_ne!(background_effect.damage.current_commit(), commit);
+
+        // Without the policy, focus doesn't matter.
+        let effect = niri_config::BackgroundEffect {
+            blur_unfocused_only: None,
+            ..effect
+        };
+        background_effect.update_render_elements(radius, effect, false, true);
+        assert!(background_effect.options.blur);
+    }
+
+    #[test]
+    fn capture_failure_falls_back_to_xray() {
+        let effect = niri_config::BackgroundEffect {
+            xray: Some(false),
+            blur: Some(true),
+            ..Default::default()
+        };
+
+        let mut background_effect = BackgroundEffect::new();
+        background_effect.update_render_elements(CornerRadius::default(), effect, false, false);
+        assert!(!background_effect.uses_xray());
+
+        let params = RenderParams {
+            geometry: Rectangle::from_size(Size::new(100., 100.)),
+            subregion: None,
+            visible: None,
+            clip: None,
+            scale: 1.,
+            alpha: 1.,
+            edge_falloff: None,
+            velocity: Point::new(0., 0.),
+        };
+        let postprocess =
+            background_effect.postprocess(&background_effect.blur_config, SMALL_BLUR, 1.);
+        let elem = background_effect
+            .nonxray
+            .render(None, params, None, postprocess);
+        while !background_effect.nonxray.is_capture_unsupported() {
+            elem.report_capture(false);
+        }
+
+        // Subsequent frames use xray, and config updates don't bring the framebuffer path back.
+        assert!(background_effect.uses_xray());
+        background_effect.update_render_elements(CornerRadius::from(4.), effect, false, false);
+        assert!(background_effect.uses_xray());
+    }
+
+    #[test]
+    fn forced_strategy_overrides_heuristic() {
+        let noise_only = Options {
+            noise: Some(0.1),
+            ..Default::default()
+        };
+        let blur = Options {
+            blur: true,
+            ..Default::default()
+        };
+
+        for options in [noise_only, blur] {
+            let forced = Options {
+                strategy: EffectStrategy::ForceXray,
+                ..options
+            };
+            assert!(forced.xray());
+
+            let forced = Options {
+                strategy: EffectStrategy::ForceFramebuffer,
+                ..options
+            };
+            assert!(!forced.xray());
+        }
+
+        assert!(!noise_only.xray());
+        assert!(blur.xray());
+    }
+
+    #[test]
+    fn opaque_body_exclusion_makes_ring() {
+        let rect = |x, y, w, h| Rectangle::<f64, Logical>::new(Point::new(x, y), Size::new(w, h));
+
+        // Effect extends 2 px around a square-cornered body.
+        let geometry = rect(10., 10., 14., 14.);
+        let body = opaque_body(rect(12., 12., 10., 10.), CornerRadius::default());
+        let ring = exclude_from_subregion(None, geometry, &body);
+        let mut rects: Vec<_> = ring
+            .iter()
+            .map(|(a, b)| Rectangle::from_extremities(a, b))
+            .collect();
+        rects.sort_by(|a, b| (a.loc.y, a.loc.x).partial_cmp(&(b.loc.y, b.loc.x)).unwrap());
+        assert_eq!(
+            rects,
+            [
+                rect(10., 10., 14., 2.),
+                rect(10., 12., 2., 10.),
+                rect(22., 12., 2., 10.),
+                rect(10., 22., 14., 2.),
+            ]
+        );
+
+        // With rounded corners, the corner squares of the body stay in the effect region.
+        let body = opaque_body(rect(12., 12., 10., 10.), CornerRadius::from(3.));
+        let ring = exclude_from_subregion(None, geometry, &body);
+        let area: f64 = ring.iter().map(|(a, b)| (b.x - a.x) * (b.y - a.y)).sum();
+        assert_eq!(area, 14. * 14. - 10. * 10. + 4. * 3. * 3.);
+    }
+
+    #[test]
+    fn client_region_excludes_csd_shadow() {
+        let rect = |x, y, w, h| Rectangle::<f64, Logical>::new(Point::new(x, y), Size::new(w, h));
+        let params = |region: Rectangle<i32, Logical>| {
+            render_params_for_tile(
+                rect(100., 100., 200., 100.),
+                1.,
+                false,
+                false,
+                Some(Arc::new(vec![region])),
+                // The surface has 20 px of client-side shadow around the window geometry.
+                rect(-20., -20., 240., 140.),
+                Scale::from(1.),
+                1.,
+                0,
+            )
+            .unwrap()
+        };
+
+        // The region covers the window body without the shadow, and so does the effect.
+        let params_body = params(Rectangle::new(Point::new(20, 20), Size::new(200, 100)));
+        assert_eq!(params_body.geometry, rect(100., 100., 200., 100.));
+        assert!(params_body.clip.is_none());
+        let bbox = params_body.subregion.unwrap().bbox().unwrap();
+        assert_eq!(bbox, params_body.geometry);
+
+        // A region larger than the surface is limited to the surface.
+        let params_large = params(Rectangle::new(Point::new(-50, -50), Size::new(500, 500)));
+        assert_eq!(params_large.geometry, rect(80., 80., 240., 140.));
+    }
+
+    #[test]
+    fn oversized_region_is_clipped_at_construction() {
+        let rect = |x, y, w, h| Rectangle::<f64, Logical>::new(Point::new(x, y), Size::new(w, h));
+        let region = Rectangle::new(
+            Point::new(i32::MIN / 2, i32::MIN / 2),
+            Size::new(i32::MAX, i32::MAX),
+        );
+        let params = render_params_for_tile(
+            rect(100., 100., 200., 100.),
+            1.,
+            false,
+            false,
+            Some(Arc::new(vec![region])),
+            rect(-20., -20., 240., 140.),
+            Scale::from(1.5),
+            1.,
+            0,
+        )
+        .unwrap();
+
+        // The subregion rects are bounded by the effect geometry rather than the client region.
+        let subregion = params.subregion.unwrap();
+        assert_eq!(subregion.rects.len(), 1);
+        assert_eq!(subregion.bbox().unwrap(), params.geometry);
+    }
+
+    #[test]
+    fn surface_alpha_multiplies_into_effect_alpha() {
+        let mut background_effect = BackgroundEffect::new();
+        let effect = niri_config::BackgroundEffect {
+            blur: Some(true),
+            opacity: Some(0.8),
+            ..Default::default()
+        };
+        background_effect.update_render_elements(CornerRadius::default(), effect, false, false);
+
+        assert_eq!(
+            background_effect
+                .postprocess(&background_effect.blur_config, SMALL_BLUR, 1.)
+                .alpha,
+            0.8
+        );
+        assert_eq!(
+            background_effect
+                .postprocess(&background_effect.blur_config, SMALL_BLUR, 0.5)
+                .alpha,
+            0.4
+        );
+        assert_eq!(
+            background_effect
+                .postprocess(&background_effect.blur_config, SMALL_BLUR, 0.)
+                .alpha,
+            0.
+        );
+    }
+
+    #[test]
+    fn unchanged_effect_adds_no_damage() {
+        let mut background_effect = BackgroundEffect::new();
+        let effect = niri_config::BackgroundEffect {
+            blur: Some(true),
+            ..Default::default()
+        };
+        let geometry = Rectangle::new(Point::new(10., 10.), Size::new(50., 50.));
+        let mut tracker = OutputDamageTracker::new((100, 100), 1., Transform::Normal);
+
+        let frame = |background_effect: &mut BackgroundEffect,
+                     tracker: &mut OutputDamageTracker| {
+            background_effect.update_render_elements(CornerRadius::default(), effect, false, false);
+            background_effect.update_alpha(1.);
+            let elem = background_effect.damage.render(geometry);
+            let (damage, _) = tracker.damage_output(1, &[elem]).unwrap();
+            damage.is_some_and(|damage| !damage.is_empty())
+        };
+
+        // The first frame damages everything.
+        assert!(frame(&mut background_effect, &mut tracker));
+        // Nothing changed, so no damage.
+        assert!(!frame(&mut background_effect, &mut tracker));
+        assert!(!frame(&mut background_effect, &mut tracker));
+
+        // A changed option damages again.
+        background_effect.update_alpha(0.5);
+        let elem = background_effect.damage.render(geometry);
+        let (damage, _) = tracker.damage_output(1, &[elem]).unwrap();
+        assert!(damage.is_some_and(|damage| !damage.is_empty()));
+    }
+
+    #[test]
+    fn alpha_is_dithered_only_on_8_bit_targets() {
+        assert_eq!(alpha_dither(8), 1. / 255.);
+        // E.g. RGB565.
+        assert_eq!(alpha_dither(5), 1. / 31.);
+
+        // 10-bit and half or full float targets.
+        assert_eq!(alpha_dither(10), 0.);
+        assert_eq!(alpha_dither(16), 0.);
+        assert_eq!(alpha_dither(32), 0.);
+
+        // Unknown.
+        assert_eq!(alpha_dither(0), 0.);
+    }
+}
@@ -1,15 +1,25 @@
-use std::cmp::max;
+use std::cell::Cell;
 use std::iter::{once, zip};
 use std::rc::Rc;
+use std::time::Duration;
 
 use anyhow::{ensure, Context as _};
+use niri_config::utils::MergeWith as _;
+use niri_config::BlurOffsetUnit;
 use smithay::backend::allocator::Fourcc;
-use smithay::backend::renderer::gles::{ffi, link_program, GlesError, GlesRenderer, GlesTexture};
+use smithay::backend::renderer::gles::{
+    ffi, link_program, GlesError, GlesFrame, GlesRenderer, GlesTexture,
+};
 use smithay::backend::renderer::{ContextId, Renderer as _, Texture as _};
 use smithay::gpu_span_location;
-use smithay::utils::{Buffer, Size};
+use smithay::utils::{Buffer, Logical, Physical, Point, Rectangle, Size, Transform};
 
+use crate::render_helpers::debug::{record_blur, BlurEvent};
 use crate::render_helpers::shaders::Shaders;
+use crate::render_helpers::texture_budget::{
+    gles_texture_bytes, texture_bytes, TextureReservation,
+};
+use crate::render_helpers::{create_texture_with_fallback, EFFECT_TEXTURE_FORMATS};
 
 #[derive(Debug)]
 pub struct Blur {
@@ -20,20 +30,513 @@ pub struct Blur {
     ///
     /// Created lazily and stored here to avoid recreating blur textures frequently.
     textures: Vec<GlesTexture>,
+    /// Number of passes that fit into the texture budget, or `None` if not even one pass does.
+    passes: Option<usize>,
+    /// Budget reservation for `textures`.
+    reservation: TextureReservation,
+    /// Whether the output texture holds the result of the last successful [`Blur::render()`].
+    has_output: bool,
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct BlurOptions {
     pub passes: u8,
     pub offset: f64,
+    /// Unit of `offset` and so of the blur spread, see [`BlurOptions::scaled()`].
+    pub offset_unit: BlurOffsetUnit,
+    /// Factor to multiply `offset` by for every pass after the first.
+    ///
+    /// `None` uses the same offset for all passes.
+    pub offset_growth: Option<f64>,
+    pub upsample: BlurUpsample,
+    pub algorithm: BlurAlgorithm,
+    /// Limit in bytes on the total size of effect textures.
+    pub texture_budget: Option<u64>,
+    /// Factor for mixing in the previous frame's blur, 0 to disable.
+    pub temporal_blend: f64,
+    /// Limit on the blur radius as a fraction of the smaller side of the source.
+    pub max_relative_radius: Option<f64>,
+    /// Stretch of the blur along a direction, `None` for an isotropic blur.
+    pub direction: Option<BlurDirection>,
+    /// Factors to scale the offset by along the texture x and y axes, `None` for the same on both.
+    ///
+    /// A texture stretched to the effect by a different factor per axis needs this for the blur
+    /// to stay round on screen. Like `direction`, it doesn't apply to the bicubic last pass.
+    pub axis_scale: Option<(f64, f64)>,
+}
+
+/// Stretch of the blur along a direction in texture space, for a motion blur look.
+///
+/// Only the kawase passes are stretched; the bicubic last pass stays isotropic.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BlurDirection {
+    /// Unit vector along which the blur is stretched.
+    pub dir: (f64, f64),
+    /// Factor to stretch the sample offsets by along `dir`.
+    pub stretch: f64,
+}
+
+/// Filter used for the final up pass of the blur.
+///
+/// Intermediate up passes always use the bilinear kawase filter; only the last pass, which writes
+/// the full-size output, can be switched to a smoother (and more expensive) bicubic filter.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlurUpsample {
+    #[default]
+    Bilinear,
+    /// 4×4 B-spline filter, avoids faint grid artifacts at large offsets.
+    Bicubic,
+}
+
+/// Weighting of the samples in the kawase passes.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum BlurAlgorithm {
+    /// Every sample has a fixed weight.
+    #[default]
+    Kawase,
+    /// Samples are weighted by their color similarity to the center, which keeps high-contrast
+    /// edges, like text on a bright background, from bleeding as much.
+    ///
+    /// This is only a rough approximation of a bilateral filter, applied per kawase pass.
+    Bilateral {
+        /// Color difference at which a sample's weight drops to about 60%.
+        ///
+        /// Smaller values preserve edges more.
+        range_sigma: f64,
+    },
+}
+
+impl BlurAlgorithm {
+    /// Returns the value for the `range_sigma` shader uniform, 0 for a plain kawase blur.
+    fn range_sigma(self) -> f32 {
+        match self {
+            BlurAlgorithm::Kawase => 0.,
+            BlurAlgorithm::Bilateral { range_sigma } => {
+                if range_sigma.is_nan() {
+                    0.
+                } else {
+                    range_sigma.clamp(0., 10.) as f32
+                }
+            }
+        }
+    }
+}
+
+/// Which program an up pass should be drawn with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UpProgram {
+    Bilinear,
+    Bicubic,
+}
+
+impl BlurUpsample {
+    /// Returns the program for up pass `pass` out of `passes` (0 is the smallest texture).
+    fn up_program(self, pass: usize, passes: usize) -> UpProgram {
+        match self {
+            BlurUpsample::Bicubic if pass + 1 == passes => UpProgram::Bicubic,
+            _ => UpProgram::Bilinear,
+        }
+    }
+}
+
+impl From<niri_config::BlurUpsample> for BlurUpsample {
+    fn from(value: niri_config::BlurUpsample) -> Self {
+        match value {
+            niri_config::BlurUpsample::Bilinear => Self::Bilinear,
+            niri_config::BlurUpsample::Bicubic => Self::Bicubic,
+        }
+    }
+}
+
+/// Part of the scene that a blur is drawn for.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlurContext {
+    /// Backdrops of regular windows and layer surfaces.
+    #[default]
+    Window,
+    /// The backdrop while the overview is open.
+    Overview,
+}
+
+impl BlurContext {
+    /// Returns the blur config to use in this context.
+    ///
+    /// The overview applies its own blur options on top of the regular ones, if set.
+    pub fn resolve_config(
+        self,
+        config: niri_config::Blur,
+        overview: &niri_config::Overview,
+    ) -> niri_config::Blur {
+        let mut config = config;
+        if let (Self::Overview, Some(part)) = (self, &overview.blur) {
+            config.merge_with(part);
+        }
+        config
+    }
+}
+
+/// Adjustments on top of the configured blur options.
+///
+/// Unset fields keep the value from the lower-priority source.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BlurOverride {
+    pub passes: Option<u8>,
+    pub offset: Option<f64>,
+}
+
+impl BlurOptions {
+    /// Resolves the final blur options.
+    ///
+    /// Sources in order of increasing priority: the `blur {}` config, per-surface hints, and
+    /// runtime overrides such as adaptive passes. The result is clamped to what the blur supports.
+    pub fn resolve(
+        config: &niri_config::Blur,
+        surface: BlurOverride,
+        runtime: BlurOverride,
+    ) -> Self {
+        let passes = runtime.passes.or(surface.passes).unwrap_or(config.passes);
+        let offset = runtime.offset.or(surface.offset).unwrap_or(config.offset);
+        let offset = if offset.is_nan() {
+            0.
+        } else {
+            offset.clamp(0., 100.)
+        };
+
+        let offset_growth = config.offset_growth;
+        let offset_growth = if offset_growth.is_nan() {
+            None
+        } else {
+            Some(offset_growth.clamp(0., 10.)).filter(|growth| *growth != 1.)
+        };
+
+        Self {
+            passes: passes.clamp(1, 31),
+            offset,
+            offset_unit: config.offset_unit,
+            offset_growth,
+            upsample: config.upsample.into(),
+            algorithm: match config.algorithm {
+                niri_config::BlurAlgorithm::Kawase => BlurAlgorithm::Kawase,
+                niri_config::BlurAlgorithm::Bilateral => BlurAlgorithm::Bilateral {
+                    range_sigma: config.range_sigma,
+                },
+            },
+            texture_budget: config
+                .texture_budget_mib
+                .map(|mib| u64::from(mib) * 1024 * 1024),
+            temporal_blend: config.temporal_blend.clamp(0., 1.),
+            max_relative_radius: config
+                .max_relative_radius
+                .filter(|x| !x.is_nan())
+                .map(|x| x.clamp(0., 1.)),
+            direction: None,
+            axis_scale: None,
+        }
+    }
+
+    /// Returns the matrix for the `direction` shader uniform in column-major order.
+    ///
+    /// It applies the `direction` stretch, then `axis_scale`.
+    fn direction_matrix(&self) -> [f32; 4] {
+        let [xx, yx, xy, yy] = BlurDirection::matrix(self.direction);
+        let Some((sx, sy)) = self.axis_scale else {
+            return [xx, yx, xy, yy];
+        };
+
+        let (sx, sy) = (sx as f32, sy as f32);
+        [xx * sx, yx * sy, xy * sx, yy * sy]
+    }
+
+    /// Returns these options with the offset in pixels of a texture rendered at `scale`.
+    ///
+    /// The blur passes work in texture pixels, so a logical offset is multiplied by the scale for
+    /// the blur to spread over the same logical area on outputs of any scale.
+    pub fn scaled(mut self, scale: f64) -> Self {
+        if self.offset_unit == BlurOffsetUnit::Logical {
+            self.offset *= scale;
+            self.offset_unit = BlurOffsetUnit::Physical;
+        }
+        self
+    }
+
+    /// Whether the blur would leave the source as is.
+    ///
+    /// With a zero offset, the kawase passes sample exactly at the texel centers, and the blur
+    /// degenerates into plain box downscaling followed by bilinear upscaling. That blurs a
+    /// little, depending on the number of passes, rather than leaving the source unchanged,
+    /// which is unexpected from "offset 0". So a zero offset skips the blur entirely instead.
+    pub fn is_passthrough(&self) -> bool {
+        self.offset <= PASSTHROUGH_OFFSET
+    }
+
+    /// Returns the offset for the passes through pyramid level `level`.
+    ///
+    /// Level 1 is the largest intermediate texture, written by the first down pass and read by
+    /// the last up pass.
+    pub fn level_offset(&self, level: usize) -> f64 {
+        match self.offset_growth {
+            Some(growth) => self.offset * growth.powi(level.saturating_sub(1) as i32),
+            None => self.offset,
+        }
+    }
+
+    /// Returns these options with the offset lowered to fit within `max_relative_radius`.
+    ///
+    /// Only the offset is lowered, since the passes determine the blur textures, which are
+    /// allocated beforehand. With enough passes, the radius may stay above the limit even with a
+    /// zero offset.
+    pub fn capped(mut self, source_size: Size<i32, Buffer>) -> Self {
+        let Some(fraction) = self.max_relative_radius else {
+            return self;
+        };
+
+        let max_radius = f64::from(source_size.w.min(source_size.h).max(1)) * fraction;
+        let radius = |options: &Self| f64::from(options.effective_radius_px(source_size));
+        if radius(&self) <= max_radius {
+            return self;
+        }
+
+        // The radius grows with the offset, so binary search for the largest offset that fits.
+        let mut low = 0.;
+        let mut high = self.offset;
+        for _ in 0..16 {
+            let mid = (low + high) / 2.;
+            let options = Self {
+                offset: mid,
+                ..self
+            };
+            if radius(&options) <= max_radius {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+
+        self.offset = low;
+        self
+    }
+
+    /// Estimates the blur radius in pixels for a source texture of the given size.
+    ///
+    /// The radius is the standard deviation of a Gaussian blur that looks about the same, like the
+    /// CSS `blur()` radius. It adds up the spread of every pass: bilinear resampling between pyramid
+    /// levels, and the kawase sampling patterns at `offset` half-texels of each level.
+    pub fn effective_radius_px(&self, source_size: Size<i32, Buffer>) -> f32 {
+        let passes = self.passes.clamp(1, 31) as usize;
+        let size = Size::new(source_size.w.max(1), source_size.h.max(1));
+
+        let mut variance_x = 0.;
+        let mut variance_y = 0.;
+        for (i, level) in pyramid_sizes(size, passes).enumerate().skip(1) {
+            // Level texel size in source pixels.
+            let texel_x = f64::from(size.w) / f64::from(level.w);
+            let texel_y = f64::from(size.h) / f64::from(level.h);
+
+            let offset = self.level_offset(i);
+            variance_x += pass_variance(texel_x, offset);
+            variance_y += pass_variance(texel_y, offset);
+        }
+
+        f64::max(variance_x, variance_y).sqrt() as f32
+    }
 }
 
-impl From<niri_config::Blur> for BlurOptions {
-    fn from(config: niri_config::Blur) -> Self {
+/// Speed in logical pixels per second below which the motion blur stays isotropic.
+const MOTION_BLUR_MIN_SPEED: f64 = 200.;
+/// Speed in logical pixels per second at which the motion blur reaches its full stretch.
+const MOTION_BLUR_FULL_SPEED: f64 = 4000.;
+/// Largest stretch of the motion blur along the direction of motion.
+const MOTION_BLUR_MAX_STRETCH: f64 = 3.;
+
+impl BlurDirection {
+    /// Returns the motion blur for a surface moving at `velocity` logical pixels per second.
+    ///
+    /// The stretch grows with the speed, and slow or resting surfaces get an isotropic blur.
+    pub fn from_velocity(velocity: Point<f64, Logical>) -> Option<Self> {
+        let speed = velocity.x.hypot(velocity.y);
+        if speed.is_nan() || speed <= MOTION_BLUR_MIN_SPEED {
+            return None;
+        }
+
+        let t = f64::min(
+            (speed - MOTION_BLUR_MIN_SPEED) / (MOTION_BLUR_FULL_SPEED - MOTION_BLUR_MIN_SPEED),
+            1.,
+        );
+        Some(Self {
+            dir: (velocity.x / speed, velocity.y / speed),
+            stretch: 1. + t * (MOTION_BLUR_MAX_STRETCH - 1.),
+        })
+    }
+
+    /// Returns the direction in the space of a texture with the given transform.
+    pub fn transformed(self, transform: Transform) -> Self {
+        let dir = Point::<f64, Logical>::from(self.dir);
+        let dir = transform.transform_point_in(dir, &Size::from((0., 0.)));
         Self {
-            passes: config.passes,
-            offset: config.offset,
+            dir: (dir.x, dir.y),
+            ..self
+        }
+    }
+
+    /// Returns the matrix for the `direction` shader uniform in column-major order.
+    ///
+    /// It scales the sample offsets by the stretch along the direction, and leaves them as is
+    /// across it.
+    pub fn matrix(direction: Option<Self>) -> [f32; 4] {
+        let Some(Self {
+            dir: (x, y),
+            stretch,
+        }) = direction
+        else {
+            return [1., 0., 0., 1.];
+        };
+
+        let k = stretch - 1.;
+        [1. + k * x * x, k * x * y, k * x * y, 1. + k * y * y].map(|v| v as f32)
+    }
+}
+
+/// Offsets at or below this skip the blur, see [`BlurOptions::is_passthrough()`].
+const PASSTHROUGH_OFFSET: f64 = 1e-3;
+
+/// Returns how many up passes to render into the blur textures.
+///
+/// Without `to_output`, the last pass, which writes the output texture, is left out.
+fn up_pass_count(passes: usize, to_output: bool) -> usize {
+    if to_output {
+        passes
+    } else {
+        passes - 1
+    }
+}
+
+/// Returns the pyramid level that up pass `pass` reads from (pass 0 reads the smallest level).
+///
+/// Each up pass mirrors the down pass that wrote its source, so the two use the same offset.
+fn up_pass_level(pass: usize, passes: usize) -> usize {
+    passes - pass
+}
+
+/// Returns the variance along one axis added by the down and up pass through a pyramid level.
+fn pass_variance(texel: f64, offset: f64) -> f64 {
+    // Box-filtered downsampling followed by tent-filtered (bilinear) upsampling.
+    let resample = texel * texel / 12. + texel * texel / 6.;
+
+    // Both passes sample at offset half-texels of the level. The down pattern (center weight 4,
+    // four diagonals weight 1) has variance o²/2, and the up pattern (four edges weight 1 at 2o,
+    // four diagonals weight 2 at o) has variance 4o²/3.
+    let o = offset * texel / 2.;
+    let kawase = o * o / 2. + o * o * 4. / 3.;
+
+    resample + kawase
+}
+
+/// Returns the total size in bytes of the blur textures for the given number of passes.
+fn pyramid_bytes(size: Size<i32, Buffer>, passes: usize) -> u64 {
+    pyramid_sizes(size, passes).map(texture_bytes).sum()
+}
+
+/// Returns the sizes of the output texture followed by the intermediate textures.
+///
+/// Odd sizes are rounded up, so that every texel of a level covers exactly two texels of the
+/// larger one, with the last texel hanging over the edge, see [`level_uv_scale()`].
+fn pyramid_sizes(
+    size: Size<i32, Buffer>,
+    passes: usize,
+) -> impl Iterator<Item = Size<i32, Buffer>> {
+    let mut next = size;
+    (0..=passes).map(move |_| {
+        let size = next;
+        next = Size::new((size.w + 1) / 2, (size.h + 1) / 2);
+        size
+    })
+}
+
+/// Returns the factor from texture coordinates of pyramid level `small` to those of the next
+/// larger level `large`.
+///
+/// Stretching an odd-sized level over the smaller one would sample it at slightly more than two
+/// texels per texel, with the sample positions drifting across the texture. This shifts the
+/// blurred content by up to a pixel in places. Instead, `small` covers two texels of `large` per
+/// texel exactly and extends past its edge by half a texel, where clamp-to-edge applies. Down
+/// passes scale their coordinates by this factor, and up passes by its inverse.
+fn level_uv_scale(large: Size<i32, Buffer>, small: Size<i32, Buffer>) -> (f32, f32) {
+    let scale = |large: i32, small: i32| {
+        if large <= 1 {
+            1.
+        } else {
+            (small * 2) as f32 / large as f32
         }
+    };
+    (scale(large.w, small.w), scale(large.h, small.h))
+}
+
+/// Returns the largest number of passes up to `requested` whose textures fit into `available`
+/// bytes, or `None` if not even one pass fits.
+fn fit_passes(size: Size<i32, Buffer>, requested: usize, available: Option<u64>) -> Option<usize> {
+    let Some(available) = available else {
+        return Some(requested);
+    };
+
+    (1..=requested)
+        .rev()
+        .find(|&passes| pyramid_bytes(size, passes) <= available)
+}
+
+/// Controller that lowers blur passes while frames go over the refresh budget.
+///
+/// Changing the number of passes reallocates the blur texture pyramid, so changes are rate-limited:
+/// passes drop by one after several consecutive slow frames, and only come back after a much longer
+/// run of frames with comfortable headroom. Frames in between reset both counters.
+#[derive(Debug, Default)]
+pub struct AdaptiveBlurPasses {
+    /// How many passes to subtract from the configured count.
+    reduction: u8,
+    /// Consecutive frames over the budget.
+    slow_frames: u8,
+    /// Consecutive frames comfortably under the budget.
+    fast_frames: u8,
+}
+
+impl AdaptiveBlurPasses {
+    /// Consecutive over-budget frames before dropping a pass.
+    const DROP_AFTER: u8 = 3;
+    /// Consecutive frames with headroom before restoring a pass.
+    const RESTORE_AFTER: u8 = 60;
+
+    /// Returns the number of passes to use given the configured count.
+    pub fn passes(&self, configured: u8) -> u8 {
+        configured.saturating_sub(self.reduction).max(1)
+    }
+
+    /// Records how long a frame took to render against the refresh interval.
+    ///
+    /// Returns `true` if the pass count changed.
+    pub fn record(&mut self, frame_time: Duration, budget: Duration, configured: u8) -> bool {
+        if frame_time > budget {
+            self.fast_frames = 0;
+            self.slow_frames = self.slow_frames.saturating_add(1);
+
+            if self.slow_frames >= Self::DROP_AFTER && self.passes(configured) > 1 {
+                self.slow_frames = 0;
+                self.reduction += 1;
+                return true;
+            }
+        } else if frame_time * 4 < budget * 3 {
+            self.slow_frames = 0;
+            self.fast_frames = self.fast_frames.saturating_add(1);
+
+            if self.fast_frames >= Self::RESTORE_AFTER && self.reduction > 0 {
+                self.fast_frames = 0;
+                self.reduction -= 1;
+                return true;
+            }
+        } else {
+            self.slow_frames = 0;
+            self.fast_frames = 0;
+        }
+
+        false
     }
 }
 
@@ -44,6 +547,9 @@ pub struct BlurProgram(Rc<BlurProgramInner>);
 struct BlurProgramInner {
     down: BlurProgramInternal,
     up: BlurProgramInternal,
+    up_bicubic: BlurProgramInternal,
+    /// Whether the GL programs were already deleted.
+    destroyed: Cell<bool>,
 }
 
 #[derive(Debug)]
@@ -52,27 +558,73 @@ struct BlurProgramInternal {
     uniform_tex: ffi::types::GLint,
     uniform_half_pixel: ffi::types::GLint,
     uniform_offset: ffi::types::GLint,
+    uniform_uv_scale: ffi::types::GLint,
+    /// Missing from the bicubic program, which doesn't support directional blur.
+    uniform_direction: ffi::types::GLint,
+    /// Missing from the bicubic program, which doesn't support bilateral weighting.
+    uniform_range_sigma: ffi::types::GLint,
     attrib_vert: ffi::types::GLint,
 }
 
-unsafe fn compile_program(gl: &ffi::Gles2, src: &str) -> Result<BlurProgramInternal, GlesError> {
+unsafe fn compile_program(gl: &ffi::Gles2, src: &str) -> anyhow::Result<BlurProgramInternal> {
     let program = unsafe { link_program(gl, include_str!("shaders/blur.vert"), src)? };
 
     let vert = c"vert";
     let tex = c"tex";
     let half_pixel = c"half_pixel";
     let offset = c"offset";
+    let uv_scale = c"uv_scale";
+    let direction = c"direction";
+    let range_sigma = c"range_sigma";
 
-    Ok(BlurProgramInternal {
+    let internal = BlurProgramInternal {
         program,
         uniform_tex: gl.GetUniformLocation(program, tex.as_ptr()),
         uniform_half_pixel: gl.GetUniformLocation(program, half_pixel.as_ptr()),
         uniform_offset: gl.GetUniformLocation(program, offset.as_ptr()),
+        uniform_uv_scale: gl.GetUniformLocation(program, uv_scale.as_ptr()),
+        uniform_direction: gl.GetUniformLocation(program, direction.as_ptr()),
+        uniform_range_sigma: gl.GetUniformLocation(program, range_sigma.as_ptr()),
         attrib_vert: gl.GetAttribLocation(program, vert.as_ptr()),
-    })
+    };
+
+    if let Err(err) = internal.check_locations() {
+        gl.DeleteProgram(program);
+        return Err(err);
+    }
+
+    Ok(internal)
+}
+
+impl BlurProgramInternal {
+    /// Checks that all uniforms and attributes were found in the program.
+    ///
+    /// GL returns -1 for names missing from the program, e.g. due to a typo or because the
+    /// shader compiler optimized them out. Setting them is silently ignored, which would leave the
+    /// backdrop unblurred with no error.
+    fn check_locations(&self) -> anyhow::Result<()> {
+        for (name, location) in [
+            ("uniform tex", self.uniform_tex),
+            ("uniform half_pixel", self.uniform_half_pixel),
+            ("uniform offset", self.uniform_offset),
+            ("uniform uv_scale", self.uniform_uv_scale),
+            ("attribute vert", self.attrib_vert),
+        ] {
+            ensure!(location >= 0, "{name} not found in the program");
+        }
+
+        Ok(())
+    }
 }
 
 impl BlurProgram {
+    fn up(&self, program: UpProgram) -> &BlurProgramInternal {
+        match program {
+            UpProgram::Bilinear => &self.0.up,
+            UpProgram::Bicubic => &self.0.up_bicubic,
+        }
+    }
+
     pub fn compile(renderer: &mut GlesRenderer) -> anyhow::Result<Self> {
         renderer
             .with_context(move |gl| unsafe {
@@ -80,17 +632,46 @@ impl BlurProgram {
                     .context("error compiling blur_down shader")?;
                 let up = compile_program(gl, include_str!("shaders/blur_up.frag"))
                     .context("error compiling blur_up shader")?;
-                Ok(Self(Rc::new(BlurProgramInner { down, up })))
+                let up_bicubic = compile_program(gl, include_str!("shaders/blur_up_bicubic.frag"))
+                    .context("error compiling blur_up_bicubic shader")?;
+                Ok(Self(Rc::new(BlurProgramInner {
+                    down,
+                    up,
+                    up_bicubic,
+                    destroyed: Cell::new(false),
+                })))
             })
             .context("error making GL context current")?
     }
 
+    /// Deletes the GL programs.
+    ///
+    /// The programs are shared between all clones, so this deletes them for every clone. Calling
+    /// this again, including on another clone, does nothing.
     pub fn destroy(self, renderer: &mut GlesRenderer) -> Result<(), GlesError> {
-        renderer.with_context(move |gl| unsafe {
-            gl.DeleteProgram(self.0.down.program);
-            gl.DeleteProgram(self.0.up.program);
+        let Some(programs) = self.take_programs() else {
+            return Ok(());
+        };
+
+        renderer.with_context(|gl| unsafe {
+            for program in programs {
+                gl.DeleteProgram(program);
+            }
         })
     }
+
+    /// Returns the GL programs to delete, or `None` if they were already taken.
+    fn take_programs(&self) -> Option<[ffi::types::GLuint; 3]> {
+        if self.0.destroyed.replace(true) {
+            return None;
+        }
+
+        Some([
+            self.0.down.program,
+            self.0.up.program,
+            self.0.up_bicubic.program,
+        ])
+    }
 }
 
 impl Blur {
@@ -100,6 +681,9 @@ impl Blur {
             program,
             renderer_context_id: renderer.context_id(),
             textures: Vec::new(),
+            passes: None,
+            reservation: TextureReservation::default(),
+            has_output: false,
         })
     }
 
@@ -107,6 +691,34 @@ impl Blur {
         self.renderer_context_id.clone()
     }
 
+    /// Whether the last [`Blur::prepare_textures()`] couldn't fit any passes into the texture
+    /// budget.
+    ///
+    /// In this case there are no blur textures, and the caller should draw without blur.
+    pub fn is_over_budget(&self) -> bool {
+        self.passes.is_none()
+    }
+
+    /// Returns the output texture followed by the intermediate textures, large to small.
+    ///
+    /// After [`Blur::render()`], the smallest texture holds the result of the last down pass.
+    pub fn levels(&self) -> &[GlesTexture] {
+        &self.textures
+    }
+
+    /// Returns the output texture of the last successful [`Blur::render()`], if it's still intact.
+    ///
+    /// A failed render that didn't get to draw anything leaves it intact, so the caller can keep
+    /// showing the previous blur.
+    pub fn last_output(&self) -> Option<&GlesTexture> {
+        self.textures.first().filter(|_| self.has_output)
+    }
+
+    /// Returns the size in bytes of the current blur textures.
+    pub fn texture_memory_bytes(&self) -> u64 {
+        self.textures.iter().map(gles_texture_bytes).sum()
+    }
+
     pub fn prepare_textures(
         &mut self,
         mut create_texture: impl FnMut(Fourcc, Size<i32, Buffer>) -> Result<GlesTexture, GlesError>,
@@ -115,9 +727,28 @@ impl Blur {
     ) -> anyhow::Result<()> {
         let _span = tracy_client::span!("Blur::prepare_textures");
 
-        let passes = options.passes.clamp(1, 31) as usize;
+        let requested = options.passes.clamp(1, 31) as usize;
         let size = source.size();
 
+        let available = self.reservation.available(options.texture_budget);
+        let passes = fit_passes(size, requested, available);
+        if passes != self.passes {
+            match passes {
+                Some(passes) if passes < requested => {
+                    debug!("texture budget only fits {passes} of {requested} blur passes");
+                }
+                Some(_) => (),
+                None => debug!("texture budget doesn't fit any blur passes, skipping blur"),
+            }
+        }
+        self.passes = passes;
+
+        let Some(passes) = passes else {
+            self.textures.clear();
+            self.reservation.set(0);
+            return Ok(());
+        };
+
         if let Some(output) = self.textures.first_mut() {
             let old_size = output.size();
             if old_size != size {
@@ -137,23 +768,25 @@ impl Blur {
             }
         }
 
-        // Create any missing textures.
-        let mut w = size.w;
-        let mut h = size.h;
-        for i in 0..=passes {
-            let size = Size::new(w, h);
-            w = max(1, w / 2);
-            h = max(1, h / 2);
+        self.reservation.set(pyramid_bytes(size, passes));
 
+        // Create any missing textures.
+        for (i, size) in pyramid_sizes(size, passes).enumerate() {
             if self.textures.len() > i {
                 // This texture already exists.
                 continue;
             }
 
-            // debug!("creating texture for step {i} sized {w} × {h}");
+            if i == 0 {
+                self.has_output = false;
+            }
+
+            // debug!("creating texture for step {i} sized {} × {}", size.w, size.h);
 
-            let texture: GlesTexture =
-                create_texture(Fourcc::Abgr8888, size).context("error creating texture")?;
+            let (texture, _) = create_texture_with_fallback(EFFECT_TEXTURE_FORMATS, |fourcc| {
+                create_texture(fourcc, size)
+            })
+            .context("error creating texture")?;
             self.textures.push(texture);
         }
 
@@ -172,13 +805,146 @@ impl Blur {
         let _span = tracy_client::span!("Blur::render");
         trace!("rendering blur");
 
+        // Callers normally skip the blur altogether in this case. Capping to the maximum radius
+        // can also lower the offset all the way down to a passthrough.
+        if options.capped(source.size()).is_passthrough() {
+            return Ok(source.clone());
+        }
+
+        self.render_passes(renderer, source, options, true)?;
+        self.has_output = true;
+        Ok(self.textures[0].clone())
+    }
+
+    /// Renders all blur passes except for the last up pass.
+    ///
+    /// The last pass is then drawn with [`Blur::draw_last_pass()`] straight into a frame, which
+    /// skips writing and then reading back the full-size output texture.
+    pub fn render_without_last_pass(
+        &mut self,
+        renderer: &mut GlesRenderer,
+        source: &GlesTexture,
+        options: BlurOptions,
+    ) -> anyhow::Result<()> {
+        let _span = tracy_client::span!("Blur::render_without_last_pass");
+        trace!("rendering blur without the last pass");
+
+        ensure!(
+            !options.capped(source.size()).is_passthrough(),
+            "passthrough blur has no passes"
+        );
+
+        // The output texture won't hold this blur, so it's no good as a fallback for later ones.
+        self.has_output = false;
+        self.render_passes(renderer, source, options, false)
+    }
+
+    /// Draws the last up pass of a blur rendered with [`Blur::render_without_last_pass()`] into
+    /// the framebuffer currently bound to `frame`.
+    ///
+    /// `dst` and `damage` are in framebuffer coordinates. Like drawing the output texture, the
+    /// result is blended on top with premultiplied alpha.
+    pub fn draw_last_pass(
+        &self,
+        frame: &mut GlesFrame,
+        options: BlurOptions,
+        dst: Rectangle<i32, Physical>,
+        damage: &[Rectangle<i32, Physical>],
+    ) -> Result<(), GlesError> {
+        let (Some(passes), Some(output), Some(src)) =
+            (self.passes, self.textures.first(), self.textures.get(1))
+        else {
+            return Ok(());
+        };
+        let options = options.capped(output.size());
+
+        let program = self
+            .program
+            .up(options.upsample.up_program(passes - 1, passes));
+        let src_size = src.size();
+        let uv_scale = level_uv_scale(output.size(), src_size);
+        let src = src.tex_id();
+
+        frame.with_context(|gl| unsafe {
+            let mut viewport = [0i32; 4];
+            gl.GetIntegerv(ffi::VIEWPORT, viewport.as_mut_ptr());
+            let mut scissor = [0i32; 4];
+            gl.GetIntegerv(ffi::SCISSOR_BOX, scissor.as_mut_ptr());
+
+            gl.Enable(ffi::BLEND);
+            gl.BlendFunc(ffi::ONE, ffi::ONE_MINUS_SRC_ALPHA);
+            gl.Enable(ffi::SCISSOR_TEST);
+
+            gl.UseProgram(program.program);
+            gl.Uniform1i(program.uniform_tex, 0);
+            gl.Uniform1f(program.uniform_offset, options.level_offset(1) as f32);
+            let direction = options.direction_matrix();
+            gl.UniformMatrix2fv(program.uniform_direction, 1, ffi::FALSE, direction.as_ptr());
+            gl.Uniform1f(program.uniform_range_sigma, options.algorithm.range_sigma());
+            // During upsampling, half_pixel is half of the source pixel.
+            let half_pixel = (0.5 / src_size.w as f32, 0.5 / src_size.h as f32);
+            gl.Uniform2f(program.uniform_half_pixel, half_pixel.0, half_pixel.1);
+            // The frame is drawn to like the output texture would be.
+            gl.Uniform2f(program.uniform_uv_scale, 1. / uv_scale.0, 1. / uv_scale.1);
+
+            gl.ActiveTexture(ffi::TEXTURE0);
+            gl.BindTexture(ffi::TEXTURE_2D, src);
+            gl.TexParameteri(ffi::TEXTURE_2D, ffi::TEXTURE_MIN_FILTER, ffi::LINEAR as i32);
+            gl.TexParameteri(ffi::TEXTURE_2D, ffi::TEXTURE_MAG_FILTER, ffi::LINEAR as i32);
+            gl.TexParameteri(
+                ffi::TEXTURE_2D,
+                ffi::TEXTURE_WRAP_S,
+                ffi::CLAMP_TO_EDGE as i32,
+            );
+            gl.TexParameteri(
+                ffi::TEXTURE_2D,
+                ffi::TEXTURE_WRAP_T,
+                ffi::CLAMP_TO_EDGE as i32,
+            );
+
+            let vertices: [f32; 12] = [0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0];
+            gl.EnableVertexAttribArray(program.attrib_vert as u32);
+            gl.BindBuffer(ffi::ARRAY_BUFFER, 0);
+            gl.VertexAttribPointer(
+                program.attrib_vert as u32,
+                2,
+                ffi::FLOAT,
+                ffi::FALSE,
+                0,
+                vertices.as_ptr().cast(),
+            );
+
+            gl.Viewport(dst.loc.x, dst.loc.y, dst.size.w, dst.size.h);
+            for rect in damage {
+                gl.Scissor(rect.loc.x, rect.loc.y, rect.size.w, rect.size.h);
+                gl.DrawArrays(ffi::TRIANGLES, 0, 6);
+            }
+
+            gl.DisableVertexAttribArray(program.attrib_vert as u32);
+
+            // Restore state set by GlesFrame that we just modified.
+            gl.Viewport(viewport[0], viewport[1], viewport[2], viewport[3]);
+            gl.Scissor(scissor[0], scissor[1], scissor[2], scissor[3]);
+        })
+    }
+
+    /// Renders the blur passes, including the last up pass into the output texture if
+    /// `to_output` is set.
+    fn render_passes(
+        &mut self,
+        renderer: &mut GlesRenderer,
+        source: &GlesTexture,
+        options: BlurOptions,
+        to_output: bool,
+    ) -> anyhow::Result<()> {
         ensure!(
             renderer.context_id() == self.renderer_context_id,
             "wrong renderer"
         );
 
-        let passes = options.passes.clamp(1, 31) as usize;
+        let passes = self.passes.context("blur is over the texture budget")?;
         let size = source.size();
+        let options = options.capped(size);
 
         ensure!(
             self.textures.len() == passes + 1,
@@ -198,6 +964,7 @@ impl Blur {
             output.is_unique_reference(),
             "output texture has a non-unique reference"
         );
+        let output_id = output.tex_id();
 
         renderer.with_profiled_context(gpu_span_location!("Blur::render"), |gl| unsafe {
             while gl.GetError() != ffi::NO_ERROR {}
@@ -209,12 +976,21 @@ impl Blur {
 
             let mut fbos = [0; 2];
             gl.GenFramebuffers(fbos.len() as _, fbos.as_mut_ptr());
+            // Under memory pressure, this can fail and leave the names at 0. Drawing would then
+            // silently go nowhere and leave garbage in the output texture.
+            ensure!(fbos[0] != 0, "error creating framebuffer");
             gl.BindFramebuffer(ffi::DRAW_FRAMEBUFFER, fbos[0]);
 
+            let mut result = Ok(());
+
+            let direction = options.direction_matrix();
+            let range_sigma = options.algorithm.range_sigma();
+
             let program = &self.program.0.down;
             gl.UseProgram(program.program);
             gl.Uniform1i(program.uniform_tex, 0);
-            gl.Uniform1f(program.uniform_offset, options.offset as f32);
+            gl.UniformMatrix2fv(program.uniform_direction, 1, ffi::FALSE, direction.as_ptr());
+            gl.Uniform1f(program.uniform_range_sigma, range_sigma);
 
             let vertices: [f32; 12] = [0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0];
             gl.EnableVertexAttribArray(program.attrib_vert as u32);
@@ -230,15 +1006,22 @@ impl Blur {
 
             let src = once(source).chain(&self.textures[1..]);
             let dst = &self.textures[1..];
-            for (src, dst) in zip(src, dst) {
+            for (i, (src, dst)) in zip(src, dst).enumerate() {
                 let dst_size = dst.size();
                 let w = dst_size.w;
                 let h = dst_size.h;
                 gl.Viewport(0, 0, w, h);
 
+                // Down pass i writes level i + 1.
+                let offset = options.level_offset(i + 1);
+                gl.Uniform1f(program.uniform_offset, offset as f32);
+
                 // During downsampling, half_pixel is half of the destination pixel.
                 gl.Uniform2f(program.uniform_half_pixel, 0.5 / w as f32, 0.5 / h as f32);
 
+                let uv_scale = level_uv_scale(src.size(), dst_size);
+                gl.Uniform2f(program.uniform_uv_scale, uv_scale.0, uv_scale.1);
+
                 let src = src.tex_id();
                 let dst = dst.tex_id();
 
@@ -251,6 +1034,11 @@ impl Blur {
                     0,
                 );
 
+                result = check_framebuffer_status(gl.CheckFramebufferStatus(ffi::DRAW_FRAMEBUFFER));
+                if result.is_err() {
+                    break;
+                }
+
                 gl.BindTexture(ffi::TEXTURE_2D, src);
                 gl.TexParameteri(ffi::TEXTURE_2D, ffi::TEXTURE_MIN_FILTER, ffi::LINEAR as i32);
                 gl.TexParameteri(ffi::TEXTURE_2D, ffi::TEXTURE_MAG_FILTER, ffi::LINEAR as i32);
@@ -271,26 +1059,50 @@ impl Blur {
             gl.DisableVertexAttribArray(program.attrib_vert as u32);
 
             // Up
-            let program = &self.program.0.up;
-            gl.UseProgram(program.program);
-            gl.Uniform1i(program.uniform_tex, 0);
-            gl.Uniform1f(program.uniform_offset, options.offset as f32);
-
             let vertices: [f32; 12] = [0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0, 0.0, 1.0, 1.0, 1.0, 0.0];
-            gl.EnableVertexAttribArray(program.attrib_vert as u32);
-            gl.BindBuffer(ffi::ARRAY_BUFFER, 0);
-            gl.VertexAttribPointer(
-                program.attrib_vert as u32,
-                2,
-                ffi::FLOAT,
-                ffi::FALSE,
-                0,
-                vertices.as_ptr().cast(),
-            );
+            let mut current: Option<&BlurProgramInternal> = None;
 
             let src = self.textures.iter().rev();
             let dst = self.textures.iter().rev().skip(1);
-            for (src, dst) in zip(src, dst) {
+            let up_passes = if result.is_ok() {
+                up_pass_count(passes, to_output)
+            } else {
+                0
+            };
+            for (pass, (src, dst)) in zip(src, dst).take(up_passes).enumerate() {
+                let program = self.program.up(options.upsample.up_program(pass, passes));
+                if !current.is_some_and(|current| std::ptr::eq(current, program)) {
+                    if let Some(current) = current {
+                        gl.DisableVertexAttribArray(current.attrib_vert as u32);
+                    }
+
+                    gl.UseProgram(program.program);
+                    gl.Uniform1i(program.uniform_tex, 0);
+                    gl.UniformMatrix2fv(
+                        program.uniform_direction,
+                        1,
+                        ffi::FALSE,
+                        direction.as_ptr(),
+                    );
+                    gl.Uniform1f(program.uniform_range_sigma, range_sigma);
+
+                    gl.EnableVertexAttribArray(program.attrib_vert as u32);
+                    gl.BindBuffer(ffi::ARRAY_BUFFER, 0);
+                    gl.VertexAttribPointer(
+                        program.attrib_vert as u32,
+                        2,
+                        ffi::FLOAT,
+                        ffi::FALSE,
+                        0,
+                        vertices.as_ptr().cast(),
+                    );
+
+                    current = Some(program);
+                }
+
+                let offset = options.level_offset(up_pass_level(pass, passes));
+                gl.Uniform1f(program.uniform_offset, offset as f32);
+
                 let dst_size = dst.size();
                 let w = dst_size.w;
                 let h = dst_size.h;
@@ -302,6 +1114,9 @@ impl Blur {
                 let src_h = src_size.h as f32;
                 gl.Uniform2f(program.uniform_half_pixel, 0.5 / src_w, 0.5 / src_h);
 
+                let uv_scale = level_uv_scale(dst_size, src_size);
+                gl.Uniform2f(program.uniform_uv_scale, 1. / uv_scale.0, 1. / uv_scale.1);
+
                 let src = src.tex_id();
                 let dst = dst.tex_id();
 
@@ -314,6 +1129,17 @@ impl Blur {
                     0,
                 );
 
+                result = check_framebuffer_status(gl.CheckFramebufferStatus(ffi::DRAW_FRAMEBUFFER));
+                if result.is_err() {
+                    break;
+                }
+
+                // Until this pass succeeds, the output holds neither the previous blur nor this
+                // one.
+                if dst == output_id {
+                    self.has_output = false;
+                }
+
                 gl.BindTexture(ffi::TEXTURE_2D, src);
                 gl.TexParameteri(ffi::TEXTURE_2D, ffi::TEXTURE_MIN_FILTER, ffi::LINEAR as i32);
                 gl.TexParameteri(ffi::TEXTURE_2D, ffi::TEXTURE_MAG_FILTER, ffi::LINEAR as i32);
@@ -331,12 +1157,886 @@ impl Blur {
                 gl.DrawArrays(ffi::TRIANGLES, 0, 6);
             }
 
-            gl.DisableVertexAttribArray(program.attrib_vert as u32);
+            if let Some(current) = current {
+                gl.DisableVertexAttribArray(current.attrib_vert as u32);
+            }
 
             gl.BindFramebuffer(ffi::DRAW_FRAMEBUFFER, 0);
             gl.DeleteFramebuffers(fbos.len() as _, fbos.as_ptr());
-        })?;
 
-        Ok(self.textures[0].clone())
+            result
+        })??;
+
+        // Only report blurs that were actually drawn.
+        record_blur(|| BlurEvent::Blur { size });
+
+        Ok(())
+    }
+}
+
+/// Checks that a blur pass framebuffer can be drawn to.
+fn check_framebuffer_status(status: ffi::types::GLenum) -> anyhow::Result<()> {
+    ensure!(
+        status == ffi::FRAMEBUFFER_COMPLETE,
+        "framebuffer is incomplete: status {status:#x}"
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use smithay::backend::renderer::{
+        Bind as _, Color32F, ExportMem as _, Frame as _, ImportMem as _, Offscreen as _,
+    };
+
+    use super::*;
+    use crate::render_helpers::{copy_framebuffer, test_renderer};
+
+    #[test]
+    fn destroying_program_twice_is_safe() {
+        let internal = |program| BlurProgramInternal {
+            program,
+            uniform_tex: -1,
+            uniform_half_pixel: -1,
+            uniform_offset: -1,
+            uniform_uv_scale: -1,
+            uniform_direction: -1,
+            uniform_range_sigma: -1,
+            attrib_vert: -1,
+        };
+        let program = BlurProgram(Rc::new(BlurProgramInner {
+            down: internal(1),
+            up: internal(2),
+            up_bicubic: internal(3),
+            destroyed: Cell::new(false),
+        }));
+        let clone = program.clone();
+
+        // The programs are deleted once, no matter which clone is destroyed.
+        assert_eq!(program.take_programs(), Some([1, 2, 3]));
+        assert_eq!(program.take_programs(), None);
+        assert_eq!(clone.take_programs(), None);
+    }
+
+    #[test]
+    fn missing_location_fails_compilation() {
+        let internal = || BlurProgramInternal {
+            program: 1,
+            uniform_tex: 0,
+            uniform_half_pixel: 1,
+            uniform_offset: 2,
+            uniform_uv_scale: 3,
+            uniform_direction: 4,
+            uniform_range_sigma: 5,
+            attrib_vert: 0,
+        };
+        assert!(internal().check_locations().is_ok());
+
+        // E.g. a typo in the shader, or the offset optimized out.
+        let broken = BlurProgramInternal {
+            uniform_offset: -1,
+            ..internal()
+        };
+        let err = broken.check_locations().unwrap_err();
+        assert_eq!(err.to_string(), "uniform offset not found in the program");
+
+        let broken = BlurProgramInternal {
+            attrib_vert: -1,
+            ..internal()
+        };
+        let err = broken.check_locations().unwrap_err();
+        assert_eq!(err.to_string(), "attribute vert not found in the program");
+    }
+
+    #[test]
+    fn bilinear_uses_regular_program_for_all_passes() {
+        for pass in 0..4 {
+            assert_eq!(
+                BlurUpsample::Bilinear.up_program(pass, 4),
+                UpProgram::Bilinear
+            );
+        }
+    }
+
+    #[test]
+    fn bicubic_only_applies_to_final_pass() {
+        let programs: Vec<_> = (0..4)
+            .map(|pass| BlurUpsample::Bicubic.up_program(pass, 4))
+            .collect();
+        assert_eq!(
+            programs,
+            [
+                UpProgram::Bilinear,
+                UpProgram::Bilinear,
+                UpProgram::Bilinear,
+                UpProgram::Bicubic,
+            ]
+        );
+
+        // With a single pass, the only up pass is also the final one.
+        assert_eq!(BlurUpsample::Bicubic.up_program(0, 1), UpProgram::Bicubic);
+    }
+
+    #[test]
+    fn resolve_reads_upsample_from_config() {
+        let options = |config: &str| {
+            let config = niri_config::Config::parse_mem(config).unwrap();
+            BlurOptions::resolve(
+                &config.blur,
+                BlurOverride::default(),
+                BlurOverride::default(),
+            )
+            .upsample
+        };
+
+        assert_eq!(options(""), BlurUpsample::Bilinear);
+        let bicubic = options(
+            r#"
+            blur {
+                upsample "bicubic"
+            }
+            "#,
+        );
+        assert_eq!(bicubic, BlurUpsample::Bicubic);
+    }
+
+    #[test]
+    fn resolve_precedence() {
+        let config = niri_config::Blur {
+            passes: 3,
+            offset: 3.,
+            ..Default::default()
+        };
+        let none = BlurOverride::default();
+        let surface = BlurOverride {
+            passes: Some(2),
+            offset: Some(5.),
+        };
+        let runtime = BlurOverride {
+            passes: Some(1),
+            offset: Some(7.),
+        };
+        let resolve = |surface, runtime| {
+            let options = BlurOptions::resolve(&config, surface, runtime);
+            (options.passes, options.offset)
+        };
+
+        assert_eq!(resolve(none, none), (3, 3.));
+        assert_eq!(resolve(surface, none), (2, 5.));
+        assert_eq!(resolve(none, runtime), (1, 7.));
+        assert_eq!(resolve(surface, runtime), (1, 7.));
+
+        // Fields are overridden independently.
+        let runtime_passes = BlurOverride {
+            passes: Some(1),
+            offset: None,
+        };
+        assert_eq!(resolve(surface, runtime_passes), (1, 5.));
+        let surface_offset = BlurOverride {
+            passes: None,
+            offset: Some(5.),
+        };
+        assert_eq!(resolve(surface_offset, none), (3, 5.));
+    }
+
+    #[test]
+    fn resolve_clamps() {
+        let config = niri_config::Blur::default();
+        let resolve = |passes, offset| {
+            let runtime = BlurOverride {
+                passes: Some(passes),
+                offset: Some(offset),
+            };
+            let options = BlurOptions::resolve(&config, BlurOverride::default(), runtime);
+            (options.passes, options.offset)
+        };
+
+        assert_eq!(resolve(0, -1.), (1, 0.));
+        assert_eq!(resolve(255, 1000.), (31, 100.));
+        assert_eq!(resolve(2, f64::NAN), (2, 0.));
+    }
+
+    #[test]
+    fn resolve_per_output() {
+        let config = niri_config::Config::parse_mem(
+            r#"
+            blur {
+                passes 2
+                noise 0.05
+            }
+
+            output "DP-1" {
+                blur {
+                    passes 4
+                    offset 5
+                }
+            }
+
+            output "eDP-1" {
+                blur {
+                    passes 1
+                }
+            }
+            "#,
+        )
+        .unwrap();
+
+        let resolve = |connector: &str| {
+            let name = niri_config::OutputName {
+                connector: connector.to_owned(),
+                make: None,
+                model: None,
+                serial: None,
+            };
+            let blur_config = config.blur_for_output(&name);
+            let options = BlurOptions::resolve(
+                &blur_config,
+                BlurOverride::default(),
+                BlurOverride::default(),
+            );
+            (options.passes, options.offset, blur_config.noise)
+        };
+
+        assert_eq!(resolve("DP-1"), (4, 5., 0.05));
+        assert_eq!(resolve("eDP-1"), (1, 3., 0.05));
+        // Outputs without a config use the global blur config.
+        assert_eq!(resolve("HDMI-A-1"), (2, 3., 0.05));
+    }
+
+    #[test]
+    fn incomplete_framebuffer_is_an_error() {
+        assert!(check_framebuffer_status(ffi::FRAMEBUFFER_COMPLETE).is_ok());
+
+        let err = check_framebuffer_status(ffi::FRAMEBUFFER_INCOMPLETE_ATTACHMENT).unwrap_err();
+        assert_eq!(err.to_string(), "framebuffer is incomplete: status 0x8cd6");
+    }
+
+    #[test]
+    fn effective_radius_grows_with_passes_and_offset() {
+        let size = Size::new(1920, 1080);
+        let radius = |passes, offset| {
+            let options = BlurOptions {
+                passes,
+                offset,
+                ..Default::default()
+            };
+            options.effective_radius_px(size)
+        };
+
+        for offset in [0., 1., 3., 10.] {
+            for passes in 1..8 {
+                assert!(radius(passes, offset) < radius(passes + 1, offset));
+            }
+        }
+
+        for passes in 1..8 {
+            for offset in [0., 0.5, 1., 3., 10.] {
+                assert!(radius(passes, offset) < radius(passes, offset + 0.5));
+            }
+        }
+
+        // The default settings correspond to a moderately large blur.
+        assert_eq!(radius(3, 3.).round(), 19.);
+    }
+
+    /// Samples a single-channel texture with bilinear filtering and clamp-to-edge wrapping.
+    fn sample_bilinear(tex: &[f32], w: usize, h: usize, u: f32, v: f32) -> f32 {
+        let x = u * w as f32 - 0.5;
+        let y = v * h as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let texel = |x: f32, y: f32| {
+            let x = (x as isize).clamp(0, w as isize - 1) as usize;
+            let y = (y as isize).clamp(0, h as isize - 1) as usize;
+            tex[y * w + x]
+        };
+
+        let top = texel(x0, y0) * (1. - fx) + texel(x0 + 1., y0) * fx;
+        let bottom = texel(x0, y0 + 1.) * (1. - fx) + texel(x0 + 1., y0 + 1.) * fx;
+        top * (1. - fy) + bottom * fy
+    }
+
+    /// CPU version of a down pass with blur_down.frag, returns the next level and its size.
+    fn down_pass(src: &[f32], w: usize, h: usize, offset: f32) -> (Vec<f32>, usize, usize) {
+        let size = Size::new(w as i32, h as i32);
+        let small = pyramid_sizes(size, 1).nth(1).unwrap();
+        let uv_scale = level_uv_scale(size, small);
+        let (dw, dh) = (small.w as usize, small.h as usize);
+        let o = (0.5 / dw as f32 * offset, 0.5 / dh as f32 * offset);
+
+        let mut dst = Vec::with_capacity(dw * dh);
+        for j in 0..dh {
+            for i in 0..dw {
+                let u = (i as f32 + 0.5) / dw as f32 * uv_scale.0;
+                let v = (j as f32 + 0.5) / dh as f32 * uv_scale.1;
+                let sample = |du: f32, dv: f32| sample_bilinear(src, w, h, u + du, v + dv);
+
+                let sum = sample(0., 0.) * 4.
+                    + sample(-o.0, -o.1)
+                    + sample(o.0, -o.1)
+                    + sample(-o.0, o.1)
+                    + sample(o.0, o.1);
+                dst.push(sum / 8.);
+            }
+        }
+        (dst, dw, dh)
+    }
+
+    /// CPU version of an up pass with blur_up.frag into a `dw`×`dh` level.
+    fn up_pass(src: &[f32], w: usize, h: usize, dw: usize, dh: usize, offset: f32) -> Vec<f32> {
+        let uv_scale = level_uv_scale(
+            Size::new(dw as i32, dh as i32),
+            Size::new(w as i32, h as i32),
+        );
+        let o = (0.5 / w as f32 * offset, 0.5 / h as f32 * offset);
+
+        let mut dst = Vec::with_capacity(dw * dh);
+        for j in 0..dh {
+            for i in 0..dw {
+                let u = (i as f32 + 0.5) / dw as f32 / uv_scale.0;
+                let v = (j as f32 + 0.5) / dh as f32 / uv_scale.1;
+                let sample = |du: f32, dv: f32| sample_bilinear(src, w, h, u + du, v + dv);
+
+                let edges = sample(-o.0 * 2., 0.)
+                    + sample(o.0 * 2., 0.)
+                    + sample(0., -o.1 * 2.)
+                    + sample(0., o.1 * 2.);
+                let corners =
+                    sample(-o.0, o.1) + sample(o.0, o.1) + sample(-o.0, -o.1) + sample(o.0, -o.1);
+                dst.push((edges + corners * 2.) / 12.);
+            }
+        }
+        dst
+    }
+
+    /// Returns the center of mass of a single-channel texture in pixels.
+    fn centroid(tex: &[f32], w: usize) -> (f32, f32) {
+        let mut sum = 0.;
+        let mut x = 0.;
+        let mut y = 0.;
+        for (i, value) in tex.iter().enumerate() {
+            sum += value;
+            x += value * ((i % w) as f32 + 0.5);
+            y += value * ((i / w) as f32 + 0.5);
+        }
+        (x / sum, y / sum)
+    }
+
+    #[test]
+    fn odd_sized_blur_stays_aligned() {
+        // A small square off the center of an odd-sized source.
+        let (w, h) = (101, 101);
+        let mut source = vec![0.; w * h];
+        for y in 54..59 {
+            for x in 44..49 {
+                source[y * w + x] = 1.;
+            }
+        }
+
+        let mut levels = vec![(source.clone(), w, h)];
+        for _ in 0..3 {
+            let (src, w, h) = levels.last().unwrap();
+            let next = down_pass(src, *w, *h, 1.);
+            levels.push(next);
+        }
+        let (mut blurred, mut bw, mut bh) = levels.pop().unwrap();
+        while let Some((_, w, h)) = levels.pop() {
+            blurred = up_pass(&blurred, bw, bh, w, h, 1.);
+            (bw, bh) = (w, h);
+        }
+
+        // Stretching the odd levels would shift the result by almost half a pixel here.
+        let (sx, sy) = centroid(&source, w);
+        let (bx, by) = centroid(&blurred, w);
+        assert!(
+            (bx - sx).abs() < 0.05 && (by - sy).abs() < 0.05,
+            "blurred centroid ({bx}, {by}) drifted from ({sx}, {sy})"
+        );
+    }
+
+    #[test]
+    fn direct_blur_skips_output_texture() {
+        // Up passes write levels from the second smallest to the output texture at level 0.
+        let written = |passes, to_output| {
+            let dst_levels = (0..passes).rev();
+            dst_levels
+                .take(up_pass_count(passes, to_output))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(written(3, true), [2, 1, 0]);
+        // Drawing the last pass straight into the frame never touches the output texture.
+        assert_eq!(written(3, false), [2, 1]);
+        assert!(written(1, false).is_empty());
+    }
+
+    #[test]
+    fn egl_direct_blur_draws_last_pass_into_frame() {
+        const SIZE: i32 = 32;
+        let mut renderer = test_renderer();
+        let size = Size::<i32, Physical>::new(SIZE, SIZE);
+        let rect = Rectangle::from_size(size);
+
+        let fill = |renderer: &mut GlesRenderer, texture: &GlesTexture, color: Color32F| {
+            let mut texture = texture.clone();
+            let mut target = renderer.bind(&mut texture).unwrap();
+            let mut frame = renderer
+                .render(&mut target, size, Transform::Normal)
+                .unwrap();
+            frame.clear(color, &[rect]).unwrap();
+            let _sync = frame.finish().unwrap();
+        };
+        let read = |renderer: &mut GlesRenderer, texture: &GlesTexture| {
+            let mut texture = texture.clone();
+            let target = renderer.bind(&mut texture).unwrap();
+            let mapping = copy_framebuffer(renderer, &target, Fourcc::Abgr8888).unwrap();
+            renderer.map_texture(&mapping).unwrap().to_vec()
+        };
+
+        // Opaque vertical stripes, 4 px wide.
+        let pixels: Vec<u8> = (0..SIZE * SIZE)
+            .flat_map(|i| {
+                if i % SIZE / 4 % 2 == 0 {
+                    [255; 4]
+                } else {
+                    [0, 0, 0, 255]
+                }
+            })
+            .collect();
+        let source = renderer
+            .import_memory(&pixels, Fourcc::Abgr8888, Size::new(SIZE, SIZE), false)
+            .unwrap();
+
+        let options = BlurOptions {
+            passes: 2,
+            offset: 1.,
+            ..Default::default()
+        };
+        let mut blur = Blur::new(&mut renderer).unwrap();
+        blur.prepare_textures(
+            |fourcc, size| renderer.create_buffer(fourcc, size),
+            &source,
+            options,
+        )
+        .unwrap();
+        assert_eq!(blur.levels().len(), 3);
+
+        // The output texture isn't written to.
+        let sentinel = Color32F::new(1., 0., 0., 1.);
+        fill(&mut renderer, &blur.levels()[0], sentinel);
+        blur.render_without_last_pass(&mut renderer, &source, options)
+            .unwrap();
+        let output = read(&mut renderer, &blur.levels()[0]);
+        assert!(output.chunks_exact(4).all(|px| px == [255, 0, 0, 255]));
+
+        // Instead, the last pass is drawn into the frame.
+        let mut texture = renderer
+            .create_buffer(
+                Fourcc::Abgr8888,
+                size.to_logical(1).to_buffer(1, Transform::Normal),
+            )
+            .unwrap();
+        fill(&mut renderer, &texture, Color32F::TRANSPARENT);
+        {
+            let mut target = renderer.bind(&mut texture).unwrap();
+            let mut frame = renderer
+                .render(&mut target, size, Transform::Normal)
+                .unwrap();
+            blur.draw_last_pass(&mut frame, options, rect, &[rect])
+                .unwrap();
+            let _sync = frame.finish().unwrap();
+        }
+        let direct = read(&mut renderer, &texture);
+
+        // It matches the regular blur into the output texture.
+        let blurred = blur.render(&mut renderer, &source, options).unwrap();
+        let regular = read(&mut renderer, &blurred);
+        assert!(regular.chunks_exact(4).any(|px| px[0] != 0 && px[0] != 255));
+        for (a, b) in zip(&direct, &regular) {
+            assert!(a.abs_diff(*b) <= 1, "{direct:?} != {regular:?}");
+        }
+    }
+
+    #[test]
+    fn egl_failed_blur_keeps_last_output() {
+        let mut renderer = test_renderer();
+        let source = |renderer: &mut GlesRenderer, size: i32| {
+            let pixels = vec![255; (size * size * 4) as usize];
+            renderer
+                .import_memory(&pixels, Fourcc::Abgr8888, Size::new(size, size), false)
+                .unwrap()
+        };
+        let large = source(&mut renderer, 64);
+        let small = source(&mut renderer, 32);
+
+        let options = BlurOptions {
+            passes: 2,
+            offset: 1.,
+            ..Default::default()
+        };
+        let mut blur = Blur::new(&mut renderer).unwrap();
+        let prepare = |blur: &mut Blur, renderer: &mut GlesRenderer, source: &GlesTexture| {
+            blur.prepare_textures(
+                |fourcc, size| renderer.create_buffer(fourcc, size),
+                source,
+                options,
+            )
+            .unwrap();
+        };
+        prepare(&mut blur, &mut renderer, &large);
+        assert!(blur.last_output().is_none());
+
+        let blurred = blur.render(&mut renderer, &large, options).unwrap();
+        let tex_id = blurred.tex_id();
+        drop(blurred);
+        assert_eq!(blur.last_output().map(|t| t.tex_id()), Some(tex_id));
+
+        // A render that fails before drawing anything leaves the previous blur in place.
+        assert!(blur.render(&mut renderer, &small, options).is_err());
+        assert_eq!(blur.last_output().map(|t| t.tex_id()), Some(tex_id));
+
+        // Blurring without the output texture leaves it behind the latest blur.
+        blur.render_without_last_pass(&mut renderer, &large, options)
+            .unwrap();
+        assert!(blur.last_output().is_none());
+
+        // New textures have no blur in them.
+        blur.render(&mut renderer, &large, options).unwrap();
+        prepare(&mut blur, &mut renderer, &small);
+        assert!(blur.last_output().is_none());
+    }
+
+    #[test]
+    fn zero_offset_is_passthrough() {
+        let (w, h) = (8, 8);
+        let checkerboard: Vec<f32> = (0..w * h).map(|i| ((i % w + i / w) % 2) as f32).collect();
+
+        // With a zero offset, every sample lands between four texels of the previous level, so
+        // the down pass is a plain 2×2 box filter. This wipes out the checkerboard completely,
+        // rather than leaving the source as is.
+        let (down, ..) = down_pass(&checkerboard, w, h, 0.);
+        assert!(down.iter().all(|x| (x - 0.5).abs() < 1e-6));
+
+        // So a zero offset skips the blur instead.
+        let options = BlurOptions {
+            passes: 3,
+            offset: 0.,
+            ..Default::default()
+        };
+        assert!(options.is_passthrough());
+
+        let options = BlurOptions {
+            offset: 0.5,
+            ..options
+        };
+        assert!(!options.is_passthrough());
+    }
+
+    #[test]
+    fn logical_offset_scales_with_output() {
+        let config = niri_config::Blur {
+            offset_unit: BlurOffsetUnit::Logical,
+            ..Default::default()
+        };
+        let options =
+            BlurOptions::resolve(&config, BlurOverride::default(), BlurOverride::default());
+
+        let scaled = options.scaled(2.);
+        assert_eq!(scaled.offset, config.offset * 2.);
+        assert_eq!(scaled.offset_unit, BlurOffsetUnit::Physical);
+        // Scaling again doesn't double up.
+        assert_eq!(scaled.scaled(2.), scaled);
+
+        // The scale-2 texture is twice as large, so the blur spreads over the same logical area.
+        let radius_1 = options
+            .scaled(1.)
+            .effective_radius_px(Size::new(1000, 1000));
+        let radius_2 = scaled.effective_radius_px(Size::new(2000, 2000));
+        assert!((radius_2 / radius_1 - 2.).abs() < 0.1);
+
+        // Physical offsets are used as is.
+        let options = BlurOptions::resolve(
+            &niri_config::Blur::default(),
+            BlurOverride::default(),
+            BlurOverride::default(),
+        );
+        assert_eq!(options.scaled(2.), options);
+    }
+
+    #[test]
+    fn resolve_bilateral_from_config() {
+        let resolve = |config: &str| {
+            let config = niri_config::Config::parse_mem(config).unwrap();
+            BlurOptions::resolve(
+                &config.blur,
+                BlurOverride::default(),
+                BlurOverride::default(),
+            )
+            .algorithm
+        };
+
+        // A zero range_sigma keeps the plain kawase weights in the shaders.
+        let kawase = resolve("");
+        assert_eq!(kawase, BlurAlgorithm::Kawase);
+        assert_eq!(kawase.range_sigma(), 0.);
+
+        let bilateral = resolve(
+            r#"
+            blur {
+                algorithm "bilateral"
+            }
+            "#,
+        );
+        assert_eq!(bilateral, BlurAlgorithm::Bilateral { range_sigma: 0.2 });
+        assert_eq!(bilateral.range_sigma(), 0.2);
+
+        let bilateral = resolve(
+            r#"
+            blur {
+                algorithm "bilateral"
+                range-sigma 0.05
+            }
+            "#,
+        );
+        assert_eq!(bilateral, BlurAlgorithm::Bilateral { range_sigma: 0.05 });
+        assert_eq!(bilateral.range_sigma(), 0.05);
+
+        // Invalid values from elsewhere are sanitized for the shader.
+        let bilateral = |range_sigma| BlurAlgorithm::Bilateral { range_sigma }.range_sigma();
+        assert_eq!(bilateral(-1.), 0.);
+        assert_eq!(bilateral(f64::NAN), 0.);
+        assert_eq!(bilateral(f64::INFINITY), 10.);
+    }
+
+    #[test]
+    fn velocity_stretches_blur_along_motion() {
+        // At rest and at low speeds, the blur is isotropic.
+        assert_eq!(BlurDirection::from_velocity(Point::new(0., 0.)), None);
+        assert_eq!(BlurDirection::from_velocity(Point::new(100., 0.)), None);
+        assert_eq!(BlurDirection::matrix(None), [1., 0., 0., 1.]);
+
+        // Horizontal motion stretches only the horizontal offsets.
+        let direction = BlurDirection::from_velocity(Point::new(-2000., 0.)).unwrap();
+        assert_eq!(direction.dir, (-1., 0.));
+        assert!(1. < direction.stretch && direction.stretch < MOTION_BLUR_MAX_STRETCH);
+        let [xx, xy, yx, yy] = BlurDirection::matrix(Some(direction));
+        assert_eq!(xx, direction.stretch as f32);
+        assert_eq!((xy, yx, yy), (0., 0., 1.));
+
+        // Faster motion stretches more, up to the limit.
+        let fast = BlurDirection::from_velocity(Point::new(0., 1e6)).unwrap();
+        assert_eq!(fast.dir, (0., 1.));
+        assert_eq!(fast.stretch, MOTION_BLUR_MAX_STRETCH);
+
+        // Diagonal motion shears the offsets, and a rotated texture swaps the axes.
+        let diagonal = BlurDirection::from_velocity(Point::new(3000., 3000.)).unwrap();
+        assert!(BlurDirection::matrix(Some(diagonal))[1] > 0.);
+        let rotated = fast.transformed(Transform::_90);
+        assert!(rotated.dir.0.abs() > 0.99 && rotated.dir.1.abs() < 1e-9);
+    }
+
+    #[test]
+    fn axis_scale_scales_offsets_per_axis() {
+        let options = BlurOptions {
+            axis_scale: Some((2., 0.5)),
+            ..Default::default()
+        };
+        assert_eq!(options.direction_matrix(), [2., 0., 0., 0.5]);
+
+        // On top of the motion stretch.
+        let options = BlurOptions {
+            direction: BlurDirection::from_velocity(Point::new(1e6, 0.)),
+            ..options
+        };
+        let stretch = MOTION_BLUR_MAX_STRETCH as f32;
+        assert_eq!(options.direction_matrix(), [2. * stretch, 0., 0., 0.5]);
+    }
+
+    #[test]
+    fn overview_context_uses_its_own_config() {
+        let config = niri_config::Blur::default();
+        let mut overview = niri_config::Overview::default();
+
+        // Without an overview blur, both contexts match.
+        let window = BlurContext::Window.resolve_config(config, &overview);
+        assert_eq!(
+            BlurContext::Overview.resolve_config(config, &overview),
+            window
+        );
+
+        overview.blur = Some(niri_config::BlurPart {
+            passes: Some(5),
+            noise: Some(niri_config::FloatOrInt(0.)),
+            ..Default::default()
+        });
+        let window = BlurContext::Window.resolve_config(config, &overview);
+        let in_overview = BlurContext::Overview.resolve_config(config, &overview);
+        assert_eq!(window, config);
+        assert_eq!(in_overview.noise, 0.);
+        // Options not set for the overview are kept.
+        assert_eq!(in_overview.offset, config.offset);
+
+        let resolve = |config| {
+            BlurOptions::resolve(&config, BlurOverride::default(), BlurOverride::default())
+        };
+        assert_ne!(resolve(window), resolve(in_overview));
+        assert_eq!(resolve(in_overview).passes, 5);
+    }
+
+    #[test]
+    fn offset_growth_sets_offset_per_pass() {
+        let config = niri_config::Blur {
+            passes: 3,
+            offset: 2.,
+            offset_growth: 1.5,
+            ..Default::default()
+        };
+        let options =
+            BlurOptions::resolve(&config, BlurOverride::default(), BlurOverride::default());
+        let passes = usize::from(options.passes);
+
+        // Down pass i writes level i + 1.
+        let down: Vec<_> = (0..passes).map(|i| options.level_offset(i + 1)).collect();
+        assert_eq!(down, [2., 3., 4.5]);
+
+        // Up passes go from the smallest level back up, mirroring the down passes.
+        let up: Vec<_> = (0..passes)
+            .map(|pass| options.level_offset(up_pass_level(pass, passes)))
+            .collect();
+        assert_eq!(up, [4.5, 3., 2.]);
+
+        // Spreading out the later passes widens the blur.
+        let constant = BlurOptions {
+            offset_growth: None,
+            ..options
+        };
+        assert!(
+            constant.effective_radius_px(Size::new(1920, 1080))
+                < options.effective_radius_px(Size::new(1920, 1080))
+        );
+
+        // The default growth keeps the offset constant.
+        let config = niri_config::Blur::default();
+        let options =
+            BlurOptions::resolve(&config, BlurOverride::default(), BlurOverride::default());
+        assert_eq!(options.offset_growth, None);
+        assert_eq!(options.level_offset(3), config.offset);
+    }
+
+    #[test]
+    fn extreme_offset_is_capped_to_max_radius() {
+        let size = Size::new(1000, 1000);
+        let options = BlurOptions {
+            passes: 3,
+            offset: 100.,
+            ..Default::default()
+        };
+        assert_eq!(options.capped(size), options);
+
+        // At most 2% of 1000 px.
+        let options = BlurOptions {
+            max_relative_radius: Some(0.02),
+            ..options
+        };
+        let capped = options.capped(size);
+        assert!(capped.offset < options.offset);
+        let radius = capped.effective_radius_px(size);
+        assert!(19.9 < radius && radius <= 20., "{radius}");
+
+        // Smaller sources get capped further.
+        let small = options.capped(Size::new(1000, 500));
+        assert!(small.offset < capped.offset);
+
+        // Even a zero offset blurs a bit with three passes, so it's capped to a passthrough.
+        let options = BlurOptions {
+            max_relative_radius: Some(0.001),
+            ..options
+        };
+        assert_eq!(options.capped(size).offset, 0.);
+        assert!(!options.is_passthrough());
+        assert!(options.capped(size).is_passthrough());
+    }
+
+    #[test]
+    fn texture_budget_reduces_passes() {
+        let size = Size::new(100, 100);
+        assert_eq!(pyramid_bytes(size, 1), (100 * 100 + 50 * 50) * 4);
+
+        assert_eq!(fit_passes(size, 3, None), Some(3));
+
+        let full = pyramid_bytes(size, 3);
+        assert_eq!(fit_passes(size, 3, Some(full)), Some(3));
+        assert_eq!(fit_passes(size, 3, Some(full - 1)), Some(2));
+
+        let single = pyramid_bytes(size, 1);
+        assert_eq!(fit_passes(size, 3, Some(single)), Some(1));
+        assert_eq!(fit_passes(size, 3, Some(single - 1)), None);
+    }
+
+    #[test]
+    fn texture_budget_is_shared_between_reservations() {
+        let size = Size::new(100, 100);
+        let limit = Some(pyramid_bytes(size, 3) + pyramid_bytes(size, 1));
+
+        let mut other = TextureReservation::default();
+        other.set(pyramid_bytes(size, 3));
+
+        // Another effect uses most of the budget, so a new one only gets a single pass.
+        let ours = TextureReservation::default();
+        assert_eq!(fit_passes(size, 3, ours.available(limit)), Some(1));
+
+        // Once the other effect goes away, the budget is released.
+        drop(other);
+        assert_eq!(fit_passes(size, 3, ours.available(limit)), Some(3));
+    }
+
+    #[test]
+    fn adaptive_passes_ramp_down_and_up_with_hysteresis() {
+        let budget = Duration::from_millis(16);
+        let slow = Duration::from_millis(20);
+        let fast = Duration::from_millis(8);
+        let borderline = Duration::from_millis(14);
+
+        let mut adaptive = AdaptiveBlurPasses::default();
+        let mut trace = Vec::new();
+        let mut run = |adaptive: &mut AdaptiveBlurPasses, frame_time, count| {
+            for _ in 0..count {
+                adaptive.record(frame_time, budget, 4);
+                trace.push(adaptive.passes(4));
+            }
+        };
+
+        // A single slow frame doesn't change anything.
+        run(&mut adaptive, slow, 2);
+        run(&mut adaptive, fast, 1);
+        assert_eq!(adaptive.passes(4), 4);
+
+        // Sustained slow frames drop one pass at a time, down to a minimum of 1.
+        run(&mut adaptive, slow, 3);
+        assert_eq!(adaptive.passes(4), 3);
+        run(&mut adaptive, slow, 3);
+        assert_eq!(adaptive.passes(4), 2);
+        run(&mut adaptive, slow, 30);
+        assert_eq!(adaptive.passes(4), 1);
+
+        // Borderline frames neither drop nor restore.
+        run(&mut adaptive, borderline, 200);
+        assert_eq!(adaptive.passes(4), 1);
+
+        // Restoring requires a long run of fast frames, and a slow frame resets the run.
+        run(&mut adaptive, fast, 59);
+        run(&mut adaptive, slow, 1);
+        run(&mut adaptive, fast, 59);
+        assert_eq!(adaptive.passes(4), 1);
+        run(&mut adaptive, fast, 1);
+        assert_eq!(adaptive.passes(4), 2);
+
+        run(&mut adaptive, fast, 1000);
+        assert_eq!(adaptive.passes(4), 4);
+
+        // The pass count never changes on two consecutive frames.
+        for w in trace.windows(3) {
+            assert!(w[0] == w[1] || w[1] == w[2]);
+        }
     }
 }
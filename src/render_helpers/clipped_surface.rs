@@ -124,7 +124,8 @@ impl<R: NiriRenderer> ClippedSurfaceRenderElement<R> {
         }
     }
 
-    fn rounded_corners(
+    /// Returns the square areas of `geo` that the corner rounding can make transparent.
+    pub fn rounded_corners(
         geo: Rectangle<f64, Logical>,
         corner_radius: CornerRadius,
     ) -> [Rectangle<f64, Logical>; 4] {
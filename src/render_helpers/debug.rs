@@ -1,8 +1,10 @@
+use std::cell::RefCell;
+
 use smithay::backend::renderer::damage::OutputDamageTracker;
 use smithay::backend::renderer::element::{Element, Id, Kind};
 use smithay::backend::renderer::utils::CommitCounter;
 use smithay::backend::renderer::Color32F;
-use smithay::utils::Scale;
+use smithay::utils::{Buffer, Logical, Rectangle, Scale, Size};
 
 use super::renderer::NiriRenderer;
 use super::solid_color::SolidColorRenderElement;
@@ -75,3 +77,99 @@ pub fn draw_damage<R: NiriRenderer>(
         elements.insert(0, OutputRenderElements::SolidColor(color));
     }
 }
+
+type BlurHookFn = Box<dyn FnMut(&BlurEvent)>;
+
+thread_local! {
+    static EFFECT_COLLECTOR: RefCell<Option<Vec<EffectDescriptor>>> = const { RefCell::new(None) };
+    static BLUR_HOOK: RefCell<Option<BlurHookFn>> = const { RefCell::new(None) };
+}
+
+/// Kind of a rendered background effect element.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EffectKind {
+    XrayBackground,
+    XrayBackdrop,
+    Framebuffer,
+}
+
+/// Lightweight description of a rendered background effect element.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EffectDescriptor {
+    pub kind: EffectKind,
+    pub geometry: Rectangle<f64, Logical>,
+    pub blur: bool,
+    /// Number of rects in the effect subregion, if any.
+    pub subregion_rects: Option<usize>,
+}
+
+/// Collects descriptors of background effect elements rendered on this thread.
+///
+/// Recording is a no-op unless a collector is installed. The collector is uninstalled on drop.
+#[derive(Debug)]
+pub struct EffectCollector(());
+
+impl EffectCollector {
+    pub fn install() -> Self {
+        EFFECT_COLLECTOR.with_borrow_mut(|collector| *collector = Some(Vec::new()));
+        Self(())
+    }
+
+    pub fn take(self) -> Vec<EffectDescriptor> {
+        EFFECT_COLLECTOR
+            .with_borrow_mut(|collector| collector.take())
+            .unwrap_or_default()
+    }
+}
+
+impl Drop for EffectCollector {
+    fn drop(&mut self) {
+        EFFECT_COLLECTOR.with_borrow_mut(|collector| *collector = None);
+    }
+}
+
+/// Records an effect descriptor if a collector is installed.
+pub fn record_effect(descriptor: impl FnOnce() -> EffectDescriptor) {
+    EFFECT_COLLECTOR.with_borrow_mut(|collector| {
+        if let Some(collector) = collector {
+            collector.push(descriptor());
+        }
+    });
+}
+
+/// Blur work that was actually done rather than reused from a cache.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BlurEvent {
+    /// A framebuffer effect element captured the framebuffer anew.
+    Capture { id: Id, size: Size<i32, Buffer> },
+    /// Blur passes were rendered for a source of this size.
+    Blur { size: Size<i32, Buffer> },
+}
+
+/// Hook called for blur work done on this thread, for profiling and tests.
+///
+/// Reporting is a no-op unless a hook is installed. The hook is uninstalled on drop.
+#[derive(Debug)]
+pub struct BlurHook(());
+
+impl BlurHook {
+    pub fn install(hook: impl FnMut(&BlurEvent) + 'static) -> Self {
+        BLUR_HOOK.with_borrow_mut(|current| *current = Some(Box::new(hook)));
+        Self(())
+    }
+}
+
+impl Drop for BlurHook {
+    fn drop(&mut self) {
+        BLUR_HOOK.with_borrow_mut(|current| *current = None);
+    }
+}
+
+/// Reports blur work to the hook if one is installed.
+pub fn record_blur(event: impl FnOnce() -> BlurEvent) {
+    BLUR_HOOK.with_borrow_mut(|hook| {
+        if let Some(hook) = hook {
+            hook(&event());
+        }
+    });
+}
@@ -7,12 +7,16 @@ use smithay::backend::renderer::element::{Id, RenderElementStates};
 use smithay::backend::renderer::gles::{GlesFrame, GlesRenderer, GlesTexture};
 use smithay::backend::renderer::utils::CommitCounter;
 use smithay::backend::renderer::{
-    Bind as _, Color32F, ContextId, FrameContext as _, Offscreen as _, Renderer as _, Texture,
+    Bind as _, Color32F, ContextId, Frame as _, FrameContext as _, Offscreen as _, Renderer as _,
+    Texture,
 };
-use smithay::utils::{Buffer, Logical, Physical, Scale, Size, Transform};
+use smithay::utils::{Buffer, Logical, Physical, Rectangle, Scale, Size, Transform};
 
 use crate::niri::OutputRenderElements;
 use crate::render_helpers::blur::{Blur, BlurOptions};
+use crate::render_helpers::texture_budget::{
+    gles_texture_bytes, texture_bytes, TextureReservation,
+};
 
 #[derive(Debug)]
 pub struct EffectBuffer {
@@ -23,6 +27,8 @@ pub struct EffectBuffer {
     size: Size<i32, Buffer>,
     /// Scale of the effect buffer.
     scale: Scale<f64>,
+    /// Transform of the buffer contents, like a buffer transform of a surface.
+    transform: Transform,
     /// Options for blurring.
     blur_options: BlurOptions,
 
@@ -35,6 +41,12 @@ pub struct EffectBuffer {
 
     /// Commit counter that takes into account both original and blurred texture changes.
     commit_counter: CommitCounter,
+    /// Commit at which rendering the blur last failed.
+    ///
+    /// All xray elements sampling this buffer share one blurred texture per commit. When blurring
+    /// fails, [`EffectBuffer::render()`] returns an error right away for the rest of the elements
+    /// and frames, instead of retrying, until the contents or the blur options change.
+    blur_failed_at: Option<CommitCounter>,
 }
 
 #[derive(Debug)]
@@ -52,18 +64,24 @@ enum Elements {
 struct Offscreen {
     /// The texture with the offscreen contents.
     texture: GlesTexture,
+    /// Budget reservation for `texture`.
+    reservation: TextureReservation,
     /// Id of the renderer context that the texture comes from.
     renderer_context_id: ContextId<GlesTexture>,
     /// Scale of the texture.
     scale: Scale<f64>,
+    /// Transform that the damage tracker renders with.
+    transform: Transform,
     /// Damage tracker for drawing to the texture.
     damage: OutputDamageTracker,
     /// Render element states from the last render into the offscreen.
     states: RenderElementStates,
-    /// Rendered blurred version of the texture.
+    /// Rendered blurred version of the texture, with the commit that it was rendered at.
     ///
-    /// When texture needs to be reblurred, this field must be reset to `None`.
-    blurred: Option<GlesTexture>,
+    /// It's only valid for that commit, so an unchanged backdrop, like a static wallpaper, is
+    /// blurred once and then reused across frames. When texture needs to be reblurred, this field
+    /// should also be reset to `None` to release the old texture early.
+    blurred: Option<(CommitCounter, GlesTexture)>,
 }
 
 impl Default for Elements {
@@ -78,11 +96,13 @@ impl EffectBuffer {
             id: Id::new(),
             size: Size::default(),
             scale: Scale::from(1.),
+            transform: Transform::Normal,
             blur_options: BlurOptions::default(),
             elements: Elements::default(),
             offscreen: None,
             blur: None,
             commit_counter: CommitCounter::default(),
+            blur_failed_at: None,
         }
     }
 
@@ -95,20 +115,50 @@ impl EffectBuffer {
     }
 
     pub fn logical_size(&self) -> Size<f64, Logical> {
-        self.size.to_f64().to_logical(self.scale, Transform::Normal)
+        self.size.to_f64().to_logical(self.scale, self.transform)
     }
 
     pub fn scale(&self) -> Scale<f64> {
         self.scale
     }
 
+    pub fn transform(&self) -> Transform {
+        self.transform
+    }
+
+    /// Converts a rectangle in logical buffer coordinates to texture coordinates.
+    pub fn logical_to_buffer(&self, rect: Rectangle<f64, Logical>) -> Rectangle<f64, Buffer> {
+        rect.to_buffer(self.scale, self.transform, &self.logical_size())
+    }
+
     pub fn render_element_states(&self) -> Option<&RenderElementStates> {
         self.offscreen.as_ref().map(|o| &o.states)
     }
 
-    pub fn update_size(&mut self, size: Size<i32, Physical>, scale: Scale<f64>) {
-        self.size = size.to_logical(1).to_buffer(1, Transform::Normal);
+    /// Returns the size in bytes of the current offscreen and blur textures.
+    pub fn texture_memory_bytes(&self) -> u64 {
+        // The blurred texture is owned by the blur, or is the offscreen texture itself.
+        let offscreen = self
+            .offscreen
+            .as_ref()
+            .map_or(0, |offscreen| gles_texture_bytes(&offscreen.texture));
+        let blur = self.blur.as_ref().map_or(0, Blur::texture_memory_bytes);
+        offscreen + blur
+    }
+
+    /// Updates the size and the transform of the buffer contents.
+    ///
+    /// `size` is in the upright orientation. With a rotating `transform`, the texture is allocated
+    /// with the sides swapped.
+    pub fn update_size(
+        &mut self,
+        size: Size<i32, Physical>,
+        scale: Scale<f64>,
+        transform: Transform,
+    ) {
+        self.size = size.to_logical(1).to_buffer(1, transform);
         self.scale = scale;
+        self.transform = transform;
     }
 
     pub fn update_blur_options(&mut self, options: BlurOptions) {
@@ -117,6 +167,7 @@ impl EffectBuffer {
         }
 
         self.blur_options = options;
+        self.blur_failed_at = None;
 
         if let Some(offscreen) = &mut self.offscreen {
             if offscreen.blurred.is_some() {
@@ -145,7 +196,7 @@ impl EffectBuffer {
             return false;
         };
 
-        if blur {
+        if blur && !self.is_blur_passthrough() {
             if let Err(err) = self.prepare_blur(renderer) {
                 warn!("error preparing blur: {err:?}");
                 return false;
@@ -201,26 +252,31 @@ impl EffectBuffer {
                 .context("error creating texture")?;
 
             let buffer_size = self.size.to_logical(1, Transform::Normal).to_physical(1);
-            let damage = OutputDamageTracker::new(buffer_size, self.scale, Transform::Normal);
+            let damage = OutputDamageTracker::new(buffer_size, self.scale, self.transform);
 
-            self.offscreen.insert(Offscreen {
+            let offscreen = self.offscreen.insert(Offscreen {
                 texture,
+                reservation: TextureReservation::default(),
                 renderer_context_id: renderer.context_id(),
                 scale: self.scale,
+                transform: self.transform,
                 damage,
                 states: RenderElementStates::default(),
                 blurred: None,
-            })
+            });
+            offscreen.reservation.set(texture_bytes(self.size));
+            offscreen
         };
 
-        // Recreate the damage tracker if the scale changes. We already recreate it for buffer size
-        // changes, and transform is always Normal.
-        if offscreen.scale != self.scale {
+        // Recreate the damage tracker if the scale or the transform changes. We already recreate it
+        // for buffer size changes.
+        if offscreen.scale != self.scale || offscreen.transform != self.transform {
             offscreen.scale = self.scale;
+            offscreen.transform = self.transform;
 
-            trace!("recreating damage tracker due to scale change");
+            trace!("recreating damage tracker due to scale or transform change");
             let buffer_size = self.size.to_logical(1, Transform::Normal).to_physical(1);
-            offscreen.damage = OutputDamageTracker::new(buffer_size, self.scale, Transform::Normal);
+            offscreen.damage = OutputDamageTracker::new(buffer_size, self.scale, self.transform);
 
             self.commit_counter.increment();
             offscreen.blurred = None;
@@ -264,7 +320,11 @@ impl EffectBuffer {
 
     fn prepare_blur(&mut self, renderer: &mut GlesRenderer) -> anyhow::Result<()> {
         let offscreen = self.offscreen.as_mut().context("missing offscreen")?;
-        if offscreen.blurred.is_some() {
+        if offscreen
+            .blurred
+            .as_ref()
+            .is_some_and(|(commit, _)| *commit == self.commit_counter)
+        {
             // Already rendered.
             return Ok(());
         }
@@ -301,25 +361,317 @@ impl EffectBuffer {
         Ok(())
     }
 
+    /// Whether the blur options leave the contents as is.
+    fn is_blur_passthrough(&self) -> bool {
+        // Capping to the maximum radius can lower the offset all the way down to a passthrough.
+        self.blur_options.capped(self.size).is_passthrough()
+    }
+
+    /// Returns the blur pyramid textures, large to small, or an empty slice without blur.
+    pub fn blur_levels(&self) -> &[GlesTexture] {
+        self.blur.as_ref().map_or(&[], Blur::levels)
+    }
+
     pub fn render(&mut self, frame: &mut GlesFrame, blur: bool) -> anyhow::Result<GlesTexture> {
         let offscreen = self.offscreen.as_mut().context("offscreen is missing")?;
 
-        if !blur {
+        ensure!(
+            offscreen.renderer_context_id == frame.context_id(),
+            "wrong renderer context id"
+        );
+
+        // Without blur, with a passthrough blur, or if blur doesn't fit into the texture budget,
+        // use the plain texture.
+        if !blur
+            || self.is_blur_passthrough()
+            || self.blur.as_ref().is_some_and(Blur::is_over_budget)
+        {
             return Ok(offscreen.texture.clone());
         }
 
-        let texture = if let Some(texture) = &offscreen.blurred {
-            texture.clone()
-        } else {
-            let blur = self.blur.as_mut().context("blur is missing")?;
+        ensure!(
+            self.blur_failed_at != Some(self.commit_counter),
+            "blur already failed for the current contents"
+        );
+
+        // The blurred texture is computed by the first element drawn in a frame and reused by
+        // the rest, and by later frames, until the contents change.
+        let blur = &mut self.blur;
+        let blur_options = self.blur_options;
+        let res = memoized(&mut offscreen.blurred, self.commit_counter, || {
+            let blur = blur.as_mut().context("blur is missing")?;
             let mut guard = frame.renderer();
             let renderer = guard.as_mut();
-            let blurred = blur
-                .render(renderer, &offscreen.texture, self.blur_options)
-                .context("error rendering blur")?;
-            offscreen.blurred.insert(blurred).clone()
+            blur.render(renderer, &offscreen.texture, blur_options)
+                .context("error rendering blur")
+        });
+
+        if res.is_err() {
+            self.blur_failed_at = Some(self.commit_counter);
+        }
+
+        res
+    }
+}
+
+/// Returns the value in `slot` if it was rendered at `commit`, otherwise renders and stores it.
+///
+/// A stale value is dropped before rendering, so that its texture can be rendered into again.
+fn memoized<T: Clone>(
+    slot: &mut Option<(CommitCounter, T)>,
+    commit: CommitCounter,
+    render: impl FnOnce() -> anyhow::Result<T>,
+) -> anyhow::Result<T> {
+    match slot {
+        Some((rendered_at, value)) if *rendered_at == commit => return Ok(value.clone()),
+        _ => *slot = None,
+    }
+
+    let value = render()?;
+    Ok(slot.insert((commit, value)).1.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    use anyhow::bail;
+    use smithay::backend::renderer::element::Kind;
+
+    use super::*;
+    use crate::render_helpers::debug::{BlurEvent, BlurHook};
+    use crate::render_helpers::solid_color::SolidColorRenderElement;
+    use crate::render_helpers::{create_texture, test_renderer};
+
+    const SIZE: i32 = 64;
+    const GRAY: [f32; 4] = [0.5, 0.5, 0.5, 1.];
+    const WHITE: [f32; 4] = [1., 1., 1., 1.];
+
+    fn blurred_buffer() -> EffectBuffer {
+        let mut buffer = EffectBuffer::new();
+        buffer.update_size(Size::new(SIZE, SIZE), Scale::from(1.), Transform::Normal);
+        buffer.update_blur_options(BlurOptions {
+            passes: 2,
+            offset: 1.,
+            ..Default::default()
+        });
+        buffer
+    }
+
+    /// Returns a backdrop covering the whole buffer. It's only damaged when `commit` changes.
+    fn backdrop(
+        id: &Id,
+        commit: CommitCounter,
+        color: [f32; 4],
+    ) -> OutputRenderElements<GlesRenderer> {
+        let size = Size::new(SIZE, SIZE).to_f64();
+        let elem = SolidColorRenderElement::new(
+            id.clone(),
+            Rectangle::from_size(size),
+            commit,
+            Color32F::from(color),
+            Kind::Unspecified,
+        );
+        OutputRenderElements::SolidColor(elem)
+    }
+
+    /// Renders a frame where `samples` elements sample the buffer with blur, and returns the
+    /// textures that they got.
+    fn render_frame(
+        renderer: &mut GlesRenderer,
+        buffer: &mut EffectBuffer,
+        backdrop: OutputRenderElements<GlesRenderer>,
+        samples: usize,
+    ) -> Vec<GlesTexture> {
+        buffer.elements().push(backdrop);
+        assert!(buffer.prepare(renderer, true));
+
+        with_frame(renderer, |frame| {
+            (0..samples)
+                .map(|_| buffer.render(frame, true).unwrap())
+                .collect()
+        })
+    }
+
+    fn with_frame<T>(renderer: &mut GlesRenderer, f: impl FnOnce(&mut GlesFrame) -> T) -> T {
+        let size = Size::new(SIZE, SIZE);
+        let mut texture = create_texture(renderer, size, Fourcc::Abgr8888).unwrap();
+        let mut target = renderer.bind(&mut texture).unwrap();
+        let mut frame = renderer
+            .render(&mut target, size, Transform::Normal)
+            .unwrap();
+        let res = f(&mut frame);
+        let _sync = frame.finish().unwrap();
+        res
+    }
+
+    #[test]
+    fn blurred_backdrop_is_rendered_once_per_frame() {
+        let renders = Cell::new(0);
+        let render = || {
+            renders.set(renders.get() + 1);
+            Ok(renders.get())
+        };
+
+        // Several xray elements sample the same backdrop in one frame.
+        let mut commit = CommitCounter::default();
+        let mut blurred = None;
+        for _ in 0..5 {
+            assert_eq!(memoized(&mut blurred, commit, render).unwrap(), 1);
+        }
+        assert_eq!(renders.get(), 1);
+
+        // The backdrop changed; the next frame renders it again.
+        commit.increment();
+        for _ in 0..5 {
+            assert_eq!(memoized(&mut blurred, commit, render).unwrap(), 2);
+        }
+        assert_eq!(renders.get(), 2);
+    }
+
+    #[test]
+    fn failed_render_is_not_stored() {
+        let commit = CommitCounter::default();
+        let mut blurred: Option<(CommitCounter, u32)> = None;
+        assert!(memoized(&mut blurred, commit, || bail!("no blur")).is_err());
+        assert_eq!(blurred, None);
+        assert_eq!(memoized(&mut blurred, commit, || Ok(3)).unwrap(), 3);
+    }
+
+    #[test]
+    fn egl_blur_is_rendered_once_per_contents() {
+        let mut renderer = test_renderer();
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let hook = {
+            let events = events.clone();
+            BlurHook::install(move |event| events.borrow_mut().push(event.clone()))
         };
 
-        Ok(texture)
+        let mut buffer = blurred_buffer();
+        let id = Id::new();
+        let mut commit = CommitCounter::default();
+        let blur = BlurEvent::Blur {
+            size: Size::new(SIZE, SIZE),
+        };
+
+        // Several xray elements sample the backdrop in one frame: blurred by the first one.
+        render_frame(&mut renderer, &mut buffer, backdrop(&id, commit, GRAY), 3);
+        assert_eq!(*events.borrow(), [blur.clone()]);
+
+        // New contents, blurred once more.
+        commit.increment();
+        render_frame(&mut renderer, &mut buffer, backdrop(&id, commit, WHITE), 3);
+        assert_eq!(*events.borrow(), [blur.clone(), blur]);
+
+        // Nothing is reported without a hook.
+        drop(hook);
+        commit.increment();
+        render_frame(&mut renderer, &mut buffer, backdrop(&id, commit, GRAY), 1);
+        assert_eq!(events.borrow().len(), 2);
+    }
+
+    #[test]
+    fn egl_failed_blur_is_attempted_once_per_contents() {
+        let mut renderer = test_renderer();
+        let mut buffer = blurred_buffer();
+        let id = Id::new();
+        let mut commit = CommitCounter::default();
+
+        // Prepared without blur, so rendering the blur fails.
+        buffer.elements().push(backdrop(&id, commit, GRAY));
+        assert!(buffer.prepare(&mut renderer, false));
+
+        // The first element tries it, the rest of the frame doesn't retry.
+        let errors = with_frame(&mut renderer, |frame| {
+            (0..3)
+                .map(|_| buffer.render(frame, true).unwrap_err().to_string())
+                .collect::<Vec<_>>()
+        });
+        let failed = "blur already failed for the current contents";
+        assert_eq!(errors, ["blur is missing", failed, failed]);
+
+        // Neither do the next frames.
+        let err = with_frame(&mut renderer, |frame| {
+            buffer.render(frame, true).unwrap_err()
+        });
+        assert_eq!(err.to_string(), failed);
+
+        // The plain texture keeps working.
+        with_frame(&mut renderer, |frame| buffer.render(frame, false).unwrap());
+
+        // New contents are tried again.
+        commit.increment();
+        buffer.elements().push(backdrop(&id, commit, WHITE));
+        assert!(buffer.prepare(&mut renderer, false));
+        let err = with_frame(&mut renderer, |frame| {
+            buffer.render(frame, true).unwrap_err()
+        });
+        assert_eq!(err.to_string(), "blur is missing");
+
+        // So are new blur options.
+        buffer.update_blur_options(BlurOptions {
+            passes: 3,
+            offset: 1.,
+            ..Default::default()
+        });
+        let err = with_frame(&mut renderer, |frame| {
+            buffer.render(frame, true).unwrap_err()
+        });
+        assert_eq!(err.to_string(), "blur is missing");
+    }
+
+    #[test]
+    fn egl_unchanged_backdrop_is_not_reblurred() {
+        let mut renderer = test_renderer();
+        let blurs = Rc::new(Cell::new(0));
+        let _hook = {
+            let blurs = blurs.clone();
+            BlurHook::install(move |_| blurs.set(blurs.get() + 1))
+        };
+
+        let mut buffer = blurred_buffer();
+        let id = Id::new();
+        let mut commit = CommitCounter::default();
+
+        // A static wallpaper: resubmitted every frame, but never damaged.
+        let mut frame = |buffer: &mut EffectBuffer, commit, color| {
+            render_frame(&mut renderer, buffer, backdrop(&id, commit, color), 1)[0].tex_id()
+        };
+        let blurred = frame(&mut buffer, commit, GRAY);
+        let buffer_commit = buffer.commit();
+        for _frame in 0..10 {
+            assert_eq!(frame(&mut buffer, commit, GRAY), blurred);
+        }
+        assert_eq!(buffer.commit(), buffer_commit);
+        assert_eq!(blurs.get(), 1);
+
+        // The wallpaper changes: the first frame after reblurs, the rest reuse it again.
+        commit.increment();
+        for _frame in 0..10 {
+            frame(&mut buffer, commit, WHITE);
+        }
+        assert_ne!(buffer.commit(), buffer_commit);
+        assert_eq!(blurs.get(), 2);
+    }
+
+    #[test]
+    fn egl_blur_capped_to_passthrough_uses_plain_texture() {
+        let mut renderer = test_renderer();
+        let mut buffer = blurred_buffer();
+        // Too small of a radius for any blur at all.
+        buffer.update_blur_options(BlurOptions {
+            passes: 2,
+            offset: 1.,
+            max_relative_radius: Some(0.001),
+            ..Default::default()
+        });
+
+        let id = Id::new();
+        let commit = CommitCounter::default();
+        let blurred = render_frame(&mut renderer, &mut buffer, backdrop(&id, commit, GRAY), 1);
+        let plain = with_frame(&mut renderer, |frame| buffer.render(frame, false).unwrap());
+        assert_eq!(blurred[0].tex_id(), plain.tex_id());
+        assert!(buffer.blur_levels().is_empty());
     }
 }
@@ -1,32 +1,165 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
+use std::rc::Rc;
 
+use anyhow::Context as _;
 use glam::{Mat3, Vec2};
 use niri_config::CornerRadius;
-use smithay::backend::allocator::Fourcc;
 use smithay::backend::renderer::element::{Element, Id, RenderElement};
 use smithay::backend::renderer::gles::{
     ffi, GlesError, GlesFrame, GlesRenderer, GlesTexture, Uniform,
 };
-use smithay::backend::renderer::utils::CommitCounter;
-use smithay::backend::renderer::{Frame as _, FrameContext, Offscreen, Texture as _};
+use smithay::backend::renderer::utils::{CommitCounter, OpaqueRegions};
+use smithay::backend::renderer::{
+    Bind as _, Color32F, Frame as _, FrameContext, Offscreen, Renderer as _, Texture as _,
+};
 use smithay::gpu_span_location;
 use smithay::utils::user_data::UserDataMap;
-use smithay::utils::{Buffer, Logical, Physical, Rectangle, Scale, Transform};
+use smithay::utils::{Buffer, Logical, Physical, Rectangle, Scale, Size, Transform};
 
 use crate::backend::tty::{TtyFrame, TtyRenderer, TtyRendererError};
-use crate::render_helpers::background_effect::RenderParams;
+use crate::render_helpers::background_effect::{
+    bind_average_texture, frame_alpha_dither, EdgeFalloff, EdgeRing, Postprocess, RenderParams,
+};
 use crate::render_helpers::blur::{Blur, BlurOptions};
-use crate::render_helpers::renderer::AsGlesFrame as _;
-use crate::render_helpers::shaders::{mat3_uniform, Shaders};
+use crate::render_helpers::clipped_surface::ClippedSurfaceRenderElement;
+use crate::render_helpers::debug::{record_blur, BlurEvent};
+use crate::render_helpers::renderer::{AsGlesFrame as _, EffectRenderer, EffectTexture};
+use crate::render_helpers::shaders::{mat3_uniform, texture_transform_mat, Shaders};
+use crate::render_helpers::texture_budget::{
+    gles_texture_bytes, texture_bytes, TextureReservation, TextureUsage, TextureUsageReport,
+};
+use crate::render_helpers::{create_texture_with_fallback, EFFECT_TEXTURE_FORMATS};
 use crate::utils::region::TransformedRegion;
 
+thread_local! {
+    /// Ids of framebuffer effect elements fully hidden behind opaque elements in the last render.
+    static OCCLUDED: RefCell<HashSet<Id>> = RefCell::new(HashSet::new());
+}
+
+/// Finds framebuffer effect elements fully hidden behind opaque elements above them.
+///
+/// Elements must be added in render order, from top to bottom. Occluded elements skip capturing
+/// and blurring the framebuffer, since nothing of it would be visible anyway.
+#[derive(Debug, Default)]
+pub struct OcclusionTracker {
+    /// Opaque regions of the elements added so far.
+    opaque: Vec<Rectangle<i32, Physical>>,
+    occluded: HashSet<Id>,
+}
+
+impl OcclusionTracker {
+    pub fn add<E: Element>(&mut self, elem: &E, scale: Scale<f64>) {
+        let geo = elem.geometry(scale);
+
+        if elem.is_framebuffer_effect()
+            && !geo.is_empty()
+            && geo.subtract_rects(self.opaque.iter().copied()).is_empty()
+        {
+            self.occluded.insert(elem.id().clone());
+        }
+
+        self.opaque
+            .extend(elem.opaque_regions(scale).iter().map(|rect| {
+                let mut rect = *rect;
+                rect.loc += geo.loc;
+                rect
+            }));
+    }
+
+    /// Makes the results available to the elements when they are drawn.
+    ///
+    /// This replaces the results of the previous render.
+    pub fn apply(self) {
+        OCCLUDED.set(self.occluded);
+    }
+
+    /// Clears the results of the previous render, so that no elements are considered occluded.
+    pub fn clear() {
+        OCCLUDED.with_borrow_mut(|occluded| occluded.clear());
+    }
+}
+
+fn is_occluded(id: &Id) -> bool {
+    OCCLUDED.with_borrow(|occluded| occluded.contains(id))
+}
+
+/// Checks that overlay elements, like the pointer, stay out of framebuffer captures.
+///
+/// Elements are drawn from bottom to top, and a framebuffer effect captures everything drawn
+/// before it. So an overlay that ends up below a framebuffer effect element gets blurred into its
+/// backdrop, leaving a smeared ghost of the pointer.
+#[derive(Debug, Default)]
+pub struct CaptureOrder {
+    /// Whether a framebuffer effect element was added so far.
+    has_effect: bool,
+    /// Whether an overlay element was added after a framebuffer effect element.
+    captures_overlay: bool,
+}
+
+impl CaptureOrder {
+    /// Adds the next element in render order, from top to bottom.
+    pub fn add<E: Element>(&mut self, elem: &E, is_overlay: bool) {
+        if is_overlay && self.has_effect {
+            self.captures_overlay = true;
+        }
+        self.has_effect |= elem.is_framebuffer_effect();
+    }
+
+    /// Whether any overlay element ends up in a framebuffer capture.
+    pub fn captures_overlay(&self) -> bool {
+        self.captures_overlay
+    }
+}
+
+/// Number of framebuffer captures in a row that must fail for the effect to give up on capturing.
+const MAX_CAPTURE_FAILURES: u32 = 3;
+
 #[derive(Debug)]
 pub struct FramebufferEffect {
     id: Id,
     commit: CommitCounter,
+    /// Whether the owning surface is being interactively resized.
+    resizing: bool,
+    /// Whether to snap the effect to whole physical pixels.
+    pixel_snap: bool,
+    /// Color to fill the off-screen part of the capture with, if any.
+    offscreen_color: Option<Color32F>,
+    /// Fraction of its size that the framebuffer texture can shrink by and still be reused.
+    shrink_threshold: f64,
+    /// Whether to round framebuffer texture sizes up to powers of two.
+    power_of_two_sizes: bool,
+    /// Limit on the framebuffer texture width and height, 0 to disable.
+    max_texture_size: i32,
+    /// Failed framebuffer captures, reported back from the render elements.
+    capture_failures: Rc<CaptureFailures>,
+    /// Texture memory, reported back from the render caches.
+    texture_usage: Rc<TextureUsage>,
+    /// Textures allocated ahead of the first capture.
+    prewarmed: Rc<RefCell<Option<Prewarmed>>>,
 }
 
+/// Textures allocated ahead of the first capture, see [`FramebufferEffect::prewarm()`].
 #[derive(Debug)]
+struct Prewarmed<T = GlesTexture, B = Blur, Id = ContextId<GlesTexture>> {
+    framebuffer: T,
+    /// Budget reservation for `framebuffer`.
+    reservation: TextureReservation,
+    /// Blur with its pyramid textures prepared for `framebuffer`.
+    blur: Option<B>,
+    /// Context that the textures were created on.
+    context_id: Id,
+}
+
+/// Counter of framebuffer captures that failed in a row.
+///
+/// Capturing can be unsupported on some render targets, e.g. on some drivers. Once enough
+/// captures fail in a row, the counter sticks, so that the owner can stop trying and switch to
+/// another way of rendering the effect.
+#[derive(Debug, Default)]
+struct CaptureFailures(Cell<u32>);
+
+#[derive(Debug, Clone)]
 pub struct FramebufferEffectElement {
     id: Id,
     commit: CommitCounter,
@@ -34,19 +167,78 @@ pub struct FramebufferEffectElement {
     clip_geo: Rectangle<f64, Logical>,
     corner_radius: CornerRadius,
     subregion: Option<TransformedRegion>,
+    /// Visible part of the effect in the same coordinate space as `geometry`.
+    visible: Option<Rectangle<f64, Logical>>,
+    /// Fade-out toward the edges of the subregion, relative to `clip_geo`.
+    edge_falloff: Option<EdgeFalloff>,
     scale: f32,
     blur_options: Option<BlurOptions>,
-    noise: f32,
-    saturation: f32,
+    postprocess: Postprocess,
+    /// Whether to stretch the previous capture instead of capturing anew.
+    resizing: bool,
+    /// Whether to snap the geometry to whole physical pixels.
+    pixel_snap: bool,
+    /// Color to fill the off-screen part of the capture with, or `None` to clamp to the output.
+    offscreen_color: Option<Color32F>,
+    /// Fraction of its size that the framebuffer texture can shrink by and still be reused.
+    shrink_threshold: f64,
+    /// Whether to round framebuffer texture sizes up to powers of two.
+    power_of_two_sizes: bool,
+    /// Limit on the framebuffer texture width and height, 0 to disable.
+    max_texture_size: i32,
+    /// Where to report failed framebuffer captures.
+    capture_failures: Rc<CaptureFailures>,
+    /// Where the render caches report their texture memory.
+    texture_usage: Rc<TextureUsage>,
+    /// Textures for the first capture to take over, if allocated ahead of time.
+    prewarmed: Rc<RefCell<Option<Prewarmed>>>,
 }
 
 #[derive(Debug)]
 struct Inner {
+    /// Texture that the framebuffer contents are blitted into.
     framebuffer: Option<GlesTexture>,
+    /// Budget reservation for `framebuffer`.
+    framebuffer_reservation: TextureReservation,
     blur: Option<Blur>,
+    /// Texture to draw from, valid for the current frame.
+    ///
+    /// This is the blurred texture owned by `blur` or, with temporal blending, by `temporal`.
+    /// When blur is off, it is an alias of `framebuffer`. Every capture resets it before
+    /// `framebuffer` can be recreated or written to, except while resizing, when the previous one
+    /// is kept and stretched.
     intermediate: Option<GlesTexture>,
     /// Reusable storage for subregion-filtered damage rects.
     subregion_damage: Vec<Rectangle<i32, Physical>>,
+    /// Copies of recent blurred textures for temporal blending.
+    temporal: TemporalHistory<GlesTexture>,
+    /// Whether the last blur pass is drawn straight into the frame, valid for the current frame.
+    ///
+    /// In this case there's no intermediate texture.
+    direct: bool,
+    /// Share of these textures in the effect's texture memory.
+    usage: TextureUsageReport,
+}
+
+/// Capture parameters that must stay the same for the previous frame's blur to line up.
+type CaptureKey = (Rectangle<i32, Physical>, Transform);
+
+/// Two textures that alternate between holding the current and the previous frame's blur.
+///
+/// The blurred texture itself is owned by `Blur` and overwritten every frame, so it's copied here.
+#[derive(Debug)]
+struct TemporalHistory<T> {
+    textures: [Option<T>; 2],
+    /// Index of the texture for the current frame.
+    current: usize,
+    /// Whether the current texture has been written this frame.
+    written: bool,
+    /// Whether the other texture holds the previous frame's blur, captured with the same key.
+    has_previous: bool,
+    /// Capture parameters of the current frame.
+    key: Option<CaptureKey>,
+    /// Budget reservation for `textures`.
+    reservation: TextureReservation,
 }
 
 impl FramebufferEffect {
@@ -54,20 +246,165 @@ impl FramebufferEffect {
         Self {
             id: Id::new(),
             commit: CommitCounter::default(),
+            resizing: false,
+            pixel_snap: false,
+            offscreen_color: None,
+            shrink_threshold: 0.,
+            power_of_two_sizes: false,
+            max_texture_size: niri_config::Blur::default().max_texture_size as i32,
+            capture_failures: Rc::new(CaptureFailures::default()),
+            texture_usage: Rc::new(TextureUsage::default()),
+            prewarmed: Rc::new(RefCell::new(None)),
         }
     }
 
+    pub fn id(&self) -> &Id {
+        &self.id
+    }
+
     pub fn damage(&mut self) {
         self.commit.increment();
     }
 
+    /// Starts over with a new element id, damaging it and dropping the captured textures.
+    ///
+    /// The textures live in the render element cache keyed by the id, so they would otherwise be
+    /// drawn again if the element shows up before its next capture.
+    pub fn reset(&mut self) {
+        self.id = Id::new();
+        self.commit.increment();
+    }
+
+    /// Sets whether the owning surface is being interactively resized.
+    ///
+    /// During a resize, the effect keeps stretching its last capture rather than reallocating and
+    /// blurring textures at a new size every frame. Once the resize ends, the effect is damaged to
+    /// capture again at the final size.
+    pub fn set_resizing(&mut self, resizing: bool) {
+        if self.resizing == resizing {
+            return;
+        }
+
+        self.resizing = resizing;
+        if !resizing {
+            self.commit.increment();
+        }
+    }
+
+    /// Sets whether to snap the effect to whole physical pixels.
+    ///
+    /// At fractional positions, the captured area changes size by a pixel here and there as the
+    /// effect moves, so the backdrop is resampled differently every frame and the blur shimmers.
+    /// Snapping keeps the size fixed, at the cost of being up to half a pixel off.
+    pub fn set_pixel_snap(&mut self, pixel_snap: bool) {
+        if self.pixel_snap == pixel_snap {
+            return;
+        }
+
+        self.pixel_snap = pixel_snap;
+        self.commit.increment();
+    }
+
+    /// Sets the color to fill the off-screen part of the effect with before blurring.
+    ///
+    /// By default, the capture is clamped to the output, and the blur stretches the pixels at the
+    /// output edge, which looks like smearing. With a color, the blur fades towards it instead.
+    pub fn set_offscreen_color(&mut self, color: Option<Color32F>) {
+        if self.offscreen_color == color {
+            return;
+        }
+
+        self.offscreen_color = color;
+        self.commit.increment();
+    }
+
+    /// Sets the fraction of its size that the framebuffer texture can shrink by and still be
+    /// reused.
+    ///
+    /// While an effect keeps shrinking, e.g. during a zoom-out animation, this avoids reallocating
+    /// the textures every frame. The larger texture is stretched to the effect all the same.
+    pub fn set_shrink_threshold(&mut self, threshold: f64) {
+        self.shrink_threshold = threshold;
+    }
+
+    /// Sets whether to round framebuffer texture sizes up to powers of two.
+    ///
+    /// Effects of many slightly different sizes then share a few texture sizes, which lets them
+    /// reuse each other's textures, at the cost of capturing and blurring more pixels.
+    pub fn set_power_of_two_sizes(&mut self, power_of_two: bool) {
+        self.power_of_two_sizes = power_of_two;
+    }
+
+    /// Sets the limit on the framebuffer texture width and height, 0 to disable.
+    ///
+    /// Larger effects are captured at a lower resolution and stretched when drawing, which bounds
+    /// the capture and blur cost on very large outputs at the cost of some blur sharpness.
+    pub fn set_max_texture_size(&mut self, max: u32) {
+        self.max_texture_size = i32::try_from(max).unwrap_or(i32::MAX);
+    }
+
+    /// Whether framebuffer capture kept failing, so the effect should be rendered another way.
+    pub fn is_capture_unsupported(&self) -> bool {
+        self.capture_failures.is_unsupported()
+    }
+
+    /// Returns the size in bytes of the effect's textures, across all outputs it's drawn on.
+    ///
+    /// This is updated on every capture.
+    pub fn texture_memory_bytes(&self) -> u64 {
+        self.texture_usage.bytes()
+    }
+
+    /// Allocates the textures for the first capture ahead of time.
+    ///
+    /// Call this before the effect first shows up, e.g. on the first frame of an open animation,
+    /// with its expected size in physical pixels. A first capture at that size then takes over the
+    /// framebuffer and blur textures instead of allocating them mid-frame.
+    pub fn prewarm(
+        &self,
+        renderer: &mut GlesRenderer,
+        size: Size<i32, Physical>,
+        blur_options: Option<BlurOptions>,
+    ) -> anyhow::Result<()> {
+        let _span = tracy_client::span!("FramebufferEffect::prewarm");
+
+        let needed = size.to_logical(1).to_buffer(1, Transform::Normal);
+        let (needed, _) = cap_capture_size(needed, self.max_texture_size);
+        let size = prewarm_size(needed, self.power_of_two_sizes);
+        let size = clamp_texture_size(size, self.max_texture_size);
+        trace!(
+            "prewarming framebuffer texture sized {} × {}",
+            size.w,
+            size.h
+        );
+
+        let (framebuffer, reservation) = create_framebuffer(renderer, size)?;
+
+        let mut blur = Blur::new(renderer);
+        let options = blur_options.filter(|options| !options.capped(size).is_passthrough());
+        if let (Some(blur), Some(options)) = (&mut blur, options) {
+            blur.prepare_textures(
+                |fourcc, size| renderer.create_buffer(fourcc, size),
+                &framebuffer,
+                options,
+            )?;
+        }
+
+        *self.prewarmed.borrow_mut() = Some(Prewarmed {
+            framebuffer,
+            reservation,
+            blur,
+            context_id: renderer.effect_context_id(),
+        });
+        Ok(())
+    }
+
     pub fn render(
         &self,
         ns: Option<usize>,
         params: RenderParams,
         blur_options: Option<BlurOptions>,
-        noise: f32,
-        saturation: f32,
+        postprocess: Postprocess,
     ) -> FramebufferEffectElement {
         let (clip_geo, corner_radius) = params
             .clip
@@ -85,20 +422,181 @@ impl FramebufferEffect {
             clip_geo,
             corner_radius,
             subregion: params.subregion,
+            visible: params.visible,
+            edge_falloff: params.edge_falloff,
             scale: params.scale as f32,
             blur_options,
-            noise,
-            saturation,
+            postprocess,
+            resizing: self.resizing,
+            pixel_snap: self.pixel_snap,
+            offscreen_color: self.offscreen_color,
+            shrink_threshold: self.shrink_threshold,
+            power_of_two_sizes: self.power_of_two_sizes,
+            max_texture_size: self.max_texture_size,
+            capture_failures: self.capture_failures.clone(),
+            texture_usage: self.texture_usage.clone(),
+            prewarmed: self.prewarmed.clone(),
+        }
+    }
+}
+
+impl<T, B, Id: PartialEq> Prewarmed<T, B, Id> {
+    /// Takes the prewarmed textures for a capture on the given context.
+    ///
+    /// Textures from another context can't be used there, so they're dropped.
+    fn take(slot: &mut Option<Self>, context_id: &Id) -> Option<Self> {
+        slot.take()
+            .filter(|prewarmed| prewarmed.context_id == *context_id)
+    }
+}
+
+impl CaptureFailures {
+    /// Records the result of a capture.
+    fn record(&self, success: bool) {
+        let failures = self.0.get();
+        if failures >= MAX_CAPTURE_FAILURES {
+            return;
+        }
+
+        if success {
+            self.0.set(0);
+        } else {
+            self.0.set(failures + 1);
+            if failures + 1 == MAX_CAPTURE_FAILURES {
+                warn!("framebuffer capture keeps failing, giving up on it");
+            }
         }
     }
+
+    fn is_unsupported(&self) -> bool {
+        self.0.get() >= MAX_CAPTURE_FAILURES
+    }
 }
 
 impl FramebufferEffectElement {
+    /// Reports the result of a framebuffer capture back to the effect.
+    pub(super) fn report_capture(&self, success: bool) {
+        self.capture_failures.record(success);
+    }
+
+    /// Returns the active postprocess stages in the order they're applied, for debugging.
+    pub fn pipeline_order(&self) -> String {
+        self.postprocess.pipeline_order(false, self.clips())
+    }
+
+    /// Whether drawing rounds the corners or clips to the clip geometry.
+    fn clips(&self) -> bool {
+        let CornerRadius {
+            top_left,
+            top_right,
+            bottom_right,
+            bottom_left,
+        } = self.corner_radius;
+        let rounded = top_left > 0. || top_right > 0. || bottom_right > 0. || bottom_left > 0.;
+
+        rounded || !self.clip_geo.contains_rect(self.geometry)
+    }
+
+    /// Whether the effect covers everything below it inside the clip.
+    ///
+    /// The subregion and the edge falloff leave parts of the clip transparent.
+    fn is_opaque(&self) -> bool {
+        self.postprocess.is_opaque() && self.subregion.is_none() && self.edge_falloff.is_none()
+    }
+
+    /// Whether drawing needs the postprocess and clip shader.
+    ///
+    /// Without rounded corners, clipping and color adjustments, the texture can be drawn as is,
+    /// e.g. for a maximized window with plain blur. The effect opacity doesn't need the shader.
+    fn needs_shader(&self) -> bool {
+        self.clips()
+            || self.edge_falloff.is_some()
+            || self.postprocess.noise != 0.
+            || self.postprocess.saturation != 1.
+            || self.postprocess.tint != Color32F::TRANSPARENT
+            || self.postprocess.edge_ring.is_some()
+    }
+
+    /// Whether the last blur pass can draw straight into the frame.
+    ///
+    /// This works when the effect covers the whole output and the blurred texture would be drawn
+    /// as is, skipping the full-size output texture and the extra pass over the screen to draw it.
+    fn draws_blur_directly(
+        &self,
+        dst: Rectangle<i32, Physical>,
+        output_rect: Rectangle<i32, Physical>,
+    ) -> bool {
+        let Some(options) = self.blur_options else {
+            return false;
+        };
+
+        dst == output_rect
+            && self.subregion.is_none()
+            && self.visible.is_none()
+            && !self.needs_shader()
+            && self.postprocess.alpha == 1.
+            && !self.resizing
+            && !options.is_passthrough()
+            && options.temporal_blend == 0.
+            // The direct path blurs at the full output size.
+            && (self.max_texture_size <= 0 || dst.size.w.max(dst.size.h) <= self.max_texture_size)
+    }
+
+    /// Returns the area that the effect captures and draws to.
+    ///
+    /// This is the output itself, further limited to the visible region if there's one. With an
+    /// off-screen color, the off-screen part is captured too, filled with the color.
+    fn clamp_rect(
+        &self,
+        src: Rectangle<f64, Buffer>,
+        dst: Rectangle<i32, Physical>,
+        output_rect: Rectangle<i32, Physical>,
+    ) -> Option<Rectangle<i32, Physical>> {
+        let bounds = if self.offscreen_color.is_some() {
+            dst
+        } else {
+            output_rect
+        };
+
+        let Some(visible) = self.visible else {
+            return Some(bounds);
+        };
+
+        // src is the crop of the element in element-local coordinates, and it's drawn to dst.
+        let crop = src.to_logical(1., Transform::Normal, &src.size);
+        let crop_to_dst = dst.size.to_f64().to_logical(1.) / crop.size;
+
+        let loc = visible.loc - self.geometry.loc - crop.loc;
+        let mut visible = Rectangle::new(
+            loc.upscale(crop_to_dst).to_physical(1.),
+            visible.size.upscale(crop_to_dst).to_physical(1.),
+        )
+        .to_i32_round();
+        visible.loc += dst.loc;
+
+        bounds.intersection(visible)
+    }
+
+    /// Returns the color to fill the capture texture with before blitting into `blit_dst`.
+    ///
+    /// Clearing is only needed when the blit doesn't cover the whole texture, which happens when
+    /// the off-screen part is captured too.
+    fn clear_color(
+        &self,
+        blit_dst: Rectangle<i32, Buffer>,
+        size: Size<i32, Buffer>,
+    ) -> Option<Color32F> {
+        self.offscreen_color
+            .filter(|_| blit_dst != Rectangle::from_size(size))
+    }
+
     fn compute_uniforms(
         &self,
         crop: Rectangle<f64, Logical>,
         transform: Transform,
-    ) -> [Uniform<'static>; 7] {
+        average_bound: bool,
+        alpha_dither: f32,
+    ) -> [Uniform<'static>; 16] {
         let offset = crop.loc - (self.clip_geo.loc - self.geometry.loc);
         let offset = Vec2::new(offset.x as f32, offset.y as f32);
         let crop_size = Vec2::new(crop.size.w as f32, crop.size.h as f32);
@@ -109,25 +607,66 @@ impl FramebufferEffectElement {
             Mat3::from_scale(crop_size / clip_size) * Mat3::from_translation(offset / crop_size);
 
         // Revert the effect of the texture transform.
-        let transform_mat = Mat3::from_translation(Vec2::new(0.5, 0.5))
-            * Mat3::from_cols_array(transform.matrix().as_ref())
-            * Mat3::from_translation(Vec2::new(-0.5, -0.5));
-        let input_to_clip_geo = input_to_clip_geo * transform_mat;
+        let input_to_clip_geo = input_to_clip_geo * texture_transform_mat(transform);
 
         let clip_geo_size = (self.clip_geo.size.w as f32, self.clip_geo.size.h as f32);
+        let [adaptive_tint, average] = Postprocess::adaptive_tint_uniforms(average_bound);
+        let [region_bounds, region_falloff] = EdgeFalloff::uniforms(self.edge_falloff);
+        let [ring_color, ring_width] = EdgeRing::uniforms(self.postprocess.edge_ring);
 
         [
             Uniform::new("niri_scale", self.scale),
             Uniform::new("geo_size", clip_geo_size),
-            Uniform::new("corner_radius", <[f32; 4]>::from(self.corner_radius)),
+            Uniform::new(
+                "corner_radius",
+                <[f32; 4]>::from(self.corner_radius.sanitized()),
+            ),
             mat3_uniform("input_to_geo", input_to_clip_geo),
-            Uniform::new("noise", self.noise),
-            Uniform::new("saturation", self.saturation),
+            Uniform::new("noise", self.postprocess.noise),
+            Uniform::new("noise_seed", self.postprocess.noise_seed),
+            Uniform::new("saturation", self.postprocess.saturation),
             Uniform::new("bg_color", [0f32, 0., 0., 0.]),
+            Uniform::new("tint", self.postprocess.tint.components()),
+            adaptive_tint,
+            average,
+            region_bounds,
+            region_falloff,
+            Uniform::new("alpha_dither", alpha_dither),
+            ring_color,
+            ring_width,
         ]
     }
 }
 
+impl FramebufferEffectElement {
+    /// Draws the last blur pass straight into the frame, see [`Self::draws_blur_directly()`].
+    fn draw_blur_directly(
+        &self,
+        frame: &mut GlesFrame<'_, '_>,
+        dst: Rectangle<i32, Physical>,
+        damage: &[Rectangle<i32, Physical>],
+        inner: &mut Inner,
+    ) -> Result<(), GlesError> {
+        let (Some(blur), Some(options)) = (&inner.blur, self.blur_options) else {
+            return Ok(());
+        };
+
+        // Convert to framebuffer coordinates the same way as in capture_framebuffer().
+        let output_size = frame.output_size();
+        let transform = frame.transformation();
+        let fb_dst = transform.transform_rect_in(dst, &output_size);
+
+        let fb_damage = &mut inner.subregion_damage;
+        fb_damage.clear();
+        fb_damage.extend(damage.iter().map(|rect| {
+            let rect = Rectangle::new(rect.loc + dst.loc, rect.size);
+            transform.transform_rect_in(rect, &output_size)
+        }));
+
+        blur.draw_last_pass(frame, options, fb_dst, fb_damage)
+    }
+}
+
 impl Element for FramebufferEffectElement {
     fn id(&self) -> &Id {
         &self.id
@@ -144,7 +683,44 @@ impl Element for FramebufferEffectElement {
     }
 
     fn geometry(&self, scale: Scale<f64>) -> Rectangle<i32, Physical> {
-        self.geometry.to_physical_precise_round(scale)
+        if self.pixel_snap {
+            // Round the size on its own, so that it doesn't depend on the position.
+            let geo = self.geometry.to_physical(scale);
+            Rectangle::new(geo.loc.to_i32_round(), geo.size.to_i32_round())
+        } else {
+            self.geometry.to_physical_precise_round(scale)
+        }
+    }
+
+    fn opaque_regions(&self, scale: Scale<f64>) -> OpaqueRegions<i32, Physical> {
+        if !self.is_opaque() {
+            return OpaqueRegions::default();
+        }
+
+        let mut area = self.clip_geo.intersection(self.geometry);
+        if let Some(visible) = self.visible {
+            area = area.and_then(|area| area.intersection(visible));
+        }
+        let Some(area) = area else {
+            return OpaqueRegions::default();
+        };
+
+        let elem_loc = self.geometry(scale).loc;
+        let mut area = area.to_physical_precise_down(scale);
+        area.loc -= elem_loc;
+
+        // Subtract the rounded corners.
+        let corners = ClippedSurfaceRenderElement::<GlesRenderer>::rounded_corners(
+            self.clip_geo,
+            self.corner_radius.sanitized(),
+        );
+        let corners = corners.into_iter().map(|rect| {
+            let mut rect = rect.to_physical_precise_up(scale);
+            rect.loc -= elem_loc;
+            rect
+        });
+
+        OpaqueRegions::from_slice(&Rectangle::subtract_rects_many([area], corners))
     }
 
     fn is_framebuffer_effect(&self) -> bool {
@@ -162,30 +738,52 @@ impl RenderElement<GlesRenderer> for FramebufferEffectElement {
     ) -> Result<(), GlesError> {
         let _span = tracy_client::span!("FramebufferEffectElement::capture_framebuffer");
         let location = gpu_span_location!("FramebufferEffectElement::capture_framebuffer");
-        frame.with_gpu_span(location, |frame| {
+        let res = frame.with_gpu_span(location, |frame| {
             let output_rect = Rectangle::from_size(frame.output_size());
             let transform = frame.transformation();
+            let direct = self.draws_blur_directly(dst, output_rect);
 
             let mut guard = frame.renderer();
 
-            let inner = cache
-                .get_or_insert::<RefCell<Inner>, _>(|| RefCell::new(Inner::new(guard.as_mut())));
+            let inner = cache.get_or_insert::<RefCell<Inner>, _>(|| {
+                let renderer = guard.as_mut();
+                let mut inner = Inner::new(renderer, self.texture_usage.clone());
+                let context_id = renderer.context_id();
+                let mut prewarmed = self.prewarmed.borrow_mut();
+                if let Some(prewarmed) = Prewarmed::take(&mut prewarmed, &context_id) {
+                    trace!("taking over prewarmed textures");
+                    inner.adopt(prewarmed);
+                }
+                RefCell::new(inner)
+            });
             let mut inner = inner.borrow_mut();
             let inner = &mut *inner;
 
-            inner.intermediate = None;
+            // Nothing will be visible, so keep the previous intermediate texture and skip the
+            // capture and blur work.
+            if is_occluded(&self.id) {
+                trace!("skipping capture for an occluded element");
+                return Ok(());
+            }
 
             // We want clamp-to-edge behavior for out-of-bounds pixels. However, glBlitFramebuffer
             // seems to skip out-of-bounds pixels, even though my reading of the docs suggests
             // otherwise (we use GL_LINEAR filter). So, clamp dst to the framebuffer bounds
-            // ourselves.
-            let clamped_dst = match dst.intersection(output_rect) {
-                Some(clamped) => clamped,
-                None => return Ok(()),
+            // ourselves, unless the off-screen part should be filled with a color instead.
+            //
+            // Also clamp to the visible region, so that hidden parts aren't captured and blurred.
+            let clamp_rect = self.clamp_rect(src, dst, output_rect);
+            let clamped = clamp_rect.and_then(|rect| dst.intersection(rect));
+            let on_output = clamped.and_then(|rect| rect.intersection(output_rect));
+            let (Some(clamped_dst), Some(on_output)) = (clamped, on_output) else {
+                inner.intermediate = None;
+                inner.direct = false;
+                return Ok(());
             };
             let clamp_scale = clamped_dst.size.to_f64() / dst.size.to_f64();
 
             let dst = transform.transform_rect_in(clamped_dst, &output_rect.size);
+            let blit_src = transform.transform_rect_in(on_output, &output_rect.size);
 
             // Compute size from our geometry and scale.
             //
@@ -214,25 +812,66 @@ impl RenderElement<GlesRenderer> for FramebufferEffectElement {
 
             let size = size.to_logical(1).to_buffer(1, Transform::Normal);
 
-            // Recreate framebuffer if needed.
-            if inner
-                .framebuffer
-                .as_ref()
-                .is_some_and(|fb| fb.size() != size)
-            {
-                inner.framebuffer = None;
-            }
-            let framebuffer = if let Some(fb) = &inner.framebuffer {
-                fb
+            // Zoomed-out effects are drawn smaller than their geometry, so capture at a coarser
+            // resolution. The blur offset is lowered by the same factor below, so the blur keeps
+            // shrinking together with the effect as described above.
+            let downscale = if direct {
+                1
             } else {
-                trace!("creating framebuffer texture sized {} × {}", size.w, size.h);
-                let renderer = guard.as_mut();
-                let texture = renderer.create_buffer(Fourcc::Abgr8888, size)?;
-                inner.framebuffer.insert(texture)
+                let zoom_x = f64::from(dst.size.w) / f64::from(size.w.max(1));
+                let zoom_y = f64::from(dst.size.h) / f64::from(size.h.max(1));
+                capture_downscale(Scale::from((zoom_x, zoom_y)))
+            };
+            let needed = downscale_size(size, downscale);
+
+            // Capture huge effects at a lower resolution, which the draw stretches back up like
+            // any other texture size.
+            let (needed, cap) = cap_capture_size(needed, self.max_texture_size);
+
+            // Even computed from geometry, the size still changes by a pixel here and there, e.g.
+            // as clamp_scale shrinks while the element slides off the output. Snap it to buckets
+            // to avoid reallocating every frame. The whole texture is always stretched to dst, and
+            // input_to_geo maps from texture coordinates, so the exact size doesn't matter there.
+            let sizing = CaptureSizing {
+                resizing: self.resizing,
+                shrink_threshold: self.shrink_threshold,
+                power_of_two: self.power_of_two_sizes,
+                max_texture_size: self.max_texture_size,
+            };
+            let Some(framebuffer) = framebuffer_for_capture(
+                guard.as_mut(),
+                &mut inner.framebuffer,
+                &mut inner.framebuffer_reservation,
+                &mut inner.intermediate,
+                needed,
+                sizing,
+            )?
+            else {
+                trace!("stretching the previous capture during a resize");
+                return Ok(());
             };
+            let size = framebuffer.size();
+
+            let blit_dst = blit_target(dst, blit_src, size);
+            let clear_color = self.clear_color(blit_dst, size);
+
+            inner.direct = false;
+
+            record_blur(|| BlurEvent::Capture {
+                id: self.id.clone(),
+                size,
+            });
 
             // Prepare blur textures.
-            let mut blur = Option::zip(inner.blur.as_mut(), self.blur_options);
+            let blur_options = self
+                .blur_options
+                .map(|options| {
+                    let divisor = f64::from(downscale) * cap;
+                    capture_blur_options(options, needed, size, divisor, transform)
+                })
+                // Capping to the maximum radius can leave nothing to blur.
+                .filter(|options| !options.capped(size).is_passthrough());
+            let mut blur = Option::zip(inner.blur.as_mut(), blur_options);
             if let Some((b, options)) = &mut blur {
                 let renderer = guard.as_mut();
                 if let Err(err) = b.prepare_textures(
@@ -242,6 +881,8 @@ impl RenderElement<GlesRenderer> for FramebufferEffectElement {
                 ) {
                     warn!("error preparing blur textures: {err:?}");
                     blur = None;
+                } else if b.is_over_budget() {
+                    blur = None;
                 }
             }
 
@@ -271,15 +912,22 @@ impl RenderElement<GlesRenderer> for FramebufferEffectElement {
                     0,
                 );
 
+                // Fill the off-screen part that the blit won't cover.
+                if let Some(color) = clear_color {
+                    let [r, g, b, a] = color.components();
+                    gl.ClearColor(r, g, b, a);
+                    gl.Clear(ffi::COLOR_BUFFER_BIT);
+                }
+
                 gl.BlitFramebuffer(
-                    dst.loc.x,
-                    dst.loc.y,
-                    dst.loc.x + dst.size.w,
-                    dst.loc.y + dst.size.h,
-                    0,
-                    0,
-                    size.w,
-                    size.h,
+                    blit_src.loc.x,
+                    blit_src.loc.y,
+                    blit_src.loc.x + blit_src.size.w,
+                    blit_src.loc.y + blit_src.size.h,
+                    blit_dst.loc.x,
+                    blit_dst.loc.y,
+                    blit_dst.loc.x + blit_dst.size.w,
+                    blit_dst.loc.y + blit_dst.size.h,
                     ffi::COLOR_BUFFER_BIT,
                     ffi::LINEAR,
                 );
@@ -297,25 +945,67 @@ impl RenderElement<GlesRenderer> for FramebufferEffectElement {
                 }
             })??;
 
-            // If blur is off, use the unblurred texture.
-            if self.blur_options.is_none() {
+            // If blur is off or unavailable (e.g. it doesn't fit into the texture budget), use the
+            // unblurred texture.
+            let Some((blur, options)) = blur else {
+                inner.temporal.reset();
                 inner.intermediate = Some(framebuffer.clone());
                 return Ok(());
-            }
+            };
 
-            if let Some((blur, options)) = blur {
-                let mut guard = frame.renderer();
-                let renderer = guard.as_mut();
+            let mut guard = frame.renderer();
+            let renderer = guard.as_mut();
+
+            if direct {
+                inner.temporal.reset();
+                match blur.render_without_last_pass(renderer, framebuffer, options) {
+                    Ok(()) => inner.direct = true,
+                    Err(err) => warn!("error rendering blur: {err:?}"),
+                }
+            } else {
                 match blur.render(renderer, framebuffer, options) {
-                    Ok(blurred) => inner.intermediate = Some(blurred),
+                    Ok(mut blurred) => {
+                        if options.temporal_blend > 0. {
+                            let alpha = options.temporal_blend as f32;
+                            let result = inner
+                                .temporal
+                                .store(renderer, &blurred, (dst, transform))
+                                .and_then(|()| inner.temporal.blend_previous(renderer, alpha));
+                            match result {
+                                // Postprocessing is then applied once on top of the blended blur.
+                                Ok(blended) => blurred = blended,
+                                Err(err) => {
+                                    warn!("error storing blur for temporal blending: {err:?}");
+                                    inner.temporal.reset();
+                                }
+                            }
+                        } else {
+                            inner.temporal.reset();
+                        }
+
+                        inner.intermediate = Some(blurred);
+                    }
                     Err(err) => {
                         warn!("error rendering blur: {err:?}");
+                        inner.temporal.reset();
+                        // Keep showing the last good blur rather than nothing.
+                        inner.intermediate = blur.last_output().cloned();
                     }
                 }
             }
 
             Ok(())
-        })
+        });
+
+        self.report_capture(res.is_ok());
+
+        if let Some(inner) = cache.get::<RefCell<Inner>>() {
+            let mut inner = inner.borrow_mut();
+            let bytes = inner.texture_memory_bytes();
+            inner.usage.set(bytes);
+        }
+
+        res
     }
 
     fn draw(
@@ -336,13 +1026,18 @@ impl RenderElement<GlesRenderer> for FramebufferEffectElement {
         let mut inner = inner.borrow_mut();
         let inner = &mut *inner;
 
+        if inner.direct {
+            return self.draw_blur_directly(frame, dst, damage, inner);
+        }
+
         let Some(texture) = &inner.intermediate else {
             return Ok(());
         };
 
         // Clamp the same way as in capture_framebuffer().
         let output_rect = Rectangle::from_size(frame.output_size());
-        let clamped_dst = match dst.intersection(output_rect) {
+        let clamp_rect = self.clamp_rect(src, dst, output_rect);
+        let clamped_dst = match clamp_rect.and_then(|rect| dst.intersection(rect)) {
             Some(clamped) => clamped,
             None => return Ok(()),
         };
@@ -388,12 +1083,26 @@ impl RenderElement<GlesRenderer> for FramebufferEffectElement {
             clamped_dst.size.to_f64().upscale(dst_to_src).to_logical(1.),
         );
 
-        let program = Shaders::get_from_frame(frame).postprocess_and_clip.clone();
-        let uniforms = program
-            .is_some()
-            .then(|| self.compute_uniforms(crop, frame.transformation()));
+        let program = if self.needs_shader() {
+            Shaders::get_from_frame(frame).postprocess_and_clip.clone()
+        } else {
+            None
+        };
+        let levels = inner.blur.as_ref().map_or(&[][..], Blur::levels);
+        let average = program
+            .as_ref()
+            .and_then(|_| self.postprocess.average_level(levels));
+        let alpha_dither = frame_alpha_dither(frame)?;
+        let uniforms = program.is_some().then(|| {
+            let transform = frame.transformation();
+            self.compute_uniforms(crop, transform, average.is_some(), alpha_dither)
+        });
         let uniforms = uniforms.as_ref().map_or(&[][..], |x| &x[..]);
 
+        if average.is_some() {
+            bind_average_texture(frame, average)?;
+        }
+
         frame.render_texture_from_to(
             texture,
             Rectangle::from_size(texture.size().to_f64()),
@@ -402,10 +1111,16 @@ impl RenderElement<GlesRenderer> for FramebufferEffectElement {
             &[],
             // The intermediate texture has the same transform as the frame.
             frame.transformation().invert(),
-            1.,
+            self.postprocess.alpha,
             program.as_ref(),
             uniforms,
-        )
+        )?;
+
+        if average.is_some() {
+            bind_average_texture(frame, None)?;
+        }
+
+        Ok(())
     }
 }
 
@@ -446,12 +1161,1458 @@ impl<'render> RenderElement<TtyRenderer<'render>> for FramebufferEffectElement {
 }
 
 impl Inner {
-    fn new(renderer: &mut GlesRenderer) -> Self {
+    fn new(renderer: &mut GlesRenderer, usage: Rc<TextureUsage>) -> Self {
         Inner {
             framebuffer: None,
+            framebuffer_reservation: TextureReservation::default(),
             blur: Blur::new(renderer),
             intermediate: None,
             subregion_damage: Vec::new(),
+            temporal: TemporalHistory::default(),
+            direct: false,
+            usage: TextureUsageReport::new(usage),
+        }
+    }
+
+    /// Takes over textures allocated ahead of the first capture.
+    ///
+    /// The capture then reuses them like textures from a previous capture if the size matches.
+    fn adopt(&mut self, prewarmed: Prewarmed) {
+        self.framebuffer = Some(prewarmed.framebuffer);
+        self.framebuffer_reservation = prewarmed.reservation;
+        if prewarmed.blur.is_some() {
+            self.blur = prewarmed.blur;
+        }
+    }
+
+    /// Returns the size in bytes of the current textures.
+    fn texture_memory_bytes(&self) -> u64 {
+        // The intermediate texture is owned by the blur, or is the framebuffer texture itself.
+        let framebuffer = self.framebuffer.as_ref().map_or(0, gles_texture_bytes);
+        let blur = self.blur.as_ref().map_or(0, Blur::texture_memory_bytes);
+        let temporal: u64 = self
+            .temporal
+            .textures
+            .iter()
+            .flatten()
+            .map(gles_texture_bytes)
+            .sum();
+        framebuffer + blur + temporal
+    }
+}
+
+impl<T> Default for TemporalHistory<T> {
+    fn default() -> Self {
+        Self {
+            textures: [None, None],
+            current: 0,
+            written: false,
+            has_previous: false,
+            key: None,
+            reservation: TextureReservation::default(),
+        }
+    }
+}
+
+impl<T> TemporalHistory<T> {
+    /// Starts a new frame, making the current texture the previous one.
+    ///
+    /// If the capture parameters changed, for example because the window moved, the previous
+    /// frame's blur no longer lines up with the current one and is discarded. This avoids ghosting
+    /// on such scene cuts.
+    fn advance(&mut self, key: CaptureKey) {
+        self.has_previous = self.written && self.key == Some(key);
+        self.key = Some(key);
+        self.written = false;
+        self.current ^= 1;
+    }
+
+    /// Returns the slot for the current frame's texture.
+    fn current_mut(&mut self) -> &mut Option<T> {
+        &mut self.textures[self.current]
+    }
+
+    /// Returns the previous frame's blur, if it can be blended with the current one.
+    fn previous(&self) -> Option<&T> {
+        if !self.has_previous {
+            return None;
+        }
+        self.textures[self.current ^ 1].as_ref()
+    }
+
+    /// Drops all history, for example when temporal blending is turned off.
+    fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+impl TemporalHistory<GlesTexture> {
+    /// Copies the current frame's blurred texture into the history.
+    fn store(
+        &mut self,
+        renderer: &mut GlesRenderer,
+        blurred: &GlesTexture,
+        key: CaptureKey,
+    ) -> anyhow::Result<()> {
+        self.advance(key);
+
+        let size = blurred.size();
+        let slot = self.current_mut();
+        if slot.as_ref().is_some_and(|texture| texture.size() != size) {
+            *slot = None;
+        }
+        let target = if let Some(texture) = slot {
+            texture
+        } else {
+            let (texture, _) = create_texture_with_fallback(EFFECT_TEXTURE_FORMATS, |fourcc| {
+                renderer.create_buffer(fourcc, size)
+            })
+            .context("error creating texture")?;
+            slot.insert(texture)
+        };
+
+        copy_texture(renderer, blurred, target).context("error copying texture")?;
+        self.written = true;
+
+        let bytes: u64 = self
+            .textures
+            .iter()
+            .flatten()
+            .map(|t| texture_bytes(t.size()))
+            .sum();
+        self.reservation.set(bytes);
+
+        Ok(())
+    }
+
+    /// Mixes the previous frame's blur into the current one and returns the result.
+    ///
+    /// Since the previous frame's texture is itself a mix, this keeps a running average of the
+    /// raw blur. Without a previous frame, this returns the current frame's copy as is.
+    fn blend_previous(
+        &self,
+        renderer: &mut GlesRenderer,
+        alpha: f32,
+    ) -> anyhow::Result<GlesTexture> {
+        let mut current = self.textures[self.current]
+            .clone()
+            .context("current texture is missing")?;
+        if let Some(previous) = self.previous() {
+            mix_texture(renderer, previous, &mut current, alpha)
+                .context("error mixing textures")?;
+        }
+        Ok(current)
+    }
+}
+
+fn copy_texture(
+    renderer: &mut GlesRenderer,
+    src: &GlesTexture,
+    dst: &GlesTexture,
+) -> Result<(), GlesError> {
+    let src_size = src.size();
+    let dst_size = dst.size();
+
+    renderer.with_context(|gl| unsafe {
+        while gl.GetError() != ffi::NO_ERROR {}
+
+        gl.Disable(ffi::SCISSOR_TEST);
+
+        let mut fbos = [0; 2];
+        gl.GenFramebuffers(fbos.len() as _, fbos.as_mut_ptr());
+
+        gl.BindFramebuffer(ffi::READ_FRAMEBUFFER, fbos[0]);
+        gl.FramebufferTexture2D(
+            ffi::READ_FRAMEBUFFER,
+            ffi::COLOR_ATTACHMENT0,
+            ffi::TEXTURE_2D,
+            src.tex_id(),
+            0,
+        );
+
+        gl.BindFramebuffer(ffi::DRAW_FRAMEBUFFER, fbos[1]);
+        gl.FramebufferTexture2D(
+            ffi::DRAW_FRAMEBUFFER,
+            ffi::COLOR_ATTACHMENT0,
+            ffi::TEXTURE_2D,
+            dst.tex_id(),
+            0,
+        );
+
+        gl.BlitFramebuffer(
+            0,
+            0,
+            src_size.w,
+            src_size.h,
+            0,
+            0,
+            dst_size.w,
+            dst_size.h,
+            ffi::COLOR_BUFFER_BIT,
+            ffi::LINEAR,
+        );
+
+        gl.BindFramebuffer(ffi::FRAMEBUFFER, 0);
+        gl.DeleteFramebuffers(fbos.len() as _, fbos.as_ptr());
+
+        if gl.GetError() != ffi::NO_ERROR {
+            Err(GlesError::BlitError)
+        } else {
+            Ok(())
         }
+    })?
+}
+
+/// Draws `src` on top of `dst` with the given alpha.
+///
+/// Blurred textures are opaque, so this results in `mix(dst, src, alpha)`.
+fn mix_texture(
+    renderer: &mut GlesRenderer,
+    src: &GlesTexture,
+    dst: &mut GlesTexture,
+    alpha: f32,
+) -> anyhow::Result<()> {
+    let size = dst.size().to_logical(1, Transform::Normal).to_physical(1);
+    let rect = Rectangle::from_size(size);
+
+    let mut fb = renderer.bind(dst).context("error binding texture")?;
+    let mut frame = renderer
+        .render(&mut fb, size, Transform::Normal)
+        .context("error starting frame")?;
+    frame
+        .render_texture_from_to(
+            src,
+            Rectangle::from_size(src.size().to_f64()),
+            rect,
+            &[rect],
+            &[],
+            Transform::Normal,
+            alpha,
+            None,
+            &[],
+        )
+        .context("error rendering texture")?;
+    frame.finish().context("error finishing frame")?;
+
+    Ok(())
+}
+
+/// Largest factor to divide the capture size by for zoomed-out effects.
+const MAX_CAPTURE_DOWNSCALE: i32 = 4;
+
+/// Granularity of framebuffer texture sizes.
+const FRAMEBUFFER_SIZE_STEP: i32 = 8;
+
+/// Returns the framebuffer texture size to use for capturing `needed` pixels.
+///
+/// Sizes are rounded up to a multiple of [`FRAMEBUFFER_SIZE_STEP`], or to a power of two if
+/// `power_of_two` is set. The current size is kept while it's at most one step larger than the
+/// rounded size, so that jitter around a boundary doesn't flip between two sizes. For powers of
+/// two, one step larger is twice the size.
+fn quantize_framebuffer_size(
+    needed: Size<i32, Buffer>,
+    current: Option<Size<i32, Buffer>>,
+    power_of_two: bool,
+) -> Size<i32, Buffer> {
+    let step = FRAMEBUFFER_SIZE_STEP;
+    let round_up = |x: i32| {
+        if power_of_two {
+            (x.max(step) as u32).next_power_of_two() as i32
+        } else {
+            (x.max(1) + step - 1) / step * step
+        }
+    };
+    let fits = |current: i32, needed: i32| {
+        let rounded = round_up(needed);
+        // One step up from a power of two is the next power of two.
+        let max = if power_of_two {
+            rounded * 2
+        } else {
+            rounded + step
+        };
+        needed.max(1) <= current && current <= max
+    };
+
+    match current {
+        Some(current) if fits(current.w, needed.w) && fits(current.h, needed.h) => current,
+        _ => Size::new(round_up(needed.w), round_up(needed.h)),
+    }
+}
+
+/// Returns the factor to divide the capture size by for an effect drawn at `zoom` of its size.
+///
+/// The factor is a power of two, so that it stays the same through most of a zoom animation and
+/// doesn't reallocate the textures every frame.
+fn capture_downscale(zoom: Scale<f64>) -> i32 {
+    // Don't go below the drawn resolution of either side.
+    let zoom = f64::max(zoom.x, zoom.y);
+
+    let mut factor = 1;
+    while factor < MAX_CAPTURE_DOWNSCALE && zoom * f64::from(factor * 2) <= 1. {
+        factor *= 2;
+    }
+    factor
+}
+
+/// Returns how many times more pixels the `size` texture has than the `needed` capture along
+/// each side.
+///
+/// The whole texture is stretched to the effect, so the blur offset, which is in texture pixels,
+/// has to grow by the same factor to keep the blur radius on screen.
+fn capture_oversample(needed: Size<i32, Buffer>, size: Size<i32, Buffer>) -> Scale<f64> {
+    let x = f64::from(size.w) / f64::from(needed.w.max(1));
+    let y = f64::from(size.h) / f64::from(needed.h.max(1));
+    Scale::from((x, y))
+}
+
+/// Returns the blur options for capturing `needed` pixels into a `size` texture.
+///
+/// `divisor` is how many times fewer pixels the capture has than the effect on screen.
+fn capture_blur_options(
+    options: BlurOptions,
+    needed: Size<i32, Buffer>,
+    size: Size<i32, Buffer>,
+    divisor: f64,
+    transform: Transform,
+) -> BlurOptions {
+    // Rounding up to a power of two can pad the sides by different factors. The offset grows by
+    // their geometric mean, and the axis scale makes up the difference along each side.
+    let oversample = capture_oversample(needed, size);
+    let mean = (oversample.x * oversample.y).sqrt();
+    let axis_scale = (oversample.x / mean, oversample.y / mean);
+
+    BlurOptions {
+        offset: options.offset * mean / divisor,
+        // The captured texture has the frame transform.
+        direction: options.direction.map(|dir| dir.transformed(transform)),
+        axis_scale: Some(axis_scale).filter(|(x, y)| x != y),
+        ..options
+    }
+}
+
+/// Scales `needed` down to fit into `max` pixels on either side, keeping the aspect ratio.
+///
+/// Returns the scaled size and the factor it was divided by. `max` of 0 disables the limit.
+fn cap_capture_size(needed: Size<i32, Buffer>, max: i32) -> (Size<i32, Buffer>, f64) {
+    let largest = needed.w.max(needed.h);
+    if max <= 0 || largest <= max {
+        return (needed, 1.);
+    }
+
+    let factor = f64::from(largest) / f64::from(max);
+    let div = |x: i32| ((f64::from(x) / factor).ceil() as i32).clamp(1, max);
+    (Size::new(div(needed.w), div(needed.h)), factor)
+}
+
+/// Limits both sides of a texture `size` to `max`, undoing any rounding up past it.
+fn clamp_texture_size(size: Size<i32, Buffer>, max: i32) -> Size<i32, Buffer> {
+    if max <= 0 {
+        return size;
+    }
+    Size::new(size.w.min(max), size.h.min(max))
+}
+
+/// Divides `size` by `factor`, rounding up.
+fn downscale_size(size: Size<i32, Buffer>, factor: i32) -> Size<i32, Buffer> {
+    let div = |x: i32| (x.max(1) + factor - 1) / factor;
+    Size::new(div(size.w), div(size.h))
+}
+
+/// Returns the part of the `size` texture captured from `dst` that `blit_src` ends up in.
+///
+/// All rectangles are in framebuffer coordinates. `blit_src` is the on-output part of `dst`, so
+/// without an off-screen color they're the same, and the blit covers the whole texture.
+fn blit_target(
+    dst: Rectangle<i32, Physical>,
+    blit_src: Rectangle<i32, Physical>,
+    size: Size<i32, Buffer>,
+) -> Rectangle<i32, Buffer> {
+    let scale_x = f64::from(size.w) / f64::from(dst.size.w);
+    let scale_y = f64::from(size.h) / f64::from(dst.size.h);
+    let x = |x: i32| (f64::from(x - dst.loc.x) * scale_x).round() as i32;
+    let y = |y: i32| (f64::from(y - dst.loc.y) * scale_y).round() as i32;
+
+    let x1 = x(blit_src.loc.x);
+    let y1 = y(blit_src.loc.y);
+    let x2 = x(blit_src.loc.x + blit_src.size.w);
+    let y2 = y(blit_src.loc.y + blit_src.size.h);
+    Rectangle::new((x1, y1).into(), (x2 - x1, y2 - y1).into())
+}
+
+/// Returns the framebuffer texture size to capture `needed` pixels at, or `None` to keep drawing
+/// the previous capture.
+///
+/// During an interactive resize, the size changes every frame, so an existing capture is
+/// stretched to the new size instead of reallocating and blurring the textures again.
+fn capture_size(
+    needed: Size<i32, Buffer>,
+    current: Option<Size<i32, Buffer>>,
+    resizing: bool,
+    has_capture: bool,
+    shrink_threshold: f64,
+    power_of_two: bool,
+) -> Option<Size<i32, Buffer>> {
+    if resizing && has_capture {
+        return None;
+    }
+
+    // Keep a larger texture while the effect shrinks by less than the threshold. The whole
+    // texture is stretched to the effect anyway, so it just captures at a higher resolution.
+    let fits = |current: i32, needed: i32| {
+        needed <= current && f64::from(current - needed) <= f64::from(current) * shrink_threshold
+    };
+    if let Some(current) = current {
+        if fits(current.w, needed.w) && fits(current.h, needed.h) {
+            return Some(current);
+        }
+    }
+
+    Some(quantize_framebuffer_size(needed, current, power_of_two))
+}
+
+/// Returns the framebuffer size to prewarm for an effect that will need `needed`.
+///
+/// This is the size that the first capture picks without an existing texture.
+fn prewarm_size(needed: Size<i32, Buffer>, power_of_two: bool) -> Size<i32, Buffer> {
+    quantize_framebuffer_size(needed, None, power_of_two)
+}
+
+/// Creates a framebuffer texture along with its reservation in the texture budget.
+fn create_framebuffer<R: EffectRenderer>(
+    renderer: &mut R,
+    size: Size<i32, Buffer>,
+) -> Result<(R::Texture, TextureReservation), GlesError> {
+    let (texture, _) = create_texture_with_fallback(EFFECT_TEXTURE_FORMATS, |fourcc| {
+        renderer.create_effect_texture(fourcc, size)
+    })?;
+    let mut reservation = TextureReservation::default();
+    reservation.set(texture_bytes(size));
+    Ok((texture, reservation))
+}
+
+/// Returns the framebuffer texture to capture into at `size`, recreating it if needed.
+fn ensure_framebuffer<'a, R: EffectRenderer>(
+    renderer: &mut R,
+    framebuffer: &'a mut Option<R::Texture>,
+    reservation: &mut TextureReservation,
+    size: Size<i32, Buffer>,
+) -> Result<&'a R::Texture, GlesError> {
+    // When blur is off, intermediate aliases the framebuffer texture. The caller resets
+    // intermediate before this, so the framebuffer is normally unique here. If someone else still
+    // holds on to it, we mustn't blit over their contents, so recreate it.
+    if let Some(fb) = &*framebuffer {
+        if let Some(reason) = framebuffer_recreate_reason(fb.effect_size(), size, fb.is_unique()) {
+            trace!("recreating framebuffer texture: {reason}");
+            *framebuffer = None;
+        }
+    }
+
+    if framebuffer.is_none() {
+        trace!("creating framebuffer texture sized {} × {}", size.w, size.h);
+        let (texture, new_reservation) = create_framebuffer(renderer, size)?;
+        *reservation = new_reservation;
+        *framebuffer = Some(texture);
+    }
+
+    Ok(framebuffer.as_ref().unwrap())
+}
+
+/// How [`framebuffer_for_capture()`] picks the framebuffer size.
+#[derive(Debug, Clone, Copy)]
+struct CaptureSizing {
+    /// Whether the owning surface is being interactively resized.
+    resizing: bool,
+    shrink_threshold: f64,
+    power_of_two: bool,
+    max_texture_size: i32,
+}
+
+/// Returns the framebuffer texture to capture `needed` pixels into, or `None` to keep drawing
+/// the previous capture during a resize.
+///
+/// A new capture resets `intermediate` first, since it may alias the framebuffer.
+fn framebuffer_for_capture<'a, R: EffectRenderer>(
+    renderer: &mut R,
+    framebuffer: &'a mut Option<R::Texture>,
+    reservation: &mut TextureReservation,
+    intermediate: &mut Option<R::Texture>,
+    needed: Size<i32, Buffer>,
+    sizing: CaptureSizing,
+) -> Result<Option<&'a R::Texture>, GlesError> {
+    let current = framebuffer.as_ref().map(EffectTexture::effect_size);
+    let has_capture = intermediate.is_some();
+    let Some(size) = capture_size(
+        needed,
+        current,
+        sizing.resizing,
+        has_capture,
+        sizing.shrink_threshold,
+        sizing.power_of_two,
+    ) else {
+        return Ok(None);
+    };
+    let size = clamp_texture_size(size, sizing.max_texture_size);
+
+    *intermediate = None;
+    ensure_framebuffer(renderer, framebuffer, reservation, size).map(Some)
+}
+
+fn framebuffer_recreate_reason(
+    old_size: Size<i32, Buffer>,
+    new_size: Size<i32, Buffer>,
+    is_unique: bool,
+) -> Option<&'static str> {
+    if old_size != new_size {
+        Some("size changed")
+    } else if !is_unique {
+        Some("not unique")
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use smithay::backend::allocator::Fourcc;
+    use smithay::backend::renderer::element::Kind;
+    use smithay::backend::renderer::gles::UniformValue;
+    use smithay::backend::renderer::ExportMem as _;
+    use smithay::utils::Point;
+
+    use super::*;
+    use crate::render_helpers::solid_color::SolidColorRenderElement;
+    use crate::render_helpers::{copy_framebuffer, create_texture, test_renderer};
+
+    #[test]
+    fn framebuffer_recreation() {
+        let small = Size::new(100, 100);
+        let large = Size::new(200, 100);
+
+        // Blur off: intermediate aliased the framebuffer, but was reset before the check.
+        assert_eq!(framebuffer_recreate_reason(small, small, true), None);
+        // Size change.
+        assert_eq!(
+            framebuffer_recreate_reason(small, large, true),
+            Some("size changed")
+        );
+        // Blur on with a stale alias still alive somewhere.
+        assert_eq!(
+            framebuffer_recreate_reason(large, large, false),
+            Some("not unique")
+        );
+    }
+
+    #[test]
+    fn first_capture_reuses_prewarmed_framebuffer() {
+        let needed = Size::new(757, 413);
+        for power_of_two in [false, true] {
+            let prewarmed = prewarm_size(needed, power_of_two);
+
+            // The first capture after the prewarm keeps the prewarmed texture.
+            let size =
+                capture_size(needed, Some(prewarmed), false, false, 0.1, power_of_two).unwrap();
+            assert_eq!(size, prewarmed);
+            assert_eq!(framebuffer_recreate_reason(prewarmed, size, true), None);
+        }
+
+        // The textures are taken over once, and only on the context they were created on.
+        let prewarmed = || Prewarmed {
+            framebuffer: "framebuffer",
+            reservation: TextureReservation::default(),
+            blur: None::<()>,
+            context_id: 1,
+        };
+        let mut slot = Some(prewarmed());
+        let taken = Prewarmed::take(&mut slot, &1).unwrap();
+        assert_eq!(taken.framebuffer, "framebuffer");
+        assert!(Prewarmed::take(&mut slot, &1).is_none());
+
+        let mut slot = Some(prewarmed());
+        assert!(Prewarmed::take(&mut slot, &2).is_none());
+        assert!(slot.is_none());
+    }
+
+    /// Renderer that records the textures it creates.
+    #[derive(Default)]
+    struct StubRenderer {
+        context: u32,
+        created: Vec<Size<i32, Buffer>>,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct StubTexture(Rc<Size<i32, Buffer>>);
+
+    impl EffectRenderer for StubRenderer {
+        type Texture = StubTexture;
+        type ContextId = u32;
+
+        fn effect_context_id(&self) -> u32 {
+            self.context
+        }
+
+        fn create_effect_texture(
+            &mut self,
+            _fourcc: Fourcc,
+            size: Size<i32, Buffer>,
+        ) -> Result<StubTexture, GlesError> {
+            self.created.push(size);
+            Ok(StubTexture(Rc::new(size)))
+        }
+    }
+
+    impl EffectTexture for StubTexture {
+        fn effect_size(&self) -> Size<i32, Buffer> {
+            *self.0
+        }
+
+        fn is_unique(&self) -> bool {
+            Rc::strong_count(&self.0) == 1
+        }
+    }
+
+    /// Renders a frame filled with `color` with `elem` on top, and returns the pixels.
+    fn render_frame(
+        renderer: &mut GlesRenderer,
+        elem: &FramebufferEffectElement,
+        cache: &UserDataMap,
+        color: Color32F,
+    ) -> Vec<[u8; 4]> {
+        let size = Size::new(64, 64);
+        let mut texture = create_texture(renderer, size, Fourcc::Abgr8888).unwrap();
+        let mut target = renderer.bind(&mut texture).unwrap();
+        let mut frame = renderer
+            .render(&mut target, size, Transform::Normal)
+            .unwrap();
+        frame.clear(color, &[Rectangle::from_size(size)]).unwrap();
+
+        let src = elem.src();
+        let dst = elem.geometry(Scale::from(1.));
+        let damage = Rectangle::from_size(dst.size);
+        elem.capture_framebuffer(&mut frame, src, dst, cache)
+            .unwrap();
+        elem.draw(&mut frame, src, dst, &[damage], &[], Some(cache))
+            .unwrap();
+        let _sync = frame.finish().unwrap();
+
+        let mapping = copy_framebuffer(renderer, &target, Fourcc::Abgr8888).unwrap();
+        let bytes = renderer.map_texture(&mapping).unwrap();
+        bytes
+            .chunks_exact(4)
+            .map(|px| [px[0], px[1], px[2], px[3]])
+            .collect()
+    }
+
+    #[test]
+    fn egl_blur_off_resize_blur_on() {
+        let mut renderer = test_renderer();
+        let cache = UserDataMap::new();
+        let inner = |cache: &UserDataMap| {
+            let inner = cache.get::<RefCell<Inner>>().unwrap().borrow();
+            let fb = inner.framebuffer.as_ref().unwrap();
+            let intermediate = inner.intermediate.as_ref().unwrap();
+            (fb.size(), fb.tex_id() == intermediate.tex_id())
+        };
+
+        // Darkened, so that the drawn effect differs from the frame around it.
+        let mut elem = test_element(Rectangle::new(Point::new(8., 8.), Size::new(32., 32.)));
+        elem.blur_options = None;
+        elem.postprocess.tint = Color32F::new(0., 0., 0., 0.5);
+
+        // Every frame has a different color, so a stale texture would show up.
+        let red = Color32F::new(1., 0., 0., 1.);
+        let green = Color32F::new(0., 1., 0., 1.);
+        let blue = Color32F::new(0., 0., 1., 1.);
+
+        // Blur off: the intermediate texture is the framebuffer.
+        let pixels = render_frame(&mut renderer, &elem, &cache, red);
+        assert_eq!(inner(&cache), (Size::new(32, 32), true));
+        assert_ne!(pixels[20 * 64 + 20], pixels[0]);
+
+        // A size change recreates the framebuffer, and the intermediate texture follows it. The
+        // result is the same as for an effect drawn for the first time.
+        elem.geometry.size.w = 48.;
+        elem.clip_geo = elem.geometry;
+        let pixels = render_frame(&mut renderer, &elem, &cache, green);
+        assert_eq!(inner(&cache), (Size::new(48, 32), true));
+        assert_eq!(
+            pixels,
+            render_frame(&mut renderer, &elem, &UserDataMap::new(), green)
+        );
+
+        // Blur on: the intermediate texture is the blurred one, not the framebuffer.
+        elem.blur_options = Some(BlurOptions {
+            passes: 2,
+            offset: 1.,
+            ..Default::default()
+        });
+        let pixels = render_frame(&mut renderer, &elem, &cache, blue);
+        assert_eq!(inner(&cache), (Size::new(48, 32), false));
+        assert_eq!(
+            pixels,
+            render_frame(&mut renderer, &elem, &UserDataMap::new(), blue)
+        );
+    }
+
+    #[test]
+    fn framebuffer_is_reused_across_captures() {
+        let small = Size::new(100, 100);
+        let large = Size::new(200, 100);
+
+        let mut renderer = StubRenderer::default();
+        let mut framebuffer = None;
+        let mut reservation = TextureReservation::default();
+        let mut capture = |renderer: &mut StubRenderer, framebuffer: &mut _, size| {
+            ensure_framebuffer(renderer, framebuffer, &mut reservation, size)
+                .unwrap()
+                .clone()
+        };
+
+        // Repeated captures at the same size reuse the framebuffer.
+        drop(capture(&mut renderer, &mut framebuffer, small));
+        drop(capture(&mut renderer, &mut framebuffer, small));
+        assert_eq!(renderer.created, [small]);
+
+        // A size change recreates it.
+        drop(capture(&mut renderer, &mut framebuffer, large));
+        assert_eq!(renderer.created, [small, large]);
+
+        // So does a stale alias that is still alive.
+        let alias = capture(&mut renderer, &mut framebuffer, large);
+        let fb = capture(&mut renderer, &mut framebuffer, large);
+        assert_eq!(renderer.created, [small, large, large]);
+        assert!(!Rc::ptr_eq(&alias.0, &fb.0));
+    }
+
+    #[test]
+    fn capture_takes_over_prewarmed_framebuffer() {
+        let size = prewarm_size(Size::new(757, 413), false);
+
+        let mut renderer = StubRenderer {
+            context: 1,
+            ..Default::default()
+        };
+        let (texture, reservation) = create_framebuffer(&mut renderer, size).unwrap();
+        let mut slot = Some(Prewarmed {
+            framebuffer: texture,
+            reservation,
+            blur: None::<()>,
+            context_id: renderer.effect_context_id(),
+        });
+
+        // The first capture adopts the prewarmed framebuffer instead of creating one.
+        let prewarmed = Prewarmed::take(&mut slot, &renderer.effect_context_id()).unwrap();
+        let mut framebuffer = Some(prewarmed.framebuffer);
+        let mut reservation = prewarmed.reservation;
+        ensure_framebuffer(&mut renderer, &mut framebuffer, &mut reservation, size).unwrap();
+        assert_eq!(renderer.created, [size]);
+
+        // On another context, the capture creates its own.
+        let (texture, reservation) = create_framebuffer(&mut renderer, size).unwrap();
+        let mut slot = Some(Prewarmed {
+            framebuffer: texture,
+            reservation,
+            blur: None::<()>,
+            context_id: 1,
+        });
+        renderer.context = 2;
+        assert!(Prewarmed::take(&mut slot, &renderer.effect_context_id()).is_none());
+        let mut framebuffer = None;
+        let mut reservation = TextureReservation::default();
+        ensure_framebuffer(&mut renderer, &mut framebuffer, &mut reservation, size).unwrap();
+        assert_eq!(renderer.created, [size, size, size]);
+    }
+
+    #[test]
+    fn resize_stretches_previous_capture() {
+        let mut renderer = StubRenderer::default();
+        let mut framebuffer = None;
+        let mut reservation = TextureReservation::default();
+        let mut intermediate = None;
+        let mut effect = FramebufferEffect::new();
+
+        // Captures like capture_framebuffer(), and returns whether it captured anew.
+        let mut capture = |renderer: &mut StubRenderer, resizing, needed| {
+            let sizing = CaptureSizing {
+                resizing,
+                shrink_threshold: 0.,
+                power_of_two: false,
+                max_texture_size: 0,
+            };
+            let fb = framebuffer_for_capture(
+                renderer,
+                &mut framebuffer,
+                &mut reservation,
+                &mut intermediate,
+                needed,
+                sizing,
+            )
+            .unwrap();
+            // Without blur, the effect draws from an alias of the framebuffer.
+            let Some(fb) = fb.cloned() else {
+                return false;
+            };
+            intermediate = Some(fb);
+            true
+        };
+
+        assert!(capture(&mut renderer, effect.resizing, Size::new(400, 300)));
+        assert_eq!(renderer.created, [Size::new(400, 304)]);
+
+        // During the resize, the previous capture is stretched rather than reallocated.
+        effect.set_resizing(true);
+        let commit = effect.commit;
+        for w in (400..800).step_by(37) {
+            assert!(!capture(&mut renderer, effect.resizing, Size::new(w, 300)));
+        }
+        assert_eq!(renderer.created, [Size::new(400, 304)]);
+
+        // Ending the resize damages the effect, and the next capture is at the final size.
+        effect.set_resizing(false);
+        assert_ne!(effect.commit, commit);
+        assert!(capture(&mut renderer, effect.resizing, Size::new(800, 300)));
+        assert_eq!(renderer.created, [Size::new(400, 304), Size::new(800, 304)]);
+    }
+
+    #[test]
+    fn resize_without_previous_capture_captures() {
+        let mut renderer = StubRenderer::default();
+        let sizing = CaptureSizing {
+            resizing: true,
+            shrink_threshold: 0.,
+            power_of_two: false,
+            max_texture_size: 0,
+        };
+
+        // Without a previous capture, there's nothing to stretch.
+        let fb = framebuffer_for_capture(
+            &mut renderer,
+            &mut None,
+            &mut TextureReservation::default(),
+            &mut None,
+            Size::new(100, 100),
+            sizing,
+        )
+        .unwrap();
+        assert!(fb.is_some());
+        assert_eq!(renderer.created, [Size::new(104, 104)]);
+    }
+
+    #[test]
+    fn small_shrink_reuses_larger_framebuffer() {
+        let current = Some(Size::new(808, 608));
+
+        // Within 10%, the existing framebuffer is kept, even past the size step hysteresis.
+        let size = capture_size(Size::new(760, 560), current, false, true, 0.1, false).unwrap();
+        assert_eq!(size, Size::new(808, 608));
+        assert_eq!(framebuffer_recreate_reason(size, size, true), None);
+
+        // Without a threshold, the same shrink reallocates.
+        let size = capture_size(Size::new(760, 560), current, false, true, 0., false).unwrap();
+        assert_eq!(size, Size::new(760, 560));
+
+        // Shrinking past the threshold reallocates.
+        let size = capture_size(Size::new(700, 560), current, false, true, 0.1, false).unwrap();
+        assert_eq!(size, Size::new(704, 560));
+
+        // Growing always reallocates.
+        let size = capture_size(Size::new(810, 560), current, false, true, 0.1, false).unwrap();
+        assert_eq!(size, Size::new(816, 560));
+    }
+
+    #[test]
+    fn framebuffer_size_is_stable_under_small_zoom_changes() {
+        let mut current = None;
+        let mut sizes = Vec::new();
+        for (w, h) in [
+            (801, 601),
+            (800, 600),
+            (799, 599),
+            (801, 600),
+            (800, 601),
+            (796, 598),
+        ] {
+            let size = quantize_framebuffer_size(Size::new(w, h), current, false);
+            current = Some(size);
+            sizes.push((size.w, size.h));
+        }
+        assert_eq!(sizes, [(808, 608); 6]);
+
+        // Jitter around a step boundary keeps the larger size.
+        let current = quantize_framebuffer_size(Size::new(801, 600), None, false);
+        assert_eq!(current, Size::new(808, 600));
+        assert_eq!(
+            quantize_framebuffer_size(Size::new(800, 600), Some(current), false),
+            current
+        );
+
+        // One step larger than the rounded size is kept, anything more reallocates.
+        assert_eq!(
+            quantize_framebuffer_size(Size::new(793, 600), Some(current), false),
+            current
+        );
+        assert_eq!(
+            quantize_framebuffer_size(Size::new(792, 600), Some(current), false),
+            Size::new(792, 600)
+        );
+
+        // Near-zero sizes at extreme zoom still get a valid texture.
+        assert_eq!(
+            quantize_framebuffer_size(Size::new(0, 1), None, false),
+            Size::new(8, 8)
+        );
+    }
+
+    #[test]
+    fn power_of_two_sizes_pool_nearby_sizes() {
+        let a = quantize_framebuffer_size(Size::new(700, 450), None, true);
+        let b = quantize_framebuffer_size(Size::new(760, 500), None, true);
+        assert_eq!(a, Size::new(1024, 512));
+        assert_eq!(a, b);
+
+        // With the step rounding, the same sizes get textures of their own.
+        assert_ne!(
+            quantize_framebuffer_size(Size::new(700, 450), None, false),
+            quantize_framebuffer_size(Size::new(760, 500), None, false)
+        );
+
+        // An exact power of two isn't padded.
+        assert_eq!(
+            quantize_framebuffer_size(Size::new(512, 256), None, true),
+            Size::new(512, 256)
+        );
+
+        // The current texture is kept through jitter at a power of two boundary, but not once
+        // it's more than twice the rounded size.
+        assert_eq!(
+            quantize_framebuffer_size(Size::new(512, 256), Some(a), true),
+            a
+        );
+        assert_eq!(
+            quantize_framebuffer_size(Size::new(256, 256), Some(a), true),
+            Size::new(256, 256)
+        );
+
+        // The blur offset grows with the padding to keep its on-screen radius.
+        let oversample = capture_oversample(Size::new(512, 256), Size::new(1024, 512));
+        assert_eq!(oversample, Scale::from(2.));
+        assert_eq!(capture_oversample(a, a), Scale::from(1.));
+        let options = BlurOptions {
+            offset: 3.,
+            ..Default::default()
+        };
+        let padded = capture_blur_options(
+            options,
+            Size::new(512, 256),
+            Size::new(1024, 512),
+            1.,
+            Transform::Normal,
+        );
+        assert_eq!(padded.offset, 6.);
+        assert_eq!(padded.axis_scale, None);
+
+        // Unevenly padded sides get their own offsets.
+        let needed = Size::new(700, 450);
+        let padded = capture_blur_options(options, needed, a, 1., Transform::Normal);
+        let (x, y) = padded.axis_scale.unwrap();
+        let offset_x = padded.offset * x;
+        let offset_y = padded.offset * y;
+        assert!((offset_x - 3. * 1024. / 700.).abs() < 1e-9, "{offset_x}");
+        assert!((offset_y - 3. * 512. / 450.).abs() < 1e-9, "{offset_y}");
+    }
+
+    #[test]
+    fn zoomed_out_effect_captures_at_lower_resolution() {
+        let needed = Size::new(800, 600);
+        let capture = |zoom: f64| {
+            let factor = capture_downscale(Scale::from(zoom));
+            quantize_framebuffer_size(downscale_size(needed, factor), None, false)
+        };
+
+        assert_eq!(capture(1.), Size::new(800, 600));
+        assert_eq!(capture(0.9), Size::new(800, 600));
+        assert_eq!(capture(0.5), Size::new(400, 304));
+        assert_eq!(capture(0.3), Size::new(400, 304));
+        assert_eq!(capture(0.25), Size::new(200, 152));
+        // The factor is capped.
+        assert_eq!(capture(0.01), Size::new(200, 152));
+
+        // The less zoomed-out side decides.
+        assert_eq!(capture_downscale(Scale::from((0.2, 0.6))), 1);
+
+        // Zooming in doesn't capture at a higher resolution.
+        assert_eq!(capture_downscale(Scale::from(2.)), 1);
+    }
+
+    #[test]
+    fn huge_effect_capture_is_capped() {
+        // A fullscreen effect on an 8K output.
+        let needed = Size::new(7680, 4320);
+
+        let (capped, factor) = cap_capture_size(needed, 2560);
+        assert_eq!(capped, Size::new(2560, 1440));
+        assert_eq!(factor, 3.);
+        assert_eq!(quantize_framebuffer_size(capped, None, false), capped);
+
+        // Rounding up to a power of two doesn't go past the cap either.
+        let size = quantize_framebuffer_size(capped, None, true);
+        let size = clamp_texture_size(size, 2560);
+        assert_eq!(size, Size::new(2560, 2048));
+
+        // The blur offset shrinks together with the capture to keep its on-screen radius.
+        let options = BlurOptions {
+            offset: 3.,
+            ..Default::default()
+        };
+        let options = capture_blur_options(options, capped, size, factor, Transform::Normal);
+        let (x, y) = options.axis_scale.unwrap();
+        assert!(options.offset * x < 3.);
+        assert!(options.offset * y < 3.);
+
+        // Smaller effects and a disabled cap are left alone.
+        let small = Size::new(1920, 1080);
+        assert_eq!(cap_capture_size(small, 2560), (small, 1.));
+        assert_eq!(cap_capture_size(needed, 0), (needed, 1.));
+        assert_eq!(clamp_texture_size(needed, 0), needed);
+    }
+
+    #[test]
+    fn temporal_history_keeps_previous_frame() {
+        let key = (Rectangle::from_size(Size::new(100, 100)), Transform::Normal);
+        let mut history = TemporalHistory::<u32>::default();
+
+        // First frame: nothing to blend with yet.
+        history.advance(key);
+        assert_eq!(history.previous(), None);
+        *history.current_mut() = Some(1);
+        history.written = true;
+
+        // Second frame: the first frame's blur is retained for blending.
+        history.advance(key);
+        assert_eq!(history.previous(), Some(&1));
+        *history.current_mut() = Some(2);
+        history.written = true;
+
+        history.advance(key);
+        assert_eq!(history.previous(), Some(&2));
+        // The oldest texture is reused for the current frame.
+        assert_eq!(history.current_mut(), &Some(1));
+        *history.current_mut() = Some(3);
+        history.written = true;
+
+        // The window moved: don't blend the stale blur to avoid ghosting.
+        let moved = (
+            Rectangle::new(Point::new(10, 0), Size::new(100, 100)),
+            key.1,
+        );
+        history.advance(moved);
+        assert_eq!(history.previous(), None);
+        history.written = true;
+
+        history.advance(moved);
+        assert!(history.previous().is_some());
+
+        // A frame that failed to store its blur doesn't count as the previous one.
+        history.advance(moved);
+        assert_eq!(history.previous(), None);
+
+        history.reset();
+        history.advance(moved);
+        assert_eq!(history.previous(), None);
+    }
+
+    fn test_element(geometry: Rectangle<f64, Logical>) -> FramebufferEffectElement {
+        FramebufferEffectElement {
+            id: Id::new(),
+            commit: CommitCounter::default(),
+            geometry,
+            clip_geo: geometry,
+            corner_radius: CornerRadius::default(),
+            subregion: None,
+            visible: None,
+            edge_falloff: None,
+            scale: 1.,
+            blur_options: Some(BlurOptions::default()),
+            postprocess: Postprocess {
+                noise: 0.,
+                noise_seed: 0.,
+                saturation: 1.,
+                tint: Color32F::TRANSPARENT,
+                adaptive_tint: false,
+                alpha: 1.,
+                edge_ring: None,
+            },
+            resizing: false,
+            pixel_snap: false,
+            offscreen_color: None,
+            shrink_threshold: 0.,
+            power_of_two_sizes: false,
+            max_texture_size: 0,
+            capture_failures: Rc::new(CaptureFailures::default()),
+            texture_usage: Rc::new(TextureUsage::default()),
+            prewarmed: Rc::new(RefCell::new(None)),
+        }
+    }
+
+    #[test]
+    fn opaque_effect_reports_opaque_regions_without_corners() {
+        let scale = Scale::from(1.);
+        let geometry = Rectangle::new(Point::new(10., 10.), Size::new(100., 50.));
+        let mut elem = test_element(geometry);
+        elem.corner_radius = CornerRadius::from(8.);
+
+        // A translucent tint lets the captured background through, which may be transparent.
+        elem.postprocess.tint = Color32F::new(0., 0., 0., 0.5);
+        assert!(elem.opaque_regions(scale).is_empty());
+
+        // An opaque tint covers the whole element except for the rounded corners.
+        elem.postprocess.tint = Color32F::new(0., 0., 0., 1.);
+        let rects = elem.opaque_regions(scale).to_vec();
+        let rect = |x, y, w, h| Rectangle::<i32, Physical>::new(Point::new(x, y), Size::new(w, h));
+        let bounds = rect(0, 0, 100, 50);
+        let corners = [
+            rect(0, 0, 8, 8),
+            rect(92, 0, 8, 8),
+            rect(92, 42, 8, 8),
+            rect(0, 42, 8, 8),
+        ];
+        for r in &rects {
+            assert!(bounds.contains_rect(*r));
+            assert!(corners.iter().all(|corner| !corner.overlaps(*r)));
+        }
+        let area: i32 = rects.iter().map(|r| r.size.w * r.size.h).sum();
+        assert_eq!(area, 100 * 50 - 4 * 8 * 8);
+
+        // Effect opacity and the adaptive tint make it translucent again.
+        let faded = FramebufferEffectElement {
+            postprocess: Postprocess {
+                alpha: 0.9,
+                ..elem.postprocess
+            },
+            ..elem.clone()
+        };
+        assert!(faded.opaque_regions(scale).is_empty());
+        let adaptive = FramebufferEffectElement {
+            postprocess: Postprocess {
+                adaptive_tint: true,
+                ..elem.postprocess
+            },
+            ..elem.clone()
+        };
+        assert!(adaptive.opaque_regions(scale).is_empty());
+
+        // So does a client region, which may leave holes.
+        let region = FramebufferEffectElement {
+            subregion: Some(TransformedRegion::new(
+                Arc::new(vec![Rectangle::from_size(Size::new(10, 10))]),
+                Scale::from(1.),
+                Point::new(0., 0.),
+            )),
+            ..elem
+        };
+        assert!(region.opaque_regions(scale).is_empty());
+    }
+
+    #[test]
+    fn fully_occluded_element_skips_capture() {
+        let solid = |x: f64, w: f64, color: Color32F| {
+            let geo = Rectangle::new(Point::new(x, 0.), Size::new(w, 100.));
+            SolidColorRenderElement::new(
+                Id::new(),
+                geo,
+                CommitCounter::default(),
+                color,
+                Kind::Unspecified,
+            )
+        };
+        let scale = Scale::from(1.);
+
+        let covered = test_element(Rectangle::from_size(Size::new(100., 100.)));
+        let peeking = test_element(Rectangle::from_size(Size::new(120., 100.)));
+        let behind_translucent =
+            test_element(Rectangle::new(Point::new(200., 0.), Size::new(100., 100.)));
+
+        let mut tracker = OcclusionTracker::default();
+        // Two opaque windows side by side together cover the first effect.
+        tracker.add(&solid(0., 60., Color32F::new(0., 0., 0., 1.)), scale);
+        tracker.add(&solid(60., 40., Color32F::new(0., 0., 0., 1.)), scale);
+        tracker.add(&solid(200., 100., Color32F::new(0., 0., 0., 0.5)), scale);
+        tracker.add(&covered, scale);
+        tracker.add(&peeking, scale);
+        tracker.add(&behind_translucent, scale);
+        tracker.apply();
+
+        assert!(is_occluded(&covered.id));
+        assert!(!is_occluded(&peeking.id));
+        assert!(!is_occluded(&behind_translucent.id));
+
+        OcclusionTracker::clear();
+        assert!(!is_occluded(&covered.id));
+    }
+
+    #[test]
+    fn repeated_capture_failures_stick() {
+        let effect = FramebufferEffect::new();
+        let params = || RenderParams {
+            geometry: Rectangle::from_size(Size::new(100., 100.)),
+            subregion: None,
+            visible: None,
+            clip: None,
+            scale: 1.,
+            alpha: 1.,
+            edge_falloff: None,
+            velocity: Point::new(0., 0.),
+        };
+        let postprocess = test_element(Rectangle::default()).postprocess;
+        let elem = effect.render(None, params(), None, postprocess);
+
+        // A success in between starts the count over.
+        for _ in 1..MAX_CAPTURE_FAILURES {
+            elem.report_capture(false);
+        }
+        elem.report_capture(true);
+        assert!(!effect.is_capture_unsupported());
+
+        for _ in 0..MAX_CAPTURE_FAILURES {
+            elem.report_capture(false);
+        }
+        assert!(effect.is_capture_unsupported());
+
+        // Once given up on, capturing isn't retried, including by elements of later frames.
+        let elem = effect.render(None, params(), None, postprocess);
+        elem.report_capture(true);
+        assert!(effect.is_capture_unsupported());
+    }
+
+    #[test]
+    fn pointer_on_top_is_not_captured() {
+        let pointer = SolidColorRenderElement::new(
+            Id::new(),
+            Rectangle::from_size(Size::new(16., 16.)),
+            CommitCounter::default(),
+            Color32F::new(1., 1., 1., 1.),
+            Kind::Cursor,
+        );
+        let effect = test_element(Rectangle::from_size(Size::new(100., 100.)));
+
+        // The pointer is pushed first, so it's drawn after the effect captures the framebuffer.
+        let mut order = CaptureOrder::default();
+        order.add(&pointer, true);
+        order.add(&effect, false);
+        assert!(!order.captures_overlay());
+
+        // Below the effect, the pointer would be blurred into the backdrop.
+        let mut order = CaptureOrder::default();
+        order.add(&effect, false);
+        order.add(&pointer, true);
+        assert!(order.captures_overlay());
+    }
+
+    #[test]
+    fn fullscreen_blur_draws_directly() {
+        let output_rect = Rectangle::from_size(Size::new(100, 100));
+        let mut elem = test_element(Rectangle::from_size(Size::new(100., 100.)));
+        elem.blur_options = Some(BlurOptions {
+            passes: 3,
+            offset: 3.,
+            ..Default::default()
+        });
+        assert!(elem.draws_blur_directly(output_rect, output_rect));
+
+        // Not covering the whole output.
+        let smaller = Rectangle::from_size(Size::new(50, 100));
+        assert!(!elem.draws_blur_directly(smaller, output_rect));
+
+        // Anything that needs the shader or blending with an alpha.
+        let rounded = FramebufferEffectElement {
+            corner_radius: CornerRadius::from(8.),
+            ..elem.clone()
+        };
+        assert!(!rounded.draws_blur_directly(output_rect, output_rect));
+
+        let mut transparent = elem.clone();
+        transparent.postprocess.alpha = 0.5;
+        assert!(!transparent.draws_blur_directly(output_rect, output_rect));
+
+        // Temporal blending needs the blurred texture.
+        let mut temporal = elem.clone();
+        temporal.blur_options.as_mut().unwrap().temporal_blend = 0.5;
+        assert!(!temporal.draws_blur_directly(output_rect, output_rect));
+
+        // Without blur, there's no last pass to draw.
+        let unblurred = FramebufferEffectElement {
+            blur_options: None,
+            ..elem
+        };
+        assert!(!unblurred.draws_blur_directly(output_rect, output_rect));
+    }
+
+    #[test]
+    fn plain_blur_skips_shader() {
+        let mut elem = test_element(Rectangle::from_size(Size::new(100., 100.)));
+        elem.postprocess.alpha = 0.5;
+        assert!(!elem.needs_shader());
+
+        let rounded = FramebufferEffectElement {
+            corner_radius: CornerRadius::from(8.),
+            ..elem.clone()
+        };
+        assert!(rounded.needs_shader());
+
+        let clipped = FramebufferEffectElement {
+            clip_geo: Rectangle::from_size(Size::new(50., 100.)),
+            ..elem.clone()
+        };
+        assert!(clipped.needs_shader());
+
+        let noisy = FramebufferEffectElement {
+            postprocess: Postprocess {
+                noise: 0.02,
+                ..elem.postprocess
+            },
+            ..elem.clone()
+        };
+        assert!(noisy.needs_shader());
+
+        let tinted = FramebufferEffectElement {
+            postprocess: Postprocess {
+                tint: Color32F::new(0., 0., 0., 0.1),
+                ..elem.postprocess
+            },
+            ..elem
+        };
+        assert!(tinted.needs_shader());
+    }
+
+    #[test]
+    fn visible_region_limits_capture() {
+        let geometry = Rectangle::new(Point::new(10., 10.), Size::new(100., 100.));
+        let src = Rectangle::from_size(Size::new(100., 100.));
+        // Drawn at scale 2.
+        let dst = Rectangle::new(Point::new(20, 20), Size::new(200, 200));
+        let output_rect = Rectangle::from_size(Size::new(400, 400));
+
+        let mut elem = test_element(geometry);
+        assert_eq!(elem.clamp_rect(src, dst, output_rect), Some(output_rect));
+
+        // Only the left half is visible, so only the left half is captured.
+        elem.visible = Some(Rectangle::new(Point::new(10., 10.), Size::new(50., 100.)));
+        let clamp_rect = elem.clamp_rect(src, dst, output_rect).unwrap();
+        let captured = dst.intersection(clamp_rect).unwrap();
+        assert_eq!(
+            captured,
+            Rectangle::new(Point::new(20, 20), Size::new(100, 200))
+        );
+
+        // The same for a cropped element.
+        let src = Rectangle::new(Point::new(25., 0.), Size::new(75., 100.));
+        let dst = Rectangle::new(Point::new(70, 20), Size::new(150, 200));
+        let clamp_rect = elem.clamp_rect(src, dst, output_rect).unwrap();
+        let captured = dst.intersection(clamp_rect).unwrap();
+        assert_eq!(
+            captured,
+            Rectangle::new(Point::new(70, 20), Size::new(50, 200))
+        );
+
+        // Covering the whole output, it can't draw the blur directly anymore.
+        let full = Rectangle::from_size(Size::new(100., 100.));
+        let mut elem = test_element(full);
+        elem.blur_options = Some(BlurOptions {
+            passes: 3,
+            offset: 3.,
+            ..Default::default()
+        });
+        let output_rect = Rectangle::from_size(Size::new(100, 100));
+        assert!(elem.draws_blur_directly(output_rect, output_rect));
+        elem.visible = Some(Rectangle::from_size(Size::new(50., 100.)));
+        assert!(!elem.draws_blur_directly(output_rect, output_rect));
+    }
+
+    #[test]
+    fn pixel_snap_keeps_capture_region_stable() {
+        let scale = Scale::from(1.);
+        let geo = |x| Rectangle::new(Point::new(x, 10.), Size::new(100.4, 50.));
+
+        // Without snapping, the size changes with the fractional position.
+        let a = test_element(geo(10.0)).geometry(scale);
+        let b = test_element(geo(10.2)).geometry(scale);
+        assert_ne!(a.size, b.size);
+
+        let snapped = |x| FramebufferEffectElement {
+            pixel_snap: true,
+            ..test_element(geo(x))
+        };
+        let a = snapped(10.0).geometry(scale);
+        let b = snapped(10.2).geometry(scale);
+        assert_eq!(a, b);
+        assert_eq!(a, Rectangle::new(Point::new(10, 10), Size::new(100, 50)));
+
+        // Moving further only ever moves the region, and its size stays fixed.
+        let c = snapped(10.6).geometry(scale);
+        assert_eq!(c, Rectangle::new(Point::new(11, 10), Size::new(100, 50)));
+    }
+
+    #[test]
+    fn offscreen_part_is_cleared_to_offscreen_color() {
+        let geometry = Rectangle::from_size(Size::new(100., 100.));
+        let src = Rectangle::from_size(Size::new(100., 100.));
+        let output_rect = Rectangle::from_size(Size::new(1000, 1000));
+        // Hanging off the left edge of the output by half.
+        let dst = Rectangle::new(Point::new(-50, 0), Size::new(100, 100));
+        let size = Size::new(200, 200);
+
+        // By default, the capture is clamped to the output and the blit covers all of it.
+        let elem = test_element(geometry);
+        let clamped = elem.clamp_rect(src, dst, output_rect).unwrap();
+        let clamped = dst.intersection(clamped).unwrap();
+        assert_eq!(clamped, Rectangle::from_size(Size::new(50, 100)));
+        let blit_dst = blit_target(clamped, clamped, size);
+        assert_eq!(blit_dst, Rectangle::from_size(size));
+        assert_eq!(elem.clear_color(blit_dst, size), None);
+
+        // With a color, the off-screen part is captured too, cleared to the color.
+        let color = Color32F::new(0.1, 0.2, 0.3, 1.);
+        let elem = FramebufferEffectElement {
+            offscreen_color: Some(color),
+            ..test_element(geometry)
+        };
+        assert_eq!(elem.clamp_rect(src, dst, output_rect), Some(dst));
+        let on_output = dst.intersection(output_rect).unwrap();
+        let blit_dst = blit_target(dst, on_output, size);
+        assert_eq!(
+            blit_dst,
+            Rectangle::new(Point::new(100, 0), Size::new(100, 200))
+        );
+        assert_eq!(elem.clear_color(blit_dst, size), Some(color));
+
+        // Fully on the output, there's nothing to clear.
+        let blit_dst = blit_target(on_output, on_output, size);
+        assert_eq!(elem.clear_color(blit_dst, size), None);
+    }
+
+    #[test]
+    fn bad_corner_radius_is_sanitized_in_uniforms() {
+        let geometry = Rectangle::from_size(Size::new(100., 100.));
+        let elem = FramebufferEffectElement {
+            corner_radius: CornerRadius {
+                top_left: -4.,
+                top_right: f32::NAN,
+                bottom_right: f32::INFINITY,
+                bottom_left: 8.,
+            },
+            ..test_element(geometry)
+        };
+
+        let uniforms = elem.compute_uniforms(geometry, Transform::Normal, false, 0.);
+        let radius = uniforms
+            .iter()
+            .find(|u| u.name == "corner_radius")
+            .map(|u| match u.value {
+                UniformValue::_4f(a, b, c, d) => [a, b, c, d],
+                _ => unreachable!(),
+            })
+            .unwrap();
+        assert_eq!(radius, [0., 0., 0., 8.]);
     }
 }
@@ -1,3 +1,4 @@
+use std::fmt::Debug;
 use std::ptr;
 
 use anyhow::{ensure, Context as _};
@@ -49,6 +50,7 @@ pub mod snapshot;
 pub mod solid_color;
 pub mod surface;
 pub mod texture;
+pub mod texture_budget;
 pub mod xray;
 
 /// A rendering context.
@@ -181,6 +183,39 @@ pub fn create_texture(
     renderer.create_buffer(fourcc, buffer_size)
 }
 
+/// Texture formats for effect textures, in order of preference.
+pub const EFFECT_TEXTURE_FORMATS: &[Fourcc] = &[Fourcc::Abgr8888, Fourcc::Argb8888];
+
+/// Creates a texture with the first format in `formats` for which `create` succeeds.
+///
+/// Returns the texture and the chosen format, or the last error if every format failed.
+///
+/// # Panics
+///
+/// Panics if `formats` is empty.
+pub fn create_texture_with_fallback<T, E: Debug>(
+    formats: &[Fourcc],
+    mut create: impl FnMut(Fourcc) -> Result<T, E>,
+) -> Result<(T, Fourcc), E> {
+    let mut last_err = None;
+    for (i, &fourcc) in formats.iter().enumerate() {
+        match create(fourcc) {
+            Ok(texture) => {
+                if i > 0 {
+                    debug!("created texture with fallback format {fourcc:?}");
+                }
+                return Ok((texture, fourcc));
+            }
+            Err(err) => {
+                debug!("error creating texture with format {fourcc:?}: {err:?}");
+                last_err = Some(err);
+            }
+        }
+    }
+
+    Err(last_err.expect("formats must not be empty"))
+}
+
 pub fn copy_framebuffer(
     renderer: &mut GlesRenderer,
     target: &GlesTarget,
@@ -384,24 +419,140 @@ fn render_elements(
         .clear(Color32F::TRANSPARENT, &[output_rect])
         .context("error clearing")?;
 
-    for element in elements {
+    let elements = elements.filter_map(|element| {
         let src = element.src();
         let dst = element.geometry(scale);
+        let mut damage = output_rect.intersection(dst)?;
+        damage.loc -= dst.loc;
+        Some((element, src, dst, damage, UserDataMap::new()))
+    });
 
-        if let Some(mut damage) = output_rect.intersection(dst) {
-            damage.loc -= dst.loc;
+    for_each_draw_step(
+        elements,
+        |(element, ..)| element.is_framebuffer_effect(),
+        |(element, src, dst, damage, cache), step| match step {
+            DrawStep::Capture => element
+                .capture_framebuffer(&mut frame, *src, *dst, cache)
+                .context("error in capture_framebuffer()"),
+            DrawStep::Draw => element
+                .draw(&mut frame, *src, *dst, &[*damage], &[], Some(cache))
+                .context("error drawing element"),
+        },
+    )?;
 
-            let cache = UserDataMap::new();
-            if element.is_framebuffer_effect() {
-                element
-                    .capture_framebuffer(&mut frame, src, dst, &cache)
-                    .context("error in capture_framebuffer()")?;
-            }
-            element
-                .draw(&mut frame, src, dst, &[damage], &[], Some(&cache))
-                .context("error drawing element")?;
+    frame.finish().context("error finishing frame")
+}
+
+/// Step of drawing an element into a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DrawStep {
+    Capture,
+    Draw,
+}
+
+/// Runs the steps to draw `elements` from bottom to top.
+///
+/// Framebuffer effects capture the framebuffer right before they are drawn rather than all up
+/// front. This way an effect sees everything below it, including lower effects along with their
+/// windows, so overlapping translucent windows stack their blur like layers of glass.
+fn for_each_draw_step<E>(
+    elements: impl Iterator<Item = E>,
+    is_framebuffer_effect: impl Fn(&E) -> bool,
+    mut step: impl FnMut(&E, DrawStep) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    for element in elements {
+        if is_framebuffer_effect(&element) {
+            step(&element, DrawStep::Capture)?;
         }
+        step(&element, DrawStep::Draw)?;
     }
 
-    frame.finish().context("error finishing frame")
+    Ok(())
+}
+
+/// Creates a surfaceless renderer with niri's resources and shaders, for tests that draw.
+#[cfg(test)]
+pub fn test_renderer() -> GlesRenderer {
+    use smithay::backend::egl::native::EGLSurfacelessDisplay;
+    use smithay::backend::egl::{EGLContext, EGLDisplay};
+
+    let mut renderer = unsafe {
+        let display = EGLDisplay::new(EGLSurfacelessDisplay).unwrap();
+        let context = EGLContext::new(&display).unwrap();
+        GlesRenderer::new(context).unwrap()
+    };
+    resources::init(&mut renderer);
+    shaders::init(&mut renderer);
+    renderer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stacked_effects_capture_lower_ones() {
+        // Bottom to top: a blur behind its window, and another window with its blur above them.
+        let elements = [
+            ("lower blur", true),
+            ("lower window", false),
+            ("upper blur", true),
+            ("upper window", false),
+        ];
+
+        let mut steps = Vec::new();
+        for_each_draw_step(
+            elements.into_iter(),
+            |(_, is_effect)| *is_effect,
+            |(name, _), step| {
+                steps.push(format!("{step:?} {name}"));
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        // The upper blur captures only once the lower window and its blur are drawn.
+        assert_eq!(
+            steps,
+            [
+                "Capture lower blur",
+                "Draw lower blur",
+                "Draw lower window",
+                "Capture upper blur",
+                "Draw upper blur",
+                "Draw upper window",
+            ]
+        );
+    }
+
+    #[test]
+    fn texture_format_fallback() {
+        let formats = [Fourcc::Abgr16161616f, Fourcc::Abgr8888, Fourcc::Argb8888];
+
+        // The preferred format is used when it works.
+        let mut tried = Vec::new();
+        let result = create_texture_with_fallback::<_, ()>(&formats, |fourcc| {
+            tried.push(fourcc);
+            Ok(fourcc)
+        });
+        assert_eq!(result, Ok((Fourcc::Abgr16161616f, Fourcc::Abgr16161616f)));
+        assert_eq!(tried, [Fourcc::Abgr16161616f]);
+
+        // Unsupported formats are skipped in order.
+        let mut tried = Vec::new();
+        let result = create_texture_with_fallback(&formats, |fourcc| {
+            tried.push(fourcc);
+            if fourcc == Fourcc::Abgr16161616f {
+                Err("unsupported")
+            } else {
+                Ok(fourcc)
+            }
+        });
+        assert_eq!(result, Ok((Fourcc::Abgr8888, Fourcc::Abgr8888)));
+        assert_eq!(tried, [Fourcc::Abgr16161616f, Fourcc::Abgr8888]);
+
+        // The last error is returned if nothing works.
+        let result = create_texture_with_fallback::<(), _>(&formats, Err);
+        assert_eq!(result, Err(Fourcc::Argb8888));
+    }
 }
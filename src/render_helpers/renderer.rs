@@ -1,8 +1,10 @@
 use smithay::backend::allocator::dmabuf::Dmabuf;
-use smithay::backend::renderer::gles::{GlesFrame, GlesRenderer, GlesTexture};
+use smithay::backend::allocator::Fourcc;
+use smithay::backend::renderer::gles::{GlesError, GlesFrame, GlesRenderer, GlesTexture};
 use smithay::backend::renderer::{
-    Bind, ExportMem, ImportAll, ImportMem, Offscreen, Renderer, RendererSuper, Texture,
+    Bind, ContextId, ExportMem, ImportAll, ImportMem, Offscreen, Renderer, RendererSuper, Texture,
 };
+use smithay::utils::{Buffer, Size};
 
 use crate::backend::tty::{TtyFrame, TtyRenderer};
 
@@ -53,6 +55,55 @@ impl AsGlesRenderer for TtyRenderer<'_> {
     }
 }
 
+/// Renderer operations that the effect code uses for managing its textures.
+///
+/// This lets tests substitute a stub that records the allocations instead of needing a GL context.
+pub trait EffectRenderer {
+    type Texture: EffectTexture;
+    type ContextId: Clone + PartialEq;
+
+    fn effect_context_id(&self) -> Self::ContextId;
+    fn create_effect_texture(
+        &mut self,
+        fourcc: Fourcc,
+        size: Size<i32, Buffer>,
+    ) -> Result<Self::Texture, GlesError>;
+}
+
+/// Texture operations that the effect code uses, see [`EffectRenderer`].
+pub trait EffectTexture {
+    fn effect_size(&self) -> Size<i32, Buffer>;
+    /// Whether nothing else holds on to the texture, so it can be drawn over.
+    fn is_unique(&self) -> bool;
+}
+
+impl EffectRenderer for GlesRenderer {
+    type Texture = GlesTexture;
+    type ContextId = ContextId<GlesTexture>;
+
+    fn effect_context_id(&self) -> Self::ContextId {
+        self.context_id()
+    }
+
+    fn create_effect_texture(
+        &mut self,
+        fourcc: Fourcc,
+        size: Size<i32, Buffer>,
+    ) -> Result<GlesTexture, GlesError> {
+        self.create_buffer(fourcc, size)
+    }
+}
+
+impl EffectTexture for GlesTexture {
+    fn effect_size(&self) -> Size<i32, Buffer> {
+        self.size()
+    }
+
+    fn is_unique(&self) -> bool {
+        self.is_unique_reference()
+    }
+}
+
 /// Trait for getting the underlying `GlesFrame`.
 pub trait AsGlesFrame<'frame, 'buffer>
 where
@@ -1,3 +1,4 @@
+use std::cell::Cell;
 use std::collections::HashMap;
 use std::ffi::CString;
 use std::rc::Rc;
@@ -42,6 +43,8 @@ struct ShaderProgramInner {
     normal: ShaderProgramInternal,
     debug: ShaderProgramInternal,
     uniform_tint: ffi::types::GLint,
+    /// Whether the GL programs were already deleted.
+    destroyed: Cell<bool>,
 }
 
 #[derive(Debug)]
@@ -154,6 +157,7 @@ unsafe fn compile_program(
                 .collect(),
         },
         uniform_tint: gl.GetUniformLocation(debug_program, tint.as_ptr()),
+        destroyed: Cell::new(false),
     })))
 }
 
@@ -169,11 +173,18 @@ impl ShaderProgram {
         })?
     }
 
+    /// Deletes the GL programs, for this and every clone. Does nothing if already deleted.
     pub fn destroy(self, renderer: &mut GlesRenderer) -> Result<(), GlesError> {
-        renderer.with_context(move |gl| unsafe {
+        if self.0.destroyed.get() {
+            return Ok(());
+        }
+
+        renderer.with_context(|gl| unsafe {
             gl.DeleteProgram(self.0.normal.program);
             gl.DeleteProgram(self.0.debug.program);
-        })
+        })?;
+        self.0.destroyed.set(true);
+        Ok(())
     }
 }
 
@@ -1,10 +1,12 @@
 #version 100
 
 attribute vec2 vert;
+// Maps from the drawn texture to the sampled one, see level_uv_scale() in blur.rs.
+uniform vec2 uv_scale;
 varying vec2 v_coords;
 
 void main() {
-    v_coords = vert;
+    v_coords = vert * uv_scale;
     // vert goes from 0 to 1; position must be from -1 to 1.
     vec2 position = vert * 2.0 - 1.0;
     gl_Position = vec4(position, 1.0, 1.0);
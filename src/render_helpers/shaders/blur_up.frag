@@ -7,23 +7,54 @@ varying vec2 v_coords;
 uniform sampler2D tex;
 uniform vec2 half_pixel;
 uniform float offset;
+// Stretches the sample offsets along the motion direction, identity for an isotropic blur.
+uniform mat2 direction;
 
-void main() {
-    vec2 o = half_pixel * offset;
+// Weights samples by their color difference to the center for an edge-preserving blur, 0 to
+// weight all samples the same.
+uniform float range_sigma;
+
+vec2 tap(vec2 v) {
+    return half_pixel * (direction * (v * offset));
+}
+
+float range_weight(vec4 color, vec4 center) {
+    if (range_sigma <= 0.0) {
+        return 1.0;
+    }
+
+    vec4 d = color - center;
+    return exp(-dot(d, d) / (2.0 * range_sigma * range_sigma));
+}
+
+vec4 center;
+vec4 sum;
+float weight;
 
-    vec4 sum = vec4(0.0);
+void add(vec2 v, float base_weight) {
+    vec4 color = texture2D(tex, v_coords + tap(v));
+    float w = base_weight * range_weight(color, center);
+    sum += color * w;
+    weight += w;
+}
+
+void main() {
+    // The up pass doesn't sample the center otherwise, so only do it when needed.
+    center = range_sigma > 0.0 ? texture2D(tex, v_coords) : vec4(0.0);
+    sum = vec4(0.0);
+    weight = 0.0;
 
     // Four edge centers
-    sum += texture2D(tex, v_coords + vec2(-o.x * 2.0, 0.0));
-    sum += texture2D(tex, v_coords + vec2( o.x * 2.0, 0.0));
-    sum += texture2D(tex, v_coords + vec2(0.0, -o.y * 2.0));
-    sum += texture2D(tex, v_coords + vec2(0.0,  o.y * 2.0));
+    add(vec2(-2.0,  0.0), 1.0);
+    add(vec2( 2.0,  0.0), 1.0);
+    add(vec2( 0.0, -2.0), 1.0);
+    add(vec2( 0.0,  2.0), 1.0);
 
     // Four diagonal corners
-    sum += texture2D(tex, v_coords + vec2(-o.x,  o.y)) * 2.0;
-    sum += texture2D(tex, v_coords + vec2( o.x,  o.y)) * 2.0;
-    sum += texture2D(tex, v_coords + vec2(-o.x, -o.y)) * 2.0;
-    sum += texture2D(tex, v_coords + vec2( o.x, -o.y)) * 2.0;
+    add(vec2(-1.0,  1.0), 2.0);
+    add(vec2( 1.0,  1.0), 2.0);
+    add(vec2(-1.0, -1.0), 2.0);
+    add(vec2( 1.0, -1.0), 2.0);
 
-    gl_FragColor = sum / 12.0;
+    gl_FragColor = sum / weight;
 }
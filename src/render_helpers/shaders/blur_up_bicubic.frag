@@ -0,0 +1,55 @@
+#version 100
+
+precision highp float;
+
+varying vec2 v_coords;
+
+uniform sampler2D tex;
+uniform vec2 half_pixel;
+uniform float offset;
+
+// Cubic B-spline weights for the four taps around the sample position.
+vec4 weights(float f) {
+    float f2 = f * f;
+    float f3 = f2 * f;
+    float g = 1.0 - f;
+    return vec4(
+        g * g * g,
+        4.0 - 6.0 * f2 + 3.0 * f3,
+        1.0 + 3.0 * f + 3.0 * f2 - 3.0 * f3,
+        f3
+    ) / 6.0;
+}
+
+vec4 row(vec2 base, vec2 spread, float y, vec4 wx) {
+    vec4 sum = vec4(0.0);
+    sum += texture2D(tex, base + spread * vec2(-1.0, y)) * wx.x;
+    sum += texture2D(tex, base + spread * vec2( 0.0, y)) * wx.y;
+    sum += texture2D(tex, base + spread * vec2( 1.0, y)) * wx.z;
+    sum += texture2D(tex, base + spread * vec2( 2.0, y)) * wx.w;
+    return sum;
+}
+
+void main() {
+    // During upsampling, half_pixel is half of the source pixel. The weights follow the source
+    // texel lattice, so at offset 1 the taps land on texel centers.
+    vec2 texel = half_pixel * 2.0;
+    vec2 f = fract(v_coords / texel - 0.5);
+
+    // Spread the taps by offset so that the blur strength roughly matches the regular up pass.
+    // Only the spacing grows: the taps stay around the current position, so the weights still
+    // shift smoothly from one texel to the next.
+    vec2 spread = texel * max(offset, 1.0);
+    vec2 base = v_coords - f * spread;
+
+    vec4 wx = weights(f.x);
+    vec4 wy = weights(f.y);
+
+    vec4 sum = vec4(0.0);
+    sum += row(base, spread, -1.0, wx) * wy.x;
+    sum += row(base, spread,  0.0, wx) * wy.y;
+    sum += row(base, spread,  1.0, wx) * wy.z;
+    sum += row(base, spread,  2.0, wx) * wy.w;
+
+    gl_FragColor = sum;
+}
@@ -27,7 +27,9 @@ uniform vec4 corner_radius;
 uniform mat3 input_to_geo;
 
 float niri_rounding_alpha(vec2 coords, vec2 size, vec4 corner_radius);
-vec4 postprocess(vec4 color);
+vec4 postprocess(vec4 color, vec2 coords);
+float dither_alpha(float alpha);
+vec4 edge_ring(vec4 color, vec2 coords, vec2 size, vec4 corner_radius);
 
 void main() {
     vec3 coords_geo = input_to_geo * vec3(v_coords, 1.0);
@@ -38,14 +40,17 @@ void main() {
     color = vec4(color.rgb, 1.0);
 #endif
 
-    color = postprocess(color);
+    color = postprocess(color, coords_geo.xy * geo_size);
 
     if (coords_geo.x < 0.0 || 1.0 < coords_geo.x || coords_geo.y < 0.0 || 1.0 < coords_geo.y) {
         // Clip outside geometry.
         color = vec4(0.0);
     } else {
+        color = edge_ring(color, coords_geo.xy * geo_size, geo_size, corner_radius);
+
         // Apply corner rounding inside geometry.
-        color = color * niri_rounding_alpha(coords_geo.xy * geo_size, geo_size, corner_radius);
+        float rounding = niri_rounding_alpha(coords_geo.xy * geo_size, geo_size, corner_radius);
+        color = color * dither_alpha(rounding);
     }
 
     // Apply final alpha and tint.
@@ -1,10 +1,11 @@
 use std::cell::RefCell;
 
-use glam::Mat3;
+use glam::{Mat3, Vec2};
 use smithay::backend::renderer::gles::{
     GlesError, GlesFrame, GlesRenderer, GlesTexProgram, Uniform, UniformName, UniformType,
     UniformValue,
 };
+use smithay::utils::Transform;
 
 use super::renderer::NiriRenderer;
 use super::shader_element::ShaderProgram;
@@ -90,7 +91,10 @@ impl Shaders {
                 concat!(
                     include_str!("clipped_surface.frag"),
                     include_str!("rounding_alpha.frag"),
-                    "\nvec4 postprocess(vec4 color) { return color; }",
+                    "\nvec4 postprocess(vec4 color, vec2 coords) { return color; }",
+                    "\nfloat dither_alpha(float alpha) { return alpha; }",
+                    "\nvec4 edge_ring(vec4 color, vec2 coords, vec2 size, vec4 corner_radius) {",
+                    " return color; }",
                 ),
                 &[
                     UniformName::new("niri_scale", UniformType::_1f),
@@ -117,8 +121,17 @@ impl Shaders {
                     UniformName::new("corner_radius", UniformType::_4f),
                     UniformName::new("input_to_geo", UniformType::Matrix3x3),
                     UniformName::new("noise", UniformType::_1f),
+                    UniformName::new("noise_seed", UniformType::_1f),
                     UniformName::new("saturation", UniformType::_1f),
                     UniformName::new("bg_color", UniformType::_4f),
+                    UniformName::new("tint", UniformType::_4f),
+                    UniformName::new("adaptive_tint", UniformType::_1f),
+                    UniformName::new("niri_average", UniformType::_1i),
+                    UniformName::new("region_bounds", UniformType::_4f),
+                    UniformName::new("region_falloff", UniformType::_1f),
+                    UniformName::new("alpha_dither", UniformType::_1f),
+                    UniformName::new("edge_ring_color", UniformType::_4f),
+                    UniformName::new("edge_ring_width", UniformType::_1f),
                 ],
             )
             .map_err(|err| {
@@ -353,6 +366,48 @@ pub fn set_custom_open_program(renderer: &mut GlesRenderer, src: Option<&str>) {
     }
 }
 
+/// Deletes all of our GL programs for the renderer.
+///
+/// GL objects can only be deleted with their context current, which isn't guaranteed on drop, so
+/// this must be called right before the renderer goes away, e.g. when its GPU is removed. The
+/// programs can't be used afterwards. Textures and smithay's texture programs are cleaned up by
+/// the renderer itself.
+pub fn destroy_all(renderer: &mut GlesRenderer) {
+    let shaders = Shaders::get(renderer);
+    let builtin = [&shaders.border, &shaders.shadow, &shaders.resize];
+    let custom = [
+        &shaders.custom_resize,
+        &shaders.custom_close,
+        &shaders.custom_open,
+    ];
+    let programs: Vec<_> = builtin
+        .into_iter()
+        .flatten()
+        .cloned()
+        .chain(custom.into_iter().filter_map(RefCell::take))
+        .collect();
+    let blur = shaders.blur.clone();
+
+    for program in programs {
+        if let Err(err) = program.destroy(renderer) {
+            warn!("error destroying shader: {err:?}");
+        }
+    }
+
+    if let Some(blur) = blur {
+        if let Err(err) = blur.destroy(renderer) {
+            warn!("error destroying blur shader: {err:?}");
+        }
+    }
+}
+
+/// Returns the matrix that reverts the effect of a texture `transform` on the texture coordinates.
+pub fn texture_transform_mat(transform: Transform) -> Mat3 {
+    Mat3::from_translation(Vec2::new(0.5, 0.5))
+        * Mat3::from_cols_array(transform.matrix().as_ref())
+        * Mat3::from_translation(Vec2::new(-0.5, -0.5))
+}
+
 pub fn mat3_uniform(name: &str, mat: Mat3) -> Uniform<'_> {
     Uniform::new(
         name,
@@ -362,3 +417,134 @@ pub fn mat3_uniform(name: &str, mat: Mat3) -> Uniform<'_> {
         },
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use smithay::backend::allocator::Fourcc;
+    use smithay::backend::renderer::{
+        Bind as _, Color32F, ExportMem as _, Frame as _, ImportMem as _, Renderer as _,
+        Texture as _,
+    };
+    use smithay::utils::{Rectangle, Size};
+
+    use super::*;
+    use crate::render_helpers::background_effect::{EdgeFalloff, EdgeRing, Postprocess};
+    use crate::render_helpers::{copy_framebuffer, create_texture, test_renderer};
+
+    const SIZE: i32 = 16;
+
+    /// Draws a square of the premultiplied `color` with the postprocess shader and returns the
+    /// RGBA pixels.
+    fn postprocess(
+        renderer: &mut GlesRenderer,
+        color: [u8; 4],
+        noise: f32,
+        scale: f32,
+        corner_radius: f32,
+    ) -> Vec<[u8; 4]> {
+        let program = Shaders::get(renderer).postprocess_and_clip.clone().unwrap();
+
+        let pixels = color.repeat((SIZE * SIZE) as usize);
+        let src = renderer
+            .import_memory(&pixels, Fourcc::Abgr8888, Size::from((SIZE, SIZE)), false)
+            .unwrap();
+
+        let [adaptive_tint, average] = Postprocess::adaptive_tint_uniforms(false);
+        let [region_bounds, region_falloff] = EdgeFalloff::uniforms(None);
+        let [ring_color, ring_width] = EdgeRing::uniforms(None);
+        let uniforms = [
+            Uniform::new("niri_scale", scale),
+            Uniform::new("geo_size", (SIZE as f32, SIZE as f32)),
+            Uniform::new("corner_radius", [corner_radius; 4]),
+            mat3_uniform("input_to_geo", Mat3::IDENTITY),
+            Uniform::new("noise", noise),
+            Uniform::new("noise_seed", 0f32),
+            Uniform::new("saturation", 1f32),
+            Uniform::new("bg_color", [0f32; 4]),
+            Uniform::new("tint", [0f32; 4]),
+            adaptive_tint,
+            average,
+            region_bounds,
+            region_falloff,
+            Uniform::new("alpha_dither", 0f32),
+            ring_color,
+            ring_width,
+        ];
+
+        let size = Size::from((SIZE, SIZE));
+        let dst = Rectangle::from_size(size);
+        let mut texture = create_texture(renderer, size, Fourcc::Abgr8888).unwrap();
+        let mut target = renderer.bind(&mut texture).unwrap();
+        let mut frame = renderer
+            .render(&mut target, size, Transform::Normal)
+            .unwrap();
+        frame.clear(Color32F::TRANSPARENT, &[dst]).unwrap();
+        frame
+            .render_texture_from_to(
+                &src,
+                Rectangle::from_size(src.size().to_f64()),
+                dst,
+                &[dst],
+                &[],
+                Transform::Normal,
+                1.,
+                Some(&program),
+                &uniforms,
+            )
+            .unwrap();
+        let _sync = frame.finish().unwrap();
+
+        let mapping = copy_framebuffer(renderer, &target, Fourcc::Abgr8888).unwrap();
+        let bytes = renderer.map_texture(&mapping).unwrap();
+        bytes
+            .chunks_exact(4)
+            .map(|px| [px[0], px[1], px[2], px[3]])
+            .collect()
+    }
+
+    #[test]
+    fn egl_postprocess_noise_is_premultiplied() {
+        let mut renderer = test_renderer();
+
+        // Half-transparent gray with strong noise and rounded corners.
+        let pixels = postprocess(&mut renderer, [48, 48, 48, 128], 1., 1., 6.);
+
+        // The noise is there.
+        assert!(pixels.iter().any(|px| px[..3] != [48, 48, 48]));
+
+        // But no pixel gets more color than its alpha allows, including the transparent corners
+        // and their anti-aliased edges.
+        for px in &pixels {
+            assert!(px[..3].iter().all(|&c| c <= px[3]), "{px:?}");
+        }
+        assert_eq!(pixels[0], [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn egl_postprocess_noise_scales_with_output() {
+        let mut renderer = test_renderer();
+        let gray = [128, 128, 128, 255];
+
+        // Returns whether every 2x2 block of pixels has a single color.
+        let blocky = |pixels: &[[u8; 4]]| {
+            let size = SIZE as usize;
+            (0..size).step_by(2).all(|y| {
+                (0..size).step_by(2).all(|x| {
+                    let px = pixels[y * size + x];
+                    [(1, 0), (0, 1), (1, 1)]
+                        .iter()
+                        .all(|(dx, dy)| pixels[(y + dy) * size + x + dx] == px)
+                })
+            })
+        };
+
+        // One grain per physical pixel at scale 1.
+        let pixels = postprocess(&mut renderer, gray, 0.5, 1., 0.);
+        assert!(!blocky(&pixels));
+
+        // One grain per 2x2 physical pixels at scale 2.
+        let pixels = postprocess(&mut renderer, gray, 0.5, 2., 0.);
+        assert!(pixels.iter().any(|px| *px != gray));
+        assert!(blocky(&pixels));
+    }
+}
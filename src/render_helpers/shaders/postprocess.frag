@@ -1,6 +1,27 @@
 uniform float noise;
+// Offset of the noise pattern, different for every effect so that their noise doesn't line up.
+uniform float noise_seed;
 uniform float saturation;
 uniform vec4 bg_color;
+uniform vec4 tint;
+uniform float adaptive_tint;
+// Smallest level of the blur pyramid, bound to texture unit 1 when adaptive_tint is on.
+uniform sampler2D niri_average;
+// Bounds of the effect region (x, y, w, h) in geometry pixels, to fade out toward.
+uniform vec4 region_bounds;
+// Distance in geometry pixels over which the effect fades out, 0 to disable.
+uniform float region_falloff;
+// Amplitude of the noise on the anti-aliased corner alpha, 0 to disable. Breaks up banding in the
+// corner edges on 8-bit targets.
+uniform float alpha_dither;
+// Premultiplied color of the highlight along the inside of the rounded geometry edge.
+uniform vec4 edge_ring_color;
+// Distance in geometry pixels over which the edge highlight fades out, 0 to disable.
+uniform float edge_ring_width;
+
+const vec3 LUMA = vec3(0.2126, 0.7152, 0.0722);
+// Number of samples per side of the grid that the adaptive tint averages.
+const int AVERAGE_GRID = 4;
 
 // Sin-less white noise by David Hoskins (MIT License).
 // https://www.shadertoy.com/view/4djSRW
@@ -11,22 +32,109 @@ float hash12(vec2 p) {
 }
 
 vec3 saturate(vec3 color, float sat) {
-    const vec3 w = vec3(0.2126, 0.7152, 0.0722);
-    return mix(vec3(dot(color, w)), color, sat);
+    return mix(vec3(dot(color, LUMA)), color, sat);
+}
+
+float dither_alpha(float alpha) {
+    // Only the anti-aliased edge has partial alpha.
+    if (alpha_dither <= 0.0 || alpha <= 0.0 || 1.0 <= alpha) {
+        return alpha;
+    }
+
+    // Offset from the color noise so that the two patterns don't line up.
+    float n = hash12(gl_FragCoord.xy + noise_seed + vec2(57.0, 113.0)) - 0.5;
+    return clamp(alpha + n * alpha_dither, 0.0, 1.0);
+}
+
+// Distance from coords to the nearest edge of the rounded geometry, for coords inside it.
+float edge_distance(vec2 coords, vec2 size, vec4 corner_radius) {
+    vec2 from_end = size - coords;
+    float dist = min(min(coords.x, coords.y), min(from_end.x, from_end.y));
+
+    vec2 center;
+    float radius;
+
+    if (coords.x < corner_radius.x && coords.y < corner_radius.x) {
+        radius = corner_radius.x;
+        center = vec2(radius, radius);
+    } else if (size.x - corner_radius.y < coords.x && coords.y < corner_radius.y) {
+        radius = corner_radius.y;
+        center = vec2(size.x - radius, radius);
+    } else if (size.x - corner_radius.z < coords.x && size.y - corner_radius.z < coords.y) {
+        radius = corner_radius.z;
+        center = vec2(size.x - radius, size.y - radius);
+    } else if (coords.x < corner_radius.w && size.y - corner_radius.w < coords.y) {
+        radius = corner_radius.w;
+        center = vec2(radius, size.y - radius);
+    } else {
+        return dist;
+    }
+
+    return min(dist, radius - distance(coords, center));
+}
+
+// coords and size are in geometry pixels.
+vec4 edge_ring(vec4 color, vec2 coords, vec2 size, vec4 corner_radius) {
+    if (edge_ring_width <= 0.0) {
+        return color;
+    }
+
+    // Brightest at the edge, easing out toward the inside.
+    float t = clamp(1.0 - edge_distance(coords, size, corner_radius) / edge_ring_width, 0.0, 1.0);
+    // Scale by alpha so that the highlight fades together with the effect.
+    vec4 ring = edge_ring_color * (t * t * color.a);
+
+    // Draw the highlight over the effect (premultiplied alpha).
+    return ring + color * (1.0 - ring.a);
 }
 
-vec4 postprocess(vec4 color) {
+// coords are in geometry pixels.
+vec4 postprocess(vec4 color, vec2 coords) {
     if (saturation != 1.0) {
         color.rgb = saturate(color.rgb, saturation);
     }
 
+    // Mix bg_color behind the texture (both premultiplied alpha).
+    color = color + bg_color * (1.0 - color.a);
+
+    vec4 t = tint;
+    if (adaptive_tint > 0.0) {
+        // Average a grid of samples over the smallest blur level. It's already blurred, so the
+        // grid doesn't miss any details and this is close to the average background color.
+        vec4 average = vec4(0.0);
+        for (int y = 0; y < AVERAGE_GRID; y++) {
+            for (int x = 0; x < AVERAGE_GRID; x++) {
+                vec2 uv = (vec2(float(x), float(y)) + 0.5) / float(AVERAGE_GRID);
+                average += texture2D(niri_average, uv);
+            }
+        }
+        average /= float(AVERAGE_GRID * AVERAGE_GRID);
+        float luma = dot(average.rgb, LUMA) / max(average.a, 0.001);
+
+        // Scale from half the tint over black to 1.5 times the tint over white, keeping alpha <= 1.
+        t *= mix(0.5, 1.5, clamp(luma, 0.0, 1.0));
+        t /= max(t.a, 1.0);
+    }
+
+    // Draw tint over the texture (premultiplied alpha).
+    color = t + color * (1.0 - t.a);
+
     if (noise > 0.0) {
-        vec2 uv = gl_FragCoord.xy;
-        color.rgb += (hash12(uv) - 0.5) * noise;
+        // One grain per logical pixel, so that it looks the same on outputs of any scale.
+        vec2 uv = floor(gl_FragCoord.xy / niri_scale) + noise_seed;
+        // Scale by alpha to stay in premultiplied space. Otherwise, noise would add color to
+        // transparent pixels and speckle the anti-aliased corner edges, which get multiplied by
+        // the rounding alpha afterwards.
+        color.rgb += (hash12(uv) - 0.5) * noise * color.a;
     }
 
-    // Mix bg_color behind the texture (both premultiplied alpha).
-    color = color + bg_color * (1.0 - color.a);
+    if (region_falloff > 0.0) {
+        // Distance to the nearest edge of the region bounds.
+        vec2 from_start = coords - region_bounds.xy;
+        vec2 from_end = region_bounds.xy + region_bounds.zw - coords;
+        float dist = min(min(from_start.x, from_start.y), min(from_end.x, from_end.y));
+        color *= clamp(dist / region_falloff, 0.0, 1.0);
+    }
 
     return color;
 }
@@ -0,0 +1,148 @@
+//! Accounting of GPU memory used by background effect textures.
+//!
+//! Effect textures are all allocated on the main thread, so usage is tracked in a thread-local
+//! counter. Each owner of textures holds a [`TextureReservation`] sized to what it currently has
+//! allocated, and releases it on drop.
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use smithay::backend::allocator::format::get_bpp;
+use smithay::backend::allocator::Fourcc;
+use smithay::backend::renderer::gles::GlesTexture;
+use smithay::backend::renderer::Texture as _;
+use smithay::utils::{Buffer, Size};
+
+/// All effect texture formats use 4 bytes per pixel.
+const BYTES_PER_PIXEL: u64 = 4;
+
+thread_local! {
+    static USED: Cell<u64> = const { Cell::new(0) };
+}
+
+/// Returns the total number of bytes currently reserved for effect textures.
+pub fn used() -> u64 {
+    USED.get()
+}
+
+/// Returns the size in bytes of a texture with the given size.
+pub fn texture_bytes(size: Size<i32, Buffer>) -> u64 {
+    size.w.max(0) as u64 * size.h.max(0) as u64 * BYTES_PER_PIXEL
+}
+
+/// Returns the size in bytes of a texture with the given size and format.
+///
+/// Textures of an unknown format are counted at [`BYTES_PER_PIXEL`].
+pub fn format_texture_bytes(size: Size<i32, Buffer>, format: Option<Fourcc>) -> u64 {
+    let bytes_per_pixel = format
+        .and_then(get_bpp)
+        .map_or(BYTES_PER_PIXEL, |bits| (bits as u64).div_ceil(8));
+    size.w.max(0) as u64 * size.h.max(0) as u64 * bytes_per_pixel
+}
+
+/// Returns the size in bytes of an existing texture, going by its actual format.
+pub fn gles_texture_bytes(texture: &GlesTexture) -> u64 {
+    format_texture_bytes(texture.size(), texture.format())
+}
+
+/// Bytes of effect textures owned by one object.
+#[derive(Debug, Default)]
+pub struct TextureReservation {
+    bytes: u64,
+}
+
+impl TextureReservation {
+    pub fn bytes(&self) -> u64 {
+        self.bytes
+    }
+
+    /// Returns how many bytes this reservation can grow to without going over `limit`.
+    pub fn available(&self, limit: Option<u64>) -> Option<u64> {
+        let others = used() - self.bytes;
+        limit.map(|limit| limit.saturating_sub(others))
+    }
+
+    /// Resizes the reservation regardless of the limit.
+    ///
+    /// Used for textures that effects can't work without, so they still count towards the budget
+    /// left for optional ones.
+    pub fn set(&mut self, bytes: u64) {
+        USED.set(used() - self.bytes + bytes);
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for TextureReservation {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}
+
+/// Bytes of textures of one effect, summed over all the places it keeps textures in.
+///
+/// For example, an effect drawn on several outputs has separate textures for each one. Each of
+/// those places holds a [`TextureUsageReport`] with its share.
+#[derive(Debug, Default)]
+pub struct TextureUsage(Cell<u64>);
+
+/// Share of one place in a [`TextureUsage`], removed on drop.
+#[derive(Debug)]
+pub struct TextureUsageReport {
+    usage: Rc<TextureUsage>,
+    bytes: u64,
+}
+
+impl TextureUsage {
+    pub fn bytes(&self) -> u64 {
+        self.0.get()
+    }
+}
+
+impl TextureUsageReport {
+    pub fn new(usage: Rc<TextureUsage>) -> Self {
+        Self { usage, bytes: 0 }
+    }
+
+    pub fn set(&mut self, bytes: u64) {
+        let usage = &self.usage.0;
+        usage.set(usage.get() - self.bytes + bytes);
+        self.bytes = bytes;
+    }
+}
+
+impl Drop for TextureUsageReport {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn texture_bytes_follow_format() {
+        let size = Size::new(100, 50);
+        assert_eq!(format_texture_bytes(size, Some(Fourcc::Abgr8888)), 20_000);
+        assert_eq!(format_texture_bytes(size, Some(Fourcc::Rgb565)), 10_000);
+        // Unknown formats count as the effect texture formats.
+        assert_eq!(format_texture_bytes(size, None), texture_bytes(size));
+    }
+
+    #[test]
+    fn usage_sums_reports() {
+        let usage = Rc::new(TextureUsage::default());
+        let mut a = TextureUsageReport::new(usage.clone());
+        let mut b = TextureUsageReport::new(usage.clone());
+
+        a.set(100);
+        b.set(50);
+        assert_eq!(usage.bytes(), 150);
+
+        a.set(20);
+        assert_eq!(usage.bytes(), 70);
+
+        drop(b);
+        assert_eq!(usage.bytes(), 20);
+    }
+}
@@ -6,18 +6,21 @@ use glam::{Mat3, Vec2};
 use niri_config::CornerRadius;
 use smithay::backend::renderer::element::{Element, Id, RenderElement};
 use smithay::backend::renderer::gles::{
-    GlesError, GlesFrame, GlesRenderer, GlesTexProgram, Uniform,
+    GlesError, GlesFrame, GlesRenderer, GlesTexProgram, GlesTexture, Uniform,
 };
 use smithay::backend::renderer::utils::{CommitCounter, OpaqueRegions};
-use smithay::backend::renderer::Color32F;
+use smithay::backend::renderer::{Color32F, ContextId, Frame as _, Renderer as _, Texture as _};
 use smithay::utils::user_data::UserDataMap;
 use smithay::utils::{Buffer, Logical, Physical, Point, Rectangle, Scale, Size, Transform};
 
 use crate::backend::tty::{TtyFrame, TtyRenderer, TtyRendererError};
-use crate::render_helpers::background_effect::RenderParams;
+use crate::render_helpers::background_effect::{
+    bind_average_texture, frame_alpha_dither, EdgeFalloff, EdgeRing, Postprocess, RenderParams,
+};
+use crate::render_helpers::debug::{record_effect, EffectDescriptor, EffectKind};
 use crate::render_helpers::effect_buffer::EffectBuffer;
 use crate::render_helpers::renderer::AsGlesFrame as _;
-use crate::render_helpers::shaders::{mat3_uniform, Shaders};
+use crate::render_helpers::shaders::{mat3_uniform, texture_transform_mat, Shaders};
 use crate::render_helpers::{RenderCtx, RenderTarget};
 use crate::utils::region::TransformedRegion;
 
@@ -26,8 +29,41 @@ pub struct Xray {
     // The buffers are per-render-target to avoid constant rerendering when screencasting.
     pub background: [Rc<RefCell<EffectBuffer>>; RenderTarget::COUNT],
     pub backdrop: [Rc<RefCell<EffectBuffer>>; RenderTarget::COUNT],
+    /// Premultiplied color drawn behind the backdrop.
     pub backdrop_color: Color32F,
-    pub workspaces: Vec<(Rectangle<f64, Logical>, Color32F)>,
+    pub workspaces: Vec<XrayWorkspace>,
+    /// Blur config of the buffers when it differs from the surfaces' own, e.g. in the overview.
+    ///
+    /// Effects sampling the xray take their noise and saturation from it.
+    pub blur_config: Option<niri_config::Blur>,
+}
+
+/// Workspace that the xray background is drawn for.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct XrayWorkspace {
+    pub geo: Rectangle<f64, Logical>,
+    /// Premultiplied background color.
+    pub bg_color: Color32F,
+    /// Whether surfaces on this workspace skip the xray effect entirely.
+    pub xray_disabled: bool,
+}
+
+/// Summary of what [`Xray::render()`] produced.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct XrayRenderResult {
+    /// Whether any workspace background elements were pushed.
+    pub pushed_background: bool,
+    /// Whether the backdrop element was pushed.
+    pub pushed_backdrop: bool,
+    /// Whether the backdrop was skipped because it was fully covered or out of bounds.
+    pub skipped_backdrop: bool,
+}
+
+impl XrayRenderResult {
+    /// Whether any element was pushed at all.
+    pub fn pushed_any(&self) -> bool {
+        self.pushed_background || self.pushed_backdrop
+    }
 }
 
 /// Position for drawing xray background.
@@ -54,6 +90,18 @@ impl XrayPos {
         self.pos_in_backdrop += offset;
         self
     }
+
+    /// Returns where an element of `size` at this position ends up in backdrop coordinates.
+    ///
+    /// Layer-shell surfaces don't scroll, so this only depends on where their layer is drawn: the
+    /// bottom and background layers move and zoom together with each workspace, while surfaces
+    /// drawn on the backdrop itself map onto it 1:1.
+    pub fn geo_in_backdrop(&self, size: Size<f64, Logical>) -> Rectangle<f64, Logical> {
+        Rectangle::new(
+            self.pos_in_backdrop.upscale(self.zoom),
+            size.upscale(self.zoom),
+        )
+    }
 }
 
 impl Default for XrayPos {
@@ -71,16 +119,22 @@ pub struct XrayElement {
     id: Id,
     geometry: Rectangle<f64, Logical>,
     src: Rectangle<f64, Buffer>,
+    /// Transform of the buffer texture contents.
+    src_transform: Transform,
     subregion: Option<TransformedRegion>,
     input_to_clip_geo: Mat3,
     clip_geo_size: Vec2,
     corner_radius: CornerRadius,
+    /// Fade-out toward the edges of the subregion, in the same space as `clip_geo_size`.
+    edge_falloff: Option<EdgeFalloff>,
     scale: f32,
     blur: bool,
-    noise: f32,
-    saturation: f32,
+    postprocess: Postprocess,
+    /// Premultiplied color that the shader mixes behind the (premultiplied) texture as is.
     bg_color: Color32F,
     program: Option<GlesTexProgram>,
+    /// Context ID of the renderer that the program and the buffer textures come from.
+    renderer_context_id: ContextId<GlesTexture>,
 }
 
 impl Xray {
@@ -90,43 +144,48 @@ impl Xray {
             backdrop: array::from_fn(|_| Rc::new(RefCell::new(EffectBuffer::new()))),
             backdrop_color: Color32F::TRANSPARENT,
             workspaces: Vec::new(),
+            blur_config: None,
         }
     }
 
-    #[allow(clippy::too_many_arguments)]
     pub fn render(
         &self,
         ctx: RenderCtx<GlesRenderer>,
         params: RenderParams,
         xray_pos: XrayPos,
         blur: bool,
-        noise: f32,
-        saturation: f32,
+        postprocess: Postprocess,
         push: &mut dyn FnMut(XrayElement),
-    ) {
+    ) -> XrayRenderResult {
+        let mut result = XrayRenderResult::default();
+
         let program = Shaders::get(ctx.renderer).postprocess_and_clip.clone();
+        let renderer_context_id = ctx.renderer.context_id();
 
         let zoom = xray_pos.zoom;
-        let pos_in_backdrop = xray_pos.pos_in_backdrop.upscale(zoom);
+        let geo_in_backdrop = xray_pos.geo_in_backdrop(params.geometry.size);
+        if self.is_disabled_at(geo_in_backdrop) {
+            return result;
+        }
 
         let (clip_geo, corner_radius) = params
             .clip
             .unwrap_or((params.geometry, CornerRadius::default()));
 
         let clip_offset = clip_geo.loc - params.geometry.loc;
-        let clip_pos_in_backdrop = pos_in_backdrop + clip_offset.upscale(zoom);
-
-        let geo_in_backdrop = Rectangle::new(pos_in_backdrop, params.geometry.size.upscale(zoom));
+        let clip_pos_in_backdrop = geo_in_backdrop.loc + clip_offset.upscale(zoom);
 
         let mut backdrop = self.backdrop[ctx.target as usize].borrow_mut();
         let backdrop_geo = Rectangle::from_size(backdrop.logical_size());
         let intersection_with_backdrop = backdrop_geo.intersection(geo_in_backdrop);
 
-        let mut skip_backdrop = intersection_with_backdrop.is_none();
+        let mut skip_backdrop = self.skips_backdrop(intersection_with_backdrop);
 
         let mut background = self.background[ctx.target as usize].borrow_mut();
         let prev = background.commit();
-        if background.prepare(ctx.renderer, blur) {
+        // Without workspaces there are no background elements to push, so don't bother preparing
+        // the buffer.
+        if !self.workspaces.is_empty() && background.prepare(ctx.renderer, blur) {
             if background.commit() != prev {
                 trace!("background damaged");
             }
@@ -134,46 +193,28 @@ impl Xray {
             let clip_geo_size = Vec2::new(clip_geo.size.w as f32, clip_geo.size.h as f32);
             let buf_size = background.logical_size();
 
-            for (ws_geo, bg_color) in &self.workspaces {
-                // If the background color is opaque, check if the workspace fully covers the
-                // element. In this case, we will skip the backdrop element since it's fully
-                // covered.
-                //
-                // FIXME: also implement some way to check if the background elements are fully
-                // covered in opaque regions, and not just the niri background color is opaque
-                let crop = if bg_color.is_opaque() && ws_geo.contains_rect(geo_in_backdrop) {
-                    skip_backdrop = true;
-                    // No need to intersect, we know it's fully covered.
-                    Some(geo_in_backdrop)
-                } else {
-                    ws_geo.intersection(geo_in_backdrop)
-                };
-
-                let Some(crop) = crop else {
+            for ws in &self.workspaces {
+                if ws.xray_disabled {
                     continue;
-                };
-
-                // If crop contains the intersection with backdrop, then the workspace fully
-                // covers the backdrop, so we can skip the backdrop.
-                //
-                // This can happen when the overview is closed (so workspaces align left/right with
-                // the backdrop) and the window is peeking out off screen to the side. In this
-                // case, this off-screen part is on top of nothing, neither workspace nor backdrop,
-                // but since the window doesn't fully cover the workspace, the check above doesn't
-                // skip the backdrop.
-                if bg_color.is_opaque()
-                    && intersection_with_backdrop
-                        .is_some_and(|backdrop| crop.contains_rect(backdrop))
-                {
-                    skip_backdrop = true;
                 }
+                let ws_geo = ws.geo;
+
+                let Some((crop, covers_backdrop)) = workspace_crop(
+                    ws_geo,
+                    ws.bg_color,
+                    geo_in_backdrop,
+                    intersection_with_backdrop,
+                ) else {
+                    continue;
+                };
+                skip_backdrop |= covers_backdrop;
 
                 // This can be different from zoom for surfaces that do not scale with
                 // workspaces, e.g. layer-shell top and overlay layer.
                 let ws_zoom = ws_geo.size / buf_size;
 
                 let src = Rectangle::new(crop.loc - ws_geo.loc, crop.size).downscale(ws_zoom);
-                let src = src.to_buffer(background.scale(), Transform::Normal, &buf_size);
+                let src = background.logical_to_buffer(src);
 
                 let buf_size = Vec2::new(buf_size.w as f32, buf_size.h as f32);
                 let pos_against_buf = (clip_pos_in_backdrop - ws_geo.loc).downscale(ws_zoom);
@@ -192,24 +233,28 @@ impl Xray {
                     id: background.id().clone(),
                     geometry,
                     src,
+                    src_transform: background.transform(),
                     subregion: params.subregion.clone(),
                     input_to_clip_geo,
                     clip_geo_size,
                     corner_radius,
+                    edge_falloff: params.edge_falloff,
                     scale: params.scale as f32,
                     blur,
-                    noise,
-                    saturation,
-                    bg_color: *bg_color,
+                    postprocess,
+                    bg_color: ws.bg_color,
                     program: program.clone(),
+                    renderer_context_id: renderer_context_id.clone(),
                 };
-                push(elem);
+                push_element(EffectKind::XrayBackground, elem, push);
+                result.pushed_background = true;
             }
         }
 
         // If the backdrop is fully covered by opaque background, we can skip it.
         if skip_backdrop {
-            return;
+            result.skipped_backdrop = true;
+            return result;
         }
 
         let prev = backdrop.commit();
@@ -219,7 +264,7 @@ impl Xray {
             }
 
             let buf_size = backdrop.logical_size();
-            let src = geo_in_backdrop.to_buffer(backdrop.scale(), Transform::Normal, &buf_size);
+            let src = backdrop.logical_to_buffer(geo_in_backdrop);
 
             let mut clip_geo_in_backdrop = Rectangle::new(clip_offset, clip_geo.size).upscale(zoom);
             clip_geo_in_backdrop.loc += geo_in_backdrop.loc;
@@ -242,32 +287,138 @@ impl Xray {
                 id: backdrop.id().clone(),
                 geometry: params.geometry,
                 src,
+                src_transform: backdrop.transform(),
                 subregion: params.subregion.clone(),
                 input_to_clip_geo,
                 clip_geo_size,
                 corner_radius: corner_radius.scaled_by(zoom as f32),
+                edge_falloff: params.edge_falloff.map(|falloff| falloff.upscale(zoom)),
                 scale: params.scale as f32,
                 blur,
-                noise,
-                saturation,
+                postprocess: postprocess.upscale(zoom),
                 bg_color: self.backdrop_color,
                 program: program.clone(),
+                renderer_context_id,
             };
-            push(elem);
+            push_element(EffectKind::XrayBackdrop, elem, push);
+            result.pushed_backdrop = true;
         }
+
+        result
     }
 }
 
+impl Xray {
+    /// Whether to skip the backdrop before looking at the workspaces.
+    ///
+    /// An element outside the backdrop normally shows only workspace backgrounds. But without any
+    /// workspaces, e.g. at startup or during a transition, the backdrop is all there is, so it's
+    /// drawn unconditionally rather than leaving the effect empty for a frame.
+    fn skips_backdrop(&self, intersection_with_backdrop: Option<Rectangle<f64, Logical>>) -> bool {
+        !self.workspaces.is_empty() && intersection_with_backdrop.is_none()
+    }
+
+    /// Whether an element at `geo_in_backdrop` is on a workspace with xray disabled.
+    ///
+    /// Elements only partially over such a workspace, e.g. during a workspace switch, still get
+    /// the background of the other workspaces.
+    fn is_disabled_at(&self, geo_in_backdrop: Rectangle<f64, Logical>) -> bool {
+        self.workspaces
+            .iter()
+            .any(|ws| ws.xray_disabled && ws.geo.contains_rect(geo_in_backdrop))
+    }
+}
+
+fn push_element(kind: EffectKind, elem: XrayElement, push: &mut dyn FnMut(XrayElement)) {
+    record_effect(|| EffectDescriptor {
+        kind,
+        geometry: elem.geometry,
+        blur: elem.blur,
+        subregion_rects: elem.subregion.as_ref().map(|region| region.rects.len()),
+    });
+    push(elem);
+}
+
+/// Computes the part of an element in backdrop coordinates that is covered by a workspace.
+///
+/// Returns the crop and whether the workspace fully covers the element's backdrop, in which case
+/// the backdrop doesn't need to be drawn.
+fn workspace_crop(
+    ws_geo: Rectangle<f64, Logical>,
+    bg_color: Color32F,
+    geo_in_backdrop: Rectangle<f64, Logical>,
+    intersection_with_backdrop: Option<Rectangle<f64, Logical>>,
+) -> Option<(Rectangle<f64, Logical>, bool)> {
+    let mut covers_backdrop = false;
+
+    // If the background color is opaque, check if the workspace fully covers the element. In this
+    // case, we will skip the backdrop element since it's fully covered.
+    //
+    // FIXME: also implement some way to check if the background elements are fully covered in
+    // opaque regions, and not just the niri background color is opaque
+    let crop = if bg_color.is_opaque() && ws_geo.contains_rect(geo_in_backdrop) {
+        covers_backdrop = true;
+        // No need to intersect, we know it's fully covered.
+        geo_in_backdrop
+    } else {
+        ws_geo.intersection(geo_in_backdrop)?
+    };
+
+    // If crop contains the intersection with backdrop, then the workspace fully covers the
+    // backdrop, so we can skip the backdrop.
+    //
+    // This can happen when the overview is closed (so workspaces align left/right with the
+    // backdrop) and the window is peeking out off screen to the side. In this case, this
+    // off-screen part is on top of nothing, neither workspace nor backdrop, but since the window
+    // doesn't fully cover the workspace, the check above doesn't skip the backdrop.
+    if bg_color.is_opaque()
+        && intersection_with_backdrop.is_some_and(|backdrop| crop.contains_rect(backdrop))
+    {
+        covers_backdrop = true;
+    }
+
+    Some((crop, covers_backdrop))
+}
+
 impl XrayElement {
-    fn compute_uniforms(&self) -> [Uniform<'static>; 7] {
+    /// Returns the active postprocess stages in the order they're applied, for debugging.
+    ///
+    /// Xray elements are always drawn with the shader, which clips them to the geometry.
+    pub fn pipeline_order(&self) -> String {
+        let background = self.bg_color.a() != 0.;
+        self.postprocess.pipeline_order(background, true)
+    }
+
+    fn is_from_context(&self, context_id: &ContextId<GlesTexture>) -> bool {
+        self.renderer_context_id == *context_id
+    }
+
+    fn compute_uniforms(&self, average_bound: bool, alpha_dither: f32) -> [Uniform<'static>; 16] {
+        let [adaptive_tint, average] = Postprocess::adaptive_tint_uniforms(average_bound);
+        let [region_bounds, region_falloff] = EdgeFalloff::uniforms(self.edge_falloff);
+        let [ring_color, ring_width] = EdgeRing::uniforms(self.postprocess.edge_ring);
+        // Revert the effect of the texture transform.
+        let input_to_clip_geo = self.input_to_clip_geo * texture_transform_mat(self.src_transform);
         [
             Uniform::new("niri_scale", self.scale),
             Uniform::new("geo_size", <[f32; 2]>::from(self.clip_geo_size)),
-            Uniform::new("corner_radius", <[f32; 4]>::from(self.corner_radius)),
-            mat3_uniform("input_to_geo", self.input_to_clip_geo),
-            Uniform::new("noise", self.noise),
-            Uniform::new("saturation", self.saturation),
+            Uniform::new(
+                "corner_radius",
+                <[f32; 4]>::from(self.corner_radius.sanitized()),
+            ),
+            mat3_uniform("input_to_geo", input_to_clip_geo),
+            Uniform::new("noise", self.postprocess.noise),
+            Uniform::new("noise_seed", self.postprocess.noise_seed),
+            Uniform::new("saturation", self.postprocess.saturation),
             Uniform::new("bg_color", self.bg_color.components()),
+            Uniform::new("tint", self.postprocess.tint.components()),
+            adaptive_tint,
+            average,
+            region_bounds,
+            region_falloff,
+            Uniform::new("alpha_dither", alpha_dither),
+            ring_color,
+            ring_width,
         ]
     }
 }
@@ -306,6 +457,13 @@ impl RenderElement<GlesRenderer> for XrayElement {
         _opaque_regions: &[Rectangle<i32, Physical>],
         _cache: Option<&UserDataMap>,
     ) -> Result<(), GlesError> {
+        // The renderer may have been recreated since this element was made (e.g. after a GPU
+        // reset), in which case the program and the buffer textures are no longer valid.
+        if !self.is_from_context(&frame.context_id()) {
+            warn!("trying to render xray from different renderer");
+            return Ok(());
+        }
+
         let mut buffer = self.buffer.borrow_mut();
         let texture = match buffer.render(frame, self.blur) {
             Ok(x) => x,
@@ -318,13 +476,15 @@ impl RenderElement<GlesRenderer> for XrayElement {
         // FIXME: avoid reallocating a fresh Vec here somehow.
         let mut filtered_damage = Vec::new();
         let damage = if let Some(subregion) = &self.subregion {
-            let src_to_geo = self.geometry.size / self.src.size;
+            // Undo the texture transform so that src lines up with the geometry.
+            let area = texture.size().to_f64();
+            let own_src = self.src.to_logical(1., self.src_transform, &area);
+            let src_to_geo = self.geometry.size / own_src.size;
 
             // Compute crop in geometry coordinates.
-            let mut crop = src;
-            crop.loc -= self.src.loc;
-            crop = crop.upscale(src_to_geo);
-            let mut crop = crop.to_logical(1., Transform::Normal, &Size::default());
+            let mut crop = src.to_logical(1., self.src_transform, &area);
+            crop.loc -= own_src.loc;
+            let mut crop = crop.upscale(src_to_geo);
 
             // Then convert to subregion coordinates.
             crop.loc += self.geometry.loc;
@@ -339,9 +499,21 @@ impl RenderElement<GlesRenderer> for XrayElement {
             damage
         };
 
-        let uniforms = self.program.is_some().then(|| self.compute_uniforms());
+        let average = self
+            .program
+            .as_ref()
+            .and_then(|_| self.postprocess.average_level(buffer.blur_levels()));
+        let alpha_dither = frame_alpha_dither(frame)?;
+        let uniforms = self
+            .program
+            .is_some()
+            .then(|| self.compute_uniforms(average.is_some(), alpha_dither));
         let uniforms = uniforms.as_ref().map_or(&[][..], |x| &x[..]);
 
+        if average.is_some() {
+            bind_average_texture(frame, average)?;
+        }
+
         frame.render_texture_from_to(
             &texture,
             src,
@@ -349,11 +521,17 @@ impl RenderElement<GlesRenderer> for XrayElement {
             damage,
             // FIXME: opaque regions need to be filtered like damage.
             &[],
-            Transform::Normal,
-            1.,
+            self.src_transform,
+            self.postprocess.alpha,
             self.program.as_ref(),
             uniforms,
-        )
+        )?;
+
+        if average.is_some() {
+            bind_average_texture(frame, None)?;
+        }
+
+        Ok(())
     }
 }
 
@@ -380,3 +558,295 @@ impl<'render> RenderElement<TtyRenderer<'render>> for XrayElement {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::iter::{once, zip};
+    use std::sync::Arc;
+
+    use niri_config::Color;
+    use smithay::backend::allocator::Fourcc;
+    use smithay::backend::renderer::element::Kind;
+
+    use super::*;
+    use crate::niri::OutputRenderElements;
+    use crate::render_helpers::debug::EffectCollector;
+    use crate::render_helpers::solid_color::{SolidColorBuffer, SolidColorRenderElement};
+    use crate::render_helpers::{render_to_vec, test_renderer};
+
+    fn rect(x: f64, y: f64, w: f64, h: f64) -> Rectangle<f64, Logical> {
+        Rectangle::new(Point::new(x, y), Size::new(w, h))
+    }
+
+    #[test]
+    fn static_layer_surface_backdrop_mapping() {
+        // A bottom-layer panel at the top of a 1920×1080 output.
+        let loc = Point::new(0., 0.);
+        let size = Size::new(1920., 40.);
+
+        // Background layer surfaces drawn on the backdrop map onto it 1:1, so the panel sees the
+        // wallpaper right behind it.
+        let pos = XrayPos::default().offset(loc);
+        assert_eq!(pos.geo_in_backdrop(size), rect(0., 0., 1920., 40.));
+
+        // The same holds for the bottom layer on a workspace at rest.
+        let pos = XrayPos::new(Point::new(0., 0.), 1.).offset(loc);
+        assert_eq!(pos.geo_in_backdrop(size), rect(0., 0., 1920., 40.));
+
+        // During a workspace switch, the bottom layer moves together with its workspace.
+        let pos = XrayPos::new(Point::new(0., -540.), 1.).offset(loc);
+        assert_eq!(pos.geo_in_backdrop(size), rect(0., -540., 1920., 40.));
+
+        // In the overview, it also zooms together with its workspace.
+        let pos = XrayPos::new(Point::new(480., 270.), 0.5).offset(Point::new(0., 1040.));
+        assert_eq!(pos.geo_in_backdrop(size), rect(480., 790., 960., 20.));
+    }
+
+    #[test]
+    fn opaque_workspace_covering_element_skips_backdrop() {
+        let ws = rect(0., 0., 100., 100.);
+        let geo = rect(10., 10., 20., 20.);
+        let opaque = Color32F::new(0., 0., 0., 1.);
+
+        let (crop, covers) = workspace_crop(ws, opaque, geo, Some(geo)).unwrap();
+        assert_eq!(crop, geo);
+        assert!(covers);
+
+        // Translucent background never covers the backdrop.
+        let translucent = Color32F::new(0., 0., 0., 0.5);
+        let (crop, covers) = workspace_crop(ws, translucent, geo, Some(geo)).unwrap();
+        assert_eq!(crop, geo);
+        assert!(!covers);
+    }
+
+    #[test]
+    fn opaque_workspace_covering_backdrop_part_skips_backdrop() {
+        // Element peeking out of the workspace and the backdrop to the right.
+        let ws = rect(0., 0., 100., 100.);
+        let geo = rect(90., 10., 20., 20.);
+        let backdrop = rect(0., 0., 100., 100.).intersection(geo);
+        let opaque = Color32F::new(0., 0., 0., 1.);
+
+        let (crop, covers) = workspace_crop(ws, opaque, geo, backdrop).unwrap();
+        assert_eq!(crop, rect(90., 10., 10., 20.));
+        assert!(covers);
+    }
+
+    #[test]
+    fn no_workspaces_falls_back_to_backdrop() {
+        let mut xray = Xray::new();
+        let geo = rect(10., 10., 20., 20.);
+
+        // With no workspaces, the backdrop is pushed even outside its bounds.
+        assert!(!xray.skips_backdrop(Some(geo)));
+        assert!(!xray.skips_backdrop(None));
+
+        xray.workspaces.push(XrayWorkspace {
+            geo: rect(0., 0., 100., 100.),
+            bg_color: Color32F::TRANSPARENT,
+            xray_disabled: false,
+        });
+        assert!(!xray.skips_backdrop(Some(geo)));
+        assert!(xray.skips_backdrop(None));
+    }
+
+    #[test]
+    fn disabled_workspace_skips_xray() {
+        let mut xray = Xray::new();
+        let ws = |x, xray_disabled| XrayWorkspace {
+            geo: rect(x, 0., 100., 100.),
+            bg_color: Color32F::new(0., 0., 0., 1.),
+            xray_disabled,
+        };
+        xray.workspaces.push(ws(0., false));
+        xray.workspaces.push(ws(100., true));
+
+        // A window on the disabled workspace gets no xray elements at all.
+        assert!(xray.is_disabled_at(rect(110., 10., 20., 20.)));
+        assert!(!xray.is_disabled_at(rect(10., 10., 20., 20.)));
+
+        // Mid-switch, a window still over the other workspace keeps its background there.
+        assert!(!xray.is_disabled_at(rect(90., 10., 20., 20.)));
+    }
+
+    #[test]
+    fn rotated_buffer_keeps_its_transform() {
+        let mut buffer = EffectBuffer::new();
+        buffer.update_size(Size::new(1920, 1080), Scale::from(1.), Transform::_90);
+        assert_eq!(buffer.transform(), Transform::_90);
+
+        // The logical size stays upright, but the texture has its sides swapped.
+        assert_eq!(buffer.logical_size(), Size::new(1920., 1080.));
+        let full = buffer.logical_to_buffer(Rectangle::from_size(Size::new(1920., 1080.)));
+        assert_eq!(full, Rectangle::from_size(Size::new(1080., 1920.)));
+
+        let src = buffer.logical_to_buffer(rect(0., 0., 100., 50.));
+        assert_eq!(src.size, Size::new(50., 100.));
+
+        // Mapping back with the buffer transform gives the original rectangle.
+        let area = Size::new(1080., 1920.);
+        assert_eq!(
+            src.to_logical(1., buffer.transform(), &area),
+            rect(0., 0., 100., 50.)
+        );
+    }
+
+    #[test]
+    fn workspace_not_intersecting_element() {
+        let ws = rect(0., 0., 100., 100.);
+        let geo = rect(200., 10., 20., 20.);
+        let opaque = Color32F::new(0., 0., 0., 1.);
+        assert_eq!(workspace_crop(ws, opaque, geo, None), None);
+    }
+
+    fn test_element(renderer_context_id: ContextId<GlesTexture>) -> XrayElement {
+        XrayElement {
+            buffer: Rc::new(RefCell::new(EffectBuffer::new())),
+            id: Id::new(),
+            geometry: rect(0., 0., 10., 10.),
+            src: Rectangle::from_size(Size::new(10., 10.)),
+            src_transform: Transform::Normal,
+            subregion: None,
+            input_to_clip_geo: Mat3::IDENTITY,
+            clip_geo_size: Vec2::new(10., 10.),
+            corner_radius: CornerRadius::default(),
+            edge_falloff: None,
+            scale: 1.,
+            blur: false,
+            postprocess: Postprocess {
+                noise: 0.,
+                noise_seed: 0.,
+                saturation: 1.,
+                tint: Color32F::TRANSPARENT,
+                adaptive_tint: false,
+                alpha: 1.,
+                edge_ring: None,
+            },
+            bg_color: Color32F::TRANSPARENT,
+            program: None,
+            renderer_context_id,
+        }
+    }
+
+    #[test]
+    fn egl_workspace_background_composites_premultiplied() {
+        let mut renderer = test_renderer();
+        let size = Size::new(10, 10);
+
+        // A 50% white backdrop.
+        let buffer = Rc::new(RefCell::new(EffectBuffer::new()));
+        {
+            let mut buffer = buffer.borrow_mut();
+            buffer.update_size(size, Scale::from(1.), Transform::Normal);
+            let white = SolidColorRenderElement::new(
+                Id::new(),
+                Rectangle::from_size(size.to_f64().to_logical(1.)),
+                CommitCounter::default(),
+                Color32F::new(0.5, 0.5, 0.5, 0.5),
+                Kind::Unspecified,
+            );
+            buffer
+                .elements()
+                .push(OutputRenderElements::SolidColor(white));
+            assert!(buffer.prepare(&mut renderer, false));
+        }
+
+        // Workspace backgrounds reach xray through their solid color buffers.
+        let background = SolidColorBuffer::new((10., 10.), Color::new_unpremul(1., 0., 0., 0.5));
+        let elem = XrayElement {
+            buffer,
+            bg_color: background.color(),
+            program: Shaders::get(&mut renderer).postprocess_and_clip.clone(),
+            ..test_element(renderer.context_id())
+        };
+
+        let pixels = render_to_vec(
+            &mut renderer,
+            size,
+            Scale::from(1.),
+            Transform::Normal,
+            Fourcc::Abgr8888,
+            once(elem),
+        )
+        .unwrap();
+
+        // 50% white over 50% red: 75% opaque, unpremultiplied (1, 1/3, 1/3).
+        for px in pixels.chunks_exact(4) {
+            let expected = [191, 64, 64, 191];
+            assert!(zip(px, expected).all(|(a, b)| a.abs_diff(b) <= 1), "{px:?}");
+        }
+    }
+
+    #[test]
+    fn context_mismatch_is_detected() {
+        let context_id = ContextId::new();
+        let elem = test_element(context_id.clone());
+
+        assert!(elem.is_from_context(&context_id));
+        assert!(!elem.is_from_context(&ContextId::new()));
+    }
+
+    #[test]
+    fn pushed_elements_are_recorded() {
+        let mut pushed = 0;
+        let mut push = |_: XrayElement| pushed += 1;
+
+        // Nothing is recorded without a collector.
+        push_element(
+            EffectKind::XrayBackground,
+            test_element(ContextId::new()),
+            &mut push,
+        );
+
+        let collector = EffectCollector::install();
+
+        let mut background = test_element(ContextId::new());
+        background.blur = true;
+        push_element(EffectKind::XrayBackground, background, &mut push);
+
+        let mut backdrop = test_element(ContextId::new());
+        backdrop.geometry = rect(5., 5., 10., 10.);
+        backdrop.subregion = Some(TransformedRegion::new(
+            Arc::new(vec![Rectangle::from_size(Size::new(2, 2)); 3]),
+            Scale::from(1.),
+            Point::new(0., 0.),
+        ));
+        push_element(EffectKind::XrayBackdrop, backdrop, &mut push);
+
+        let descriptors = collector.take();
+        assert_eq!(pushed, 3);
+        assert_eq!(
+            descriptors,
+            [
+                EffectDescriptor {
+                    kind: EffectKind::XrayBackground,
+                    geometry: rect(0., 0., 10., 10.),
+                    blur: true,
+                    subregion_rects: None,
+                },
+                EffectDescriptor {
+                    kind: EffectKind::XrayBackdrop,
+                    geometry: rect(5., 5., 10., 10.),
+                    blur: false,
+                    subregion_rects: Some(3),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn render_result_pushed_any() {
+        let result = XrayRenderResult {
+            skipped_backdrop: true,
+            ..Default::default()
+        };
+        assert!(!result.pushed_any());
+
+        let result = XrayRenderResult {
+            pushed_background: true,
+            skipped_backdrop: true,
+            ..Default::default()
+        };
+        assert!(result.pushed_any());
+    }
+}
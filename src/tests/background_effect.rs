@@ -0,0 +1,51 @@
+use super::*;
+use crate::handlers::background_effect::get_aggregated_blur_region;
+
+#[test]
+fn destroyed_subsurface_leaves_aggregated_blur_region() {
+    let mut f = Fixture::new();
+    f.add_output(1, (1920, 1080));
+
+    let id = f.add_client();
+    let window = f.client(id).create_window();
+    let surface = window.surface.clone();
+    window.commit();
+    f.roundtrip(id);
+
+    let window = f.client(id).window(&surface);
+    window.attach_new_buffer();
+    window.set_size(100, 100);
+    window.ack_last_and_commit();
+    f.double_roundtrip(id);
+
+    let client = f.client(id);
+    let (child, subsurface) = client.create_subsurface(&surface);
+    let effect = client.set_blur_region(&child, &[(0, 0, 50, 10)]);
+    subsurface.set_position(10, 30);
+    subsurface.set_desync();
+    child.commit();
+    // The subsurface position is applied on the parent commit.
+    client.window(&surface).commit();
+    f.double_roundtrip(id);
+
+    let rects = |f: &mut Fixture| {
+        let mapped = f.niri().layout.windows().next().unwrap().1;
+        let root = mapped.toplevel().wl_surface().clone();
+        get_aggregated_blur_region(&root).map(|rects| {
+            rects
+                .iter()
+                .map(|r| (r.loc.x, r.loc.y, r.size.w, r.size.h))
+                .collect::<Vec<_>>()
+        })
+    };
+    assert_eq!(rects(&mut f), Some(vec![(10, 30, 50, 10)]));
+
+    // Destroying the subsurface doesn't commit the parent, but the cached region must not keep
+    // the subsurface's part.
+    effect.destroy();
+    subsurface.destroy();
+    child.destroy();
+    f.double_roundtrip(id);
+
+    assert_eq!(rects(&mut f), None);
+}
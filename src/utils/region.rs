@@ -1,5 +1,7 @@
 use std::cmp::{max, min};
 use std::collections::BTreeSet;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 
 use smithay::utils::{Logical, Physical, Point, Rectangle, Scale};
@@ -18,34 +20,128 @@ pub struct TransformedRegion {
     pub scale: Scale<f64>,
     /// Translation to apply to each rect after scaling.
     pub offset: Point<f64, Logical>,
+    /// Bounding box of `rects`, so that it's not recomputed for every use.
+    bbox: Option<Rectangle<i32, Logical>>,
 }
 
 impl TransformedRegion {
+    /// Creates a region, computing the bounding box of `rects` once.
+    pub fn new(
+        rects: Arc<Vec<Rectangle<i32, Logical>>>,
+        scale: Scale<f64>,
+        offset: Point<f64, Logical>,
+    ) -> Self {
+        let bbox = rects.iter().copied().reduce(Rectangle::merge);
+        Self {
+            rects,
+            scale,
+            offset,
+            bbox,
+        }
+    }
+
     /// Returns an iterator over the top-left and bottom-right corners of transformed rects.
     pub fn iter(&self) -> impl Iterator<Item = (Point<f64, Logical>, Point<f64, Logical>)> + '_ {
-        self.rects.iter().map(|r| {
-            // Here we start in a happy i32 world where everything lines up, and rectangle loc +
-            // size is exactly equal to the adjacent rectangle's loc.
-            //
-            // Unfortunately, we're about to descend to the floating point hell. And we *really*
-            // want adjacent rects to remain adjacent no matter what. So we'll convert our rects to
-            // their extremities (rather than loc and size), and operate on those. Coordinates from
-            // adjacent rects will undergo exactly the same floating point operations, so when
-            // they're ultimately rounded to physical pixels, they will remain adjacent.
-            let r = r.to_f64();
-
-            let mut a = r.loc;
-            // f64 is enough to represent this i32 addition exactly.
-            let mut b = r.loc + r.size.to_point();
-
-            a = a.upscale(self.scale);
-            b = b.upscale(self.scale);
-
-            a += self.offset;
-            b += self.offset;
-
-            (a, b)
-        })
+        self.rects.iter().map(|r| self.transform(*r))
+    }
+
+    /// Returns the top-left and bottom-right corners of a transformed rect.
+    fn transform(&self, r: Rectangle<i32, Logical>) -> (Point<f64, Logical>, Point<f64, Logical>) {
+        // Here we start in a happy i32 world where everything lines up, and rectangle loc + size is
+        // exactly equal to the adjacent rectangle's loc.
+        //
+        // Unfortunately, we're about to descend to the floating point hell. And we *really* want
+        // adjacent rects to remain adjacent no matter what. So we'll convert our rects to their
+        // extremities (rather than loc and size), and operate on those. Coordinates from adjacent
+        // rects will undergo exactly the same floating point operations, so when they're ultimately
+        // rounded to physical pixels, they will remain adjacent.
+        let r = r.to_f64();
+
+        let mut a = r.loc;
+        // f64 is enough to represent this i32 addition exactly.
+        let mut b = r.loc + r.size.to_point();
+
+        a = a.upscale(self.scale);
+        b = b.upscale(self.scale);
+
+        a += self.offset;
+        b += self.offset;
+
+        (a, b)
+    }
+
+    /// Returns the bounding box of the transformed rects, or `None` if there are none.
+    ///
+    /// The transform is monotonic, so this is exactly the merge of all transformed rects.
+    pub fn bbox(&self) -> Option<Rectangle<f64, Logical>> {
+        let (a, b) = self.transform(self.bbox?);
+        Some(Rectangle::from_extremities(a, b))
+    }
+
+    /// Replaces the rects with their bounding box if there are more than `max_rects` of them.
+    ///
+    /// Every rect is drawn separately, so this bounds the draw count for pathological regions, at
+    /// the cost of covering some area outside the region. `0` disables the limit.
+    pub fn simplified(mut self, max_rects: usize) -> Self {
+        if max_rects == 0 || self.rects.len() <= max_rects {
+            return self;
+        }
+
+        if let Some(bbox) = self.bbox {
+            self.rects = Arc::new(vec![bbox]);
+        }
+        self
+    }
+
+    /// Returns this region with its rects clipped to `clip`.
+    ///
+    /// `clip` is in the same coordinate space as `self.iter()`. It is rounded outwards to the
+    /// region's own coordinates, so nothing inside `clip` is ever removed.
+    pub fn clipped(self, clip: Rectangle<f64, Logical>) -> Self {
+        let a = (clip.loc - self.offset).downscale(self.scale);
+        let b = (clip.loc + clip.size.to_point() - self.offset).downscale(self.scale);
+        let a = Point::<i32, Logical>::new(a.x.floor() as i32, a.y.floor() as i32);
+        let b = Point::<i32, Logical>::new(b.x.ceil() as i32, b.y.ceil() as i32);
+        let clip = Rectangle::from_extremities(a, b);
+
+        // Avoid reallocating the (possibly shared) rects when nothing needs clipping.
+        if self.bbox.is_none_or(|bbox| clip.contains_rect(bbox)) {
+            return self;
+        }
+
+        let rects = self
+            .rects
+            .iter()
+            .filter_map(|r| r.intersection(clip))
+            .collect();
+
+        Self::new(Arc::new(rects), self.scale, self.offset)
+    }
+
+    /// Returns this region with `exclude` subtracted.
+    ///
+    /// `exclude` is in the same coordinate space as `self.iter()`. Excluded rects are rounded
+    /// inwards to the region's own coordinates, so nothing outside `exclude` is ever removed.
+    pub fn subtract(&self, exclude: &[Rectangle<f64, Logical>]) -> Self {
+        let region = rects_to_region(&self.rects);
+        let mut excluded = RegionAttributes::default();
+        for r in exclude {
+            let a = (r.loc - self.offset).downscale(self.scale);
+            let b = (r.loc + r.size.to_point() - self.offset).downscale(self.scale);
+            let a = Point::<i32, Logical>::new(a.x.ceil() as i32, a.y.ceil() as i32);
+            let b = Point::<i32, Logical>::new(b.x.floor() as i32, b.y.floor() as i32);
+            if b.x <= a.x || b.y <= a.y {
+                continue;
+            }
+
+            let r = Rectangle::from_extremities(a, b);
+            excluded.rects.push((RectangleKind::Add, r));
+        }
+
+        let mut rects = Vec::new();
+        region_subtract(&region, &excluded, &mut rects);
+
+        Self::new(Arc::new(rects), self.scale, self.offset)
     }
 
     /// Intersects damage with this subregion.
@@ -56,6 +152,27 @@ impl TransformedRegion {
         dst: Rectangle<i32, Physical>,
         damage: &[Rectangle<i32, Physical>],
         filtered: &mut Vec<Rectangle<i32, Physical>>,
+    ) {
+        // Fast path: when the crop contains every rect (e.g. the whole surface is visible), no
+        // rect needs to be clipped to the crop.
+        let cs = crop.size.to_point();
+        let contained = self.bbox.is_none_or(|bbox| {
+            let (a, b) = self.transform(bbox);
+            let a = a - crop.loc;
+            let b = b - crop.loc;
+            0. <= a.x && 0. <= a.y && b.x <= cs.x && b.y <= cs.y
+        });
+
+        self.filter_damage_inner(crop, dst, damage, filtered, !contained);
+    }
+
+    fn filter_damage_inner(
+        &self,
+        crop: Rectangle<f64, Logical>,
+        dst: Rectangle<i32, Physical>,
+        damage: &[Rectangle<i32, Physical>],
+        filtered: &mut Vec<Rectangle<i32, Physical>>,
+        clip_to_crop: bool,
     ) {
         let scale = dst.size.to_f64() / crop.size;
 
@@ -67,8 +184,14 @@ impl TransformedRegion {
             b -= crop.loc;
 
             // Intersect with crop.
-            let ia = Point::new(f64::max(a.x, 0.), f64::max(a.y, 0.));
-            let ib = Point::new(f64::min(b.x, cs.x), f64::min(b.y, cs.y));
+            let (ia, ib) = if clip_to_crop {
+                (
+                    Point::new(f64::max(a.x, 0.), f64::max(a.y, 0.)),
+                    Point::new(f64::min(b.x, cs.x), f64::min(b.y, cs.y)),
+                )
+            } else {
+                (a, b)
+            };
             if ib.x <= ia.x || ib.y <= ia.y {
                 // No intersection.
                 continue;
@@ -91,6 +214,34 @@ impl TransformedRegion {
     }
 }
 
+// Client regions go through three rect limits, from the protocol to the draw:
+//
+// 1. LARGE_REGION_RECTS counts the rects as the client sent them. It only triggers a warning, since
+//    such a region is slow to decompose, but is still processed as is.
+// 2. MAX_NON_OVERLAPPING_RECTS caps the decomposition result. It bounds the memory and the work of
+//    everything downstream, whatever the config says.
+// 3. The `max-region-rects` blur option (TransformedRegion::simplified()) caps the rects drawn per
+//    effect. It is set per config, is lower by default, and can be disabled.
+//
+// So a region past limit 2 is always a single bounding box, and limit 3 only matters below limit 2.
+
+/// Number of rects in a region above which decomposing it gets noticeably slow.
+///
+/// Decomposing goes over all rects for every band between their Y coordinates, so the work grows
+/// quadratically with the rect count.
+pub const LARGE_REGION_RECTS: usize = 256;
+
+/// Whether a region has enough rects to be slow to decompose, see [`LARGE_REGION_RECTS`].
+pub fn is_large_region(region: &RegionAttributes) -> bool {
+    region.rects.len() > LARGE_REGION_RECTS
+}
+
+/// Limit on the number of rects that a region decomposes into.
+///
+/// Regions come from clients, and every rect costs some work for every draw. Past this limit, the
+/// region is replaced with its bounding box.
+const MAX_NON_OVERLAPPING_RECTS: usize = 1024;
+
 pub fn region_to_non_overlapping_rects(
     region: &RegionAttributes,
     output: &mut Vec<Rectangle<i32, Logical>>,
@@ -118,107 +269,322 @@ pub fn region_to_non_overlapping_rects(
 
     // Iterate over Y bands.
     for hi in ys {
-        spans.clear();
+        band_spans(region, lo, hi, &mut spans);
 
-        'region: for (kind, r) in &region.rects {
-            // Skip rects that don't overlap with the Y band.
-            if hi <= r.loc.y || r.loc.y + r.size.h <= lo {
-                continue;
+        for (x1, x2) in spans.drain(..) {
+            output.push(Rectangle::from_extremities((x1, lo), (x2, hi)));
+        }
+
+        lo = hi;
+    }
+
+    if output.len() > MAX_NON_OVERLAPPING_RECTS {
+        static WARNED: AtomicBool = AtomicBool::new(false);
+        if !WARNED.swap(true, Ordering::Relaxed) {
+            warn!(
+                "region decomposes into {} rects, over the limit of {}; \
+                 using its bounding box instead",
+                output.len(),
+                MAX_NON_OVERLAPPING_RECTS
+            );
+        }
+
+        let bbox = output.drain(..).reduce(Rectangle::merge);
+        output.extend(bbox);
+    }
+}
+
+/// Computes the intersection of two regions as non-overlapping rects.
+pub fn region_intersection(
+    a: &RegionAttributes,
+    b: &RegionAttributes,
+    output: &mut Vec<Rectangle<i32, Logical>>,
+) {
+    let _span = tracy_client::span!("region_intersection");
+
+    output.clear();
+
+    // Collect all unique Y coordinates from both regions.
+    let ys = BTreeSet::from_iter(
+        a.rects
+            .iter()
+            .chain(&b.rects)
+            .flat_map(|(_, r)| [r.loc.y, r.loc.y + r.size.h]),
+    );
+
+    let mut ys = ys.into_iter();
+    let Some(mut lo) = ys.next() else {
+        // Both regions were empty.
+        return;
+    };
+
+    let mut spans_a = Vec::<(i32, i32)>::new();
+    let mut spans_b = Vec::<(i32, i32)>::new();
+
+    // Iterate over Y bands.
+    for hi in ys {
+        band_spans(a, lo, hi, &mut spans_a);
+        band_spans(b, lo, hi, &mut spans_b);
+
+        // Both span lists are sorted and non-overlapping, so intersect them in one pass.
+        let mut i = 0;
+        let mut j = 0;
+        while i < spans_a.len() && j < spans_b.len() {
+            let (a1, a2) = spans_a[i];
+            let (b1, b2) = spans_b[j];
+
+            let x1 = max(a1, b1);
+            let x2 = min(a2, b2);
+            if x1 < x2 {
+                output.push(Rectangle::from_extremities((x1, lo), (x2, hi)));
             }
 
-            let mut x1 = r.loc.x;
-            let mut x2 = r.loc.x + r.size.w;
-            if x1 == x2 {
-                // Empty rect.
-                continue;
+            // Advance whichever span ends first.
+            if a2 < b2 {
+                i += 1;
+            } else {
+                j += 1;
+            }
+        }
+
+        lo = hi;
+    }
+}
+
+/// Computes `a` minus `b` as non-overlapping rects.
+pub fn region_subtract(
+    a: &RegionAttributes,
+    b: &RegionAttributes,
+    output: &mut Vec<Rectangle<i32, Logical>>,
+) {
+    let _span = tracy_client::span!("region_subtract");
+
+    output.clear();
+
+    // Collect all unique Y coordinates from both regions, so that b's edges split a's bands.
+    let ys = BTreeSet::from_iter(
+        a.rects
+            .iter()
+            .chain(&b.rects)
+            .flat_map(|(_, r)| [r.loc.y, r.loc.y + r.size.h]),
+    );
+
+    let mut ys = ys.into_iter();
+    let Some(mut lo) = ys.next() else {
+        // Both regions were empty.
+        return;
+    };
+
+    let mut spans_a = Vec::<(i32, i32)>::new();
+    let mut spans_b = Vec::<(i32, i32)>::new();
+
+    // Iterate over Y bands.
+    for hi in ys {
+        band_spans(a, lo, hi, &mut spans_a);
+        band_spans(b, lo, hi, &mut spans_b);
+
+        // Both span lists are sorted and non-overlapping, so subtract them in one pass.
+        let mut j = 0;
+        for &(a1, a2) in &spans_a {
+            // Skip b spans that end before this a span.
+            while j < spans_b.len() && spans_b[j].1 <= a1 {
+                j += 1;
+            }
+
+            // Cut the b spans overlapping this a span out of it.
+            let mut x1 = a1;
+            for &(b1, b2) in spans_b[j..].iter().take_while(|(b1, _)| *b1 < a2) {
+                if x1 < b1 {
+                    output.push(Rectangle::from_extremities((x1, lo), (b1, hi)));
+                }
+                x1 = max(x1, b2);
+            }
+            if x1 < a2 {
+                output.push(Rectangle::from_extremities((x1, lo), (a2, hi)));
             }
+        }
+
+        lo = hi;
+    }
+}
+
+/// Computes the region's spans within the Y band `[lo, hi)`.
+///
+/// Writes a sorted list of non-overlapping `[start, end)` tuples into `spans`.
+fn band_spans(region: &RegionAttributes, lo: i32, hi: i32, spans: &mut Vec<(i32, i32)>) {
+    spans.clear();
 
-            match *kind {
-                RectangleKind::Add => {
-                    // Iterate over existing spans backwards.
-                    for i in (0..spans.len()).rev() {
-                        let (start, end) = spans[i];
-
-                        // New span is to the right.
-                        if end < x1 {
-                            spans.insert(i + 1, (x1, x2));
-                            continue 'region;
-                        }
-
-                        // New span is to the left.
-                        if x2 < start {
-                            continue;
-                        }
-
-                        // New span overlaps this span; merge them.
-                        spans.remove(i);
-                        x1 = min(x1, start);
-                        x2 = max(x2, end);
+    'region: for (kind, r) in &region.rects {
+        // Skip rects that don't overlap with the Y band.
+        if hi <= r.loc.y || r.loc.y + r.size.h <= lo {
+            continue;
+        }
+
+        let mut x1 = r.loc.x;
+        let mut x2 = r.loc.x + r.size.w;
+        if x1 == x2 {
+            // Empty rect.
+            continue;
+        }
+
+        match *kind {
+            RectangleKind::Add => {
+                // Iterate over existing spans backwards.
+                for i in (0..spans.len()).rev() {
+                    let (start, end) = spans[i];
+
+                    // New span is to the right.
+                    if end < x1 {
+                        spans.insert(i + 1, (x1, x2));
+                        continue 'region;
+                    }
+
+                    // New span is to the left.
+                    if x2 < start {
+                        continue;
                     }
 
-                    spans.insert(0, (x1, x2));
+                    // New span overlaps this span; merge them.
+                    spans.remove(i);
+                    x1 = min(x1, start);
+                    x2 = max(x2, end);
                 }
-                RectangleKind::Subtract => {
-                    // Iterate over existing spans backwards.
-                    for i in (0..spans.len()).rev() {
-                        let (start, end) = spans[i];
-
-                        // Subtract span is to the right.
-                        if end <= x1 {
-                            continue 'region;
-                        }
-
-                        // Subtract span is to the left.
-                        if x2 <= start {
-                            continue;
-                        }
-
-                        // Subtract span overlaps this span.
-                        spans.remove(i);
-                        if x2 < end {
-                            spans.insert(i, (x2, end));
-                        }
-                        if start < x1 {
-                            spans.insert(i, (start, x1));
-                        }
+
+                spans.insert(0, (x1, x2));
+            }
+            RectangleKind::Subtract => {
+                // Iterate over existing spans backwards.
+                for i in (0..spans.len()).rev() {
+                    let (start, end) = spans[i];
+
+                    // Subtract span is to the right.
+                    if end <= x1 {
+                        continue 'region;
+                    }
+
+                    // Subtract span is to the left.
+                    if x2 <= start {
+                        continue;
+                    }
+
+                    // Subtract span overlaps this span.
+                    spans.remove(i);
+                    if x2 < end {
+                        spans.insert(i, (x2, end));
+                    }
+                    if start < x1 {
+                        spans.insert(i, (start, x1));
                     }
                 }
             }
         }
+    }
+}
 
-        for (x1, x2) in spans.drain(..) {
-            output.push(Rectangle::from_extremities((x1, lo), (x2, hi)));
+/// Converts non-overlapping rects back into a region made of `Add` rects.
+///
+/// This is the inverse of [`region_to_non_overlapping_rects()`].
+pub fn rects_to_region(rects: &[Rectangle<i32, Logical>]) -> RegionAttributes {
+    RegionAttributes {
+        rects: rects.iter().map(|r| (RectangleKind::Add, *r)).collect(),
+    }
+}
+
+/// Formats a region and its decomposition into non-overlapping rects as a test reproducer.
+///
+/// The region is written as a `check()` call of the tests in this file, followed by the rects in
+/// the format of their snapshots.
+pub fn format_region_reproducer(
+    region: &RegionAttributes,
+    rects: &[Rectangle<i32, Logical>],
+) -> String {
+    let extremities = |r: &Rectangle<i32, Logical>| {
+        let (x1, y1) = (r.loc.x, r.loc.y);
+        (x1, y1, x1 + r.size.w, y1 + r.size.h)
+    };
+
+    let mut s = String::from("check(&[");
+    for (i, (kind, r)) in region.rects.iter().enumerate() {
+        if i > 0 {
+            s.push_str(", ");
         }
+        let kind = match kind {
+            RectangleKind::Add => "Add",
+            RectangleKind::Subtract => "Subtract",
+        };
+        let (x1, y1, x2, y2) = extremities(r);
+        write!(s, "({kind}, ({x1}, {y1}, {x2}, {y2}))").unwrap();
+    }
+    s.push_str("])\n");
 
-        lo = hi;
+    for r in rects {
+        let (x1, y1, x2, y2) = extremities(r);
+        writeln!(s, "{x1:2} {y1:2} - {x2:2} {y2:2}").unwrap();
     }
+    s
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashSet;
     use std::fmt::Write as _;
+    use std::sync::Arc;
 
     use insta::assert_snapshot;
     use proptest::prelude::*;
-    use smithay::utils::{Logical, Point, Rectangle, Size};
+    use smithay::utils::{Logical, Point, Rectangle, Scale, Size};
     use smithay::wayland::compositor::{RectangleKind, RegionAttributes};
 
-    use super::region_to_non_overlapping_rects;
+    use super::{
+        format_region_reproducer, rects_to_region, region_intersection, region_subtract,
+        region_to_non_overlapping_rects, TransformedRegion, MAX_NON_OVERLAPPING_RECTS,
+    };
+
+    fn covered_cells(rects: &[Rectangle<i32, Logical>]) -> HashSet<(i32, i32)> {
+        let mut cells = HashSet::new();
+        for r in rects {
+            for y in r.loc.y..r.loc.y + r.size.h {
+                for x in r.loc.x..r.loc.x + r.size.w {
+                    cells.insert((x, y));
+                }
+            }
+        }
+        cells
+    }
+
+    fn arbitrary_region() -> impl Strategy<Value = RegionAttributes> {
+        proptest::collection::vec(
+            (
+                prop_oneof![Just(RectangleKind::Add), Just(RectangleKind::Subtract)],
+                (0..20i32, 0..20i32, 0..20i32, 0..20i32),
+            ),
+            1..10,
+        )
+        .prop_map(|rects| RegionAttributes {
+            rects: rects
+                .into_iter()
+                .map(|(kind, (x, y, w, h))| {
+                    (kind, Rectangle::new(Point::new(x, y), Size::new(w, h)))
+                })
+                .collect(),
+        })
+    }
 
     #[allow(clippy::type_complexity)]
-    fn check(rects: &[(RectangleKind, (i32, i32, i32, i32))]) -> String {
-        let region = RegionAttributes {
+    fn region(rects: &[(RectangleKind, (i32, i32, i32, i32))]) -> RegionAttributes {
+        RegionAttributes {
             rects: rects
                 .iter()
                 .map(|(kind, (x1, y1, x2, y2))| {
                     (*kind, Rectangle::from_extremities((*x1, *y1), (*x2, *y2)))
                 })
                 .collect(),
-        };
-        let mut output = Vec::new();
-        region_to_non_overlapping_rects(&region, &mut output);
+        }
+    }
+
+    fn format_rects(rects: &[Rectangle<i32, Logical>]) -> String {
         let mut s = String::new();
-        for r in &output {
+        for r in rects {
             let x1 = r.loc.x;
             let y1 = r.loc.y;
             let x2 = x1 + r.size.w;
@@ -228,6 +594,63 @@ mod tests {
         s
     }
 
+    #[allow(clippy::type_complexity)]
+    fn check(rects: &[(RectangleKind, (i32, i32, i32, i32))]) -> String {
+        let mut output = Vec::new();
+        region_to_non_overlapping_rects(&region(rects), &mut output);
+        format_rects(&output)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn check_intersection(
+        a: &[(RectangleKind, (i32, i32, i32, i32))],
+        b: &[(RectangleKind, (i32, i32, i32, i32))],
+    ) -> String {
+        let mut output = Vec::new();
+        region_intersection(&region(a), &region(b), &mut output);
+        format_rects(&output)
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn check_subtract(
+        a: &[(RectangleKind, (i32, i32, i32, i32))],
+        b: &[(RectangleKind, (i32, i32, i32, i32))],
+    ) -> String {
+        let mut output = Vec::new();
+        region_subtract(&region(a), &region(b), &mut output);
+        format_rects(&output)
+    }
+
+    /// Draws the decomposed rects into a grid covering their bounding box.
+    ///
+    /// Every output rect is filled with its own letter, so both the covered area and the way it
+    /// was split into rects are visible at a glance.
+    #[allow(clippy::type_complexity)]
+    fn check_grid(rects: &[(RectangleKind, (i32, i32, i32, i32))]) -> String {
+        let mut output = Vec::new();
+        region_to_non_overlapping_rects(&region(rects), &mut output);
+        format_grid(&output)
+    }
+
+    fn format_grid(rects: &[Rectangle<i32, Logical>]) -> String {
+        let Some(bbox) = rects.iter().copied().reduce(|a, b| a.merge(b)) else {
+            return String::new();
+        };
+
+        let mut s = String::new();
+        for y in bbox.loc.y..bbox.loc.y + bbox.size.h {
+            for x in bbox.loc.x..bbox.loc.x + bbox.size.w {
+                let c = rects
+                    .iter()
+                    .position(|r| r.contains((x, y)))
+                    .map_or('.', |i| char::from(b'a' + (i % 26) as u8));
+                s.push(c);
+            }
+            s.push('\n');
+        }
+        s
+    }
+
     #[test]
     fn test_region_to_non_overlapping_rects() {
         use RectangleKind::*;
@@ -279,6 +702,414 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_region_reproducer() {
+        use RectangleKind::*;
+
+        let region = region(&[(Add, (0, 0, 20, 20)), (Subtract, (5, 5, 15, 15))]);
+        let mut rects = Vec::new();
+        region_to_non_overlapping_rects(&region, &mut rects);
+
+        assert_snapshot!(
+            format_region_reproducer(&region, &rects),
+            @"
+        check(&[(Add, (0, 0, 20, 20)), (Subtract, (5, 5, 15, 15))])
+         0  0 - 20  5
+         0  5 -  5 15
+        15  5 - 20 15
+         0 15 - 20 20
+        "
+        );
+    }
+
+    #[test]
+    fn test_region_coincident_edges() {
+        use RectangleKind::*;
+
+        // subtract_touching_right_edge
+        assert_snapshot!(
+            check(&[(Add, (0, 0, 10, 10)), (Subtract, (10, 0, 20, 10))]),
+            @" 0  0 - 10 10"
+        );
+
+        // subtract_touching_left_edge
+        assert_snapshot!(
+            check(&[(Add, (10, 0, 20, 10)), (Subtract, (0, 0, 10, 10))]),
+            @"10  0 - 20 10"
+        );
+
+        // subtract_touching_bottom_edge
+        assert_snapshot!(
+            check(&[(Add, (0, 0, 10, 10)), (Subtract, (0, 10, 10, 20))]),
+            @" 0  0 - 10 10"
+        );
+
+        // subtract_sharing_left_edge
+        assert_snapshot!(
+            check(&[(Add, (0, 0, 10, 10)), (Subtract, (0, 0, 4, 10))]),
+            @" 4  0 - 10 10"
+        );
+
+        // subtract_sharing_right_edge
+        assert_snapshot!(
+            check(&[(Add, (0, 0, 10, 10)), (Subtract, (6, 0, 10, 10))]),
+            @" 0  0 -  6 10"
+        );
+
+        // subtract_sharing_top_edge
+        assert_snapshot!(
+            check(&[(Add, (0, 0, 10, 10)), (Subtract, (0, 0, 10, 4))]),
+            @" 0  4 - 10 10"
+        );
+
+        // subtract_exactly_equal
+        assert_snapshot!(
+            check(&[(Add, (0, 0, 10, 10)), (Subtract, (0, 0, 10, 10))]),
+            @""
+        );
+
+        // subtract_zero_width_on_edge
+        assert_snapshot!(
+            check(&[(Add, (0, 0, 10, 10)), (Subtract, (10, 0, 10, 10))]),
+            @" 0  0 - 10 10"
+        );
+
+        // add_into_subtracted_gap_exactly
+        assert_snapshot!(
+            check(&[
+                (Add, (0, 0, 12, 4)),
+                (Subtract, (4, 0, 8, 4)),
+                (Add, (4, 0, 8, 4)),
+            ]),
+            @" 0  0 - 12  4"
+        );
+
+        // add_touching_both_neighbors
+        assert_snapshot!(
+            check(&[
+                (Add, (0, 0, 4, 4)),
+                (Add, (8, 0, 12, 4)),
+                (Add, (4, 0, 8, 4)),
+            ]),
+            @" 0  0 - 12  4"
+        );
+
+        // split_span_then_subtract_at_split_edges
+        assert_snapshot!(
+            check_grid(&[
+                (Add, (0, 0, 12, 3)),
+                (Subtract, (4, 0, 8, 3)),
+                (Subtract, (8, 1, 12, 2)),
+                (Subtract, (0, 1, 4, 2)),
+            ]),
+            @r"
+        aaaa....bbbb
+        ............
+        cccc....dddd
+        "
+        );
+    }
+
+    #[test]
+    fn test_region_grid() {
+        use RectangleKind::*;
+
+        assert_snapshot!(check_grid(&[]), @"");
+
+        // donut
+        assert_snapshot!(
+            check_grid(&[(Add, (0, 0, 6, 6)), (Subtract, (2, 2, 4, 4))]),
+            @"
+        aaaaaa
+        aaaaaa
+        bb..cc
+        bb..cc
+        dddddd
+        dddddd
+        "
+        );
+
+        // l_shape
+        assert_snapshot!(
+            check_grid(&[(Add, (0, 0, 2, 6)), (Add, (0, 4, 6, 6))]),
+            @"
+        aa....
+        aa....
+        aa....
+        aa....
+        bbbbbb
+        bbbbbb
+        "
+        );
+
+        // checkerboard
+        assert_snapshot!(
+            check_grid(&[
+                (Add, (0, 0, 2, 2)),
+                (Add, (4, 0, 6, 2)),
+                (Add, (2, 2, 4, 4)),
+                (Add, (0, 4, 2, 6)),
+                (Add, (4, 4, 6, 6)),
+            ]),
+            @"
+        aa..bb
+        aa..bb
+        ..cc..
+        ..cc..
+        dd..ee
+        dd..ee
+        "
+        );
+    }
+
+    #[test]
+    fn test_region_intersection() {
+        use RectangleKind::*;
+
+        // empty
+        assert_snapshot!(check_intersection(&[], &[(Add, (0, 0, 10, 10))]), @"");
+
+        // disjoint
+        assert_snapshot!(
+            check_intersection(&[(Add, (0, 0, 10, 10))], &[(Add, (20, 20, 30, 30))]),
+            @""
+        );
+
+        // partially_overlapping
+        assert_snapshot!(
+            check_intersection(&[(Add, (0, 0, 10, 10))], &[(Add, (5, 5, 15, 15))]),
+            @" 5  5 - 10 10"
+        );
+
+        // nested
+        assert_snapshot!(
+            check_intersection(
+                &[(Add, (0, 0, 20, 20)), (Subtract, (5, 5, 15, 15))],
+                &[(Add, (2, 2, 18, 18))],
+            ),
+            @"
+         2  2 - 18  5
+         2  5 -  5 15
+        15  5 - 18 15
+         2 15 - 18 18
+        "
+        );
+    }
+
+    #[test]
+    fn test_region_over_rect_limit_becomes_bbox() {
+        use RectangleKind::*;
+
+        // A comb of 1-px teeth, each of which is its own rect.
+        let teeth =
+            |n: i32| -> Vec<_> { (0..n).map(|i| (Add, (i * 2, 0, i * 2 + 1, 4))).collect() };
+
+        let mut output = Vec::new();
+        let n = MAX_NON_OVERLAPPING_RECTS as i32;
+        region_to_non_overlapping_rects(&region(&teeth(n)), &mut output);
+        assert_eq!(output.len(), MAX_NON_OVERLAPPING_RECTS);
+
+        region_to_non_overlapping_rects(&region(&teeth(n + 1)), &mut output);
+        assert_eq!(
+            output,
+            [Rectangle::from_extremities((0, 0), (n * 2 + 1, 4))]
+        );
+    }
+
+    #[test]
+    fn test_region_subtract() {
+        use RectangleKind::*;
+
+        // centered: leaves a frame around the hole
+        let mut output = Vec::new();
+        region_subtract(
+            &region(&[(Add, (0, 0, 20, 20))]),
+            &region(&[(Add, (5, 5, 15, 15))]),
+            &mut output,
+        );
+        let area: i32 = output.iter().map(|r| r.size.w * r.size.h).sum();
+        assert_eq!(area, 20 * 20 - 10 * 10);
+        assert_snapshot!(
+            format_rects(&output),
+            @"
+         0  0 - 20  5
+         0  5 -  5 15
+        15  5 - 20 15
+         0 15 - 20 20
+        "
+        );
+
+        // disjoint: nothing to subtract
+        assert_snapshot!(
+            check_subtract(&[(Add, (0, 0, 10, 10))], &[(Add, (20, 20, 30, 30))]),
+            @" 0  0 - 10 10"
+        );
+
+        // disjoint within the same band
+        assert_snapshot!(
+            check_subtract(&[(Add, (0, 0, 10, 10))], &[(Add, (20, 0, 30, 10))]),
+            @" 0  0 - 10 10"
+        );
+
+        // several holes within one band
+        assert_snapshot!(
+            check_subtract(
+                &[(Add, (0, 0, 20, 10))],
+                &[(Add, (2, 0, 4, 10)), (Add, (8, 0, 12, 10)), (Add, (18, 0, 25, 10))],
+            ),
+            @"
+         0  0 -  2 10
+         4  0 -  8 10
+        12  0 - 18 10
+        "
+        );
+
+        // everything subtracted
+        assert_snapshot!(
+            check_subtract(&[(Add, (5, 5, 10, 10))], &[(Add, (0, 0, 20, 20))]),
+            @""
+        );
+    }
+
+    #[test]
+    fn test_transformed_region_subtract() {
+        let region = TransformedRegion::new(
+            Arc::new(vec![Rectangle::from_size(Size::new(10, 10))]),
+            Scale::from(1.),
+            Point::new(0., 0.),
+        );
+
+        // Excluding the middle leaves a ring.
+        let ring = region.subtract(&[Rectangle::new(Point::new(2., 2.), Size::new(6., 6.))]);
+        assert_snapshot!(format_grid(&ring.rects), @r"
+        aaaaaaaaaa
+        aaaaaaaaaa
+        bb......cc
+        bb......cc
+        bb......cc
+        bb......cc
+        bb......cc
+        bb......cc
+        dddddddddd
+        dddddddddd
+        ");
+
+        // Excluded rects are converted to region coordinates and rounded inwards.
+        let region = TransformedRegion::new(
+            Arc::new(vec![Rectangle::from_size(Size::new(5, 5))]),
+            Scale::from(2.),
+            Point::new(10., 10.),
+        );
+        let ring = region.subtract(&[Rectangle::new(Point::new(11., 12.), Size::new(8., 6.))]);
+        assert_snapshot!(format_rects(&ring.rects), @r"
+         0  0 -  5  1
+         0  1 -  1  4
+         4  1 -  5  4
+         0  4 -  5  5
+        ");
+        assert_eq!(ring.scale, region.scale);
+        assert_eq!(ring.offset, region.offset);
+    }
+
+    #[test]
+    fn test_transformed_region_clipped() {
+        let region = TransformedRegion::new(
+            Arc::new(vec![
+                Rectangle::new(Point::new(-1000, -1000), Size::new(2000, 1005)),
+                Rectangle::new(Point::new(0, 5), Size::new(5, 5)),
+                Rectangle::new(Point::new(20, 20), Size::new(5, 5)),
+            ]),
+            Scale::from(2.),
+            Point::new(10., 10.),
+        );
+
+        // The clip is converted to region coordinates and rounded outwards, and rects outside of
+        // it are dropped.
+        let clipped = region
+            .clone()
+            .clipped(Rectangle::new(Point::new(11., 10.), Size::new(8., 30.)));
+        assert_snapshot!(format_rects(&clipped.rects), @r"
+         0  0 -  5  5
+         0  5 -  5 10
+        ");
+        // The stored bounding box follows the clipped rects.
+        assert_eq!(
+            clipped.bbox(),
+            Some(Rectangle::new(Point::new(10., 10.), Size::new(10., 20.)))
+        );
+        assert_eq!(clipped.scale, region.scale);
+        assert_eq!(clipped.offset, region.offset);
+
+        // Nothing to clip keeps the same rects.
+        let clipped = region.clone().clipped(Rectangle::new(
+            Point::new(-3000., -3000.),
+            Size::new(6000., 6000.),
+        ));
+        assert!(Arc::ptr_eq(&clipped.rects, &region.rects));
+    }
+
+    #[test]
+    fn test_transformed_region_simplified() {
+        // A checkerboard decomposes into a rect per filled cell.
+        let mut cells = Vec::new();
+        for y in 0..6 {
+            for x in 0..6 {
+                if (x + y) % 2 == 0 {
+                    cells.push((RectangleKind::Add, (x, y, x + 1, y + 1)));
+                }
+            }
+        }
+        let mut rects = Vec::new();
+        region_to_non_overlapping_rects(&region(&cells), &mut rects);
+        assert_eq!(rects.len(), 18);
+
+        let region = TransformedRegion::new(Arc::new(rects), Scale::from(2.), Point::new(10., 10.));
+
+        // At or below the threshold, the rects stay precise.
+        let simplified = region.clone().simplified(18);
+        assert_eq!(simplified.rects.len(), 18);
+        let simplified = region.clone().simplified(0);
+        assert_eq!(simplified.rects.len(), 18);
+
+        // Above it, they collapse to the bounding box.
+        let simplified = region.clone().simplified(8);
+        assert_snapshot!(format_rects(&simplified.rects), @r"
+         0  0 -  6  6
+        ");
+        assert_eq!(simplified.scale, region.scale);
+        assert_eq!(simplified.offset, region.offset);
+    }
+
+    #[test]
+    fn filter_damage_contained_crop_matches_general_path() {
+        let region = TransformedRegion::new(
+            Arc::new(vec![
+                Rectangle::new(Point::new(0, 0), Size::new(10, 5)),
+                Rectangle::new(Point::new(0, 5), Size::new(3, 5)),
+                Rectangle::new(Point::new(7, 5), Size::new(3, 5)),
+            ]),
+            Scale::from(1.5),
+            Point::new(2.25, 3.5),
+        );
+
+        let crop = Rectangle::new(Point::new(1., 2.), Size::new(20., 20.));
+        let dst = Rectangle::new(Point::new(10, 10), Size::new(25, 25));
+        let damage = [
+            Rectangle::new(Point::new(0, 0), Size::new(8, 8)),
+            Rectangle::new(Point::new(5, 6), Size::new(20, 3)),
+            Rectangle::new(Point::new(12, 0), Size::new(13, 25)),
+        ];
+
+        let mut fast = Vec::new();
+        region.filter_damage(crop, dst, &damage, &mut fast);
+
+        let mut general = Vec::new();
+        region.filter_damage_inner(crop, dst, &damage, &mut general, true);
+
+        assert!(!fast.is_empty());
+        assert_eq!(fast, general);
+    }
+
     proptest! {
         #[test]
         fn non_overlapping_output(
@@ -316,5 +1147,85 @@ mod tests {
                 }
             }
         }
+
+        #[test]
+        fn decomposition_matches_cells(
+            rects in proptest::collection::vec(
+                (
+                    prop_oneof![Just(RectangleKind::Add), Just(RectangleKind::Subtract)],
+                    // Coarse coordinates to make coincident edges common.
+                    (0..4i32, 0..4i32, 0..4i32, 0..4i32),
+                ),
+                1..10,
+            )
+        ) {
+            let region = RegionAttributes {
+                rects: rects
+                    .into_iter()
+                    .map(|(kind, (x, y, w, h))| {
+                        (kind, Rectangle::new(Point::new(x * 2, y * 2), Size::new(w * 2, h * 2)))
+                    })
+                    .collect(),
+            };
+
+            let mut expected = HashSet::new();
+            for (kind, r) in &region.rects {
+                let cells = covered_cells(&[*r]);
+                match kind {
+                    RectangleKind::Add => expected.extend(cells),
+                    RectangleKind::Subtract => expected.retain(|cell| !cells.contains(cell)),
+                }
+            }
+
+            let mut output = Vec::new();
+            region_to_non_overlapping_rects(&region, &mut output);
+            prop_assert_eq!(covered_cells(&output), expected);
+
+            // Spans touching within a band are always merged.
+            for a in &output {
+                for b in &output {
+                    prop_assert!(
+                        !(a.loc.y == b.loc.y && a.loc.x + a.size.w == b.loc.x),
+                        "unmerged adjacent rects: {:?} and {:?}",
+                        a,
+                        b,
+                    );
+                }
+            }
+        }
+
+        #[test]
+        fn round_trip_through_region(region in arbitrary_region()) {
+            let mut first: Vec<Rectangle<i32, Logical>> = Vec::new();
+            region_to_non_overlapping_rects(&region, &mut first);
+
+            let recomposed = rects_to_region(&first);
+            prop_assert!(recomposed
+                .rects
+                .iter()
+                .all(|(kind, _)| matches!(kind, RectangleKind::Add)));
+
+            let mut second = Vec::new();
+            region_to_non_overlapping_rects(&recomposed, &mut second);
+
+            prop_assert_eq!(covered_cells(&first), covered_cells(&second));
+        }
+
+        #[test]
+        fn intersection_matches_cells(a in arbitrary_region(), b in arbitrary_region()) {
+            let mut rects_a = Vec::new();
+            region_to_non_overlapping_rects(&a, &mut rects_a);
+            let mut rects_b = Vec::new();
+            region_to_non_overlapping_rects(&b, &mut rects_b);
+
+            let mut output = Vec::new();
+            region_intersection(&a, &b, &mut output);
+
+            let expected: HashSet<_> = covered_cells(&rects_a)
+                .intersection(&covered_cells(&rects_b))
+                .copied()
+                .collect();
+            prop_assert_eq!(covered_cells(&output), expected);
+        }
     }
 }
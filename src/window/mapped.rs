@@ -28,7 +28,7 @@ use crate::layout::{
     LayoutElementRenderSnapshot, SizingMode,
 };
 use crate::niri_render_elements;
-use crate::render_helpers::background_effect::BackgroundEffectElement;
+use crate::render_helpers::background_effect::{BackgroundEffectElement, TileParams};
 use crate::render_helpers::border::BorderRenderElement;
 use crate::render_helpers::offscreen::OffscreenData;
 use crate::render_helpers::renderer::NiriRenderer;
@@ -718,22 +718,26 @@ impl LayoutElement for Mapped {
                 effect.xray = Some(false);
             }
             let xray_pos = xray_pos.offset(offset.to_f64());
-            background_effect::render_for_tile(
-                ctx.as_gles(),
-                None,
+            let tile = TileParams {
                 geometry,
-                scale.x,
-                false,
-                surface,
+                visible: None,
+                scale: scale.x,
+                clip_to_geometry: false,
                 surface_off,
                 surface_anim_scale,
-                self.blur_config,
-                popup_rules.geometry_corner_radius.unwrap_or_default(),
+                blur_config: self.blur_config,
+                radius: popup_rules.geometry_corner_radius.unwrap_or_default(),
                 effect,
-                false,
+                should_block_out: false,
+                alpha: 1.,
+                resizing: false,
+                focused: self.is_focused(),
                 xray_pos,
-                &mut |elem| push(elem.into()),
-            );
+                velocity: Point::new(0., 0.),
+            };
+            background_effect::render_for_tile(ctx.as_gles(), None, surface, tile, &mut |elem| {
+                push(elem.into())
+            });
         }
     }
 
@@ -741,30 +745,36 @@ impl LayoutElement for Mapped {
         &self,
         ctx: RenderCtx<GlesRenderer>,
         geometry: Rectangle<f64, Logical>,
+        visible: Option<Rectangle<f64, Logical>>,
         scale: f64,
         clip_to_geometry: bool,
         surface_anim_scale: Scale<f64>,
         radius: CornerRadius,
+        alpha: f32,
         xray_pos: XrayPos,
+        velocity: Point<f64, Logical>,
         push: &mut dyn FnMut(BackgroundEffectElement),
     ) {
         let should_block_out = ctx.target.should_block_out(self.rules.block_out_from);
-        background_effect::render_for_tile(
-            ctx,
-            None,
+        let tile = TileParams {
             geometry,
+            visible,
             scale,
             clip_to_geometry,
-            self.toplevel().wl_surface(),
-            self.buf_loc().to_f64(),
+            surface_off: self.buf_loc().to_f64(),
             surface_anim_scale,
-            self.blur_config,
+            blur_config: self.blur_config,
             radius,
-            self.rules.background_effect,
+            effect: self.rules.background_effect,
             should_block_out,
+            alpha,
+            resizing: self.interactive_resize.is_some(),
+            focused: self.is_focused(),
             xray_pos,
-            push,
-        );
+            velocity,
+        };
+        let surface = self.toplevel().wl_surface();
+        background_effect::render_for_tile(ctx, None, surface, tile, push);
     }
 
     fn request_size(